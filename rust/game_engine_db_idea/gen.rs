@@ -1,3 +1,11 @@
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Color {
+    Red = 0,
+    Green = 1,
+    Blue = 2,
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
 pub struct Vec3f {
@@ -6,4 +14,69 @@ pub struct Vec3f {
     pub z: f32,
 }
 
-pub const UUID: u128 = 0x20000000000000000000000000000001;
+pub type Segment = [Vec3f; 2];
+
+/// Handle to `Vec3f`s stored out-of-line, in the `VecHeap` of the map holding the instance.
+pub type Path = u32;
+
+#[repr(C, u32)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Shape {
+    Nothing = 0,
+    Point(Vec3f) = 1,
+    Segment(Segment) = 2,
+    Path(Path) = 3,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union Bits {
+    pub f: f32,
+    pub u: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Entity {
+    pub visible: bool,
+    pub color: Color,
+    pub shape: Shape,
+    pub initial: char,
+    pub owner: u128,
+    pub bits: Bits,
+}
+
+pub const UUID: u128 = 0x20000000000000000000000000000010;
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+    use std::mem::{align_of, size_of};
+
+    #[test]
+    fn layout_matches_db() {
+        assert_eq!(size_of::<Color>(), 4);
+        assert_eq!(align_of::<Color>(), 4);
+        assert_eq!(size_of::<Vec3f>(), 12);
+        assert_eq!(align_of::<Vec3f>(), 4);
+        assert_eq!(::std::mem::offset_of!(Vec3f, x), 0);
+        assert_eq!(::std::mem::offset_of!(Vec3f, y), 4);
+        assert_eq!(::std::mem::offset_of!(Vec3f, z), 8);
+        assert_eq!(size_of::<Segment>(), 24);
+        assert_eq!(align_of::<Segment>(), 4);
+        assert_eq!(size_of::<Path>(), 4);
+        assert_eq!(align_of::<Path>(), 4);
+        assert_eq!(size_of::<Shape>(), 28);
+        assert_eq!(align_of::<Shape>(), 4);
+        assert_eq!(size_of::<Bits>(), 4);
+        assert_eq!(align_of::<Bits>(), 4);
+        assert_eq!(size_of::<Entity>(), 80);
+        assert_eq!(align_of::<Entity>(), 16);
+        assert_eq!(::std::mem::offset_of!(Entity, visible), 0);
+        assert_eq!(::std::mem::offset_of!(Entity, color), 4);
+        assert_eq!(::std::mem::offset_of!(Entity, shape), 8);
+        assert_eq!(::std::mem::offset_of!(Entity, initial), 36);
+        assert_eq!(::std::mem::offset_of!(Entity, owner), 48);
+        assert_eq!(::std::mem::offset_of!(Entity, bits), 64);
+    }
+}
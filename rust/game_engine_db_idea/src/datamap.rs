@@ -45,6 +45,19 @@ pub struct DenseDataMap {
     ofni: Vec<u32>, // Indexed by Info::index(). Retrieves an info index.
     info: Vec<Info>, // Indexed by K::index().
     free: Vec<u32>, // List of info indices which were used at some point but are now available.
+    heap: VecHeap, // Out-of-line storage for the unsized parts of items.
+}
+
+/// Out-of-line storage for unsized data (i.e VECs) that items refer to by handle, since items themselves must all
+/// be the same size.
+///
+/// Handle 0 is the empty buffer, so that zeroed memory is a valid, empty VEC.
+/// The heap knows nothing about the layout of items, so buffers must be removed explicitly (see
+/// `DB::drop_instance`). Copying an item's bytes does not copy its buffers either.
+#[derive(Debug, Default, Clone, Hash, PartialEq, Eq)]
+pub struct VecHeap {
+    bufs: Vec<Option<Vec<u8>>>, // Indexed by handle - 1.
+    free: Vec<u32>, // List of handles which were used at some point but are now available.
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -108,6 +121,7 @@ impl DenseDataMap {
             ofni: Vec::with_capacity(cap),
             info: Vec::with_capacity(cap),
             free: Vec::with_capacity(free_cap),
+            heap: VecHeap::new(),
         }
     }
     #[inline]
@@ -119,6 +133,10 @@ impl DenseDataMap {
         assert_eq!(self.pool.len() % self.item_size, 0);
         self.pool.len() / self.item_size
     }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pool.is_empty()
+    }
     pub fn insert_uninitialized(&mut self) -> (K, &mut [u8]) {
         let i = self.len();
         let (info_i, info) = match self.free.pop() {
@@ -141,6 +159,12 @@ impl DenseDataMap {
         self.ofni.push(info_i as _);
         (K::with_index_and_generation(info_i, info.generation()), &mut self[i])
     }
+    /// Like `insert_uninitialized`, but also lends the heap, for initializing items that own buffers.
+    pub fn insert_uninitialized_with_heap(&mut self) -> (K, &mut [u8], &mut VecHeap) {
+        let k = self.insert_uninitialized().0;
+        let r = self.items_range(self.len() - 1 .. self.len());
+        (k, &mut self.pool[r], &mut self.heap)
+    }
     pub fn insert_zeroed(&mut self) -> K {
         let (k, mem) = self.insert_uninitialized();
         unsafe {
@@ -160,11 +184,14 @@ impl DenseDataMap {
             }
         }
     }
+    /// # Safety
+    ///
+    /// `k` must be contained in this map.
     pub unsafe fn remove_unchecked(&mut self, k: K) {
         self.remove_with_info_i(k.index())
     }
     pub fn swap_remove(&mut self, i: usize) {
-        let info_i = self.ofni[i as usize];
+        let info_i = self.ofni[i];
         unsafe {
             self.remove_with_info_i(info_i as _)
         }
@@ -174,6 +201,7 @@ impl DenseDataMap {
         let i = self.info[info_i].index();
         self.swap_remove_in_pool(i);
         self.info[self.ofni[last_i] as usize].set_index(i);
+        self.ofni.swap_remove(i);
         self.info[info_i].make_vacant();
         self.free.push(info_i as _);
     }
@@ -194,10 +222,16 @@ impl DenseDataMap {
             Some(info) => info.is_occupied() && info.generation() == k.generation(),
         }
     }
+    /// # Safety
+    ///
+    /// `k` must be contained in this map.
     #[inline]
     pub unsafe fn get_unchecked(&self, k: K) -> &[u8] {
         &self[self.info[k.index()].index()]
     }
+    /// # Safety
+    ///
+    /// `k` must be contained in this map.
     #[inline]
     pub unsafe fn get_unchecked_mut(&mut self, k: K) -> &mut [u8] {
         let i = self.info[k.index()].index();
@@ -217,6 +251,26 @@ impl DenseDataMap {
             None
         }
     }
+    pub fn get_mut_with_heap(&mut self, k: K) -> Option<(&mut [u8], &mut VecHeap)> {
+        if !self.contains_key(k) {
+            return None;
+        }
+        let i = self.info[k.index()].index();
+        let r = self.items_range(i .. i+1);
+        Some((&mut self.pool[r], &mut self.heap))
+    }
+    #[inline]
+    pub fn item_size(&self) -> usize {
+        self.item_size
+    }
+    #[inline]
+    pub fn heap(&self) -> &VecHeap {
+        &self.heap
+    }
+    #[inline]
+    pub fn heap_mut(&mut self) -> &mut VecHeap {
+        &mut self.heap
+    }
     pub fn key_at(&self, i: usize) -> K {
         let info_i = self.ofni[i] as usize;
         K::with_index_and_generation(info_i, self.info[info_i].generation())
@@ -224,6 +278,50 @@ impl DenseDataMap {
 }
 
 
+impl VecHeap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Number of live buffers.
+    pub fn len(&self) -> usize {
+        self.bufs.len() - self.free.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Returns the handle to `buf`, which is 0 if it is empty.
+    pub fn insert(&mut self, buf: Vec<u8>) -> u32 {
+        if buf.is_empty() {
+            return 0;
+        }
+        match self.free.pop() {
+            Some(h) => {
+                self.bufs[h as usize - 1] = Some(buf);
+                h
+            },
+            None => {
+                self.bufs.push(Some(buf));
+                self.bufs.len() as _
+            },
+        }
+    }
+    pub fn get(&self, h: u32) -> &[u8] {
+        match h {
+            0 => &[],
+            _ => self.bufs[h as usize - 1].as_ref().expect("use of a removed VEC handle"),
+        }
+    }
+    pub fn remove(&mut self, h: u32) -> Vec<u8> {
+        if h == 0 {
+            return Vec::new();
+        }
+        let buf = self.bufs[h as usize - 1].take().expect("VEC handle removed twice");
+        self.free.push(h);
+        buf
+    }
+}
+
+
 impl K {
     #[inline] pub fn with_index(i: usize) -> Self { Self::with_index_and_generation(i, 0) }
     #[inline] pub fn with_index_and_generation(index: usize, generation: u32) -> Self { Self { index: index as _, generation } }
@@ -249,19 +347,19 @@ impl Info {
 
 
 impl DenseDataMap {
-    pub fn iter(&self) -> Iter {
+    pub fn iter(&self) -> Iter<'_> {
         Iter::new(self)
     }
-    pub fn iter_mut(&mut self) -> IterMut {
+    pub fn iter_mut(&mut self) -> IterMut<'_> {
         IterMut::new(self)
     }
-    pub fn keys(&self) -> Keys {
+    pub fn keys(&self) -> Keys<'_> {
         Keys::new(self)
     }
-    pub fn values(&self) -> Values {
+    pub fn values(&self) -> Values<'_> {
         Values::new(self)
     }
-    pub fn values_mut(&mut self) -> ValuesMut {
+    pub fn values_mut(&mut self) -> ValuesMut<'_> {
         ValuesMut::new(self)
     }
 }
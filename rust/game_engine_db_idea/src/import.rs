@@ -0,0 +1,421 @@
+//! Reading back the `db.ini` format which `DB::export` writes.
+//!
+//! Each `[uuid]` section describes one id, with optional `name`, `struct`, `type`, `elem`, `offset`, `len`,
+//! `discriminant`, `size` and `align` keys.
+//! `struct`, `type` and `elem` refer to other ids by uuid, and may point to sections further down the file.
+//! Blank lines and `#` comments are ignored.
+//! UNION members which own VECs are rejected, since these VECs could never be freed.
+//! Layouts are checked too, since instances are read and written through them: every type must have a `size` and
+//! an `align`, every STRUCT field an `offset`, and all three must match what `DB::update_layouts` computes.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+use super::{db, DB};
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    /// A line which isn't a section header, a known `key = value` pair, a comment or blank.
+    Syntax { line: usize, msg: String },
+    /// A `struct`, `type` or `elem` key refers to a uuid which has no section.
+    DanglingUuid { line: usize, key: &'static str, uuid: u128 },
+    /// A well-formed line which describes an invalid schema, e.g a UNION member which owns VECs.
+    Invalid { line: usize, msg: String },
+}
+
+impl ImportError {
+    /// 1-based line number the error is about, if any.
+    pub fn line(&self) -> Option<usize> {
+        match *self {
+            ImportError::Io(_) => None,
+            ImportError::Syntax { line, .. } | ImportError::DanglingUuid { line, .. } | ImportError::Invalid { line, .. } => Some(line),
+        }
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ImportError::Io(ref e) => write!(f, "{}", e),
+            ImportError::Syntax { line, ref msg } | ImportError::Invalid { line, ref msg } => write!(f, "line {}: {}", line, msg),
+            ImportError::DanglingUuid { line, key, uuid } => write!(f, "line {}: `{}` refers to {:#x}, which has no section", line, key, uuid),
+        }
+    }
+}
+
+impl Error for ImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ImportError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+static KEYS: [&str; 9] = ["name", "struct", "type", "elem", "offset", "len", "discriminant", "size", "align"];
+
+impl DB {
+    /// Parses what `export` wrote. Uuids of reserved ids (e.g primitive types) get their reserved id back, so that
+    /// `db::F32` and friends keep working on the result.
+    pub fn import<R: BufRead>(r: R) -> Result<Self, ImportError> {
+        let mut db = DB::new();
+        let mut section = None;
+        let mut seen_keys = HashSet::new();
+        let mut refs = Vec::new(); // (line, key, id, uuid), resolved once all sections are known.
+        let mut lines = Lines::default();
+
+        for (i, line) in r.lines().enumerate() {
+            let line_nb = i + 1;
+            let line = line?;
+            let line = line.trim();
+            let err = |msg: String| ImportError::Syntax { line: line_nb, msg };
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(err(format!("unterminated section header `{}`", line)));
+                }
+                let uuid = parse_uuid(line[1 .. line.len()-1].trim()).map_err(err)?;
+                if db.uuid_reverse.contains_key(&uuid) {
+                    return Err(err(format!("section {:#x} appears twice", uuid)));
+                }
+                let id = match db::reserved_id_of_uuid(uuid) {
+                    Some(id) => {
+                        db.uuid.insert(id, uuid);
+                        db.uuid_reverse.insert(uuid, id);
+                        id
+                    },
+                    None => db.add_new_uuid(uuid),
+                };
+                section = Some(id);
+                seen_keys.clear();
+                lines.sections.insert(id, line_nb);
+                continue;
+            }
+
+            let id = section.ok_or_else(|| err(format!("`{}` is outside of any section", line)))?;
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[.. i].trim(), line[i+1 ..].trim()),
+                None => return Err(err(format!("expected `[uuid]` or `key = value`, found `{}`", line))),
+            };
+            let key = *KEYS.iter().find(|k| **k == key).ok_or_else(|| err(format!("unknown key `{}`", key)))?;
+            if !seen_keys.insert(key) {
+                return Err(err(format!("`{}` is set twice", key)));
+            }
+            lines.keys.insert((id, key), line_nb);
+            match key {
+                "name" => { db.name.insert(id, parse_string(value).map_err(err)?); },
+                "struct" | "type" | "elem" => refs.push((line_nb, key, id, parse_uuid(value).map_err(err)?)),
+                "offset" => { db.offset.insert(id, parse_integer(value).map_err(err)?); },
+                "len" => { db.len.insert(id, parse_integer(value).map_err(err)?); },
+                "discriminant" => { db.discriminant.insert(id, parse_integer(value).map_err(err)?); },
+                "size" => { db.size.insert(id, parse_integer(value).map_err(err)?); },
+                "align" => {
+                    let align: usize = parse_integer(value).map_err(err)?;
+                    if !align.is_power_of_two() {
+                        return Err(err(format!("alignment {} is not a power of two", align)));
+                    }
+                    db.align.insert(id, align);
+                },
+                _ => unreachable!{},
+            }
+        }
+
+        for &(line, key, id, uuid) in &refs {
+            let target = *db.uuid_reverse.get(&uuid).ok_or(ImportError::DanglingUuid { line, key, uuid })?;
+            match key {
+                "struct" => db.struct_.insert(id, target),
+                "type" => db.type_.insert(id, target),
+                _ => db.elem.insert(id, target),
+            };
+        }
+        check_layouts(&db, &lines)?;
+        // VECs owned by UNION members could never be freed, see `DB::drop_instance`.
+        for &(line, key, id, _) in &refs {
+            let owner = match db.struct_.get(&id) {
+                Some(&owner) if key == "type" && db.kind_of(owner) == db::UNION => owner,
+                _ => continue,
+            };
+            if db.owns_vecs(db.type_[&id]) {
+                let msg = format!("UNION member `{}::{}` owns VECs", db.name.get(&owner).map_or("?", |s| s), db.name.get(&id).map_or("?", |s| s));
+                return Err(ImportError::Invalid { line, msg });
+            }
+        }
+        Ok(db)
+    }
+}
+
+#[derive(Default)]
+struct Lines {
+    sections: HashMap<u32, usize>,
+    keys: HashMap<(u32, &'static str), usize>,
+}
+
+impl Lines {
+    // The line of `key` in the section of `id`, or the section header if `key` isn't there.
+    fn of(&self, id: u32, key: &'static str) -> usize {
+        self.keys.get(&(id, key)).cloned().unwrap_or(self.sections[&id])
+    }
+}
+
+// The name of `id`, qualified by its owner for members, or its uuid if it has no name.
+fn display_name(db: &DB, id: u32) -> String {
+    let name = db.name.get(&id).cloned().unwrap_or_else(|| format!("{:#x}", db.uuid[&id]));
+    match db.struct_.get(&id) {
+        Some(&owner) => format!("{}::{}", display_name(db, owner), name),
+        None => name,
+    }
+}
+
+// Checks, in file order, that everything `DB::update_layouts` relies on is there, then that the imported layouts
+// are the ones it computes.
+fn check_layouts(db: &DB, lines: &Lines) -> Result<(), ImportError> {
+    let invalid = |id: u32, key: &'static str, msg: String| ImportError::Invalid { line: lines.of(id, key), msg };
+    let referenced: HashSet<u32> = db.struct_.keys().filter_map(|m| db.type_.get(m)).chain(db.elem.values()).cloned().collect();
+    let is_type = |id: u32| referenced.contains(&id) || (id >= db::HIGHEST_RESERVED_ID_EXCLUSIVE && !db.struct_.contains_key(&id));
+    let mut ids: Vec<u32> = lines.sections.keys().cloned().collect();
+    ids.sort_by_key(|id| lines.sections[id]);
+
+    for &id in &ids {
+        let name = || display_name(db, id);
+        if let Some(&owner) = db.struct_.get(&id) {
+            let kind = db.kind_of(owner);
+            if (kind == db::STRUCT || kind == db::UNION) && !db.type_.contains_key(&id) {
+                return Err(invalid(id, "type", format!("member `{}` has no `type`", name())));
+            }
+            if kind == db::STRUCT && !db.offset.contains_key(&id) {
+                return Err(invalid(id, "offset", format!("field `{}` has no `offset`", name())));
+            }
+        }
+        if !is_type(id) {
+            continue;
+        }
+        if id >= db::HIGHEST_RESERVED_ID_EXCLUSIVE {
+            let kind = db.kind_of(id);
+            if !(db::ARRAY ..= db::VEC).contains(&kind) {
+                return Err(invalid(id, "type", format!("`{}` is of kind `{}`, which is not a kind of type", name(), display_name(db, kind))));
+            }
+            if (kind == db::ARRAY || kind == db::VEC) && !db.elem.contains_key(&id) {
+                return Err(invalid(id, "elem", format!("`{}` has no `elem`", name())));
+            }
+            if kind == db::ARRAY && !db.len.contains_key(&id) {
+                return Err(invalid(id, "len", format!("`{}` has no `len`", name())));
+            }
+        }
+        if !db.size.contains_key(&id) {
+            return Err(invalid(id, "size", format!("`{}` has no `size`", name())));
+        }
+        if !db.align.contains_key(&id) {
+            return Err(invalid(id, "align", format!("`{}` has no `align`", name())));
+        }
+    }
+
+    let mut laid_out = db.clone();
+    for &id in ids.iter().filter(|&&id| db::is_scalar(id)) {
+        laid_out.size.insert(id, db::size_of_scalar(id));
+        laid_out.align.insert(id, db::align_of_scalar(id));
+    }
+    laid_out.update_layouts();
+    for &id in &ids {
+        let mismatch = |key: &'static str, imported: usize, computed: usize| {
+            invalid(id, key, format!("`{}` has {} {}, but its layout gives {}", display_name(db, id), key, imported, computed))
+        };
+        for &(key, imported, computed) in &[("offset", &db.offset, &laid_out.offset), ("size", &db.size, &laid_out.size), ("align", &db.align, &laid_out.align)] {
+            if let (Some(&imported), Some(&computed)) = (imported.get(&id), computed.get(&id)) {
+                if imported != computed {
+                    return Err(mismatch(key, imported, computed));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_uuid(s: &str) -> Result<u128, String> {
+    let hex = s.strip_prefix("0x").ok_or_else(|| format!("expected a uuid such as `0x2a`, found `{}`", s))?;
+    u128::from_str_radix(hex, 16).map_err(|e| format!("invalid uuid `{}`: {}", s, e))
+}
+
+fn parse_string(s: &str) -> Result<String, String> {
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return Err(format!("expected a quoted string, found `{}`", s));
+    }
+    Ok(s[1 .. s.len()-1].to_owned())
+}
+
+fn parse_integer<T: FromStr>(s: &str) -> Result<T, String> where T::Err: fmt::Display {
+    s.parse().map_err(|e| format!("invalid integer `{}`: {}", s, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{builtin_db, db, DB, VEC3F_UUID};
+    use super::ImportError;
+
+    const X: u128 = 0x20000000000000000000000000000002;
+    const Y: u128 = 0x20000000000000000000000000000003;
+    const SEGMENT_UUID: u128 = 0x20000000000000000000000000000030;
+
+    fn import(s: &str) -> Result<DB, ImportError> {
+        DB::import(s.as_bytes())
+    }
+    fn invalid(s: &str) -> (usize, String) {
+        match import(s) {
+            Err(ImportError::Invalid { line, msg }) => (line, msg),
+            Err(e) => panic!("expected an invalid schema, got `{}`", e),
+            Ok(_) => panic!("expected an invalid schema"),
+        }
+    }
+    // `db.ini` for the builtin database, with `key` set to `value` (or removed) in the section of `uuid`. Also returns
+    // the line of that key, or of the section header if it was removed.
+    fn edited_builtin_db(uuid: u128, key: &str, value: Option<&str>) -> (String, usize) {
+        let mut exported = Vec::new();
+        builtin_db().export(&mut exported).unwrap();
+        let mut lines: Vec<String> = String::from_utf8(exported).unwrap().lines().map(str::to_owned).collect();
+        let section = lines.iter().position(|l| *l == format!("[{:#x}]", uuid)).unwrap();
+        let end = section + lines[section ..].iter().position(|l| l.is_empty()).unwrap();
+        let i = lines[section .. end].iter().position(|l| l.starts_with(&format!("{} = ", key))).map(|i| section + i);
+        let line = match (i, value) {
+            (Some(i), Some(value)) => {
+                lines[i] = format!("{} = {}", key, value);
+                i + 1
+            },
+            (None, Some(value)) => {
+                lines.insert(section + 1, format!("{} = {}", key, value));
+                section + 2
+            },
+            (Some(i), None) => {
+                lines.remove(i);
+                section + 1
+            },
+            (None, None) => panic!("{:#x} has no `{}`", uuid, key),
+        };
+        (lines.join("\n"), line)
+    }
+    fn syntax_error_line(s: &str) -> usize {
+        match import(s) {
+            Err(ImportError::Syntax { line, .. }) => line,
+            Err(e) => panic!("expected a syntax error, got `{}`", e),
+            Ok(_) => panic!("expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn syntax_errors_have_line_numbers() {
+        let header = "# Comment\n\n[0x20000000000000000000000000000001]\nname = \"A\"\n";
+        assert_eq!(syntax_error_line(&format!("{}what is this\n", header)), 5);
+        assert_eq!(syntax_error_line(&format!("{}colour = 3\n", header)), 5);
+        assert_eq!(syntax_error_line(&format!("{}\nsize = -3\n", header)), 6);
+        assert_eq!(syntax_error_line(&format!("{}align = 3\n", header)), 5);
+        assert_eq!(syntax_error_line(&format!("{}[0x20000000000000000000000000000002\n", header)), 5);
+        assert_eq!(syntax_error_line(&format!("{}[20000000000000000000000000000002]\n", header)), 5);
+        assert_eq!(syntax_error_line("size = 4\n"), 1);
+        let e = import(&format!("{}name = B\n", header)).err().unwrap();
+        assert_eq!(e.line(), Some(5));
+        assert!(e.to_string().starts_with("line 5: "), "{}", e);
+    }
+
+    #[test]
+    fn duplicates_are_errors() {
+        let section = "[0x20000000000000000000000000000001]\nname = \"A\"\n";
+        assert_eq!(syntax_error_line(&format!("{}\n{}", section, section)), 4);
+        assert_eq!(syntax_error_line(&format!("{}size = 4\nname = \"B\"\n", section)), 4);
+    }
+
+    #[test]
+    fn dangling_uuids_are_errors() {
+        let s = "[0x20000000000000000000000000000001]\nname = \"A\"\n\n[0x20000000000000000000000000000002]\nname = \"a\"\nstruct = 0x20000000000000000000000000000001\ntype = 0x20000000000000000000000000000009\n";
+        match import(s) {
+            Err(ImportError::DanglingUuid { line, key, uuid }) => {
+                assert_eq!((line, key, uuid), (7, "type", 0x20000000000000000000000000000009));
+            },
+            Err(e) => panic!("expected a dangling uuid, got `{}`", e),
+            Ok(_) => panic!("expected a dangling uuid"),
+        }
+        // References may point further down the file
+        let s = format!(
+            "[0x20000000000000000000000000000002]\nstruct = 0x20000000000000000000000000000001\n\n[0x20000000000000000000000000000001]\ntype = {:#x}\nsize = 4\nalign = 4\n\n[{:#x}]\n",
+            db::uuid_of_reserved(db::ENUM), db::uuid_of_reserved(db::ENUM),
+        );
+        let db = import(&s).unwrap();
+        assert_eq!(db.struct_[&db.uuid_reverse[&0x20000000000000000000000000000002]], db.uuid_reverse[&0x20000000000000000000000000000001]);
+    }
+
+    #[test]
+    fn union_members_cannot_own_vecs() {
+        let mut db = builtin_db();
+        let path = db.uuid_reverse[&0x20000000000000000000000000000040];
+        let u = db.add_type(0x20000000000000000000000000000080, "PathOrNot", db::UNION);
+        db.add_member(u, 0x20000000000000000000000000000081, "path", Some(path));
+        db.update_layouts();
+        let mut exported = Vec::new();
+        db.export(&mut exported).unwrap();
+        let exported = String::from_utf8(exported).unwrap();
+        let section = exported.lines().position(|l| l == "[0x20000000000000000000000000000081]").unwrap();
+        let line = section + exported.lines().skip(section).position(|l| l.starts_with("type = ")).unwrap() + 1;
+        match import(&exported) {
+            Err(ImportError::Invalid { line: l, msg }) => {
+                assert_eq!(l, line);
+                assert_eq!(msg, "UNION member `PathOrNot::path` owns VECs");
+            },
+            Err(e) => panic!("expected an invalid schema, got `{}`", e),
+            Ok(_) => panic!("expected an invalid schema"),
+        }
+    }
+
+    #[test]
+    fn missing_layout_keys_are_errors() {
+        let (s, line) = edited_builtin_db(X, "type", None);
+        assert_eq!(invalid(&s), (line, "member `Vec3f::x` has no `type`".to_owned()));
+        let (s, line) = edited_builtin_db(X, "offset", None);
+        assert_eq!(invalid(&s), (line, "field `Vec3f::x` has no `offset`".to_owned()));
+        let (s, line) = edited_builtin_db(VEC3F_UUID, "size", None);
+        assert_eq!(invalid(&s), (line, "`Vec3f` has no `size`".to_owned()));
+        let (s, line) = edited_builtin_db(db::uuid_of_primitive(db::F32), "align", None);
+        assert_eq!(invalid(&s), (line, "`f32` has no `align`".to_owned()));
+        let (s, line) = edited_builtin_db(SEGMENT_UUID, "elem", None);
+        assert_eq!(invalid(&s), (line, "`Segment` has no `elem`".to_owned()));
+        let (s, line) = edited_builtin_db(VEC3F_UUID, "type", Some(&format!("{:#x}", db::uuid_of_primitive(db::F32))));
+        assert_eq!(invalid(&s), (line, "`Vec3f` is of kind `f32`, which is not a kind of type".to_owned()));
+    }
+
+    #[test]
+    fn layouts_must_match() {
+        let (s, line) = edited_builtin_db(VEC3F_UUID, "size", Some("16"));
+        assert_eq!(invalid(&s), (line, "`Vec3f` has size 16, but its layout gives 12".to_owned()));
+        let (s, line) = edited_builtin_db(Y, "offset", Some("8"));
+        assert_eq!(invalid(&s), (line, "`Vec3f::y` has offset 8, but its layout gives 4".to_owned()));
+        let (s, line) = edited_builtin_db(db::uuid_of_primitive(db::F32), "size", Some("8"));
+        assert_eq!(invalid(&s), (line, "`f32` has size 8, but its layout gives 4".to_owned()));
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let db = builtin_db();
+        let mut exported = Vec::new();
+        db.export(&mut exported).unwrap();
+        let imported = DB::import(&exported[..]).unwrap();
+        let mut reexported = Vec::new();
+        imported.export(&mut reexported).unwrap();
+        assert!(String::from_utf8(exported).unwrap() == String::from_utf8(reexported).unwrap());
+
+        // Reserved ids are kept, user ids may not be
+        assert_eq!(imported.uuid_reverse[&db::uuid_of_primitive(db::F32)], db::F32);
+        assert_eq!(imported.name[&db::F32], "f32");
+        let (id, imported_id) = (db.uuid_reverse[&VEC3F_UUID], imported.uuid_reverse[&VEC3F_UUID]);
+        assert_eq!(imported.size[&imported_id], db.size[&id]);
+        assert_eq!(imported.members(imported_id).len(), 3);
+    }
+}
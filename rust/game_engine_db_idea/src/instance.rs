@@ -0,0 +1,420 @@
+//! Instances of the types a `DB` describes: building them from strings, printing them, and freeing the VECs they own.
+//!
+//! Instances are built from their components, as strings: scalars and ENUMs have one (the value, or the variant's
+//! name), STRUCTs one per field, ARRAYs and VECs one per element, and UNIONs and SUMs the name of a member followed
+//! by the member's own components. Missing components are zeroed, except for SUMs and ENUMs which get their first
+//! variant.
+//!
+//! The components of nested instances are written `{a, b}` (STRUCT), `[a, b]` (ARRAY, VEC) or `Name(a, b)` (UNION,
+//! SUM), e.g a SUM with a `Segment` variant holding two `Vec3f`s is built from `["Segment", "{0, 0, 0}", "{1, 2, 3}"]`
+//! at the top level, or from `"Segment({0, 0, 0}, {1, 2, 3})"` as a field.
+//!
+//! Members of UNIONs can't own VECs: which member is in use is unknown, so these VECs could never be freed.
+
+use super::{db, DB};
+use datamap::{DataMapKey, DenseDataMap, VecHeap};
+
+fn read_u32(mem: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(mem);
+    u32::from_ne_bytes(bytes)
+}
+
+fn write_u32(mem: &mut [u8], x: u32) {
+    mem.copy_from_slice(&x.to_ne_bytes());
+}
+
+fn zero(mem: &mut [u8]) {
+    for b in mem {
+        *b = 0;
+    }
+}
+
+impl DB {
+    /// Writes an instance of `t` to `mem`, from its components. VEC elements go to `heap`.
+    /// On error, `mem` is left zeroed, with nothing left behind in `heap`.
+    pub fn instantiate(&self, t: u32, mem: &mut [u8], heap: &mut VecHeap, init: &[&str]) -> Result<(), String> {
+        assert_eq!(mem.len(), self.size[&t]);
+        zero(mem);
+        self.fill(t, mem, heap, init).inspect_err(|_| {
+            self.drop_instance(t, mem, heap);
+            zero(mem);
+        })
+    }
+    /// Inserts an instance of `t` into `map`, whose items must be the size of `t`.
+    pub fn insert_instance(&self, t: u32, map: &mut DenseDataMap, init: &[&str]) -> Result<DataMapKey, String> {
+        let (k, mem, heap) = map.insert_uninitialized_with_heap();
+        match self.instantiate(t, mem, heap, init) {
+            Ok(()) => Ok(k),
+            Err(e) => {
+                map.remove(k);
+                Err(e)
+            },
+        }
+    }
+    /// Removes an instance of `t` from `map`, along with the VECs it owns.
+    pub fn remove_instance(&self, t: u32, map: &mut DenseDataMap, k: DataMapKey) {
+        if let Some((mem, heap)) = map.get_mut_with_heap(k) {
+            self.drop_instance(t, mem, heap);
+            map.remove(k);
+        }
+    }
+    /// Removes the VECs an instance of `t` owns from `heap`, and empties them in `mem`.
+    pub fn drop_instance(&self, t: u32, mem: &mut [u8], heap: &mut VecHeap) {
+        match self.kind_of(t) {
+            db::STRUCT => for m in self.members(t) {
+                let (mt, i) = (self.type_[&m], self.offset[&m]);
+                self.drop_instance(mt, &mut mem[i .. i + self.size[&mt]], heap);
+            },
+            db::ARRAY => {
+                let et = self.elem[&t];
+                if self.size[&et] > 0 {
+                    for e in mem.chunks_mut(self.size[&et]) {
+                        self.drop_instance(et, e, heap);
+                    }
+                }
+            },
+            db::SUM => if let Some(&pt) = self.variant_of(t, mem).and_then(|v| self.type_.get(&v)) {
+                let i = self.payload_offset(t);
+                self.drop_instance(pt, &mut mem[i .. i + self.size[&pt]], heap);
+            },
+            db::VEC => {
+                let et = self.elem[&t];
+                let mut buf = heap.remove(read_u32(mem));
+                write_u32(mem, 0);
+                if self.size[&et] > 0 {
+                    for e in buf.chunks_mut(self.size[&et]) {
+                        self.drop_instance(et, e, heap);
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+    /// Whether instances of `t` may own VECs, i.e whether they need `drop_instance`.
+    pub fn owns_vecs(&self, t: u32) -> bool {
+        match self.kind_of(t) {
+            db::VEC => true,
+            db::ARRAY => self.owns_vecs(self.elem[&t]),
+            db::STRUCT | db::UNION | db::SUM => self.member_types(t).into_iter().any(|mt| self.owns_vecs(mt)),
+            _ => false,
+        }
+    }
+    /// The variant a SUM or ENUM instance holds, unless its tag is invalid.
+    pub fn variant_of(&self, t: u32, mem: &[u8]) -> Option<u32> {
+        let tag = read_u32(&mem[.. db::size_of_primitive(db::TAG)]);
+        self.members(t).into_iter().find(|v| self.discriminant[v] == tag)
+    }
+
+    // `mem` must be zeroed.
+    fn fill(&self, t: u32, mem: &mut [u8], heap: &mut VecHeap, init: &[&str]) -> Result<(), String> {
+        let at_most = |n: usize| if init.len() > n {
+            Err(format!("{} takes at most {} components, found {}", self.name[&t], n, init.len()))
+        } else {
+            Ok(())
+        };
+        match self.kind_of(t) {
+            db::PRIMITIVE_TYPE | db::BOOL | db::CHAR | db::UUID => {
+                at_most(1)?;
+                match init.first() {
+                    Some(s) => db::scalar_from_str(t, mem, s).map_err(|e| format!("invalid {} `{}`: {}", self.name[&t], s, e)),
+                    None => Ok(()),
+                }
+            },
+            db::STRUCT => {
+                let fields = self.members(t);
+                at_most(fields.len())?;
+                for (i, m) in fields.into_iter().enumerate() {
+                    let (mt, o) = (self.type_[&m], self.offset[&m]);
+                    self.fill_component(mt, &mut mem[o .. o + self.size[&mt]], heap, init.get(i).cloned())?;
+                }
+                Ok(())
+            },
+            db::ARRAY => {
+                let (et, n) = (self.elem[&t], self.len[&t]);
+                at_most(n)?;
+                let es = self.size[&et];
+                for i in 0 .. n {
+                    self.fill_component(et, &mut mem[i*es .. (i+1)*es], heap, init.get(i).cloned())?;
+                }
+                Ok(())
+            },
+            db::UNION => match init.split_first() {
+                None => Ok(()),
+                Some((name, rest)) => {
+                    let m = self.member_by_name(t, name)?;
+                    let mt = self.type_[&m];
+                    if self.owns_vecs(mt) {
+                        return Err(format!("{}::{} owns VECs, which UNIONs can't", self.name[&t], self.name[&m]));
+                    }
+                    self.fill(mt, &mut mem[.. self.size[&mt]], heap, rest)
+                },
+            },
+            db::SUM => {
+                let (v, rest) = match init.split_first() {
+                    None => (self.first_variant(t)?, &[][..]),
+                    Some((name, rest)) => (self.member_by_name(t, name)?, rest),
+                };
+                write_u32(&mut mem[.. db::size_of_primitive(db::TAG)], self.discriminant[&v]);
+                match self.type_.get(&v) {
+                    Some(&pt) => {
+                        let i = self.payload_offset(t);
+                        self.fill(pt, &mut mem[i .. i + self.size[&pt]], heap, rest)
+                    },
+                    None if !rest.is_empty() => Err(format!("{}::{} has no payload", self.name[&t], self.name[&v])),
+                    None => Ok(()),
+                }
+            },
+            db::ENUM => {
+                at_most(1)?;
+                let v = match init.first() {
+                    None => self.first_variant(t)?,
+                    Some(name) => self.member_by_name(t, name)?,
+                };
+                write_u32(mem, self.discriminant[&v]);
+                Ok(())
+            },
+            db::VEC => {
+                let et = self.elem[&t];
+                let es = self.size[&et];
+                let mut buf = vec![0; es * init.len()];
+                for (i, s) in init.iter().enumerate() {
+                    if let Err(e) = self.fill_component(et, &mut buf[i*es .. (i+1)*es], heap, Some(s)) {
+                        for e in buf.chunks_mut(es.max(1)) {
+                            self.drop_instance(et, e, heap);
+                        }
+                        return Err(e);
+                    }
+                }
+                write_u32(mem, heap.insert(buf));
+                Ok(())
+            },
+            kind => panic!("{} is of kind {}, which is not a type", self.name[&t], kind),
+        }
+    }
+    fn fill_component(&self, t: u32, mem: &mut [u8], heap: &mut VecHeap, s: Option<&str>) -> Result<(), String> {
+        match s {
+            None => self.fill(t, mem, heap, &[]),
+            Some(s) => self.fill(t, mem, heap, &self.components(t, s)?),
+        }
+    }
+    fn first_variant(&self, t: u32) -> Result<u32, String> {
+        self.members(t).into_iter().next().ok_or_else(|| format!("{} has no variants", self.name[&t]))
+    }
+    // Splits the string form of a nested instance of `t` into its components.
+    fn components<'a>(&self, t: u32, s: &'a str) -> Result<Vec<&'a str>, String> {
+        let s = s.trim();
+        match self.kind_of(t) {
+            db::STRUCT => split_delimited(s, '{', '}'),
+            db::ARRAY | db::VEC => split_delimited(s, '[', ']'),
+            db::UNION | db::SUM => match s.find('(') {
+                Some(i) if s.ends_with(')') => {
+                    let mut components = vec![s[.. i].trim()];
+                    components.extend(split_top_level(&s[i+1 .. s.len()-1]));
+                    Ok(components)
+                },
+                _ => Ok(vec![s]),
+            },
+            _ => Ok(vec![s]),
+        }
+    }
+
+    /// One-line, human-readable form of an instance of `t`.
+    pub fn instance_to_string(&self, t: u32, mem: &[u8], heap: &VecHeap) -> String {
+        let elements = |et: u32, mem: &[u8]| -> String {
+            let es = self.size[&et];
+            let n = mem.len().checked_div(es).unwrap_or(0);
+            let elements: Vec<_> = (0 .. n).map(|i| self.instance_to_string(et, &mem[i*es .. (i+1)*es], heap)).collect();
+            format!("[{}]", elements.join(", "))
+        };
+        match self.kind_of(t) {
+            db::PRIMITIVE_TYPE | db::BOOL | db::CHAR | db::UUID => db::scalar_to_string(t, mem),
+            db::STRUCT | db::UNION => {
+                let fields: Vec<_> = self.members(t).into_iter().map(|m| {
+                    let (mt, i) = (self.type_[&m], self.offset.get(&m).cloned().unwrap_or(0));
+                    format!("{}: {}", self.name[&m], self.instance_to_string(mt, &mem[i .. i + self.size[&mt]], heap))
+                }).collect();
+                format!("{} {{ {} }}", self.name[&t], fields.join(", "))
+            },
+            db::ARRAY => elements(self.elem[&t], mem),
+            db::VEC => elements(self.elem[&t], heap.get(read_u32(mem))),
+            db::SUM | db::ENUM => match self.variant_of(t, mem) {
+                None => format!("<invalid {} tag {}>", self.name[&t], read_u32(&mem[.. db::size_of_primitive(db::TAG)])),
+                Some(v) => match self.type_.get(&v) {
+                    Some(&pt) if self.kind_of(t) == db::SUM => {
+                        let i = self.payload_offset(t);
+                        format!("{}({})", self.name[&v], self.instance_to_string(pt, &mem[i .. i + self.size[&pt]], heap))
+                    },
+                    _ => self.name[&v].clone(),
+                },
+            },
+            kind => panic!("{} is of kind {}, which is not a type", self.name[&t], kind),
+        }
+    }
+}
+
+fn split_delimited(s: &str, open: char, close: char) -> Result<Vec<&str>, String> {
+    if s.len() < 2 || !s.starts_with(open) || !s.ends_with(close) {
+        return Err(format!("expected `{}...{}`, found `{}`", open, close, s));
+    }
+    Ok(split_top_level(&s[1 .. s.len()-1]))
+}
+
+// Splits on commas which aren't nested in brackets. Allows a trailing comma.
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' | '[' | '(' => depth += 1,
+            '}' | ']' | ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(s[start .. i].trim());
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+    let last = s[start ..].trim();
+    if !last.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{builtin_db, db, ENTITY_UUID, DB};
+    use datamap::{DenseDataMap, VecHeap};
+
+    fn type_named(db: &DB, name: &str) -> u32 {
+        *db.name.iter().find(|&(&t, n)| n == name && !db.struct_.contains_key(&t)).unwrap().0
+    }
+    // Builds an instance of `t` and prints it back, checking that nothing was left behind on the way.
+    fn round_trip(db: &DB, t: u32, init: &[&str]) -> Result<String, String> {
+        let (mut mem, mut heap) = (vec![0xff; db.size[&t]], VecHeap::new());
+        if let Err(e) = db.instantiate(t, &mut mem, &mut heap, init) {
+            assert!(mem.iter().all(|&b| b == 0));
+            assert!(heap.is_empty());
+            return Err(e);
+        }
+        let s = db.instance_to_string(t, &mem, &heap);
+        db.drop_instance(t, &mut mem, &mut heap);
+        assert!(heap.is_empty());
+        Ok(s)
+    }
+
+    #[test]
+    fn scalars() {
+        let db = builtin_db();
+        assert_eq!(round_trip(&db, db::BOOL, &["true"]).unwrap(), "true");
+        assert_eq!(round_trip(&db, db::BOOL, &[]).unwrap(), "false");
+        assert!(round_trip(&db, db::BOOL, &["1"]).is_err());
+        assert_eq!(round_trip(&db, db::CHAR, &["'p'"]).unwrap(), "'p'");
+        assert_eq!(round_trip(&db, db::CHAR, &["é"]).unwrap(), "'é'");
+        assert!(round_trip(&db, db::CHAR, &["pq"]).is_err());
+        assert_eq!(round_trip(&db, db::UUID, &["0x2a"]).unwrap(), "0x2a");
+        assert_eq!(round_trip(&db, db::UUID, &["0x20000000000000000000000000000010"]).unwrap(), "0x20000000000000000000000000000010");
+        assert!(round_trip(&db, db::UUID, &["2a"]).is_err());
+        assert_eq!(round_trip(&db, db::I16, &["-300"]).unwrap(), "-300");
+        assert!(round_trip(&db, db::U8, &["256"]).is_err());
+        assert!(round_trip(&db, db::U8, &["1", "2"]).is_err());
+    }
+
+    #[test]
+    fn enums() {
+        let db = builtin_db();
+        let color = type_named(&db, "Color");
+        assert_eq!(round_trip(&db, color, &["Blue"]).unwrap(), "Blue");
+        assert_eq!(round_trip(&db, color, &[]).unwrap(), "Red");
+        assert!(round_trip(&db, color, &["Purple"]).is_err());
+        let (mut mem, mut heap) = (vec![0; db.size[&color]], VecHeap::new());
+        db.instantiate(color, &mut mem, &mut heap, &["Green"]).unwrap();
+        assert_eq!(db.variant_of(color, &mem), db.member_by_name(color, "Green").ok());
+        mem[0] = 42;
+        assert_eq!(db.variant_of(color, &mem), None);
+        assert_eq!(db.instance_to_string(color, &mem, &heap), "<invalid Color tag 42>");
+    }
+
+    #[test]
+    fn arrays() {
+        let db = builtin_db();
+        let segment = type_named(&db, "Segment");
+        assert_eq!(round_trip(&db, segment, &["{1, 2, 3}", "{4.5, 5, 6}"]).unwrap(), "[Vec3f { x: 1, y: 2, z: 3 }, Vec3f { x: 4.5, y: 5, z: 6 }]");
+        assert_eq!(round_trip(&db, segment, &["{1}"]).unwrap(), "[Vec3f { x: 1, y: 0, z: 0 }, Vec3f { x: 0, y: 0, z: 0 }]");
+        assert!(round_trip(&db, segment, &["{}", "{}", "{}"]).is_err());
+        assert!(round_trip(&db, segment, &["1, 2, 3"]).is_err());
+    }
+
+    #[test]
+    fn sums() {
+        let db = builtin_db();
+        let shape = type_named(&db, "Shape");
+        assert_eq!(round_trip(&db, shape, &[]).unwrap(), "Nothing");
+        assert_eq!(round_trip(&db, shape, &["Point", "1", "2", "3"]).unwrap(), "Point(Vec3f { x: 1, y: 2, z: 3 })");
+        assert_eq!(round_trip(&db, shape, &["Segment", "{1, 2, 3}"]).unwrap(), "Segment([Vec3f { x: 1, y: 2, z: 3 }, Vec3f { x: 0, y: 0, z: 0 }])");
+        assert!(round_trip(&db, shape, &["Nothing", "1"]).is_err());
+        assert!(round_trip(&db, shape, &["Circle"]).is_err());
+
+        let entity = db.uuid_reverse[&ENTITY_UUID];
+        let init = ["true", "Blue", "Segment({0, 0, 0}, {1, 1, 1})", "'e'", "0x2a", "f(0.5)"];
+        assert_eq!(
+            round_trip(&db, entity, &init).unwrap(),
+            "Entity { visible: true, color: Blue, shape: Segment([Vec3f { x: 0, y: 0, z: 0 }, Vec3f { x: 1, y: 1, z: 1 }]), initial: 'e', owner: 0x2a, bits: Bits { f: 0.5, u: 1056964608 } }",
+        );
+    }
+
+    #[test]
+    fn vecs() {
+        let db = builtin_db();
+        let path = type_named(&db, "Path");
+        assert_eq!(round_trip(&db, path, &[]).unwrap(), "[]");
+        assert_eq!(round_trip(&db, path, &["{1, 2, 3}", "{4, 5, 6}", "{7, 8, 9}"]).unwrap(), "[Vec3f { x: 1, y: 2, z: 3 }, Vec3f { x: 4, y: 5, z: 6 }, Vec3f { x: 7, y: 8, z: 9 }]");
+
+        let (mut mem, mut heap) = (vec![0; db.size[&path]], VecHeap::new());
+        db.instantiate(path, &mut mem, &mut heap, &["{1, 2, 3}"]).unwrap();
+        assert_eq!(heap.len(), 1);
+        db.drop_instance(path, &mut mem, &mut heap);
+        assert!(heap.is_empty());
+        assert!(mem.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn errors_within_vecs_leave_nothing_behind() {
+        let mut db = builtin_db();
+        let (shape, path) = (type_named(&db, "Shape"), type_named(&db, "Path"));
+        assert!(round_trip(&db, shape, &["Path", "{1, 2, 3}", "{4, oops, 6}", "{7, 8, 9}"]).is_err());
+
+        // The first inner VEC is complete when the second one fails
+        let paths = db.add_type(0x20000000000000000000000000000070, "Paths", db::VEC);
+        db.elem.insert(paths, path);
+        db.update_layout(paths);
+        assert!(round_trip(&db, paths, &["[{1, 2, 3}, {4, 5, 6}]", "[{1, 2, 3}, {oops}]"]).is_err());
+        assert_eq!(round_trip(&db, paths, &["[{1, 2, 3}]", "[]"]).unwrap(), "[[Vec3f { x: 1, y: 2, z: 3 }], []]");
+
+        let mut map = DenseDataMap::new(db.size[&paths]);
+        assert!(db.insert_instance(paths, &mut map, &["[{1, 2, 3}]", "[{oops}]"]).is_err());
+        assert!(map.is_empty());
+        assert!(map.heap().is_empty());
+        let k = db.insert_instance(paths, &mut map, &["[{1, 2, 3}]", "[{4, 5, 6}]"]).unwrap();
+        assert_eq!(map.heap().len(), 3);
+        db.remove_instance(paths, &mut map, k);
+        assert!(map.is_empty());
+        assert!(map.heap().is_empty());
+    }
+
+    #[test]
+    fn union_members_cannot_own_vecs() {
+        let mut db = builtin_db();
+        let path = type_named(&db, "Path");
+        let u = db.add_type(0x20000000000000000000000000000080, "PathOrNot", db::UNION);
+        db.add_member(u, 0x20000000000000000000000000000081, "path", Some(path));
+        db.add_member(u, 0x20000000000000000000000000000082, "not", Some(db::U32));
+        db.update_layout(u);
+        assert!(db.owns_vecs(u));
+        assert!(round_trip(&db, u, &["path", "{1, 2, 3}"]).is_err());
+        let (mut mem, mut heap) = (vec![0; db.size[&u]], VecHeap::new());
+        db.instantiate(u, &mut mem, &mut heap, &["not", "3"]).unwrap();
+        assert!(heap.is_empty());
+    }
+}
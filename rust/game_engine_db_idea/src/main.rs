@@ -1,7 +1,9 @@
 
+#[allow(dead_code, clippy::new_without_default)] // Sketch, not wired up yet
 pub mod gui;
 
 pub mod db {
+    use std::mem::align_of;
     use std::ptr;
     use std::ops::Range;
 
@@ -10,16 +12,26 @@ pub mod db {
         ($i:expr) => { $i as u128 + (1 << 120) };
     }
 
-    static NAME_OF: [&'static str; NB_PRIMITIVE_TYPES as _] = [
+    static NAME_OF: [&str; NB_PRIMITIVE_TYPES as _] = [
         "u8", "i8", "u16", "i16", "u32", "i32", "u64", "i64", "u128", "i128", "f32", "f64"
     ];
     static SIZE_OF: [usize; NB_PRIMITIVE_TYPES as _] = [
-        1, 1, 2, 2, 4, 4, 8, 8, 16, 16, 4, 4
+        1, 1, 2, 2, 4, 4, 8, 8, 16, 16, 4, 8
+    ];
+    // Unlike sizes, alignments vary across targets (e.g `u128`), so ask the compiler.
+    static ALIGN_OF: [usize; NB_PRIMITIVE_TYPES as _] = [
+        align_of::<u8>(), align_of::<i8>(), align_of::<u16>(), align_of::<i16>(), align_of::<u32>(), align_of::<i32>(),
+        align_of::<u64>(), align_of::<i64>(), align_of::<u128>(), align_of::<i128>(), align_of::<f32>(), align_of::<f64>()
     ];
     static UUID_OF: [u128; NB_PRIMITIVE_TYPES as _] = [
         uuid!(U8), uuid!(I8), uuid!(U16), uuid!(I16), uuid!(U32), uuid!(I32), uuid!(U64), uuid!(I64), uuid!(U128), uuid!(I128), uuid!(F32), uuid!(F64)
     ];
 
+    /// The reverse of `uuid!`: the reserved id this uuid stands for, if any.
+    pub fn reserved_id_of_uuid(uuid: u128) -> Option<u32> {
+        let id = uuid.wrapping_sub(uuid!(0));
+        if id < HIGHEST_RESERVED_ID_EXCLUSIVE as u128 { Some(id as u32) } else { None }
+    }
     pub fn is_primitive(t: u32) -> bool {
         t < NB_PRIMITIVE_TYPES as _
     }
@@ -31,6 +43,10 @@ pub mod db {
         assert!(is_primitive(t));
         SIZE_OF[t as usize]
     }
+    pub fn align_of_primitive(t: u32) -> usize {
+        assert!(is_primitive(t));
+        ALIGN_OF[t as usize]
+    }
     pub fn uuid_of_primitive(t: u32) -> u128 {
         assert!(is_primitive(t));
         UUID_OF[t as usize]
@@ -52,18 +68,18 @@ pub mod db {
         assert!(mem.len() == size_of_primitive(t));
         unsafe {
             match t {
-                self::U8   => format!("{}", { *(mem.as_ptr() as *const u8  ) }),
-                self::I8   => format!("{}", { *(mem.as_ptr() as *const i8  ) }),
-                self::U16  => format!("{}", { *(mem.as_ptr() as *const u16 ) }),
-                self::I16  => format!("{}", { *(mem.as_ptr() as *const i16 ) }),
-                self::U32  => format!("{}", { *(mem.as_ptr() as *const u32 ) }),
-                self::I32  => format!("{}", { *(mem.as_ptr() as *const i32 ) }),
-                self::U64  => format!("{}", { *(mem.as_ptr() as *const u64 ) }),
-                self::I64  => format!("{}", { *(mem.as_ptr() as *const i64 ) }),
-                self::U128 => format!("{}", { *(mem.as_ptr() as *const u128) }),
-                self::I128 => format!("{}", { *(mem.as_ptr() as *const i128) }),
-                self::F32  => format!("{}", { *(mem.as_ptr() as *const f32 ) }),
-                self::F64  => format!("{}", { *(mem.as_ptr() as *const f64 ) }),
+                self::U8   => format!("{}", mem[0]),
+                self::I8   => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const i8  )),
+                self::U16  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const u16 )),
+                self::I16  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const i16 )),
+                self::U32  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const u32 )),
+                self::I32  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const i32 )),
+                self::U64  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const u64 )),
+                self::I64  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const i64 )),
+                self::U128 => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const u128)),
+                self::I128 => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const i128)),
+                self::F32  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const f32 )),
+                self::F64  => format!("{}", ptr::read_unaligned(mem.as_ptr() as *const f64 )),
                 _ => unreachable!{},
             }
         }
@@ -72,24 +88,118 @@ pub mod db {
         assert!(mem.len() == size_of_primitive(t));
         unsafe {
             match t {
-                self::U8   => *(mem.as_mut_ptr() as *mut u8  ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::I8   => *(mem.as_mut_ptr() as *mut i8  ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::U16  => *(mem.as_mut_ptr() as *mut u16 ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::I16  => *(mem.as_mut_ptr() as *mut i16 ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::U32  => *(mem.as_mut_ptr() as *mut u32 ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::I32  => *(mem.as_mut_ptr() as *mut i32 ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::U64  => *(mem.as_mut_ptr() as *mut u64 ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::I64  => *(mem.as_mut_ptr() as *mut i64 ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::U128 => *(mem.as_mut_ptr() as *mut u128) = s.parse().map_err(|e| format!("{}", e))?,
-                self::I128 => *(mem.as_mut_ptr() as *mut i128) = s.parse().map_err(|e| format!("{}", e))?,
-                self::F32  => *(mem.as_mut_ptr() as *mut f32 ) = s.parse().map_err(|e| format!("{}", e))?,
-                self::F64  => *(mem.as_mut_ptr() as *mut f64 ) = s.parse().map_err(|e| format!("{}", e))?,
+                self::U8   => mem[0] = s.parse().map_err(|e| format!("{}", e))?,
+                self::I8   => ptr::write_unaligned(mem.as_mut_ptr() as *mut i8  , s.parse().map_err(|e| format!("{}", e))?),
+                self::U16  => ptr::write_unaligned(mem.as_mut_ptr() as *mut u16 , s.parse().map_err(|e| format!("{}", e))?),
+                self::I16  => ptr::write_unaligned(mem.as_mut_ptr() as *mut i16 , s.parse().map_err(|e| format!("{}", e))?),
+                self::U32  => ptr::write_unaligned(mem.as_mut_ptr() as *mut u32 , s.parse().map_err(|e| format!("{}", e))?),
+                self::I32  => ptr::write_unaligned(mem.as_mut_ptr() as *mut i32 , s.parse().map_err(|e| format!("{}", e))?),
+                self::U64  => ptr::write_unaligned(mem.as_mut_ptr() as *mut u64 , s.parse().map_err(|e| format!("{}", e))?),
+                self::I64  => ptr::write_unaligned(mem.as_mut_ptr() as *mut i64 , s.parse().map_err(|e| format!("{}", e))?),
+                self::U128 => ptr::write_unaligned(mem.as_mut_ptr() as *mut u128, s.parse().map_err(|e| format!("{}", e))?),
+                self::I128 => ptr::write_unaligned(mem.as_mut_ptr() as *mut i128, s.parse().map_err(|e| format!("{}", e))?),
+                self::F32  => ptr::write_unaligned(mem.as_mut_ptr() as *mut f32 , s.parse().map_err(|e| format!("{}", e))?),
+                self::F64  => ptr::write_unaligned(mem.as_mut_ptr() as *mut f64 , s.parse().map_err(|e| format!("{}", e))?),
                 _ => unreachable!{},
             };
             Ok(())
         }
     }
 
+    // Semantic types are stored as a primitive type, but parsed and printed their own way.
+    pub fn is_semantic(t: u32) -> bool {
+        t == BOOL || t == CHAR || t == UUID
+    }
+    pub fn is_scalar(t: u32) -> bool {
+        is_primitive(t) || is_semantic(t)
+    }
+    pub fn primitive_of_semantic(t: u32) -> u32 {
+        match t {
+            self::BOOL => U8,
+            self::CHAR => U32,
+            self::UUID => U128,
+            _ => panic!("{} is not a semantic type", t),
+        }
+    }
+    pub fn size_of_scalar(t: u32) -> usize {
+        size_of_primitive(if is_semantic(t) { primitive_of_semantic(t) } else { t })
+    }
+    pub fn align_of_scalar(t: u32) -> usize {
+        align_of_primitive(if is_semantic(t) { primitive_of_semantic(t) } else { t })
+    }
+    /// Rounds `offset` up to a multiple of `align`, which must be a power of two.
+    pub fn align_up(offset: usize, align: usize) -> usize {
+        debug_assert!(align.is_power_of_two());
+        (offset + align - 1) & !(align - 1)
+    }
+    pub fn scalar_to_string(t: u32, mem: &[u8]) -> String {
+        match t {
+            self::BOOL => format!("{}", mem[0] != 0),
+            self::CHAR => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(mem);
+                let c = u32::from_ne_bytes(bytes);
+                ::std::char::from_u32(c).map(|c| format!("{:?}", c)).unwrap_or_else(|| format!("<invalid char {:#x}>", c))
+            },
+            self::UUID => {
+                let mut bytes = [0; 16];
+                bytes.copy_from_slice(mem);
+                format!("{:#x}", u128::from_ne_bytes(bytes))
+            },
+            _ => primitive_to_string(t, mem),
+        }
+    }
+    pub fn scalar_from_str(t: u32, mem: &mut [u8], s: &str) -> Result<(), String> {
+        match t {
+            self::BOOL => {
+                let b: bool = s.parse().map_err(|e| format!("{}", e))?;
+                mem[0] = b as u8;
+                Ok(())
+            },
+            self::CHAR => {
+                // Either quoted like it is printed, or not
+                let s = if s.len() > 2 && s.starts_with('\'') && s.ends_with('\'') { &s[1 .. s.len()-1] } else { s };
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => {
+                        mem.copy_from_slice(&(c as u32).to_ne_bytes());
+                        Ok(())
+                    },
+                    _ => Err(format!("expected exactly one character, found `{}`", s)),
+                }
+            },
+            self::UUID => {
+                let uuid = match s.strip_prefix("0x") {
+                    Some(hex) => u128::from_str_radix(hex, 16),
+                    None => s.parse(),
+                };
+                mem.copy_from_slice(&uuid.map_err(|e| format!("{}", e))?.to_ne_bytes());
+                Ok(())
+            },
+            _ => primitive_from_str(t, mem, s),
+        }
+    }
+    /// Names of the reserved ids which aren't primitive types.
+    pub fn name_of_reserved(t: u32) -> Option<&'static str> {
+        Some(match t {
+            self::BOOL => "bool",
+            self::CHAR => "char",
+            self::UUID => "uuid",
+            self::ARRAY => "Array",
+            self::STRUCT => "Struct",
+            self::UNION => "Union",
+            self::SUM => "Sum",
+            self::ENUM => "Enum",
+            self::VEC => "Vec",
+            self::PRIMITIVE_TYPE => "PrimitiveType",
+            _ => return None,
+        })
+    }
+    pub fn uuid_of_reserved(t: u32) -> u128 {
+        assert!(t < HIGHEST_RESERVED_ID_EXCLUSIVE);
+        uuid!(t)
+    }
+
 
     // Primitive types
     pub const U8  : u32 =  0;
@@ -105,7 +215,7 @@ pub mod db {
     pub const F32 : u32 = 10;
     pub const F64 : u32 = 11;
     pub const NB_PRIMITIVE_TYPES: u32 = F64 + 1;
-    pub const ALL_PRIMITIVE_TYPES: Range<u32> = U8 .. F64;
+    pub const ALL_PRIMITIVE_TYPES: Range<u32> = U8 .. NB_PRIMITIVE_TYPES;
 
     // Semantics applied on top of primitive types
     pub const BOOL: u32 = 32;
@@ -122,6 +232,10 @@ pub mod db {
     // Unsized composites
     pub const VEC   : u32 = 69;
 
+    // How SUM and ENUM store their discriminant, and VEC its handle into a `VecHeap`.
+    pub const TAG: u32 = U32;
+    pub const VEC_HANDLE: u32 = U32;
+
     // Meta
     pub const PRIMITIVE_TYPE: u32 = 96;
     pub const PRIMITIVE_TYPE_UUID: u128 = uuid!(PRIMITIVE_TYPE);
@@ -130,10 +244,11 @@ pub mod db {
 }
 
 use std::collections::{BTreeMap, HashMap};
-use std::io::Write;
+use std::ops::Range;
+use std::io::{self, BufReader, Write};
 use std::fs::File;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct DB {
     highest_id: u32,
     // Use BTreeMap for sorted export
@@ -144,18 +259,34 @@ pub struct DB {
     pub struct_: HashMap<u32, u32>,
     pub offset: HashMap<u32, usize>,
     pub size: HashMap<u32, usize>,
+    pub align: HashMap<u32, usize>,
+    pub elem: HashMap<u32, u32>, // Element type of ARRAY and VEC types
+    pub len: HashMap<u32, usize>, // Length of ARRAY types
+    pub discriminant: HashMap<u32, u32>, // Tag of SUM and ENUM variants
 }
 
 pub mod datamap;
-use datamap::{DenseDataMap, DataMapKey};
+use datamap::{DenseDataMap, DataMapKey, VecHeap};
+
+pub mod import;
+pub mod instance;
+pub mod migrate;
+pub mod world;
+
+// What main() generates, so that its layout test runs along with ours.
+#[cfg(test)]
+#[path = "../gen.rs"]
+#[allow(dead_code)]
+mod gen;
 
 // Goals: 
 // - Represent mapping between Entity and "chunk of data which size is uniform and know only at run-time"
 // - Allow retrieval of "data chunk" by entity;
 // - Allow fast traversal of all chunks in one go;
 pub struct Arena {
-    map: DenseDataMap,
-    index: HashMap<u128, DataMapKey>,
+    pub map: DenseDataMap,
+    pub index: HashMap<u128, DataMapKey>,
+    pub entity: HashMap<DataMapKey, u128>, // Reverse of `index`
 }
 
 pub struct WorldDB {
@@ -163,23 +294,112 @@ pub struct WorldDB {
     pub arena: HashMap<u128, Arena>,
 }
 
+const VEC3F_UUID: u128 = 0x20000000000000000000000000000001;
+const ENTITY_UUID: u128 = 0x20000000000000000000000000000010;
+
 fn main() {
+    // db.ini is the source of truth. It is only generated from code when missing.
+    let db = match File::open("db.ini") {
+        Ok(f) => DB::import(BufReader::new(f)).unwrap_or_else(|e| panic!("db.ini: {}", e)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let db = builtin_db();
+            db.export(File::create("db.ini").unwrap()).unwrap();
+            db
+        },
+        Err(e) => panic!("db.ini: {}", e),
+    };
+    let id_vec3f = db.uuid_reverse[&VEC3F_UUID];
+    let id_entity = db.uuid_reverse[&ENTITY_UUID];
+
+    db.print_struct(id_vec3f);
+
+    let mut map = DenseDataMap::new(db.size[&id_vec3f]);
+    for init in &[["22", "53.57"], ["22", "53.57"], ["22", "53.57"], ["42", "13.56"], ["42", "13.56"], ["42", "13.56"], ["22", "53.57"], ["22", "53.57"]] {
+        db.insert_instance(id_vec3f, &mut map, init).unwrap();
+    }
+    for (k, v) in map.iter() {
+        println!("Key: {:?}", k);
+        db.print_struct_instance(id_vec3f, v, map.heap());
+    }
+
+    let mut entities = DenseDataMap::new(db.size[&id_entity]);
+    db.insert_instance(id_entity, &mut entities, &["true", "Green", "Point(1, 2, 3)", "'p'", "0x2a", "f(0.5)"]).unwrap();
+    db.insert_instance(id_entity, &mut entities, &["false", "Blue", "Segment({0, 0, 0}, {1, 1, 1})"]).unwrap();
+    let k = db.insert_instance(id_entity, &mut entities, &["true", "Red", "Path({0, 0, 0}, {1, 0, 0}, {1, 1, 0})"]).unwrap();
+    for (_, v) in entities.iter() {
+        db.print_struct_instance(id_entity, v, entities.heap());
+    }
+    db.remove_instance(id_entity, &mut entities, k);
+    assert!(entities.heap().is_empty());
+
+    write_gen_rs(&db, File::create("gen.rs").unwrap()).unwrap();
+
+    // Now, hot-edit Vec3f in a world which has instances of it
+    let mut world = WorldDB::new();
+    world.add(&db, 0x30000000000000000000000000000001, VEC3F_UUID, &["1.5", "2", "3"]).unwrap();
+    world.add(&db, 0x30000000000000000000000000000002, VEC3F_UUID, &["-4.75", "5", "6"]).unwrap();
+    world.add(&db, 0x30000000000000000000000000000003, VEC3F_UUID, &["7", "8", "9"]).unwrap();
+    world.add(&db, 0x30000000000000000000000000000001, ENTITY_UUID, &["true", "Red", "Path({1, 2, 3}, {4, 5, 6})"]).unwrap();
+    world.add(&db, 0x30000000000000000000000000000003, ENTITY_UUID, &["false", "Blue"]).unwrap();
+    world.add(&db, 0x30000000000000000000000000000004, ENTITY_UUID, &["true", "Green"]).unwrap();
+
+    let mut new_db = db.clone();
+    let id_vec3f_x = new_db.member_by_name(id_vec3f, "x").unwrap();
+    let id_vec3f_y = new_db.member_by_name(id_vec3f, "y").unwrap();
+    new_db.type_.insert(id_vec3f_x, db::I64);
+    new_db.name.insert(id_vec3f_y, "height".to_owned());
+    new_db.add_member(id_vec3f, 0x20000000000000000000000000000005, "w", Some(db::F32));
+    new_db.update_layouts();
+    for change in migrate::diff(&db, &new_db, VEC3F_UUID) {
+        println!("{:?}", change);
+    }
+    println!("{:?}", world.migrate(&db, &new_db));
+    new_db.print_struct(id_vec3f);
+    world.print(&new_db);
+
+    for (entity, instances) in world.join(&[ENTITY_UUID, VEC3F_UUID]) {
+        let (entity_heap, vec3f_heap) = (world.arena[&ENTITY_UUID].heap(), world.arena[&VEC3F_UUID].heap());
+        println!("{:#x}: {} at {}", entity, new_db.instance_to_string(id_entity, instances[0], entity_heap), new_db.instance_to_string(id_vec3f, instances[1], vec3f_heap));
+    }
+    assert_eq!(world.remove_entity(&new_db, 0x30000000000000000000000000000001), 2);
+    assert!(world.arena[&ENTITY_UUID].heap().is_empty());
+}
+
+fn write_gen_rs<W: Write>(db: &DB, mut w: W) -> io::Result<()> {
+    let id_entity = db.uuid_reverse[&ENTITY_UUID];
+    db.write_struct_rs(&mut w, id_entity)?;
+    writeln!(w)?;
+    db.write_layout_test_rs(&mut w, id_entity)
+}
+
+// The database main() starts from when there is no db.ini yet.
+fn builtin_db() -> DB {
     let mut db = DB::new();
 
-    // Init with primitive types
-    db.uuid.insert(db::PRIMITIVE_TYPE, db::PRIMITIVE_TYPE_UUID);
-    db.uuid_reverse.insert(db::PRIMITIVE_TYPE_UUID, db::PRIMITIVE_TYPE);
-    db.name.insert(db::PRIMITIVE_TYPE, "PrimitiveType".to_owned());
+    // Init with reserved ids, then primitive types
+    for t in 0 .. db::HIGHEST_RESERVED_ID_EXCLUSIVE {
+        if let Some(name) = db::name_of_reserved(t) {
+            db.uuid.insert(t, db::uuid_of_reserved(t));
+            db.uuid_reverse.insert(db::uuid_of_reserved(t), t);
+            db.name.insert(t, name.to_owned());
+            if db::is_semantic(t) {
+                db.type_.insert(t, db::primitive_of_semantic(t));
+                db.size.insert(t, db::size_of_scalar(t));
+                db.align.insert(t, db::align_of_scalar(t));
+            }
+        }
+    }
     for i in db::ALL_PRIMITIVE_TYPES {
         db.type_.insert(i, db::PRIMITIVE_TYPE);
         db.name.insert(i, db::name_of_primitive(i).to_owned());
         db.size.insert(i, db::size_of_primitive(i));
+        db.align.insert(i, db::align_of_primitive(i));
         db.uuid.insert(i, db::uuid_of_primitive(i));
         db.uuid_reverse.insert(db::uuid_of_primitive(i), i);
     }
 
     // Now, create a Vec3<f32> struct
-    let id_vec3f   = db.id_from_uuid(0x20000000000000000000000000000001);
+    let id_vec3f   = db.id_from_uuid(VEC3F_UUID);
     let id_vec3f_x = db.id_from_uuid(0x20000000000000000000000000000002);
     let id_vec3f_y = db.id_from_uuid(0x20000000000000000000000000000003);
     let id_vec3f_z = db.id_from_uuid(0x20000000000000000000000000000004);
@@ -193,58 +413,83 @@ fn main() {
     db.struct_.insert(id_vec3f_x, id_vec3f);
     db.struct_.insert(id_vec3f_y, id_vec3f);
     db.struct_.insert(id_vec3f_z, id_vec3f);
-    db.offset.insert(id_vec3f_x, 0);
-    db.offset.insert(id_vec3f_y, 4);
-    db.offset.insert(id_vec3f_z, 8);
-    {
-        let size = db.struct_size(id_vec3f);
-        db.size.insert(id_vec3f, size);
+    db.update_layout(id_vec3f);
+
+    // Then, an entity with all kinds of types
+    let color = db.add_type(0x20000000000000000000000000000020, "Color", db::ENUM);
+    for (i, name) in ["Red", "Green", "Blue"].iter().enumerate() {
+        let v = db.add_member(color, 0x20000000000000000000000000000021 + i as u128, name, None);
+        db.discriminant.insert(v, i as _);
     }
+    db.update_layout(color);
 
-    db.print_struct(id_vec3f);
+    let segment = db.add_type(0x20000000000000000000000000000030, "Segment", db::ARRAY);
+    db.elem.insert(segment, id_vec3f);
+    db.len.insert(segment, 2);
+    db.update_layout(segment);
 
-    let mut map = DenseDataMap::new(db.size[&id_vec3f]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["22", "53.57"]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["22", "53.57"]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["22", "53.57"]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["42", "13.56"]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["42", "13.56"]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["42", "13.56"]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["22", "53.57"]);
-    db.instantiate(id_vec3f, map.insert_uninitialized().1, &["22", "53.57"]);
-    for (k, v) in map.iter() {
-        println!("Key: {:?}", k);
-        db.print_struct_instance(id_vec3f, v);
+    let path = db.add_type(0x20000000000000000000000000000040, "Path", db::VEC);
+    db.elem.insert(path, id_vec3f);
+    db.update_layout(path);
+
+    let shape = db.add_type(0x20000000000000000000000000000050, "Shape", db::SUM);
+    for (i, &(name, t)) in [("Nothing", None), ("Point", Some(id_vec3f)), ("Segment", Some(segment)), ("Path", Some(path))].iter().enumerate() {
+        let v = db.add_member(shape, 0x20000000000000000000000000000051 + i as u128, name, t);
+        db.discriminant.insert(v, i as _);
     }
+    db.update_layout(shape);
+
+    let bits = db.add_type(0x20000000000000000000000000000060, "Bits", db::UNION);
+    db.add_member(bits, 0x20000000000000000000000000000061, "f", Some(db::F32));
+    db.add_member(bits, 0x20000000000000000000000000000062, "u", Some(db::U32));
+    db.update_layout(bits);
 
-    db.write_struct_rs(File::create("gen.rs").unwrap(), id_vec3f);
+    let entity = db.add_type(ENTITY_UUID, "Entity", db::STRUCT);
+    for (i, &(name, t)) in [("visible", db::BOOL), ("color", color), ("shape", shape), ("initial", db::CHAR), ("owner", db::UUID), ("bits", bits)].iter().enumerate() {
+        db.add_member(entity, ENTITY_UUID + 1 + i as u128, name, Some(t));
+    }
+    db.update_layout(entity);
 
-    db.export(File::create("db.ini").unwrap());
+    db
 }
 
 impl DB {
-    pub fn export<W: Write>(&self, mut w: W) {
+    pub fn export<W: Write>(&self, mut w: W) -> io::Result<()> {
         for (uuid, id) in &self.uuid_reverse {
-            writeln!(w, "[{:#x}]", uuid);
+            writeln!(w, "[{:#x}]", uuid)?;
             if let Some(name) = self.name.get(id) {
-                writeln!(w, "name = \"{}\"", name);
+                writeln!(w, "name = \"{}\"", name)?;
             }
             if let Some(struct_) = self.struct_.get(id) {
-                writeln!(w, "# {}", self.name[struct_]);
-                writeln!(w, "struct = {:#x}", self.uuid[struct_]);
+                writeln!(w, "# {}", self.name[struct_])?;
+                writeln!(w, "struct = {:#x}", self.uuid[struct_])?;
             }
             if let Some(ty) = self.type_.get(id) {
-                writeln!(w, "# {}", self.name[ty]);
-                writeln!(w, "type = {:#x}", self.uuid[ty]);
+                writeln!(w, "# {}", self.name[ty])?;
+                writeln!(w, "type = {:#x}", self.uuid[ty])?;
+            }
+            if let Some(elem) = self.elem.get(id) {
+                writeln!(w, "# {}", self.name[elem])?;
+                writeln!(w, "elem = {:#x}", self.uuid[elem])?;
             }
             if let Some(offset) = self.offset.get(id) {
-                writeln!(w, "offset = {}", offset);
+                writeln!(w, "offset = {}", offset)?;
+            }
+            if let Some(len) = self.len.get(id) {
+                writeln!(w, "len = {}", len)?;
+            }
+            if let Some(discriminant) = self.discriminant.get(id) {
+                writeln!(w, "discriminant = {}", discriminant)?;
             }
             if let Some(size) = self.size.get(id) {
-                writeln!(w, "size = {}", size);
+                writeln!(w, "size = {}", size)?;
             }
-            writeln!(w);
+            if let Some(align) = self.align.get(id) {
+                writeln!(w, "align = {}", align)?;
+            }
+            writeln!(w)?;
         }
+        Ok(())
     }
     pub fn new() -> Self {
         Self {
@@ -263,57 +508,279 @@ impl DB {
         id
     }
     pub fn id_from_uuid(&mut self, uuid: u128) -> u32 {
-        self.uuid_reverse.get(&uuid).map(|x| *x).unwrap_or_else(|| self.add_new_uuid(uuid))
+        self.uuid_reverse.get(&uuid).copied().unwrap_or_else(|| self.add_new_uuid(uuid))
+    }
+    /// Declares a type of the given kind (STRUCT, ARRAY, ...), leaving its definition to the caller.
+    pub fn add_type(&mut self, uuid: u128, name: &str, kind: u32) -> u32 {
+        let t = self.id_from_uuid(uuid);
+        self.name.insert(t, name.to_owned());
+        self.type_.insert(t, kind);
+        t
     }
-    pub fn instantiate(&self, t: u32, mem: &mut [u8], init: &[&str]) {
-        let mut i = 0;
+    /// Declares a field of a STRUCT or UNION, or a variant of a SUM (with a payload of type `t`) or ENUM (`t` is
+    /// `None`). Members are ordered by uuid until `update_layout` is called.
+    pub fn add_member(&mut self, owner: u32, uuid: u128, name: &str, t: Option<u32>) -> u32 {
+        let m = self.id_from_uuid(uuid);
+        self.name.insert(m, name.to_owned());
+        self.struct_.insert(m, owner);
+        if let Some(t) = t {
+            self.type_.insert(m, t);
+        }
+        m
+    }
+    /// What `t` is: PRIMITIVE_TYPE, one of the semantic types, or a composite kind. Types with no kind are STRUCTs.
+    pub fn kind_of(&self, t: u32) -> u32 {
         if db::is_primitive(t) {
-            db::instantiate_primitive(t, mem, init);
+            db::PRIMITIVE_TYPE
+        } else if db::is_semantic(t) {
+            t
         } else {
-            for (mi, m) in self.struct_fields(t).into_iter().enumerate() {
-                let mt = self.type_[&m];
-                let j = i + self.size[&mt];
-                self.instantiate(mt, &mut mem[i..j], if mi < init.len() { &init[mi..mi+1] } else { &[] } );
-                i = j;
-            }
+            self.type_.get(&t).cloned().unwrap_or(db::STRUCT)
         }
     }
+    /// Fields of a STRUCT by offset (fields with no offset yet come last), members of a UNION, or variants of a SUM or ENUM by discriminant.
+    pub fn members(&self, t: u32) -> Vec<u32> {
+        let mut members: Vec<_> = self.struct_.iter().filter(|(_, &v)| v == t).map(|(k, _)| *k).collect();
+        members.sort_by_key(|m| (self.offset.get(m).cloned().unwrap_or(usize::MAX), self.discriminant.get(m).cloned().unwrap_or(0), self.uuid[m]));
+        members
+    }
     pub fn struct_fields(&self, s: u32) -> Vec<u32> {
-        let mut fields: Vec<_> = self.struct_.iter().filter(|(_, &v)| v == s).map(|(k, _)| *k).collect();
-        fields.sort_by(|a, b| self.offset[a].cmp(&self.offset[b]));
-        fields
+        self.members(s)
+    }
+    pub fn member_by_name(&self, t: u32, name: &str) -> Result<u32, String> {
+        self.members(t).into_iter().find(|m| self.name[m] == name).ok_or_else(|| format!("{} has no member `{}`", self.name[&t], name))
+    }
+    /// Types of the fields of a STRUCT or UNION, or of the payloads of a SUM.
+    fn member_types(&self, t: u32) -> Vec<u32> {
+        self.members(t).into_iter().filter_map(|m| self.type_.get(&m).cloned()).collect()
     }
-    pub fn struct_size(&self, s: u32) -> usize {
-        self.struct_fields(s).iter().map(|m| self.size[&self.type_[&m]]).sum()
+    /// Where the payload of SUM variants starts: after the tag, aligned for every payload, like `#[repr(C, u32)]`.
+    pub fn payload_offset(&self, t: u32) -> usize {
+        let align = self.member_types(t).iter().map(|pt| self.align[pt]).max().unwrap_or(1);
+        db::align_up(db::size_of_primitive(db::TAG), align)
     }
-    pub fn write_struct_rs<W: Write>(&self, mut w: W, s: u32) {
-        writeln!(w, "#[repr(C)]");
-        writeln!(w, "#[derive(Debug, Default, Copy, Clone, PartialEq)]");
-        writeln!(w, "pub struct {} {{", self.name[&s]);
+    /// Alignment of instances of `t`, from its definition and the alignments of the types it is made of.
+    pub fn type_align(&self, t: u32) -> usize {
+        let max_align = |min: usize| self.member_types(t).iter().map(|mt| self.align[mt]).fold(min, usize::max);
+        match self.kind_of(t) {
+            db::PRIMITIVE_TYPE | db::BOOL | db::CHAR | db::UUID => db::align_of_scalar(t),
+            db::STRUCT | db::UNION => max_align(1),
+            db::SUM => max_align(db::align_of_primitive(db::TAG)),
+            db::ENUM => db::align_of_primitive(db::TAG),
+            db::ARRAY => self.align[&self.elem[&t]],
+            db::VEC => db::align_of_primitive(db::VEC_HANDLE),
+            kind => panic!("{} is of kind {}, which is not a type", self.name[&t], kind),
+        }
+    }
+    /// Size of instances of `t`, from its definition and the layouts of the types it is made of, padding included.
+    pub fn type_size(&self, t: u32) -> usize {
+        let max_size = || self.member_types(t).iter().map(|mt| self.size[mt]).max().unwrap_or(0);
+        let unpadded = match self.kind_of(t) {
+            db::PRIMITIVE_TYPE | db::BOOL | db::CHAR | db::UUID => db::size_of_scalar(t),
+            db::STRUCT => self.members(t).iter().map(|m| self.offset[m] + self.size[&self.type_[m]]).max().unwrap_or(0),
+            db::UNION => max_size(),
+            db::SUM => self.payload_offset(t) + max_size(),
+            db::ENUM => db::size_of_primitive(db::TAG),
+            db::ARRAY => self.size[&self.elem[&t]] * self.len[&t],
+            db::VEC => db::size_of_primitive(db::VEC_HANDLE),
+            kind => panic!("{} is of kind {}, which is not a type", self.name[&t], kind),
+        };
+        db::align_up(unpadded, self.type_align(t))
+    }
+    /// Lays out `t` like `#[repr(C)]` would, i.e assigns offsets to the fields of a STRUCT in order, each aligned
+    /// for its type, then stores the size and alignment of `t`.
+    /// The types it is made of must be laid out already.
+    pub fn update_layout(&mut self, t: u32) {
+        if self.kind_of(t) == db::STRUCT {
+            let mut offset = 0;
+            for m in self.members(t) {
+                let mt = self.type_[&m];
+                offset = db::align_up(offset, self.align[&mt]);
+                self.offset.insert(m, offset);
+                offset += self.size[&mt];
+            }
+        }
+        let (size, align) = (self.type_size(t), self.type_align(t));
+        self.size.insert(t, size);
+        self.align.insert(t, align);
+    }
+    /// Lays out every type, each after the types it is made of, e.g after editing a type which others contain.
+    pub fn update_layouts(&mut self) {
+        fn visit(db: &mut DB, t: u32, done: &mut Vec<u32>) {
+            if db::is_scalar(t) || done.contains(&t) {
+                return;
+            }
+            done.push(t);
+            for mt in db.member_types(t).into_iter().chain(db.elem.get(&t).cloned()) {
+                visit(db, mt, done);
+            }
+            db.update_layout(t);
+        }
+        let types: Vec<_> = self.uuid.keys().cloned().filter(|&t| t >= db::HIGHEST_RESERVED_ID_EXCLUSIVE && !self.struct_.contains_key(&t)).collect();
+        let mut done = Vec::new();
+        for t in types {
+            visit(self, t, &mut done);
+        }
+    }
+    /// Byte ranges of a STRUCT which belong to no field, i.e padding, trailing padding included.
+    pub fn padding(&self, s: u32) -> Vec<Range<usize>> {
+        let mut padding = Vec::new();
+        let mut end = 0;
         for m in self.struct_fields(s) {
-            writeln!(w, "    pub {}: {},", self.name[&m], self.name[&self.type_[&m]]);
+            if self.offset[&m] > end {
+                padding.push(end .. self.offset[&m]);
+            }
+            end = self.offset[&m] + self.size[&self.type_[&m]];
         }
-        writeln!(w, "}}");
-
-        writeln!(w);
-        writeln!(w, "pub const UUID: u128 = {:#x};", self.uuid[&s]);
+        if self.size[&s] > end {
+            padding.push(end .. self.size[&s]);
+        }
+        padding
+    }
+    /// Writes Rust definitions for `s` and every type it is made of, then its `UUID`.
+    pub fn write_struct_rs<W: Write>(&self, mut w: W, s: u32) -> io::Result<()> {
+        for t in self.rs_types(s) {
+            self.write_type_rs(&mut w, t)?;
+        }
+        writeln!(w, "pub const UUID: u128 = {:#x};", self.uuid[&s])
+    }
+    /// Writes a test for the output of `write_struct_rs`, which checks that the Rust compiler lays the types out
+    /// the way this database does.
+    pub fn write_layout_test_rs<W: Write>(&self, mut w: W, s: u32) -> io::Result<()> {
+        writeln!(w, "#[cfg(test)]")?;
+        writeln!(w, "mod layout_tests {{")?;
+        writeln!(w, "    use super::*;")?;
+        writeln!(w, "    use std::mem::{{align_of, size_of}};")?;
+        writeln!(w)?;
+        writeln!(w, "    #[test]")?;
+        writeln!(w, "    fn layout_matches_db() {{")?;
+        for t in self.rs_types(s) {
+            let name = self.rs_name(t);
+            writeln!(w, "        assert_eq!(size_of::<{}>(), {});", name, self.size[&t])?;
+            writeln!(w, "        assert_eq!(align_of::<{}>(), {});", name, self.align[&t])?;
+            if self.kind_of(t) == db::STRUCT {
+                for m in self.members(t) {
+                    writeln!(w, "        assert_eq!(::std::mem::offset_of!({}, {}), {});", name, self.name[&m], self.offset[&m])?;
+                }
+            }
+        }
+        writeln!(w, "    }}")?;
+        writeln!(w, "}}")
+    }
+    // Composite types `s` is made of, dependencies first, then `s`.
+    fn rs_types(&self, s: u32) -> Vec<u32> {
+        fn visit(db: &DB, t: u32, types: &mut Vec<u32>) {
+            if db::is_scalar(t) || types.contains(&t) {
+                return;
+            }
+            for mt in db.member_types(t).into_iter().chain(db.elem.get(&t).cloned()) {
+                visit(db, mt, types);
+            }
+            types.push(t);
+        }
+        let mut types = Vec::new();
+        visit(self, s, &mut types);
+        types
+    }
+    fn write_type_rs<W: Write>(&self, w: &mut W, t: u32) -> io::Result<()> {
+        match self.kind_of(t) {
+            db::STRUCT | db::UNION => {
+                let is_struct = self.kind_of(t) == db::STRUCT;
+                writeln!(w, "#[repr(C)]")?;
+                writeln!(w, "#[derive({})]", self.rs_derives(t))?;
+                writeln!(w, "pub {} {} {{", if is_struct { "struct" } else { "union" }, self.name[&t])?;
+                for m in self.members(t) {
+                    writeln!(w, "    pub {}: {},", self.name[&m], self.rs_name(self.type_[&m]))?;
+                }
+                writeln!(w, "}}")?;
+            },
+            db::SUM | db::ENUM => {
+                writeln!(w, "#[repr({})]", if self.kind_of(t) == db::SUM { "C, u32" } else { "u32" })?;
+                writeln!(w, "#[derive({})]", self.rs_derives(t))?;
+                writeln!(w, "pub enum {} {{", self.name[&t])?;
+                for v in self.members(t) {
+                    match self.type_.get(&v) {
+                        Some(&pt) => writeln!(w, "    {}({}) = {},", self.name[&v], self.rs_name(pt), self.discriminant[&v])?,
+                        None => writeln!(w, "    {} = {},", self.name[&v], self.discriminant[&v])?,
+                    }
+                }
+                writeln!(w, "}}")?;
+            },
+            db::ARRAY => writeln!(w, "pub type {} = [{}; {}];", self.name[&t], self.rs_name(self.elem[&t]), self.len[&t])?,
+            db::VEC => {
+                writeln!(w, "/// Handle to `{}`s stored out-of-line, in the `VecHeap` of the map holding the instance.", self.rs_name(self.elem[&t]))?;
+                writeln!(w, "pub type {} = {};", self.name[&t], db::name_of_primitive(db::VEC_HANDLE))?;
+            },
+            kind => panic!("{} is of kind {}, which is not a type", self.name[&t], kind),
+        }
+        writeln!(w)
+    }
+    fn rs_name(&self, t: u32) -> &str {
+        match t {
+            db::UUID => db::name_of_primitive(db::U128),
+            _ => &self.name[&t],
+        }
+    }
+    // Which of the `derive`s `write_struct_rs` knows about apply to `t`: Debug (1), Default (2) and PartialEq (4).
+    fn rs_derive_flags(&self, t: u32) -> u8 {
+        let all = |types: Vec<u32>| types.into_iter().fold(7, |flags, t| flags & self.rs_derive_flags(t));
+        match self.kind_of(t) {
+            db::STRUCT => all(self.members(t).iter().map(|m| self.type_[m]).collect()),
+            db::UNION => 0,
+            db::SUM => all(self.members(t).iter().filter_map(|v| self.type_.get(v).cloned()).collect()) & !2,
+            db::ENUM => 1 | 4,
+            db::ARRAY => self.rs_derive_flags(self.elem[&t]) & if self.len[&t] > 32 { !2 } else { 7 },
+            _ => 7,
+        }
+    }
+    fn rs_derives(&self, t: u32) -> String {
+        let flags = self.rs_derive_flags(t);
+        let derives: Vec<_> = [(1, "Debug"), (2, "Default"), (0, "Copy"), (0, "Clone"), (4, "PartialEq")].iter()
+            .filter(|&&(flag, _)| flag & flags == flag).map(|&(_, derive)| derive).collect();
+        derives.join(", ")
     }
 
     pub fn print_struct(&self, s: u32) {
-        println!("struct {} {{", self.name[&s]);
+        println!("struct {} {{ // size {}, align {}", self.name[&s], self.size[&s], self.align[&s]);
+        let mut padding = self.padding(s).into_iter().peekable();
         for m in self.struct_fields(s) {
-            println!("    {}: {},", self.name[&m], self.name[&self.type_[&m]]);
+            if let Some(p) = padding.next_if(|p| p.end <= self.offset[&m]) {
+                println!("    // {} bytes of padding", p.len());
+            }
+            println!("    {}: {}, // offset {}", self.name[&m], self.name[&self.type_[&m]], self.offset[&m]);
+        }
+        if let Some(p) = padding.next() {
+            println!("    // {} bytes of padding", p.len());
         }
         println!("}}");
     }
-    pub fn print_struct_instance(&self, s: u32, mem: &[u8]) {
+    pub fn print_struct_instance(&self, s: u32, mem: &[u8], heap: &VecHeap) {
+        if self.kind_of(s) != db::STRUCT {
+            println!("{}", self.instance_to_string(s, mem, heap));
+            return;
+        }
         println!("{} {{", self.name[&s]);
         for m in self.struct_fields(s) {
             let mt = self.type_[&m];
             let i = self.offset[&m];
             let j = i + self.size[&mt];
-            println!("    {}: {},", self.name[&m], db::primitive_to_string(mt, &mem[i..j]));
+            println!("    {}: {},", self.name[&m], self.instance_to_string(mt, &mem[i..j], heap));
         }
         println!("}}");
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gen_rs_is_up_to_date() {
+        let db_ini = File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/db.ini")).unwrap();
+        let db = DB::import(BufReader::new(db_ini)).unwrap();
+        let mut gen = Vec::new();
+        write_gen_rs(&db, &mut gen).unwrap();
+        assert!(String::from_utf8(gen).unwrap() == include_str!("../gen.rs"), "gen.rs is out of date with db.ini; run the program to regenerate it");
+    }
+}
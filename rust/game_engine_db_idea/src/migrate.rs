@@ -0,0 +1,402 @@
+//! Migrating instances from one version of a `DB` to the next, e.g after fields were added, removed, retyped or
+//! reordered in the editor.
+//!
+//! Types and their members are matched by uuid across versions, so renames are free. Values are converted to their
+//! new type where possible: between numeric types, bools (0 or 1), chars (their code point) and uuids (as `u128`),
+//! and member by member between versions of the same composite type. Members which are new, or whose value can't be
+//! converted (e.g retyped to another composite type), get their default value instead.
+
+use std::collections::HashMap;
+
+use super::{db, WorldDB, DB};
+use datamap::{DenseDataMap, VecHeap};
+
+/// A difference between two versions of a type, about one of its members (fields or variants).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added { member: u128 },
+    Removed { member: u128 },
+    Renamed { member: u128, from: String, to: String },
+    Retyped { member: u128, from: Option<u128>, to: Option<u128> },
+    Moved { member: u128, from: usize, to: usize },
+    Renumbered { member: u128, from: u32, to: u32 },
+}
+
+/// How the members of the type `uuid` changed from `old` to `new`.
+pub fn diff(old: &DB, new: &DB, uuid: u128) -> Vec<Change> {
+    let members = |db: &DB| -> Vec<u128> {
+        db.uuid_reverse.get(&uuid).map(|&t| db.members(t).iter().map(|m| db.uuid[m]).collect()).unwrap_or_default()
+    };
+    let (old_members, new_members) = (members(old), members(new));
+    let mut changes: Vec<_> = old_members.iter().filter(|m| !new_members.contains(m)).map(|&member| Change::Removed { member }).collect();
+    for &member in &new_members {
+        if !old_members.contains(&member) {
+            changes.push(Change::Added { member });
+            continue;
+        }
+        let (o, n) = (old.uuid_reverse[&member], new.uuid_reverse[&member]);
+        if old.name[&o] != new.name[&n] {
+            changes.push(Change::Renamed { member, from: old.name[&o].clone(), to: new.name[&n].clone() });
+        }
+        let (from, to) = (old.type_.get(&o).map(|t| old.uuid[t]), new.type_.get(&n).map(|t| new.uuid[t]));
+        if from != to {
+            changes.push(Change::Retyped { member, from, to });
+        }
+        if let (Some(&from), Some(&to)) = (old.offset.get(&o), new.offset.get(&n)) {
+            if from != to {
+                changes.push(Change::Moved { member, from, to });
+            }
+        }
+        if let (Some(&from), Some(&to)) = (old.discriminant.get(&o), new.discriminant.get(&n)) {
+            if from != to {
+                changes.push(Change::Renumbered { member, from, to });
+            }
+        }
+    }
+    changes
+}
+
+/// What `WorldDB::migrate` did.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub instances: usize,
+    /// Values which could not be converted to their new type, and got their default value instead.
+    pub defaulted: usize,
+    /// Types which no longer exist, whose instances were dropped.
+    pub dropped_types: Vec<u128>,
+    /// Types which became zero-sized (e.g all of their fields were removed), which arenas can't store. Their
+    /// instances were dropped.
+    pub zero_sized_types: Vec<u128>,
+}
+
+impl WorldDB {
+    /// Rewrites every instance from the layout of its type in `old` to its layout in `new`.
+    /// Arenas keep their entities, but keys into their maps change.
+    pub fn migrate(&mut self, old: &DB, new: &DB) -> MigrationReport {
+        let mut report = MigrationReport::default();
+        self.arena.retain(|&uuid, _| {
+            let exists = old.uuid_reverse.contains_key(&uuid) && new.uuid_reverse.contains_key(&uuid);
+            if !exists {
+                report.dropped_types.push(uuid);
+                return false;
+            }
+            let zero_sized = new.size[&new.uuid_reverse[&uuid]] == 0;
+            if zero_sized {
+                report.zero_sized_types.push(uuid);
+            }
+            !zero_sized
+        });
+
+        let mut converter = Converter { old, new, defaulted: 0 };
+        for (uuid, arena) in &mut self.arena {
+            let (old_t, new_t) = (old.uuid_reverse[uuid], new.uuid_reverse[uuid]);
+            // Instances are moved in traversal order, which is thus kept.
+            let mut map = DenseDataMap::with_capacity(new.size[&new_t], arena.map.len());
+            let mut entities = HashMap::with_capacity(arena.entity.len());
+            for (k, old_mem) in arena.map.iter() {
+                let (new_k, new_mem, new_heap) = map.insert_uninitialized_with_heap();
+                new.instantiate(new_t, new_mem, new_heap, &[]).unwrap_or_else(|e| panic!("no default {}: {}", new.name[&new_t], e));
+                converter.convert(old_t, old_mem, arena.map.heap(), new_t, new_mem, new_heap);
+                if let Some(&e) = arena.entity.get(&k) {
+                    arena.index.insert(e, new_k);
+                    entities.insert(new_k, e);
+                }
+                report.instances += 1;
+            }
+            arena.map = map;
+            arena.entity = entities;
+        }
+        report.defaulted = converter.defaulted;
+        report
+    }
+}
+
+struct Converter<'a> {
+    old: &'a DB,
+    new: &'a DB,
+    defaulted: usize,
+}
+
+impl<'a> Converter<'a> {
+    // `new_mem` must hold a default instance of `new_t`, which gets whatever can be converted from `old_mem`.
+    fn convert(&mut self, old_t: u32, old_mem: &[u8], old_heap: &VecHeap, new_t: u32, new_mem: &mut [u8], new_heap: &mut VecHeap) {
+        let (old, new) = (self.old, self.new);
+        if db::is_scalar(old_t) && db::is_scalar(new_t) {
+            if convert_scalar(old_t, old_mem, new_t, new_mem).is_err() {
+                self.defaulted += 1;
+            }
+            return;
+        }
+        if db::is_scalar(old_t) || db::is_scalar(new_t) || old.uuid[&old_t] != new.uuid[&new_t] || old.kind_of(old_t) != new.kind_of(new_t) {
+            self.defaulted += 1;
+            return;
+        }
+        match new.kind_of(new_t) {
+            db::STRUCT => for nm in new.members(new_t) {
+                let om = match self.old_member(old_t, new.uuid[&nm]) {
+                    Some(om) => om,
+                    None => continue,
+                };
+                let (ot, nt) = (old.type_[&om], new.type_[&nm]);
+                let (oi, ni) = (old.offset[&om], new.offset[&nm]);
+                self.convert(ot, &old_mem[oi .. oi + old.size[&ot]], old_heap, nt, &mut new_mem[ni .. ni + new.size[&nt]], new_heap);
+            },
+            db::ARRAY => {
+                let (oe, ne) = (old.elem[&old_t], new.elem[&new_t]);
+                let (os, ns) = (old.size[&oe], new.size[&ne]);
+                for i in 0 .. old.len[&old_t].min(new.len[&new_t]) {
+                    self.convert(oe, &old_mem[i*os .. (i+1)*os], old_heap, ne, &mut new_mem[i*ns .. (i+1)*ns], new_heap);
+                }
+            },
+            db::VEC => {
+                let (oe, ne) = (old.elem[&old_t], new.elem[&new_t]);
+                let (os, ns) = (old.size[&oe], new.size[&ne]);
+                let old_buf = old_heap.get(read_u32(old_mem));
+                let n = old_buf.len().checked_div(os).unwrap_or(0);
+                let mut buf = vec![0; n * ns];
+                for i in 0 .. n {
+                    new.instantiate(ne, &mut buf[i*ns .. (i+1)*ns], new_heap, &[]).unwrap_or_else(|e| panic!("no default {}: {}", new.name[&ne], e));
+                    self.convert(oe, &old_buf[i*os .. (i+1)*os], old_heap, ne, &mut buf[i*ns .. (i+1)*ns], new_heap);
+                }
+                // The default VEC is empty, so there's nothing to free.
+                new_mem.copy_from_slice(&new_heap.insert(buf).to_ne_bytes());
+            },
+            db::SUM | db::ENUM => {
+                let variants = old.variant_of(old_t, old_mem).and_then(|ov| Some((ov, self.new_member(new_t, old.uuid[&ov])?)));
+                let (ov, nv) = match variants {
+                    Some(variants) => variants,
+                    None => {
+                        self.defaulted += 1;
+                        return;
+                    },
+                };
+                new.drop_instance(new_t, new_mem, new_heap);
+                new.instantiate(new_t, new_mem, new_heap, &[new.name[&nv].as_str()]).unwrap();
+                if let (Some(&op), Some(&np)) = (old.type_.get(&ov), new.type_.get(&nv)) {
+                    let (oi, ni) = (old.payload_offset(old_t), new.payload_offset(new_t));
+                    self.convert(op, &old_mem[oi .. oi + old.size[&op]], old_heap, np, &mut new_mem[ni .. ni + new.size[&np]], new_heap);
+                }
+            },
+            // Which member is in use is unknown, so keep the bytes.
+            db::UNION => {
+                let n = old_mem.len().min(new_mem.len());
+                new_mem[.. n].copy_from_slice(&old_mem[.. n]);
+            },
+            kind => panic!("{} is of kind {}, which is not a type", new.name[&new_t], kind),
+        }
+    }
+    fn old_member(&self, old_t: u32, uuid: u128) -> Option<u32> {
+        self.old.uuid_reverse.get(&uuid).cloned().filter(|m| self.old.struct_.get(m) == Some(&old_t))
+    }
+    fn new_member(&self, new_t: u32, uuid: u128) -> Option<u32> {
+        self.new.uuid_reverse.get(&uuid).cloned().filter(|m| self.new.struct_.get(m) == Some(&new_t))
+    }
+}
+
+fn read_u32(mem: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(mem);
+    u32::from_ne_bytes(bytes)
+}
+
+// Goes through the decimal form of the value. Floats are truncated towards zero when the new type is an integer.
+// `new_mem` is left untouched on error, e.g when the value is out of range.
+fn convert_scalar(old_t: u32, old_mem: &[u8], new_t: u32, new_mem: &mut [u8]) -> Result<(), String> {
+    if old_t == new_t {
+        new_mem.copy_from_slice(old_mem);
+        return Ok(());
+    }
+    let number = match old_t {
+        db::BOOL => ((old_mem[0] != 0) as u8).to_string(),
+        db::CHAR | db::UUID => db::primitive_to_string(db::primitive_of_semantic(old_t), old_mem),
+        _ => db::primitive_to_string(old_t, old_mem),
+    };
+    if new_t == db::F32 || new_t == db::F64 {
+        return db::primitive_from_str(new_t, new_mem, &number);
+    }
+    let integer = if old_t == db::F32 || old_t == db::F64 {
+        let f: f64 = number.parse().map_err(|e| format!("{}", e))?;
+        if !f.is_finite() {
+            return Err(format!("{} is not a number", f));
+        }
+        if f.trunc() == 0. { "0".to_owned() } else { format!("{}", f.trunc()) }
+    } else {
+        number
+    };
+    match new_t {
+        db::BOOL => {
+            new_mem[0] = (integer != "0") as u8;
+            Ok(())
+        },
+        db::CHAR => {
+            let c = integer.parse().ok().and_then(::std::char::from_u32).ok_or_else(|| format!("{} is not a char", integer))?;
+            new_mem.copy_from_slice(&(c as u32).to_ne_bytes());
+            Ok(())
+        },
+        db::UUID => db::primitive_from_str(db::U128, new_mem, &integer),
+        _ => db::primitive_from_str(new_t, new_mem, &integer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{builtin_db, db, WorldDB, DB, ENTITY_UUID, VEC3F_UUID};
+    use super::{diff, Change};
+
+    const X: u128 = 0x20000000000000000000000000000002;
+    const Y: u128 = 0x20000000000000000000000000000003;
+    const Z: u128 = 0x20000000000000000000000000000004;
+    const W: u128 = 0x20000000000000000000000000000005;
+    const SEGMENT_UUID: u128 = 0x20000000000000000000000000000030;
+    const POINT: u128 = 0x20000000000000000000000000000052;
+    const PATH: u128 = 0x20000000000000000000000000000054;
+
+    fn id(db: &DB, uuid: u128) -> u32 {
+        db.uuid_reverse[&uuid]
+    }
+    fn remove_member(db: &mut DB, uuid: u128) {
+        let m = db.uuid_reverse.remove(&uuid).unwrap();
+        db.uuid.remove(&m);
+        db.name.remove(&m);
+        db.type_.remove(&m);
+        db.struct_.remove(&m);
+        db.offset.remove(&m);
+        db.discriminant.remove(&m);
+    }
+    fn instance(world: &WorldDB, db: &DB, entity: u128, t: u128) -> String {
+        db.instance_to_string(id(db, t), world.get(entity, t).unwrap(), world.arena[&t].heap())
+    }
+
+    #[test]
+    fn diff_matches_members_by_uuid() {
+        let old = builtin_db();
+        let mut new = old.clone();
+        let vec3f = id(&new, VEC3F_UUID);
+        new.type_.insert(id(&new, X), db::I64);
+        new.name.insert(id(&new, Y), "height".to_owned());
+        remove_member(&mut new, Z);
+        new.add_member(vec3f, W, "w", Some(db::F32));
+        new.update_layouts();
+        assert_eq!(diff(&old, &new, VEC3F_UUID), vec![
+            Change::Removed { member: Z },
+            Change::Retyped { member: X, from: Some(db::uuid_of_primitive(db::F32)), to: Some(db::uuid_of_primitive(db::I64)) },
+            Change::Renamed { member: Y, from: "y".to_owned(), to: "height".to_owned() },
+            Change::Moved { member: Y, from: 4, to: 8 },
+            Change::Added { member: W },
+        ]);
+        assert_eq!(diff(&old, &old, VEC3F_UUID), vec![]);
+
+        // Fields without an offset are laid out last
+        let mut reordered = old.clone();
+        reordered.offset.remove(&id(&reordered, X));
+        reordered.update_layouts();
+        assert_eq!(diff(&old, &reordered, VEC3F_UUID), vec![
+            Change::Moved { member: Y, from: 4, to: 0 },
+            Change::Moved { member: Z, from: 8, to: 4 },
+            Change::Moved { member: X, from: 0, to: 8 },
+        ]);
+
+        let mut renumbered = old.clone();
+        renumbered.discriminant.insert(id(&renumbered, PATH), 7);
+        assert_eq!(diff(&old, &renumbered, 0x20000000000000000000000000000050), vec![Change::Renumbered { member: PATH, from: 3, to: 7 }]);
+    }
+
+    #[test]
+    fn migrate_converts_fields() {
+        let old = builtin_db();
+        let mut world = WorldDB::new();
+        world.add(&old, 1, VEC3F_UUID, &["1.5", "-2.75", "300"]).unwrap();
+        world.add(&old, 2, VEC3F_UUID, &["-4.75", "5", "6"]).unwrap();
+
+        let mut new = old.clone();
+        let vec3f = id(&new, VEC3F_UUID);
+        new.type_.insert(id(&new, X), db::I32);
+        new.name.insert(id(&new, Y), "height".to_owned());
+        new.type_.insert(id(&new, Z), db::U8);
+        new.offset.remove(&id(&new, Z));
+        new.offset.remove(&id(&new, X));
+        new.add_member(vec3f, W, "w", Some(db::F32));
+        new.update_layouts();
+
+        let report = world.migrate(&old, &new);
+        assert_eq!((report.instances, report.defaulted), (2, 1)); // 300 doesn't fit in a u8
+        assert!(report.dropped_types.is_empty());
+        assert_eq!(world.arena[&VEC3F_UUID].map.item_size(), new.size[&vec3f]);
+        // Reordered like the new layout, floats truncated towards zero, renames kept, new fields defaulted
+        assert_eq!(instance(&world, &new, 1, VEC3F_UUID), "Vec3f { height: -2.75, x: 1, z: 0, w: 0 }");
+        assert_eq!(instance(&world, &new, 2, VEC3F_UUID), "Vec3f { height: 5, x: -4, z: 6, w: 0 }");
+
+        // Removed fields are gone, not defaulted
+        let mut newer = new.clone();
+        remove_member(&mut newer, Y);
+        newer.update_layouts();
+        assert_eq!(world.migrate(&new, &newer).defaulted, 0);
+        assert_eq!(instance(&world, &newer, 2, VEC3F_UUID), "Vec3f { x: -4, z: 6, w: 0 }");
+    }
+
+    #[test]
+    fn migrate_converts_vec_and_sum_payloads() {
+        let old = builtin_db();
+        let mut world = WorldDB::new();
+        world.add(&old, 1, ENTITY_UUID, &["true", "Blue", "Path({1.5, 2, 3}, {4, 5, 6.25})"]).unwrap();
+        world.add(&old, 2, ENTITY_UUID, &["false", "Green", "Segment({-1.5, 2, 3})"]).unwrap();
+        world.add(&old, 3, ENTITY_UUID, &["false", "Red", "Point(7, 8, 9)"]).unwrap();
+
+        let mut new = old.clone();
+        new.type_.insert(id(&new, X), db::I64);
+        new.discriminant.insert(id(&new, PATH), 7);
+        remove_member(&mut new, POINT);
+        new.update_layouts();
+
+        let report = world.migrate(&old, &new);
+        assert_eq!((report.instances, report.defaulted), (3, 1)); // The Point variant is gone
+        assert_eq!(
+            instance(&world, &new, 1, ENTITY_UUID),
+            "Entity { visible: true, color: Blue, shape: Path([Vec3f { x: 1, y: 2, z: 3 }, Vec3f { x: 4, y: 5, z: 6.25 }]), initial: '\\0', owner: 0x0, bits: Bits { f: 0, u: 0 } }",
+        );
+        assert!(instance(&world, &new, 2, ENTITY_UUID).contains("shape: Segment([Vec3f { x: -1, y: 2, z: 3 }, Vec3f { x: 0, y: 0, z: 0 }])"));
+        assert!(instance(&world, &new, 3, ENTITY_UUID).contains("shape: Nothing"));
+        // The old VEC was left behind with the old map
+        assert_eq!(world.arena[&ENTITY_UUID].heap().len(), 1);
+    }
+
+    #[test]
+    fn retyping_to_another_composite_defaults() {
+        let old = builtin_db();
+        let mut world = WorldDB::new();
+        world.add(&old, 1, ENTITY_UUID, &["true", "Blue", "Segment({1, 2, 3}, {4, 5, 6})"]).unwrap();
+
+        let mut new = old.clone();
+        let vec2f = new.add_type(0x20000000000000000000000000000090, "Vec2f", db::STRUCT);
+        new.add_member(vec2f, 0x20000000000000000000000000000091, "x", Some(db::F32));
+        new.add_member(vec2f, 0x20000000000000000000000000000092, "y", Some(db::F32));
+        let segment = id(&new, SEGMENT_UUID);
+        new.elem.insert(segment, vec2f);
+        new.update_layouts();
+
+        let report = world.migrate(&old, &new);
+        assert_eq!(report.defaulted, 2); // Both elements of the Segment
+        assert!(instance(&world, &new, 1, ENTITY_UUID).contains("shape: Segment([Vec2f { x: 0, y: 0 }, Vec2f { x: 0, y: 0 }])"));
+    }
+
+    #[test]
+    fn migrate_drops_types_which_became_zero_sized() {
+        let old = builtin_db();
+        let mut world = WorldDB::new();
+        world.add(&old, 1, VEC3F_UUID, &["1", "2", "3"]).unwrap();
+        world.add(&old, 1, ENTITY_UUID, &[]).unwrap();
+
+        let mut new = old.clone();
+        for &m in &[X, Y, Z] {
+            remove_member(&mut new, m);
+        }
+        new.update_layouts();
+        assert_eq!(new.size[&id(&new, VEC3F_UUID)], 0);
+
+        let report = world.migrate(&old, &new);
+        assert_eq!(report.zero_sized_types, vec![VEC3F_UUID]);
+        assert!(report.dropped_types.is_empty());
+        assert!(!world.arena.contains_key(&VEC3F_UUID));
+        assert!(world.get(1, ENTITY_UUID).is_some());
+    }
+}
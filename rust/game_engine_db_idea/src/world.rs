@@ -0,0 +1,242 @@
+//! Storage of component instances keyed by entity, i.e the runtime half of the database.
+//!
+//! A `WorldDB` has one `Arena` per component type, keyed by the type's uuid. Instances are laid out as the `DB`
+//! describes their type, so the same `DB` must be passed to every call until the world is migrated to another one
+//! (see `WorldDB::migrate`).
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::{Arena, WorldDB, DB};
+use datamap::{DenseDataMap, VecHeap};
+
+impl Arena {
+    pub fn new(item_size: usize) -> Self {
+        Self { map: DenseDataMap::new(item_size), index: HashMap::new(), entity: HashMap::new() }
+    }
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+    pub fn contains(&self, entity: u128) -> bool {
+        self.index.contains_key(&entity)
+    }
+    pub fn get(&self, entity: u128) -> Option<&[u8]> {
+        self.index.get(&entity).and_then(|&k| self.map.get(k))
+    }
+    pub fn get_mut(&mut self, entity: u128) -> Option<(&mut [u8], &mut VecHeap)> {
+        match self.index.get(&entity) {
+            Some(&k) => self.map.get_mut_with_heap(k),
+            None => None,
+        }
+    }
+    pub fn heap(&self) -> &VecHeap {
+        self.map.heap()
+    }
+    /// All instances along with their entity, in traversal order.
+    pub fn iter(&self) -> impl Iterator<Item = (u128, &[u8])> + '_ {
+        self.map.iter().map(move |(k, mem)| (self.entity[&k], mem))
+    }
+}
+
+impl WorldDB {
+    pub fn new() -> Self {
+        Self { arena: HashMap::new() }
+    }
+    /// Gives `entity` an instance of the type `t`, built from its components as for `DB::instantiate`.
+    /// Replaces the instance the entity already had, if any.
+    pub fn add(&mut self, db: &DB, entity: u128, t: u128, init: &[&str]) -> Result<(), String> {
+        let id = *db.uuid_reverse.get(&t).ok_or_else(|| format!("{:#x} is not in the database", t))?;
+        let size = db.size[&id];
+        if size == 0 {
+            return Err(format!("{} is zero-sized, which arenas can't store", db.name[&id]));
+        }
+        let created = !self.arena.contains_key(&t);
+        let arena = self.arena.entry(t).or_insert_with(|| Arena::new(size));
+        if arena.map.item_size() != size {
+            return Err(format!("{} has changed size since its arena was created; migrate the world first", db.name[&id]));
+        }
+        let k = match db.insert_instance(id, &mut arena.map, init) {
+            Ok(k) => k,
+            Err(e) => {
+                if created {
+                    self.arena.remove(&t);
+                }
+                return Err(e);
+            },
+        };
+        if let Some(old) = arena.index.insert(entity, k) {
+            arena.entity.remove(&old);
+            db.remove_instance(id, &mut arena.map, old);
+        }
+        arena.entity.insert(k, entity);
+        Ok(())
+    }
+    /// Removes the instance of the type `t` which `entity` has, along with the VECs it owns.
+    /// Returns whether there was one.
+    pub fn remove(&mut self, db: &DB, entity: u128, t: u128) -> bool {
+        let arena = match self.arena.get_mut(&t) {
+            Some(arena) => arena,
+            None => return false,
+        };
+        match arena.index.remove(&entity) {
+            Some(k) => {
+                arena.entity.remove(&k);
+                db.remove_instance(db.uuid_reverse[&t], &mut arena.map, k);
+                true
+            },
+            None => false,
+        }
+    }
+    /// Removes every instance `entity` has. Returns how many there were.
+    pub fn remove_entity(&mut self, db: &DB, entity: u128) -> usize {
+        let types: Vec<_> = self.arena.keys().cloned().collect();
+        types.into_iter().filter(|&t| self.remove(db, entity, t)).count()
+    }
+    pub fn get(&self, entity: u128, t: u128) -> Option<&[u8]> {
+        self.arena.get(&t).and_then(|arena| arena.get(entity))
+    }
+    /// The instance of the type `t` which `entity` has, and the heap its VECs live in.
+    pub fn get_mut(&mut self, entity: u128, t: u128) -> Option<(&mut [u8], &mut VecHeap)> {
+        self.arena.get_mut(&t).and_then(|arena| arena.get_mut(entity))
+    }
+    /// All instances of the type `t` along with their entity, in traversal order.
+    pub fn instances(&self, t: u128) -> impl Iterator<Item = (u128, &[u8])> + '_ {
+        self.arena.get(&t).into_iter().flat_map(|arena| arena.iter())
+    }
+    /// Entities which have an instance of each of `types`, along with these instances in the order of `types`.
+    /// Walks the smallest arena, so its traversal order is kept.
+    pub fn join(&self, types: &[u128]) -> Vec<(u128, Vec<&[u8]>)> {
+        let arenas: Vec<&Arena> = match types.iter().map(|t| self.arena.get(t)).collect() {
+            Some(arenas) => arenas,
+            None => return Vec::new(),
+        };
+        let smallest = match arenas.iter().min_by_key(|arena| arena.len()) {
+            Some(&smallest) => smallest,
+            None => return Vec::new(),
+        };
+        smallest.iter().filter_map(|(entity, _)| {
+            let instances: Option<Vec<_>> = arenas.iter().map(|arena| arena.get(entity)).collect();
+            instances.map(|instances| (entity, instances))
+        }).collect()
+    }
+    /// Prints every entity, ordered by uuid, with all of its instances.
+    pub fn print(&self, db: &DB) {
+        let mut types: Vec<_> = self.arena.keys().cloned().collect();
+        types.sort();
+        let entities: BTreeSet<u128> = self.arena.values().flat_map(|arena| arena.index.keys().cloned()).collect();
+        for entity in entities {
+            println!("entity {:#x}:", entity);
+            for t in &types {
+                let arena = &self.arena[t];
+                let mem = match arena.get(entity) {
+                    Some(mem) => mem,
+                    None => continue,
+                };
+                match db.uuid_reverse.get(t) {
+                    Some(&id) => db.print_struct_instance(id, mem, arena.heap()),
+                    None => println!("<{} bytes of unknown type {:#x}>", mem.len(), t),
+                }
+            }
+        }
+    }
+}
+
+impl Default for WorldDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{builtin_db, WorldDB, DB, ENTITY_UUID, VEC3F_UUID};
+
+    fn vec3f(world: &WorldDB, db: &DB, entity: u128) -> Option<String> {
+        world.get(entity, VEC3F_UUID).map(|mem| db.instance_to_string(db.uuid_reverse[&VEC3F_UUID], mem, world.arena[&VEC3F_UUID].heap()))
+    }
+
+    #[test]
+    fn add_replaces() {
+        let db = builtin_db();
+        let mut world = WorldDB::new();
+        assert!(world.add(&db, 1, VEC3F_UUID, &["oops"]).is_err());
+        assert!(world.arena.is_empty());
+        assert!(world.add(&db, 1, 0x2a, &[]).is_err());
+        assert!(world.arena.is_empty());
+
+        world.add(&db, 1, VEC3F_UUID, &["1", "2", "3"]).unwrap();
+        world.add(&db, 2, VEC3F_UUID, &["4", "5", "6"]).unwrap();
+        world.add(&db, 1, VEC3F_UUID, &["7", "8", "9"]).unwrap();
+        assert_eq!(world.arena[&VEC3F_UUID].len(), 2);
+        assert_eq!(vec3f(&world, &db, 1).unwrap(), "Vec3f { x: 7, y: 8, z: 9 }");
+        assert_eq!(vec3f(&world, &db, 2).unwrap(), "Vec3f { x: 4, y: 5, z: 6 }");
+        // A failed add keeps what was there
+        assert!(world.add(&db, 1, VEC3F_UUID, &["oops"]).is_err());
+        assert_eq!(vec3f(&world, &db, 1).unwrap(), "Vec3f { x: 7, y: 8, z: 9 }");
+
+        // Replaced VECs are freed
+        world.add(&db, 1, ENTITY_UUID, &["true", "Red", "Path({1, 2, 3})"]).unwrap();
+        world.add(&db, 1, ENTITY_UUID, &["true", "Red", "Path({4, 5, 6}, {7, 8, 9})"]).unwrap();
+        assert_eq!(world.arena[&ENTITY_UUID].heap().len(), 1);
+
+        let (mem, _) = world.get_mut(2, VEC3F_UUID).unwrap();
+        mem[.. 4].copy_from_slice(&0.5f32.to_ne_bytes());
+        assert_eq!(vec3f(&world, &db, 2).unwrap(), "Vec3f { x: 0.5, y: 5, z: 6 }");
+    }
+
+    #[test]
+    fn remove_entity_across_arenas() {
+        let db = builtin_db();
+        let mut world = WorldDB::new();
+        world.add(&db, 1, VEC3F_UUID, &[]).unwrap();
+        world.add(&db, 2, VEC3F_UUID, &[]).unwrap();
+        world.add(&db, 1, ENTITY_UUID, &["true", "Red", "Path({1, 2, 3})"]).unwrap();
+        world.add(&db, 2, ENTITY_UUID, &["true", "Red", "Path({1, 2, 3})"]).unwrap();
+
+        assert_eq!(world.remove_entity(&db, 1), 2);
+        assert_eq!(world.remove_entity(&db, 1), 0);
+        assert!(world.get(1, VEC3F_UUID).is_none());
+        assert!(world.get(1, ENTITY_UUID).is_none());
+        assert!(world.get(2, VEC3F_UUID).is_some());
+        assert!(world.get(2, ENTITY_UUID).is_some());
+        assert_eq!(world.arena[&ENTITY_UUID].heap().len(), 1);
+        assert_eq!(world.instances(VEC3F_UUID).map(|(e, _)| e).collect::<Vec<_>>(), vec![2]);
+
+        assert!(world.remove(&db, 2, ENTITY_UUID));
+        assert!(!world.remove(&db, 2, ENTITY_UUID));
+        assert!(!world.remove(&db, 2, 0x2a));
+        assert!(world.arena[&ENTITY_UUID].heap().is_empty());
+    }
+
+    #[test]
+    fn join_follows_the_smallest_arena() {
+        let db = builtin_db();
+        let mut world = WorldDB::new();
+        for e in 1 .. 6 {
+            world.add(&db, e, VEC3F_UUID, &[&e.to_string()]).unwrap();
+        }
+        for &e in &[4, 9, 2, 5] {
+            world.add(&db, e, ENTITY_UUID, &[]).unwrap();
+        }
+        // Removals reorder dense storage, which joins follow
+        world.remove(&db, 4, ENTITY_UUID);
+        world.add(&db, 4, ENTITY_UUID, &[]).unwrap();
+        let entities: Vec<_> = world.instances(ENTITY_UUID).map(|(e, _)| e).collect();
+        assert_eq!(entities, vec![5, 9, 2, 4]);
+
+        let joined = world.join(&[VEC3F_UUID, ENTITY_UUID]);
+        assert_eq!(joined.iter().map(|&(e, _)| e).collect::<Vec<_>>(), vec![5, 2, 4]);
+        for (e, instances) in &joined {
+            assert_eq!(instances.len(), 2);
+            assert_eq!(instances[0], world.get(*e, VEC3F_UUID).unwrap());
+            assert_eq!(instances[1], world.get(*e, ENTITY_UUID).unwrap());
+        }
+        let swapped: Vec<_> = world.join(&[ENTITY_UUID, VEC3F_UUID]).into_iter().map(|(e, instances)| (e, instances[0].len())).collect();
+        assert_eq!(swapped, vec![(5, db.size[&db.uuid_reverse[&ENTITY_UUID]]), (2, db.size[&db.uuid_reverse[&ENTITY_UUID]]), (4, db.size[&db.uuid_reverse[&ENTITY_UUID]])]);
+
+        assert!(world.join(&[VEC3F_UUID, 0x2a]).is_empty());
+        assert!(world.join(&[]).is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+// Run with `cargo +nightly bench`.
+// Each iteration builds a hive, marks 1/3 of the items for removal, adds a batch of pending items, then compacts.
+// `setup_only` measures everything but the compaction, so it should be subtracted from the other results.
+#![feature(test)]
+
+extern crate hive;
+extern crate test;
+
+use hive::{Hive, OrderingPolicy};
+use test::Bencher;
+
+const ITEM_COUNT: u32 = 10_000;
+const PENDING_ADD_COUNT: u32 = 1_000;
+
+fn setup(ordering_policy: OrderingPolicy<u32>) -> Hive<u32> {
+    let hive = Hive::with_ordering_policy(ordering_policy);
+    for i in 0..ITEM_COUNT {
+        hive.add(i.wrapping_mul(2654435761)); // Scrambled, so that the predicate policy has actual sorting to do
+    }
+    {
+        let lock = hive.lock();
+        for (i, item) in lock.iter().enumerate() {
+            if i % 3 == 0 {
+                lock.mark_for_removal(item);
+            }
+        }
+        for i in 0..PENDING_ADD_COUNT {
+            lock.add(i.wrapping_mul(40503));
+        }
+    }
+    hive
+}
+
+#[bench]
+fn setup_only(b: &mut Bencher) {
+    b.iter(|| setup(OrderingPolicy::Chronological));
+}
+
+#[bench]
+fn compact_chronological(b: &mut Bencher) {
+    b.iter(|| {
+        let hive = setup(OrderingPolicy::Chronological);
+        hive.compact();
+        hive
+    });
+}
+
+#[bench]
+fn compact_unordered(b: &mut Bencher) {
+    b.iter(|| {
+        let hive = setup(OrderingPolicy::Unordered);
+        hive.compact();
+        hive
+    });
+}
+
+#[bench]
+fn compact_predicate(b: &mut Bencher) {
+    b.iter(|| {
+        let hive = setup(OrderingPolicy::predicate(u32::cmp));
+        hive.compact();
+        hive
+    });
+}
+
+#[bench]
+fn compact_predicate_already_sorted(b: &mut Bencher) {
+    // Typical steady state: the main buffer is sorted from the previous compaction, only the pending adds are not.
+    b.iter(|| {
+        let hive = setup(OrderingPolicy::predicate(u32::cmp));
+        hive.compact();
+        {
+            let lock = hive.lock();
+            for i in 0..PENDING_ADD_COUNT {
+                lock.add(i.wrapping_mul(40503));
+            }
+        }
+        hive.compact();
+        hive
+    });
+}
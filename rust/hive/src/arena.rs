@@ -0,0 +1,1098 @@
+use std::{alloc::Layout, error::Error, marker::PhantomData, mem::MaybeUninit, num::{NonZero, NonZeroUsize}, ops::Deref, ptr::{self, NonNull}, sync::atomic::{AtomicPtr, AtomicUsize, Ordering}};
+
+trait LowLevelAllocator {
+    /// Whether `reserve()` only reserves address space, so that `commit()` and `decommit()` actually do something.
+    const HAS_RESERVE_COMMIT: bool;
+
+    /// Granularity of `commit()` and `decommit()`.
+    fn page_size() -> NonZero<usize>;
+
+    /// Reserves and commits at once.
+    fn allocate_uninitialized_bytes(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error>;
+
+    /// Reserves address space, without backing it with physical memory. It must be committed before being accessed.
+    fn reserve(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error>;
+
+    /// # Safety
+    /// 
+    /// * `p` must be page-aligned, and within a block of memory [*currently allocated*] via this allocator
+    unsafe fn commit(p: NonNull<[u8]>) -> Result<(), impl Error>;
+
+    /// Gives physical memory back to the OS. The address space stays reserved, and may be committed again later.
+    ///
+    /// # Safety
+    /// 
+    /// * `p` must be page-aligned, and within a block of memory [*currently allocated*] via this allocator
+    /// * Nobody may access that memory until it is committed again
+    unsafe fn decommit(p: NonNull<[u8]>) -> Result<(), impl Error>;
+
+    /// # Safety
+    /// 
+    /// * `ptr` must denote a block of memory [*currently allocated*] via this allocator
+    unsafe fn deallocate(p: NonNull<[u8]>) -> Result<(), impl Error>;
+}
+
+/// Whether this target can give memory back to the OS without freeing it (i.e decommit), e.g when defragmenting arenas.
+/// Otherwise arenas commit all of their memory upfront.
+pub const HAS_RESERVE_COMMIT: bool = <os::LowLevelAllocatorImpl as LowLevelAllocator>::HAS_RESERVE_COMMIT;
+
+#[cfg(all(windows, not(miri)))]
+#[allow(bad_style, dead_code)]
+mod os {
+    use std::{num::NonZero, ptr::NonNull};
+    use std::error::Error;
+
+    use super::{workarounds, LowLevelAllocator};
+
+    // #[cfg(feature = "std")]
+    type c_void = std::os::raw::c_void;
+    // #[cfg(not(feature = "std"))]
+    // enum c_void {}
+
+    pub const PAGE_NOACCESS: u32 = 0x01;
+    pub const PAGE_READONLY: u32 = 0x02;
+    pub const PAGE_READWRITE: u32 = 0x04;
+    pub const PAGE_WRITECOPY: u32 = 0x08;
+    pub const PAGE_EXECUTE: u32 = 0x10;
+    pub const PAGE_EXECUTE_READ: u32 = 0x20;
+    pub const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+    pub const PAGE_EXECUTE_WRITECOPY: u32 = 0x80;
+    pub const PAGE_GUARD: u32 = 0x100;
+    pub const PAGE_NOCACHE: u32 = 0x200;
+    pub const PAGE_WRITECOMBINE: u32 = 0x400;
+    pub const PAGE_ENCLAVE_THREAD_CONTROL: u32 = 0x80000000;
+    pub const PAGE_REVERT_TO_FILE_MAP: u32 = 0x80000000;
+    pub const PAGE_TARGETS_NO_UPDATE: u32 = 0x40000000;
+    pub const PAGE_TARGETS_INVALID: u32 = 0x40000000;
+    pub const PAGE_ENCLAVE_UNVALIDATED: u32 = 0x20000000;
+    pub const PAGE_ENCLAVE_DECOMMIT: u32 = 0x10000000;
+
+    pub const MEM_COMMIT: u32 = 0x1000;
+    pub const MEM_RESERVE: u32 = 0x2000;
+    pub const MEM_DECOMMIT: u32 = 0x4000;
+    pub const MEM_RELEASE: u32 = 0x8000;
+    pub const MEM_FREE: u32 = 0x10000;
+    pub const MEM_PRIVATE: u32 = 0x20000;
+    pub const MEM_MAPPED: u32 = 0x40000;
+    pub const MEM_RESET: u32 = 0x80000;
+    pub const MEM_TOP_DOWN: u32 = 0x100000;
+    pub const MEM_WRITE_WATCH: u32 = 0x200000;
+    pub const MEM_PHYSICAL: u32 = 0x400000;
+    pub const MEM_ROTATE: u32 = 0x800000;
+    pub const MEM_DIFFERENT_IMAGE_BASE_OK: u32 = 0x800000;
+    pub const MEM_RESET_UNDO: u32 = 0x1000000;
+    pub const MEM_LARGE_PAGES: u32 = 0x20000000;
+    pub const MEM_4MB_PAGES: u32 = 0x80000000;
+    pub const MEM_64K_PAGES: u32 = MEM_LARGE_PAGES | MEM_PHYSICAL;
+
+    extern "system" {
+        pub fn VirtualAlloc(
+            lpAddress: *mut c_void,
+            dwSize: usize,
+            flAllocationType: u32,
+            flProtect: u32,
+        ) -> *mut c_void;
+
+        pub fn VirtualFree(
+            lpAddress: *mut c_void,
+            dwSize: usize,
+            dwFreeType: u32,
+        ) -> i32;
+
+        // TODO: DiscardVirtualMemory(), OfferVirtualMemory() and ReclaimVirtualMemory() could be used since Windows 8.1
+    }
+
+    pub struct LowLevelAllocatorImpl;
+
+    impl LowLevelAllocatorImpl {
+        fn virtual_alloc(p: *mut c_void, size: usize, allocation_type: u32, protect: u32) -> Result<NonNull<[u8]>, std::io::Error> {
+            // SAFETY: We pass valid parameters and check the result.
+            let p = unsafe { VirtualAlloc(p, size, allocation_type, protect) };
+            if p.is_null() {
+                Err(std::io::Error::last_os_error())
+            } else {
+                // SAFETY: The pointer is obviously non-null, we just checked.
+                let p = unsafe { NonNull::new_unchecked(p) };
+                Ok(NonNull::slice_from_raw_parts(p.cast(), size))
+            }
+        }
+    }
+
+    impl LowLevelAllocator for LowLevelAllocatorImpl {
+        const HAS_RESERVE_COMMIT: bool = true;
+
+        fn page_size() -> NonZero<usize> {
+            // TODO: GetSystemInfo()'s dwPageSize. 4 KiB is right for x86 and x64 at least.
+            NonZero::new(4096).unwrap()
+        }
+
+        fn allocate_uninitialized_bytes(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error> {
+            Self::virtual_alloc(std::ptr::null_mut(), size.get(), MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE)
+        }
+
+        fn reserve(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error> {
+            Self::virtual_alloc(std::ptr::null_mut(), size.get(), MEM_RESERVE, PAGE_NOACCESS)
+        }
+
+        unsafe fn commit(p: NonNull<[u8]>) -> Result<(), impl Error> {
+            Self::virtual_alloc(workarounds::non_null_slice_ptr(p).cast().as_ptr(), p.len(), MEM_COMMIT, PAGE_READWRITE).map(|_| ())
+        }
+
+        unsafe fn decommit(p: NonNull<[u8]>) -> Result<(), impl Error> {
+            let ok = VirtualFree(workarounds::non_null_slice_ptr(p).cast().as_ptr(), p.len(), MEM_DECOMMIT);
+            if ok == 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        unsafe fn deallocate(p: NonNull<[u8]>) -> Result<(), impl Error> {
+            let ok = VirtualFree(workarounds::non_null_slice_ptr(p).cast().as_ptr(), 0, MEM_RELEASE);
+            if ok == 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+// The constants and `off_t` below are those of x86_64 and aarch64, other Linux targets get the fallback.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"), not(miri)))]
+#[allow(bad_style, dead_code)]
+mod os {
+    use std::{error::Error, num::NonZero, ptr::NonNull};
+
+    use super::{workarounds, LowLevelAllocator};
+
+    type c_void = std::os::raw::c_void;
+    type c_int = std::os::raw::c_int;
+    type c_long = std::os::raw::c_long;
+    type off_t = i64;
+
+    pub const PROT_NONE: c_int = 0;
+    pub const PROT_READ: c_int = 1;
+    pub const PROT_WRITE: c_int = 2;
+
+    pub const MAP_PRIVATE: c_int = 0x02;
+    pub const MAP_ANONYMOUS: c_int = 0x20;
+    pub const MAP_NORESERVE: c_int = 0x4000;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    pub const MADV_DONTNEED: c_int = 4;
+
+    pub const _SC_PAGESIZE: c_int = 30;
+
+    extern "C" {
+        pub fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: off_t) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+        pub fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        pub fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> c_int;
+        pub fn sysconf(name: c_int) -> c_long;
+    }
+
+    pub struct LowLevelAllocatorImpl;
+
+    impl LowLevelAllocatorImpl {
+        fn map_anonymous(size: NonZero<usize>, prot: c_int, flags: c_int) -> Result<NonNull<[u8]>, std::io::Error> {
+            // SAFETY: We pass valid parameters and check the result.
+            let p = unsafe { mmap(std::ptr::null_mut(), size.get(), prot, MAP_PRIVATE | MAP_ANONYMOUS | flags, -1, 0) };
+            if p == MAP_FAILED {
+                Err(std::io::Error::last_os_error())
+            } else {
+                // SAFETY: mmap() never succeeds with a null pointer unless asked for address 0 with MAP_FIXED
+                let p = unsafe { NonNull::new_unchecked(p) };
+                Ok(NonNull::slice_from_raw_parts(p.cast(), size.get()))
+            }
+        }
+
+        fn check(ret: c_int) -> Result<(), std::io::Error> {
+            if ret == 0 {
+                Ok(())
+            } else {
+                Err(std::io::Error::last_os_error())
+            }
+        }
+    }
+
+    impl LowLevelAllocator for LowLevelAllocatorImpl {
+        const HAS_RESERVE_COMMIT: bool = true;
+
+        fn page_size() -> NonZero<usize> {
+            // SAFETY: Always safe to call
+            let page_size = unsafe { sysconf(_SC_PAGESIZE) };
+            NonZero::new(usize::try_from(page_size).unwrap()).unwrap()
+        }
+
+        fn allocate_uninitialized_bytes(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error> {
+            Self::map_anonymous(size, PROT_READ | PROT_WRITE, 0)
+        }
+
+        fn reserve(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error> {
+            // MAP_NORESERVE: don't count the whole range against the overcommit limit, only what we commit
+            Self::map_anonymous(size, PROT_NONE, MAP_NORESERVE)
+        }
+
+        unsafe fn commit(p: NonNull<[u8]>) -> Result<(), impl Error> {
+            Self::check(mprotect(workarounds::non_null_slice_ptr(p).cast().as_ptr(), p.len(), PROT_READ | PROT_WRITE))
+        }
+
+        unsafe fn decommit(p: NonNull<[u8]>) -> Result<(), impl Error> {
+            let addr = workarounds::non_null_slice_ptr(p).cast().as_ptr();
+            // Drop the pages first, so that committing again gives zeroed pages, like on Windows
+            Self::check(madvise(addr, p.len(), MADV_DONTNEED))?;
+            Self::check(mprotect(addr, p.len(), PROT_NONE))
+        }
+
+        unsafe fn deallocate(p: NonNull<[u8]>) -> Result<(), impl Error> {
+            Self::check(munmap(workarounds::non_null_slice_ptr(p).cast().as_ptr(), p.len()))
+        }
+    }
+}
+
+#[cfg(any(miri, not(any(windows, all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))))]
+mod os {
+    use std::{error::Error, num::NonZero, ptr::NonNull};
+
+    use super::LowLevelAllocator;
+    use super::workarounds;
+
+    pub struct LowLevelAllocatorImpl;
+
+    // No virtual memory tricks here: reserving allocates everything upfront, and committing does nothing.
+    impl LowLevelAllocator for LowLevelAllocatorImpl {
+        const HAS_RESERVE_COMMIT: bool = false;
+
+        fn page_size() -> NonZero<usize> {
+            NonZero::new(1).unwrap()
+        }
+
+        fn reserve(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error> {
+            Self::allocate_uninitialized_bytes(size)
+        }
+
+        unsafe fn commit(_p: NonNull<[u8]>) -> Result<(), impl Error> {
+            return Ok(());
+            #[allow(unreachable_code)]
+            Err(std::io::Error::last_os_error())
+        }
+
+        unsafe fn decommit(_p: NonNull<[u8]>) -> Result<(), impl Error> {
+            return Ok(());
+            #[allow(unreachable_code)]
+            Err(std::io::Error::last_os_error())
+        }
+
+        fn allocate_uninitialized_bytes(size: NonZero<usize>) -> Result<NonNull<[u8]>, impl Error> {
+            let mut v = Vec::<u8>::with_capacity(size.get());
+            let p = v.as_mut_ptr();
+            std::mem::forget(v);
+            // SAFETY: The pointer of a Vec<u8> with non-zero capacity can never be null
+            return Ok(NonNull::slice_from_raw_parts(unsafe { NonNull::new_unchecked(p) }, size.get()));
+            #[allow(unreachable_code)]
+            Err(std::io::Error::last_os_error())
+        }
+
+        unsafe fn deallocate(p: NonNull<[u8]>) -> Result<(), impl Error> {
+            drop(Vec::from_raw_parts(workarounds::non_null_slice_ptr(p).as_ptr(), 0, p.len()));
+            return Ok(());
+            #[allow(unreachable_code)]
+            Err(std::io::Error::last_os_error())
+        }
+    }
+}
+
+mod workarounds {
+    use std::{mem::MaybeUninit, num::NonZero, ptr::NonNull};
+
+    // Replacement for NonNull<[T]>::as_non_null_ptr() (#[unstable(feature = "slice_ptr_get", issue = "74265")])
+    pub const fn non_null_slice_ptr<T>(p: NonNull<[T]>) -> NonNull<T> {
+        p.cast()
+    }
+
+    pub const fn non_null_slice_uninit<T>(p: NonNull<[T]>) -> NonNull<[MaybeUninit<T>]> {
+        NonNull::slice_from_raw_parts(non_null_slice_ptr(p).cast(), p.len())
+    }
+
+    // #[unstable(feature = "nonnull_provenance", issue = "135243")]
+    #[must_use]
+    #[inline]
+    pub const fn nonnull_without_provenance<T>(addr: NonZero<usize>) -> NonNull<T> {
+        let pointer = std::ptr::without_provenance_mut(addr.get());
+        // SAFETY: we know `addr` is non-zero.
+        unsafe { NonNull::new_unchecked(pointer) }
+    }
+
+    #[test]
+    fn non_null_slice_ptr_still_works() {
+        let mut data = [0u8; 1];
+        let p = NonNull::new(data.as_mut_ptr()).unwrap();
+        let s = NonNull::slice_from_raw_parts(p, data.len());
+        assert_eq!(non_null_slice_ptr(s), p);
+    }
+}
+
+// TODO: a way to list all strong/weak refs
+
+#[repr(C)] // I've sorted members from most accessed to least accessed, keep that order for cache efficiency.
+#[derive(Debug)]
+struct SuballocationHeaderInner {
+    suballocation_within_arena: NonNull<[u8]>,
+    len: usize,
+    strong_ref_count: usize,
+    generation: usize,
+    element_layout: Layout, // TODO: compress the layout's alignment?
+    arena_strong_ref: Option<ArenaStrongRef>, // TODO: we could get rid of this and instead call inc_strong/dec_strong manually when a suballocation header is added/freed
+}
+
+impl SuballocationHeaderInner {
+    fn unused(generation: usize) -> Self {
+        Self {
+            suballocation_within_arena: NonNull::slice_from_raw_parts(workarounds::nonnull_without_provenance(NonZeroUsize::MAX), 0),
+            len: 0,
+            strong_ref_count: 0,
+            element_layout: Layout::new::<u8>(),
+            arena_strong_ref: None,
+            generation,
+        }
+    }
+}
+
+#[repr(C)] // Just for a better debugging experience
+#[derive(Debug)]
+struct SuballocationHeader {
+    mutex: parking_lot::Mutex<SuballocationHeaderInner>,
+    arena_header_ptr: NonNull<ArenaHeader>, // Never changes: headers are never handed over to another arena
+}
+
+impl SuballocationHeader {
+    fn arena_header(&self) -> &ArenaHeader {
+        // SAFETY: headers live inside their arena's allocation, so the arena outlives them
+        unsafe { self.arena_header_ptr.as_ref() }
+    }
+}
+
+/// Growable array whose elements live in the left area of an arena.
+/// When it can't grow in place, it is relocated elsewhere in the arena; refs are unaffected since they point to its
+/// header (which lives in the right area and never moves).
+#[repr(C)] // Just for a better debugging experience
+#[derive(Debug)]
+pub struct RelocatableVecStrongRef<T> {
+    suballocation_header_ptr: NonNull<SuballocationHeader>,
+
+    // I don't fully understand yet why this is needed. Something about covariance. Rc<T> does this internally, so I'm just doing the same.
+    phantom: PhantomData<(T, SuballocationHeader)>,
+}
+
+// Same rules as Arc<Mutex<Vec<T>>>
+unsafe impl<T: Send> Send for RelocatableVecStrongRef<T> {}
+unsafe impl<T: Send> Sync for RelocatableVecStrongRef<T> {}
+
+// TODO: support zero-sized types
+
+impl<T> RelocatableVecStrongRef<T> {
+    fn suballocation_header(&self) -> &SuballocationHeader {
+        // SAFETY: As long as we exist, that memory will be valid
+        unsafe { self.suballocation_header_ptr.as_ref() }
+    }
+    fn take_arena_strong_ref(&self) -> Option<ArenaStrongRef> {
+        self.suballocation_header().mutex.lock().arena_strong_ref.take()
+    }
+    pub fn downgrade(&self) -> RelocatableVecWeakRef<T> {
+        let generation = self.suballocation_header().mutex.lock().generation;
+        RelocatableVecWeakRef {
+            arena_weak_ref: self.suballocation_header().arena_header().new_weak_ref(),
+            suballocation_header_ptr: self.suballocation_header_ptr,
+            generation,
+            phantom: PhantomData,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.suballocation_header().mutex.lock().len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn capacity(&self) -> usize {
+        self.suballocation_header().mutex.lock().suballocation_within_arena.len() / std::mem::size_of::<T>()
+    }
+    /// Locks the vec and gives access to its elements. Pushing to this same vec while the guard is alive deadlocks.
+    pub fn lock(&self) -> parking_lot::MappedMutexGuard<'_, [T]> {
+        parking_lot::MutexGuard::map(self.suballocation_header().mutex.lock(), |inner| {
+            // SAFETY: the first `len` elements are initialized, and the mutex guarantees exclusive access
+            unsafe { std::slice::from_raw_parts_mut(inner.suballocation_within_arena.cast::<T>().as_ptr(), inner.len) }
+        })
+    }
+    /// Panics if the arena is full.
+    pub fn push(&mut self, val: T) {
+        if self.try_push(val).is_err() {
+            panic!("The arena is full");
+        }
+    }
+    /// Gives the value back if the arena is full.
+    pub fn try_push(&mut self, val: T) -> Result<(), T> {
+        let header = self.suballocation_header();
+        let arena_header = header.arena_header();
+        let mut inner = header.mutex.lock();
+        let capacity = inner.suballocation_within_arena.len() / std::mem::size_of::<T>();
+        if inner.len == capacity {
+            let new_capacity = (capacity * 2).max(4);
+            let Ok(new_layout) = Layout::array::<T>(new_capacity) else { return Err(val) };
+            let old_block = inner.suballocation_within_arena;
+            if capacity > 0 && arena_header.try_grow_in_place(old_block, new_layout.size()) {
+                inner.suballocation_within_arena = NonNull::slice_from_raw_parts(workarounds::non_null_slice_ptr(old_block), new_layout.size());
+            } else {
+                let Some(new_block) = arena_header.allocate_left(new_layout) else { return Err(val) };
+                // SAFETY: both blocks are ours, don't overlap, and the first `len` elements are initialized.
+                // Moving them is fine since T: Unpin (see `create_relocatable_vec()`).
+                unsafe { ptr::copy_nonoverlapping(old_block.cast::<T>().as_ptr(), new_block.cast::<T>().as_ptr(), inner.len) };
+                if capacity > 0 {
+                    arena_header.free_left(old_block);
+                }
+                inner.suballocation_within_arena = new_block;
+            }
+        }
+        // SAFETY: we just made sure there is room for one more element
+        unsafe { inner.suballocation_within_arena.cast::<T>().add(inner.len).write(val) };
+        inner.len += 1;
+        Ok(())
+    }
+    pub fn pop(&mut self) -> Option<T> {
+        let mut inner = self.suballocation_header().mutex.lock();
+        if inner.len == 0 {
+            return None;
+        }
+        inner.len -= 1;
+        // SAFETY: that element was initialized, and is now past `len` so it won't be read again
+        Some(unsafe { inner.suballocation_within_arena.cast::<T>().add(inner.len).read() })
+    }
+}
+
+impl<T> Clone for RelocatableVecStrongRef<T> {
+    fn clone(&self) -> Self {
+        self.suballocation_header().mutex.lock().strong_ref_count += 1;
+        Self {
+            suballocation_header_ptr: self.suballocation_header_ptr,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for RelocatableVecStrongRef<T> {
+    fn drop(&mut self) {
+        let header = self.suballocation_header();
+        let arena_header = header.arena_header();
+        let mut suballocation_header_inner = header.mutex.lock();
+        assert_ne!(suballocation_header_inner.strong_ref_count, 0);
+        if suballocation_header_inner.strong_ref_count > 1 {
+            suballocation_header_inner.strong_ref_count -= 1;
+            return;
+        }
+        // Internal suballocations don't hold a strong ref to their own arena
+        let arena_strong_ref = suballocation_header_inner.arena_strong_ref.take();
+        let generation = suballocation_header_inner.generation.wrapping_add(1);
+        let block = suballocation_header_inner.suballocation_within_arena;
+        let len = std::mem::replace(&mut suballocation_header_inner.len, 0);
+        // Keep the block for now: a dead header with a block pins it in place (see `ArenaHeader::defragment()`)
+        suballocation_header_inner.strong_ref_count = 0;
+        suballocation_header_inner.generation = generation;
+        // Dropping elements may call into user code, which may use the arena. Don't hold any lock meanwhile.
+        drop(suballocation_header_inner);
+        // SAFETY: the first `len` elements are initialized, and nobody can access them anymore.
+        unsafe { ptr::slice_from_raw_parts_mut(block.cast::<T>().as_ptr(), len).drop_in_place() };
+        *header.mutex.lock() = SuballocationHeaderInner::unused(generation);
+        if !block.is_empty() {
+            arena_header.free_left(block);
+        }
+        if let Some(free_list) = arena_header.suballocation_headers_free_list.lock().as_mut() {
+            // If the free list is full, that header is leaked for the lifetime of the arena. No big deal.
+            let _ = free_list.try_push(self.suballocation_header_ptr);
+        }
+        // Last, because this may destroy the arena.
+        drop(arena_strong_ref);
+    }
+}
+
+#[repr(C)] // Just for a better debugging experience
+#[derive(Debug, Clone)]
+pub struct RelocatableVecWeakRef<T> {
+    arena_weak_ref: ArenaWeakRef,
+    suballocation_header_ptr: NonNull<SuballocationHeader>,
+    generation: usize,
+    phantom: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for RelocatableVecWeakRef<T> {}
+unsafe impl<T: Send> Sync for RelocatableVecWeakRef<T> {}
+
+impl<T> Default for RelocatableVecWeakRef<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RelocatableVecWeakRef<T> {
+    pub fn new() -> Self {
+        Self {
+            arena_weak_ref: ArenaWeakRef::new(),
+            suballocation_header_ptr: workarounds::nonnull_without_provenance(NonZeroUsize::MAX),
+            generation: usize::MAX,
+            phantom: Default::default(),
+        }
+    }
+    pub fn is_dangling(&self) -> bool {
+        self.arena_weak_ref.is_dangling()
+    }
+    pub fn upgrade(&self) -> Option<RelocatableVecStrongRef<T>> {
+        let arena_strong_ref = self.arena_weak_ref.upgrade()?;
+
+        // Assert that the arena's right_area_start_ptr never shrinks, otherwise the generation trick won't work: generation counters have to stay pinned in memory for as long as the arena is in use.
+        // TODO: we COULD actually shrink right_area_start_ptr but that would require the following:
+        // - Knowing when ALL weak refs to a right-side-suballocation have been dropped (i.e knowing that the suballocation is unreferenced)
+        // - If we allow external users to allocate from the right side: provide a mechanism to "mark as free", which could add the pointer to a free list allocated on the left side
+        // - Being able to swap SuballocationHeaders without breaking live refs. Probably needs some kind of indirection.
+        // - This would allow us to "bubble up" the unreferenced headers, moving them to the left
+        assert!(self.suballocation_header_ptr.addr().get() >= arena_strong_ref.arena_header().right_area_start_ptr.load(Ordering::SeqCst).addr());
+
+        // SAFETY: we have a strong ref to the arena and have proven that the pointer is in valid range
+        let mut suballocation_header_inner = unsafe { self.suballocation_header_ptr.as_ref() }.mutex.lock();
+        if suballocation_header_inner.generation == self.generation && suballocation_header_inner.strong_ref_count > 0 {
+            suballocation_header_inner.strong_ref_count += 1;
+            Some(RelocatableVecStrongRef {
+                suballocation_header_ptr: self.suballocation_header_ptr,
+                phantom: Default::default(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+// TODO: a way to list all strong/weak refs
+
+/// Lives at the start of its own allocation. The rest (the "client area") is split in two:
+/// - The left area grows to the right, and contains the elements of relocatable vecs;
+/// - The right area grows to the left, and contains suballocation headers, which never move.
+///
+/// Arenas created via `reserve()` only commit memory as both areas grow, so they can be made huge upfront.
+#[repr(C)] // Just for a better debugging experience
+#[derive(Debug)]
+pub struct ArenaHeader {
+    allocation: NonNull<[u8]>,
+    strong_ref_count: AtomicUsize,
+    weak_ref_count: AtomicUsize,
+    left_area_end_ptr: AtomicPtr<u8>,
+    right_area_start_ptr: AtomicPtr<u8>,
+    bump_mutex: parking_lot::Mutex<()>, // Serializes changes to both area pointers, so they never cross
+    // Memory is committed within [allocation.start, committed_left_end) and [committed_right_start, allocation.end).
+    // Fully committed arenas have both set to allocation.end.
+    committed_left_end_ptr: AtomicPtr<u8>,
+    committed_right_start_ptr: AtomicPtr<u8>,
+    suballocation_headers_free_list: parking_lot::Mutex<Option<RelocatableVecStrongRef<NonNull<SuballocationHeader>>>>,
+    owns_allocation: bool,
+}
+
+impl ArenaHeader {
+    pub fn min_required_size() -> NonZero<usize> {
+        NonZero::new(std::mem::align_of::<ArenaHeader>() - 1 + std::mem::size_of::<ArenaHeader>()).unwrap()
+    }
+    pub fn create(size: NonZero<usize>) -> ArenaStrongRef {
+        let allocation = os::LowLevelAllocatorImpl::allocate_uninitialized_bytes(size).unwrap();
+        assert!(allocation.len() >= size.get());
+        Self::with_allocation_impl(allocation, true, allocation.len())
+    }
+    /// Reserves `size` bytes of address space, but only commits memory as the arena fills up.
+    /// Returns `None` if the address space can't be reserved.
+    pub fn reserve(size: NonZero<usize>) -> Option<ArenaStrongRef> {
+        let size = size.max(Self::min_required_size());
+        let allocation = os::LowLevelAllocatorImpl::reserve(size).ok()?;
+        assert!(allocation.len() >= size.get());
+        // Only the header needs to be accessible from the start
+        let committed_len = Self::min_required_size().get().next_multiple_of(os::LowLevelAllocatorImpl::page_size().get()).min(allocation.len());
+        // SAFETY: the allocation is ours, and starts on a page boundary
+        if unsafe { os::LowLevelAllocatorImpl::commit(NonNull::slice_from_raw_parts(workarounds::non_null_slice_ptr(allocation), committed_len)) }.is_err() {
+            // SAFETY: we just reserved it
+            unsafe { os::LowLevelAllocatorImpl::deallocate(allocation).unwrap() };
+            return None;
+        }
+        Some(Self::with_allocation_impl(allocation, true, committed_len))
+    }
+    /// # Safety
+    /// 
+    /// * You must ensure that the `allocation` lives at least as long as the ArenaStrongRef.
+    pub unsafe fn with_allocation(allocation: NonNull<[u8]>) -> ArenaStrongRef {
+        // TODO: we should take the deallocator as well, in order to know how to free the memory!
+        // TODO: feature: when trying to allocate from an arena, if there is no more room, it could call into some user-provided strategy, possibly attempting to create a new arena with some capacity, and then redirect to THAT arena. This should be useful during development if we are on a good machine and memory usage momentarily exceeds the expected amount due to a poorly optimized gameplay section; would allow us to issue a warning but not interrupt whatever we're trying to do (profiling, debugging, looking for an issue, etc).
+        Self::with_allocation_impl(allocation, false, allocation.len())
+    }
+    // This function is "safe" as long as it's not used externally.
+    // The public API is with_allocation() and marked as unsafe.
+    // This function is NOT marked as unsafe, to force us to highlight the unsafe places within the body.
+    fn with_allocation_impl(allocation: NonNull<[u8]>, owns_allocation: bool, committed_len: usize) -> ArenaStrongRef {
+        assert!(allocation.len() >= Self::min_required_size().get());
+        assert!(allocation.len() <= isize::MAX as usize); // std's Vec panics in that case
+        // TODO: memset(0) in order to force physical pages to be allocated (consider multi-threading that?). "committing" in Windows does not do that. https://learn.microsoft.com/en-us/windows/win32/memory/reserving-and-committing-memory?redirectedfrom=MSDN
+        // TODO: Add Miri and run "MIRIFLAGS='-Zmiri-strict-provenance' cargo miri test"
+        let arena_header_slice = unsafe { 
+            workarounds::non_null_slice_uninit(allocation)
+            .as_mut()
+            .align_to_mut::<MaybeUninit<ArenaHeader>>().1
+        };
+        // SAFETY: the result pointer is still in bounds
+        let left_area_end_ptr = AtomicPtr::new(unsafe { arena_header_slice.as_mut_ptr().add(1) }.cast());
+        // SAFETY: the result pointers are still in bounds
+        let allocation_end_ptr = unsafe { workarounds::non_null_slice_ptr(allocation).add(allocation.len()) }.as_ptr();
+        let committed_left_end_ptr = if committed_len == allocation.len() {
+            allocation_end_ptr
+        } else {
+            unsafe { workarounds::non_null_slice_ptr(allocation).add(committed_len) }.as_ptr()
+        };
+        arena_header_slice[0].write(ArenaHeader {
+            allocation,
+            strong_ref_count: AtomicUsize::new(1),
+            weak_ref_count: AtomicUsize::new(1), // The entire set of all strong refs holds 1 weak ref
+            left_area_end_ptr,
+            right_area_start_ptr: AtomicPtr::new(allocation_end_ptr),
+            bump_mutex: parking_lot::Mutex::new(()),
+            committed_left_end_ptr: AtomicPtr::new(committed_left_end_ptr),
+            committed_right_start_ptr: AtomicPtr::new(allocation_end_ptr),
+            suballocation_headers_free_list: parking_lot::Mutex::new(None),
+            owns_allocation,
+        });
+        // SAFETY: we called write() just above
+        let arena_header = unsafe { arena_header_slice[0].assume_init_mut() };
+        *arena_header.suballocation_headers_free_list.get_mut() = arena_header.create_relocatable_vec_internal_to_this_arena();
+        assert_eq!(arena_header.strong_ref_count.load(Ordering::SeqCst), 1, "After creating internal structures, the arena's strong ref count must still be 1");
+        let arena_header_ptr = NonNull::from(arena_header);
+        assert_ne!(arena_header_ptr.addr(), NonZeroUsize::MAX);
+        ArenaStrongRef { arena_header_ptr, phantom: PhantomData }
+    }
+    pub fn client_area(&self) -> NonNull<[u8]> {
+        let self_p = NonNull::from(self);
+        // SAFETY: self_p is definitely from an allocated object, and the addition will not overflow
+        let start = unsafe { self_p.add(1) }.cast();
+        let end = self.allocation.addr().checked_add(self.allocation.len()).unwrap();
+        debug_assert!(self_p.addr() >= self.allocation.addr() && start.addr() <= end);
+        NonNull::slice_from_raw_parts(start, end.get() - start.addr().get())
+    }
+    /// How many bytes of the allocation are backed by memory, including the header.
+    pub fn committed_size(&self) -> usize {
+        let start = self.allocation.addr().get();
+        let end = start + self.allocation.len();
+        let committed_left_end = self.committed_left_end_ptr.load(Ordering::SeqCst).addr();
+        let committed_right_start = self.committed_right_start_ptr.load(Ordering::SeqCst).addr();
+        (committed_left_end - start) + (end - committed_right_start.max(committed_left_end))
+    }
+    /// Commits pages so that the left area is accessible up to `end`. Must be called with the bump mutex locked.
+    fn commit_left_until(&self, end: *mut u8) -> bool {
+        let committed_left_end = self.committed_left_end_ptr.load(Ordering::SeqCst);
+        if end <= committed_left_end {
+            return true;
+        }
+        let committed_right_start = self.committed_right_start_ptr.load(Ordering::SeqCst);
+        let page_size = os::LowLevelAllocatorImpl::page_size().get();
+        let new_committed_left_end = end.map_addr(|a| a.next_multiple_of(page_size)).min(committed_right_start);
+        let pages = NonNull::slice_from_raw_parts(NonNull::new(committed_left_end).unwrap(), new_committed_left_end.addr() - committed_left_end.addr());
+        // SAFETY: these pages are within our allocation, and not committed yet
+        if unsafe { os::LowLevelAllocatorImpl::commit(pages) }.is_err() {
+            return false;
+        }
+        // Both committed ranges met: everything is committed
+        let new_committed_left_end = if new_committed_left_end == committed_right_start { self.allocation_end_ptr() } else { new_committed_left_end };
+        self.committed_left_end_ptr.store(new_committed_left_end, Ordering::SeqCst);
+        true
+    }
+    /// Commits pages so that the right area is accessible from `start`. Must be called with the bump mutex locked.
+    fn commit_right_from(&self, start: *mut u8) -> bool {
+        let committed_right_start = self.committed_right_start_ptr.load(Ordering::SeqCst);
+        let committed_left_end = self.committed_left_end_ptr.load(Ordering::SeqCst);
+        let page_size = os::LowLevelAllocatorImpl::page_size().get();
+        let new_committed_right_start = start.map_addr(|a| a - a % page_size).max(committed_left_end);
+        if new_committed_right_start >= committed_right_start {
+            return true;
+        }
+        let pages = NonNull::slice_from_raw_parts(NonNull::new(new_committed_right_start).unwrap(), committed_right_start.addr() - new_committed_right_start.addr());
+        // SAFETY: these pages are within our allocation, and not committed yet
+        if unsafe { os::LowLevelAllocatorImpl::commit(pages) }.is_err() {
+            return false;
+        }
+        if new_committed_right_start == committed_left_end {
+            self.committed_left_end_ptr.store(self.allocation_end_ptr(), Ordering::SeqCst);
+        }
+        self.committed_right_start_ptr.store(new_committed_right_start, Ordering::SeqCst);
+        true
+    }
+    fn allocation_end_ptr(&self) -> *mut u8 {
+        // SAFETY: one-past-the-end of our allocation
+        unsafe { workarounds::non_null_slice_ptr(self.allocation).add(self.allocation.len()) }.as_ptr()
+    }
+    /// # Safety
+    ///
+    /// * Must only be called once the last strong ref is gone.
+    unsafe fn drop_suballocations(&self) {
+        // Each user suballocation holds a strong ref to us, so only internal ones are left by now.
+        let free_list = self.suballocation_headers_free_list.lock().take();
+        drop(free_list);
+        let _bump_guard = self.bump_mutex.lock();
+        let client_area_start = workarounds::non_null_slice_ptr(self.client_area()).as_ptr();
+        self.left_area_end_ptr.store(client_area_start, Ordering::SeqCst);
+        if !self.owns_allocation {
+            // We don't know how that memory was allocated
+            return;
+        }
+        // Give the memory back to the OS, but keep the header around for weak refs
+        let page_size = os::LowLevelAllocatorImpl::page_size().get();
+        let decommit_start = client_area_start.map_addr(|a| a.next_multiple_of(page_size)).min(self.allocation_end_ptr());
+        let pages = NonNull::slice_from_raw_parts(NonNull::new(decommit_start).unwrap(), self.allocation_end_ptr().addr() - decommit_start.addr());
+        // SAFETY: these pages are within our allocation, and nobody can access the client area anymore
+        if !pages.is_empty() && unsafe { os::LowLevelAllocatorImpl::decommit(pages) }.is_ok() {
+            self.committed_left_end_ptr.store(decommit_start, Ordering::SeqCst);
+            self.committed_right_start_ptr.store(self.allocation_end_ptr(), Ordering::SeqCst);
+        }
+    }
+    fn new_strong_ref(&self) -> ArenaStrongRef {
+        // Having `&self` means someone is holding a strong ref, so the count can't be zero.
+        let old_strong_ref_count = self.strong_ref_count.fetch_add(1, Ordering::Acquire);
+        assert_ne!(old_strong_ref_count, 0);
+        ArenaStrongRef { arena_header_ptr: NonNull::from(self), phantom: PhantomData }
+    }
+    fn new_weak_ref(&self) -> ArenaWeakRef {
+        self.weak_ref_count.fetch_add(1, Ordering::Acquire);
+        ArenaWeakRef { arena_header_ptr: NonNull::from(self) }
+    }
+    /// Bump-allocates from the left area.
+    fn allocate_left(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let _bump_guard = self.bump_mutex.lock();
+        let left_area_end = self.left_area_end_ptr.load(Ordering::SeqCst);
+        let right_area_start = self.right_area_start_ptr.load(Ordering::SeqCst);
+        let padding = left_area_end.align_offset(layout.align());
+        let available = right_area_start.addr() - left_area_end.addr();
+        if padding.checked_add(layout.size())? > available {
+            return None;
+        }
+        // SAFETY: we just checked that we stay within the client area
+        let start = unsafe { left_area_end.add(padding) };
+        // SAFETY: same
+        let end = unsafe { start.add(layout.size()) };
+        if !self.commit_left_until(end) {
+            return None;
+        }
+        self.left_area_end_ptr.store(end, Ordering::SeqCst);
+        Some(NonNull::slice_from_raw_parts(NonNull::new(start).unwrap(), layout.size()))
+    }
+    /// Grows `block` in place, which is only possible if it is the last block of the left area.
+    fn try_grow_in_place(&self, block: NonNull<[u8]>, new_size: usize) -> bool {
+        let _bump_guard = self.bump_mutex.lock();
+        let block_end = block.addr().get() + block.len();
+        let left_area_end = self.left_area_end_ptr.load(Ordering::SeqCst);
+        let right_area_start = self.right_area_start_ptr.load(Ordering::SeqCst);
+        if block_end != left_area_end.addr() || block.addr().get() + new_size > right_area_start.addr() {
+            return false;
+        }
+        // SAFETY: we just checked that we stay within the client area
+        let end = unsafe { workarounds::non_null_slice_ptr(block).as_ptr().add(new_size) };
+        if !self.commit_left_until(end) {
+            return false;
+        }
+        self.left_area_end_ptr.store(end, Ordering::SeqCst);
+        true
+    }
+    /// Only reclaims the block if it is the last one of the left area. Otherwise, it stays a hole until defragmentation.
+    fn free_left(&self, block: NonNull<[u8]>) {
+        let _bump_guard = self.bump_mutex.lock();
+        let left_area_end = self.left_area_end_ptr.load(Ordering::SeqCst);
+        if block.addr().get() + block.len() == left_area_end.addr() {
+            self.left_area_end_ptr.store(workarounds::non_null_slice_ptr(block).as_ptr(), Ordering::SeqCst);
+        }
+    }
+    fn allocate_suballocation_header(&self) -> Option<NonNull<SuballocationHeader>> {
+        let _bump_guard = self.bump_mutex.lock();
+        let left_area_end = self.left_area_end_ptr.load(Ordering::SeqCst);
+        let right_area_start = self.right_area_start_ptr.load(Ordering::SeqCst);
+        let size = std::mem::size_of::<SuballocationHeader>();
+        let start_addr = right_area_start.addr().checked_sub(size)? & !(std::mem::align_of::<SuballocationHeader>() - 1);
+        if start_addr < left_area_end.addr() {
+            return None;
+        }
+        // SAFETY: we just checked that we stay within the client area
+        let start = unsafe { right_area_start.sub(right_area_start.addr() - start_addr) }.cast::<SuballocationHeader>();
+        if !self.commit_right_from(start.cast()) {
+            return None;
+        }
+        // SAFETY: that memory is ours, unused, and properly aligned
+        unsafe {
+            start.write(SuballocationHeader {
+                mutex: parking_lot::Mutex::new(SuballocationHeaderInner::unused(0)),
+                arena_header_ptr: NonNull::from(self),
+            })
+        };
+        self.right_area_start_ptr.store(start.cast(), Ordering::SeqCst);
+        NonNull::new(start)
+    }
+    // TODO: parallel allocations, frees, and compaction
+    // TODO: pouvoir itérer sur toutes les suballocations de l'arena
+    // TODO: thought experiment (juste pour le lol/tester): dans la zone droite d'une arena, faire une suballocation, et créer une arena dedans.
+    /// Returns `None` if the arena is full.
+    pub fn create_relocatable_vec<T: Unpin>(&self) -> Option<RelocatableVecStrongRef<T>> {
+        assert_ne!(std::mem::size_of::<T>(), 0, "Zero-sized types are not supported yet");
+        let free_header = self.suballocation_headers_free_list.lock().as_mut().and_then(RelocatableVecStrongRef::pop);
+        let suballocation_header_ptr = free_header.or_else(|| self.allocate_suballocation_header())?;
+        // SAFETY: headers are never freed while their arena is alive
+        let mut suballocation_header_inner = unsafe { suballocation_header_ptr.as_ref() }.mutex.lock();
+        debug_assert_eq!(suballocation_header_inner.strong_ref_count, 0);
+        suballocation_header_inner.suballocation_within_arena = NonNull::slice_from_raw_parts(NonNull::<T>::dangling().cast(), 0);
+        suballocation_header_inner.len = 0;
+        suballocation_header_inner.strong_ref_count = 1;
+        suballocation_header_inner.element_layout = Layout::new::<T>();
+        suballocation_header_inner.arena_strong_ref = Some(self.new_strong_ref());
+        drop(suballocation_header_inner);
+        Some(RelocatableVecStrongRef { suballocation_header_ptr, phantom: PhantomData })
+    }
+    fn create_relocatable_vec_internal_to_this_arena<T: Unpin>(&self) -> Option<RelocatableVecStrongRef<T>> {
+        self.create_relocatable_vec().inspect(|x| {
+            x.take_arena_strong_ref();
+        })
+    }
+    fn suballocation_headers(&self) -> impl Iterator<Item = &SuballocationHeader> {
+        let size = std::mem::size_of::<SuballocationHeader>();
+        let end = self.allocation_end_ptr().addr();
+        let mut p = self.right_area_start_ptr.load(Ordering::SeqCst).cast::<SuballocationHeader>();
+        std::iter::from_fn(move || {
+            if p.addr() + size > end {
+                return None;
+            }
+            // SAFETY: headers are allocated contiguously from the end of the right area, and never freed while we're alive
+            let header = unsafe { &*p };
+            // SAFETY: still in bounds, or one past the end
+            p = unsafe { p.add(1) };
+            Some(header)
+        })
+    }
+    /// Slides the blocks of relocatable vecs towards the start of the left area, closing the holes left by
+    /// relocations and frees. Then gives the memory past the left area back to the OS, if the arena owns it.
+    ///
+    /// Refs are unaffected, since they point to headers, which don't move.
+    /// Returns `None` (and does nothing) if any vec is currently locked or growing: this is meant to be called when idle.
+    pub fn defragment(&self) -> Option<ArenaDefragStats> {
+        let _bump_guard = self.bump_mutex.lock();
+        let mut guards = Vec::new();
+        for header in self.suballocation_headers() {
+            let guard = header.mutex.try_lock()?;
+            if !guard.suballocation_within_arena.is_empty() {
+                guards.push(guard);
+            }
+        }
+        guards.sort_by_key(|guard| guard.suballocation_within_arena.addr());
+
+        let mut stats = ArenaDefragStats::default();
+        let mut cursor = workarounds::non_null_slice_ptr(self.client_area()).as_ptr();
+        for guard in &mut guards {
+            let block = guard.suballocation_within_arena;
+            if guard.strong_ref_count == 0 {
+                // Its elements are being dropped right now, without holding the lock. Leave it where it is.
+                // SAFETY: in bounds
+                cursor = unsafe { workarounds::non_null_slice_ptr(block).as_ptr().add(block.len()) };
+                continue;
+            }
+            // SAFETY: in bounds, since blocks only move towards the start
+            let new_start = unsafe { cursor.add(cursor.align_offset(guard.element_layout.align())) };
+            let old_start = workarounds::non_null_slice_ptr(block).as_ptr();
+            if new_start < old_start {
+                // SAFETY: both ranges are within the left area, and we hold the vec's lock. They may overlap, hence `copy()`.
+                // Moving elements is fine since they are Unpin (see `create_relocatable_vec()`).
+                unsafe { ptr::copy(old_start, new_start, block.len()) };
+                guard.suballocation_within_arena = NonNull::slice_from_raw_parts(NonNull::new(new_start).unwrap(), block.len());
+                stats.bytes_moved += block.len();
+            }
+            // SAFETY: in bounds
+            cursor = unsafe { new_start.add(block.len()) };
+        }
+        let old_left_area_end = self.left_area_end_ptr.swap(cursor, Ordering::SeqCst);
+        stats.bytes_reclaimed = old_left_area_end.addr() - cursor.addr();
+        drop(guards);
+
+        if self.owns_allocation {
+            let page_size = os::LowLevelAllocatorImpl::page_size().get();
+            let committed_left_end = self.committed_left_end_ptr.load(Ordering::SeqCst);
+            let is_fully_committed = committed_left_end == self.allocation_end_ptr();
+            let decommit_start = cursor.map_addr(|a| a.next_multiple_of(page_size));
+            let decommit_end = if is_fully_committed {
+                let right_area_start = self.right_area_start_ptr.load(Ordering::SeqCst);
+                right_area_start.map_addr(|a| a - a % page_size)
+            } else {
+                committed_left_end
+            };
+            if decommit_start < decommit_end {
+                let pages = NonNull::slice_from_raw_parts(NonNull::new(decommit_start).unwrap(), decommit_end.addr() - decommit_start.addr());
+                // SAFETY: these pages are past the end of the left area, and before the right area
+                if unsafe { os::LowLevelAllocatorImpl::decommit(pages) }.is_ok() {
+                    self.committed_left_end_ptr.store(decommit_start, Ordering::SeqCst);
+                    if is_fully_committed {
+                        self.committed_right_start_ptr.store(decommit_end, Ordering::SeqCst);
+                    }
+                    stats.bytes_decommitted = pages.len();
+                }
+            }
+        }
+        Some(stats)
+    }
+}
+
+/// What `ArenaHeader::defragment()` did.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ArenaDefragStats {
+    pub bytes_moved: usize,
+    /// How much the left area shrunk by.
+    pub bytes_reclaimed: usize,
+    /// How much memory was given back to the OS.
+    pub bytes_decommitted: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ArenaStrongRef {
+    arena_header_ptr: NonNull<ArenaHeader>,
+
+    // I don't fully understand yet why this is needed. Something about covariance. Rc<T> does this internally, so I'm just doing the same.
+    phantom: PhantomData<ArenaHeader>,
+}
+
+impl ArenaStrongRef {
+    fn arena_header(&self) -> &ArenaHeader {
+        // SAFETY: As long as we exist, header_ptr points to accessible and initialized memory. We also make sure to never access it via &mut unless we are the sole owner.
+        unsafe { self.arena_header_ptr.as_ref() }
+    }
+    pub fn downgrade(&self) -> ArenaWeakRef {
+        self.arena_header().weak_ref_count.fetch_add(1, Ordering::Acquire);
+        ArenaWeakRef {
+            arena_header_ptr: self.arena_header_ptr,
+        }
+    }
+}
+
+impl Deref for ArenaStrongRef {
+    type Target = ArenaHeader;
+    fn deref(&self) -> &ArenaHeader {
+        self.arena_header()
+    }
+}
+
+unsafe impl Send for ArenaStrongRef {}
+unsafe impl Sync for ArenaStrongRef {}
+
+impl Clone for ArenaStrongRef {
+    fn clone(&self) -> Self {
+        self.arena_header().strong_ref_count.fetch_add(1, Ordering::Acquire);
+        Self {
+            arena_header_ptr: self.arena_header_ptr,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl Drop for ArenaStrongRef {
+    fn drop(&mut self) {
+        let old_strong_ref_count = self.arena_header().strong_ref_count.fetch_sub(1, Ordering::Release);
+        assert_ne!(old_strong_ref_count, 0);
+        if old_strong_ref_count == 1 {
+            self.arena_header().strong_ref_count.load(Ordering::Acquire); // Barrier: same as Rust's "sync.rs"'s acquire!() macro
+            // SAFETY: We were the last strong owner
+            unsafe { self.arena_header().drop_suballocations() };
+            drop(ArenaWeakRef { arena_header_ptr: self.arena_header_ptr });
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ArenaWeakRef {
+    arena_header_ptr: NonNull<ArenaHeader>,
+}
+
+impl Default for ArenaWeakRef {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArenaWeakRef {
+    pub const fn new() -> Self {
+        Self {
+            arena_header_ptr: workarounds::nonnull_without_provenance(NonZeroUsize::MAX),
+        }
+    }
+    pub fn is_dangling(&self) -> bool {
+        self.arena_header_ptr.addr() == NonZeroUsize::MAX
+    }
+    fn arena_header(&self) -> Option<&ArenaHeader> {
+        if self.is_dangling() {
+            None
+        } else {
+            // SAFETY: As long as we exist, header_ptr points to accessible and initialized memory. We also make sure to never access it via &mut unless we are the sole owner.
+            Some(unsafe { self.arena_header_ptr.as_ref() })
+        }
+    }
+    pub fn upgrade(&self) -> Option<ArenaStrongRef> {
+        //
+        // Copied from Arc::upgrade()
+        //
+
+        #[inline]
+        fn checked_increment(n: usize) -> Option<usize> {
+            // Any write of 0 we can observe leaves the field in permanently zero state.
+            if n == 0 {
+                return None;
+            }
+            Some(n + 1)
+        }
+
+        if self.arena_header()?.strong_ref_count.fetch_update(Ordering::Acquire, Ordering::Relaxed, checked_increment).is_ok() {
+            Some(ArenaStrongRef {
+                arena_header_ptr: self.arena_header_ptr,
+                phantom: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl Send for ArenaWeakRef {}
+unsafe impl Sync for ArenaWeakRef {}
+
+impl Clone for ArenaWeakRef {
+    fn clone(&self) -> Self {
+        if let Some(header) = self.arena_header() {
+            header.weak_ref_count.fetch_add(1, Ordering::Acquire);
+        }
+        Self {
+            arena_header_ptr: self.arena_header_ptr,
+        }
+    }
+}
+
+impl Drop for ArenaWeakRef {
+    fn drop(&mut self) {
+        let old_weak_ref_count;
+        // Don't hold the reference for long. See further below for why.
+        if let Some(header) = self.arena_header() {
+            old_weak_ref_count = header.weak_ref_count.fetch_sub(1, Ordering::Release);
+        } else {
+            return;
+        }
+        assert_ne!(old_weak_ref_count, 0);
+        if old_weak_ref_count == 1 {
+            // Re-get and unwrap in order not to hold the reference for long. See below.
+            self.arena_header().unwrap().weak_ref_count.load(Ordering::Acquire); // Barrier: same as Rust's "sync.rs"'s acquire!() macro
+            let header = self.arena_header().unwrap();
+            let (allocation, owns_allocation) = (header.allocation, header.owns_allocation);
+            // SAFETY: We are the only referencer and have ensured we're not holding on to the header by reference past this point
+            unsafe { self.arena_header_ptr.drop_in_place() };
+            if owns_allocation {
+                // SAFETY: the header (which lived in that allocation) is gone, and nothing else refers to it.
+                // We must not deallocate before dropping the header, since dropping it reads its own fields.
+                unsafe { os::LowLevelAllocatorImpl::deallocate(allocation).unwrap() }
+            }
+        }
+    }
+}
+
@@ -0,0 +1,157 @@
+use std::{any::{Any, TypeId}, borrow::Cow, cell::RefCell, collections::HashMap, fmt, rc::{Rc, Weak}};
+
+/// Node of the context tree, e.g `process_context -> game_context -> world_context`.
+///
+/// Ref-counted: children keep their parent alive, but not the other way around. Cloning is cheap.
+/// There are no globals: each tree is fully independent, so several game instances can run in the same process.
+///
+/// Services are registered per context, by type (which may be a trait object, e.g `dyn Log`), and `find()` resolves
+/// them to the nearest provider, starting from the context itself and walking up its ancestors.
+#[derive(Clone)]
+pub struct Context(Rc<ContextInner>);
+
+/// Services that hold on to their context should store this instead, to avoid reference cycles.
+#[derive(Clone)]
+pub struct WeakContext(Weak<ContextInner>);
+
+struct ContextInner {
+    name: Cow<'static, str>,
+    parent: Option<Context>,
+    services: RefCell<HashMap<TypeId, Box<dyn Any>>>, // TypeId of S -> Rc<S>
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("path", &self.path())
+            .field("service_count", &self.0.services.borrow().len())
+            .finish()
+    }
+}
+
+impl fmt::Debug for WeakContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.upgrade() {
+            Some(context) => f.debug_tuple("WeakContext").field(&context.path()).finish(),
+            None => f.write_str("WeakContext(<dropped>)"),
+        }
+    }
+}
+
+impl PartialEq for Context {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Context {}
+
+impl Context {
+    pub fn new_root(name: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(name.into(), None)
+    }
+    pub fn new_child(&self, name: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(name.into(), Some(self.clone()))
+    }
+    fn new(name: Cow<'static, str>, parent: Option<Context>) -> Self {
+        Self(Rc::new(ContextInner {
+            name,
+            parent,
+            services: RefCell::new(HashMap::new()),
+        }))
+    }
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+    /// Names from the root down to this context, e.g "process/game/world".
+    pub fn path(&self) -> String {
+        let mut names = self.ancestors().map(Context::name).collect::<Vec<_>>();
+        names.reverse();
+        names.join("/")
+    }
+    pub fn parent(&self) -> Option<&Context> {
+        self.0.parent.as_ref()
+    }
+    pub fn root(&self) -> &Context {
+        self.ancestors().last().unwrap()
+    }
+    /// Starts with this context, then its parent, and so on up to the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = &Context> {
+        let mut next = Some(self);
+        std::iter::from_fn(move || {
+            let current = next?;
+            next = current.parent();
+            Some(current)
+        })
+    }
+    pub fn is_descendant_of(&self, ancestor: &Context) -> bool {
+        self.ancestors().any(|context| context == ancestor)
+    }
+    pub fn downgrade(&self) -> WeakContext {
+        WeakContext(Rc::downgrade(&self.0))
+    }
+    /// Registers a provider for `S` in this context, shadowing the ones of ancestors. Returns the previous one, if any.
+    pub fn provide<S: ?Sized + 'static>(&self, service: Rc<S>) -> Option<Rc<S>> {
+        let previous = self.0.services.borrow_mut().insert(TypeId::of::<S>(), Box::new(service));
+        previous.map(|previous| *previous.downcast::<Rc<S>>().unwrap())
+    }
+    pub fn remove<S: ?Sized + 'static>(&self) -> Option<Rc<S>> {
+        let previous = self.0.services.borrow_mut().remove(&TypeId::of::<S>());
+        previous.map(|previous| *previous.downcast::<Rc<S>>().unwrap())
+    }
+    /// Only looks in this context.
+    pub fn get_local<S: ?Sized + 'static>(&self) -> Option<Rc<S>> {
+        let services = self.0.services.borrow();
+        services.get(&TypeId::of::<S>()).map(|service| Rc::clone(service.downcast_ref::<Rc<S>>().unwrap()))
+    }
+    /// Nearest provider, starting from this context.
+    pub fn find<S: ?Sized + 'static>(&self) -> Option<Rc<S>> {
+        self.ancestors().find_map(Context::get_local)
+    }
+    /// Nearest provider, starting from the parent. This is what a provider uses to defer to the one it shadows.
+    pub fn find_in_parent<S: ?Sized + 'static>(&self) -> Option<Rc<S>> {
+        self.parent()?.find()
+    }
+}
+
+impl WeakContext {
+    pub fn upgrade(&self) -> Option<Context> {
+        self.0.upgrade().map(Context)
+    }
+}
+
+/// Entity-to-context resolution hook: anything that knows which context it belongs to (typically, an entity which
+/// has a special component for it) can be used to look services up, so contexts don't need to be passed everywhere.
+pub trait HasContext {
+    /// The most specialized context of `self`.
+    fn context(&self) -> Option<Context>;
+}
+
+impl HasContext for Context {
+    fn context(&self) -> Option<Context> {
+        Some(self.clone())
+    }
+}
+
+impl HasContext for WeakContext {
+    fn context(&self) -> Option<Context> {
+        self.upgrade()
+    }
+}
+
+impl<T: HasContext + ?Sized> HasContext for &T {
+    fn context(&self) -> Option<Context> {
+        (**self).context()
+    }
+}
+
+impl<T: HasContext + ?Sized> HasContext for Rc<T> {
+    fn context(&self) -> Option<Context> {
+        (**self).context()
+    }
+}
+
+/// Resolves `x`'s context, then its nearest provider for `S`. E.g `find::<dyn Log>(&entity)`.
+pub fn find<S: ?Sized + 'static>(x: &(impl HasContext + ?Sized)) -> Option<Rc<S>> {
+    x.context()?.find()
+}
@@ -0,0 +1,134 @@
+use std::{any::Any, fmt};
+
+use super::{Hive, HiveLock, RefInfo, WeakOrStrongRef, WeakRefAny};
+
+/// A component, along with the entity it belongs to.
+///
+/// Any permutation of weak/strong is possible for both members. Typically, the entity holds strong refs to its
+/// components (see `EntityComponents`), so components must only hold weak refs to their entity; but an entity may
+/// as well hold a weak ref to a component, which then holds a strong ref to its entity without creating a cycle. In
+/// that case, the entity can't be dropped until someone removes the component first.
+///
+/// To add methods, wrap this in a newtype.
+pub struct ComponentRef<E, C> {
+    pub entity: WeakOrStrongRef<E>,
+    pub component: WeakOrStrongRef<C>,
+}
+
+impl<E, C> fmt::Debug for ComponentRef<E, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ComponentRef").field("entity", &self.entity).field("component", &self.component).finish()
+    }
+}
+
+impl<E, C> ComponentRef<E, C> {
+    pub fn new(entity: WeakOrStrongRef<E>, component: WeakOrStrongRef<C>) -> Self {
+        Self { entity, component }
+    }
+    /// Same strengths as `self`.
+    pub fn clone_with(&self, entity_info: RefInfo, component_info: RefInfo) -> Self {
+        Self::new(self.entity.clone_with(entity_info), self.component.clone_with(component_info))
+    }
+    /// True once either the entity or the component has been dropped.
+    pub fn is_dangling(&self) -> bool {
+        self.entity.is_dangling() || self.component.is_dangling()
+    }
+    /// Returns `None` if either one has been dropped.
+    pub fn get<'a>(&self, entities: &'a HiveLock<'_, E>, components: &'a HiveLock<'_, C>) -> Option<(&'a E, &'a C)> {
+        Some((entities.get(&self.entity)?, components.get(&self.component)?))
+    }
+}
+
+/// The list of an entity's components, of any type. Usually made of strong refs, so that components live for as
+/// long as their entity does.
+///
+/// When the entity is dropped, this list is cleared, releasing components in reverse order of addition.
+/// A derived component holds a strong ref to its base, so derived components get dropped first, then their bases,
+/// regardless of the order in which hives are compacted (see `compact_all()`).
+#[derive(Default)]
+pub struct EntityComponents {
+    components: Vec<(WeakRefAny, Box<dyn Any>)>, // Type-erased `WeakOrStrongRef<C>`
+}
+
+impl fmt::Debug for EntityComponents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Drop for EntityComponents {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl EntityComponents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.components.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.components.is_empty()
+    }
+    pub fn add<C: 'static>(&mut self, component: WeakOrStrongRef<C>) {
+        self.components.push((component.to_weak_any(), Box::new(component)));
+    }
+    /// First component of type `C`.
+    pub fn get<C: 'static>(&self) -> Option<&WeakOrStrongRef<C>> {
+        self.components.iter().find_map(|(_, component)| component.downcast_ref())
+    }
+    pub fn contains(&self, component: &WeakRefAny) -> bool {
+        self.iter().any(|c| c == component)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &WeakRefAny> {
+        self.components.iter().map(|(component, _)| component)
+    }
+    /// Releases our ref to `component`. Returns false if it isn't in the list.
+    pub fn remove(&mut self, component: &WeakRefAny) -> bool {
+        match self.components.iter().position(|(c, _)| c == component) {
+            Some(i) => {
+                self.components.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+    /// Releases components in reverse order of addition.
+    pub fn clear(&mut self) {
+        while self.components.pop().is_some() {}
+    }
+}
+
+/// Type-erased hive, for operations which span several hives.
+pub trait AnyHive {
+    /// See `Hive::compact_and_count_dropped()`.
+    fn compact_and_count_dropped(&self) -> Option<usize>;
+}
+
+impl<T> AnyHive for Hive<T> {
+    fn compact_and_count_dropped(&self) -> Option<usize> {
+        Hive::compact_and_count_dropped(self)
+    }
+}
+
+/// Compacts each hive in turn, until none of them drops anything: dropping an item may release the last strong ref
+/// to items of other hives (e.g an entity releases its components, and a derived component releases its base), which
+/// then get dropped in the next round.
+///
+/// Returns false as soon as one of the hives is locked, in which case teardown is incomplete.
+pub fn compact_all(hives: &[&dyn AnyHive]) -> bool {
+    loop {
+        let mut dropped_count = 0;
+        for hive in hives {
+            match hive.compact_and_count_dropped() {
+                Some(count) => dropped_count += count,
+                None => return false,
+            }
+        }
+        if dropped_count == 0 {
+            return true;
+        }
+    }
+}
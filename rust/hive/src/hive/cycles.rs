@@ -0,0 +1,200 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt, rc::Rc};
+
+use super::{refs::Registry, Hive, ReferencerInfo, WeakRefAny};
+
+/// Diagnostic pass which finds cycles of strong refs, i.e items which keep each other alive and will leak.
+///
+/// Edges of the graph are strong refs which have an owner (see `RefInfo::with_owner()`), going from the owner to
+/// the referenced item. Refs without an owner can't be part of a cycle as far as we can tell, and breakable refs
+/// (see `RefInfo::breakable()`) are ignored.
+///
+/// Every hive involved in a cycle must be added, otherwise the edges starting from its items are missed.
+#[derive(Default)]
+pub struct CycleDetector {
+    registries: Vec<Rc<Registry>>,
+}
+
+impl fmt::Debug for CycleDetector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CycleDetector").field("hive_count", &self.registries.len()).finish()
+    }
+}
+
+/// One strong ref within a cycle.
+#[derive(Debug, Clone)]
+pub struct RefCycleLink {
+    pub owner: WeakRefAny,
+    pub target: WeakRefAny,
+    pub referencer: ReferencerInfo,
+}
+
+#[derive(Debug, Clone)]
+pub struct RefCycle {
+    /// Each link's target is the next link's owner, and the last link's target is the first link's owner.
+    pub links: Vec<RefCycleLink>,
+}
+
+impl RefCycle {
+    pub fn debug_names(&self) -> Vec<&str> {
+        self.links.iter().map(|link| &*link.referencer.debug_name).collect()
+    }
+}
+
+impl fmt::Display for RefCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for link in &self.links {
+            write!(f, "#{} --\"{}\"--> ", link.owner.item_guid(), link.referencer.debug_name)?;
+        }
+        match self.links.first() {
+            Some(link) => write!(f, "#{}", link.owner.item_guid()),
+            None => Ok(()),
+        }
+    }
+}
+
+type NodeKey = (*const Registry, u64);
+
+#[derive(Debug)]
+struct Graph {
+    nodes: Vec<WeakRefAny>,
+    edges: Vec<Vec<(usize, RefCycleLink)>>,
+}
+
+impl Graph {
+    fn node(&mut self, indices: &mut HashMap<NodeKey, usize>, node: &WeakRefAny) -> usize {
+        *indices.entry((Rc::as_ptr(node.registry()), node.item_guid())).or_insert_with(|| {
+            self.nodes.push(node.clone());
+            self.edges.push(vec![]);
+            self.nodes.len() - 1
+        })
+    }
+}
+
+impl CycleDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_hive<T>(&mut self, hive: &Hive<T>) -> &mut Self {
+        if !self.registries.iter().any(|registry| Rc::ptr_eq(registry, &hive.registry)) {
+            self.registries.push(Rc::clone(&hive.registry));
+        }
+        self
+    }
+    fn build_graph(&self) -> Graph {
+        let mut graph = Graph { nodes: vec![], edges: vec![] };
+        let mut indices = HashMap::new();
+        for registry in &self.registries {
+            for redirector in registry.live_redirectors() {
+                // SAFETY: redirectors of live items are alive, and we don't call into user code while borrowing it
+                let redirector = unsafe { redirector.as_ref() };
+                let target = WeakRefAny::new(Rc::clone(registry), redirector.item_guid);
+                let target_index = graph.node(&mut indices, &target);
+                for referencer in redirector.referencers() {
+                    let info = &referencer.info;
+                    let Some(owner) = &info.owner else { continue };
+                    if !referencer.is_strong.get() || info.is_breakable {
+                        continue;
+                    }
+                    let owner_index = graph.node(&mut indices, owner);
+                    let link = RefCycleLink { owner: owner.clone(), target: target.clone(), referencer: referencer.to_info() };
+                    graph.edges[owner_index].push((target_index, link));
+                }
+            }
+        }
+        graph
+    }
+    /// Reports one cycle per group of items which keep each other alive.
+    pub fn find_cycles(&self) -> Vec<RefCycle> {
+        let graph = self.build_graph();
+        strongly_connected_components(&graph.edges)
+            .into_iter()
+            .filter_map(|component| shortest_cycle_through(&graph.edges, &component))
+            .collect()
+    }
+}
+
+/// Tarjan's algorithm, without recursion since the graph can be deep.
+fn strongly_connected_components<E>(edges: &[Vec<(usize, E)>]) -> Vec<Vec<usize>> {
+    let n = edges.len();
+    let mut index = vec![usize::MAX; n];
+    let mut lowlink = vec![0; n];
+    let mut is_on_stack = vec![false; n];
+    let mut stack = vec![];
+    let mut next_index = 0;
+    let mut components = vec![];
+    for root in 0..n {
+        if index[root] != usize::MAX {
+            continue;
+        }
+        let mut call_stack = vec![(root, 0)];
+        index[root] = next_index;
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        is_on_stack[root] = true;
+        while let Some((v, next_edge)) = call_stack.last_mut() {
+            let v = *v;
+            if let Some((w, _)) = edges[v].get(*next_edge) {
+                let w = *w;
+                *next_edge += 1;
+                if index[w] == usize::MAX {
+                    index[w] = next_index;
+                    lowlink[w] = next_index;
+                    next_index += 1;
+                    stack.push(w);
+                    is_on_stack[w] = true;
+                    call_stack.push((w, 0));
+                } else if is_on_stack[w] {
+                    lowlink[v] = lowlink[v].min(index[w]);
+                }
+                continue;
+            }
+            call_stack.pop();
+            if let Some((parent, _)) = call_stack.last() {
+                lowlink[*parent] = lowlink[*parent].min(lowlink[v]);
+            }
+            if lowlink[v] == index[v] {
+                let mut component = vec![];
+                loop {
+                    let w = stack.pop().unwrap();
+                    is_on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+    components
+}
+
+/// Breadth-first search from the first node of the component back to itself, staying within the component.
+/// Returns `None` for a single node without a self-loop.
+fn shortest_cycle_through(edges: &[Vec<(usize, RefCycleLink)>], component: &[usize]) -> Option<RefCycle> {
+    let start = component[0];
+    let members = component.iter().copied().collect::<HashSet<_>>();
+    let mut parent_edge: HashMap<usize, (usize, usize)> = HashMap::new(); // node -> (from, edge index)
+    let mut queue = VecDeque::from([start]);
+    while let Some(v) = queue.pop_front() {
+        for (i, (w, _)) in edges[v].iter().enumerate() {
+            if *w == start {
+                let mut links = vec![edges[v][i].1.clone()];
+                let mut node = v;
+                while node != start {
+                    let (from, edge) = parent_edge[&node];
+                    links.push(edges[from][edge].1.clone());
+                    node = from;
+                }
+                links.reverse();
+                return Some(RefCycle { links });
+            }
+            if members.contains(w) && !parent_edge.contains_key(w) {
+                parent_edge.insert(*w, (v, i));
+                queue.push_back(*w);
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,169 @@
+use std::{mem, rc::Rc};
+
+use super::{imp::EvacuationPlan, refs::{Referencer, Registry}, Hive, Item, WeakOrStrongRef};
+
+/// What a defragmentation pass did.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DefragStats {
+    pub items_moved: usize,
+    /// Items which moved because shrinking their buffer reallocated it. Up to the allocator, so not in `items_moved`
+    /// nor `bytes_moved`.
+    pub items_reallocated: usize,
+    pub redirectors_moved: usize,
+    pub referencers_moved: usize,
+    pub bytes_moved: usize,
+    pub chunks_freed: usize,
+    pub bytes_freed: usize,
+}
+
+impl std::ops::AddAssign for DefragStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.items_moved += rhs.items_moved;
+        self.items_reallocated += rhs.items_reallocated;
+        self.redirectors_moved += rhs.redirectors_moved;
+        self.referencers_moved += rhs.referencers_moved;
+        self.bytes_moved += rhs.bytes_moved;
+        self.chunks_freed += rhs.chunks_freed;
+        self.bytes_freed += rhs.bytes_freed;
+    }
+}
+
+/// Reflection: exposes every `WeakOrStrongRef` stored in a value, so that defragmentation can relocate their referencers.
+///
+/// Refs which are not visited are left alone, so forgetting one is only a missed opportunity, never a dangling pointer.
+pub trait VisitRefs {
+    fn visit_refs(&mut self, visitor: &mut RefVisitor);
+}
+
+/// Moves the referencers of the refs it visits into as few chunks as possible, then frees the emptied chunks when
+/// finished. Refs may point into any hive.
+#[derive(Debug, Default)]
+pub struct RefVisitor {
+    plans: Vec<(Rc<Registry>, EvacuationPlan)>,
+    stats: DefragStats,
+}
+
+impl RefVisitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn visit<T>(&mut self, r: &mut WeakOrStrongRef<T>) {
+        let registry = r.registry();
+        let i = match self.plans.iter().position(|(other, _)| Rc::ptr_eq(other, registry)) {
+            Some(i) => i,
+            None => {
+                self.plans.push((Rc::clone(registry), registry.referencers().plan_evacuation()));
+                self.plans.len() - 1
+            },
+        };
+        if r.relocate_referencer(&self.plans[i].1) {
+            self.stats.referencers_moved += 1;
+            self.stats.bytes_moved += mem::size_of::<Referencer>();
+        }
+    }
+    pub fn visit_all<V: VisitRefs + ?Sized>(&mut self, value: &mut V) {
+        value.visit_refs(self);
+    }
+    /// Frees the chunks which were emptied.
+    pub fn finish(mut self) -> DefragStats {
+        for (registry, _) in self.plans.drain(..) {
+            let pool = registry.referencers();
+            let chunks_freed = pool.release_empty_chunks();
+            self.stats.chunks_freed += chunks_freed;
+            self.stats.bytes_freed += chunks_freed * pool.chunk_size_in_bytes();
+        }
+        self.stats
+    }
+}
+
+impl<T> VisitRefs for WeakOrStrongRef<T> {
+    fn visit_refs(&mut self, visitor: &mut RefVisitor) {
+        visitor.visit(self);
+    }
+}
+
+impl<V: VisitRefs> VisitRefs for Option<V> {
+    fn visit_refs(&mut self, visitor: &mut RefVisitor) {
+        if let Some(value) = self {
+            value.visit_refs(visitor);
+        }
+    }
+}
+
+impl<V: VisitRefs> VisitRefs for Vec<V> {
+    fn visit_refs(&mut self, visitor: &mut RefVisitor) {
+        self.iter_mut().for_each(|value| value.visit_refs(visitor));
+    }
+}
+
+impl<T> Hive<T> {
+    /// Moves items and their redirectors into as few chunks as possible, and frees the others.
+    /// Compacts first. Referencers are left alone, see `defragment_with_referencers()`.
+    ///
+    /// Meant to be called when idle, which `&mut self` enforces for this hive's items.
+    pub fn defragment(&mut self) -> DefragStats {
+        let mut stats = DefragStats::default();
+        let item_size = mem::size_of::<Item<T>>();
+
+        // Pending adds end up in the main buffer (unless they are pending removal, and get dropped instead), and all but
+        // one of their chunks are freed
+        let surviving_pending_add_count = self.pending_adds.iter().filter(|item| !item.is_pending_removal()).count();
+        let pending_chunk_count = self.pending_adds.chunk_count();
+        assert!(self.compact(), "Cannot be locked since we have `&mut self`");
+        stats.items_moved += surviving_pending_add_count;
+        stats.bytes_moved += surviving_pending_add_count * item_size;
+        stats.chunks_freed += pending_chunk_count - 1;
+        stats.bytes_freed += (pending_chunk_count - 1) * self.pending_adds.chunk_capacity() * item_size;
+
+        let items = self.items.get_mut();
+        let old_ptr = items.as_ptr();
+        let old_capacity = items.capacity();
+        items.shrink_to_fit();
+        stats.bytes_freed += (old_capacity - items.capacity()) * item_size;
+        if items.as_ptr() != old_ptr {
+            items.iter().for_each(Item::update_redirector);
+            stats.items_reallocated += items.len();
+        }
+
+        stats += defragment_redirectors(&self.registry, items);
+        stats
+    }
+    /// Same as `defragment()`, but also relocates the referencers of refs stored in items (which may point into any hive).
+    pub fn defragment_with_referencers(&mut self) -> DefragStats where T: VisitRefs {
+        let mut stats = self.defragment();
+        let mut visitor = RefVisitor::new();
+        for item in self.items.get_mut() {
+            item.value.visit_refs(&mut visitor);
+        }
+        stats += visitor.finish();
+        stats
+    }
+}
+
+fn defragment_redirectors<T>(registry: &Registry, items: &mut [Item<T>]) -> DefragStats {
+    let mut stats = DefragStats::default();
+    let pool = registry.redirectors();
+    let plan = pool.plan_evacuation();
+    let mut moved = |redirector| {
+        // SAFETY: the redirector is ours, and nobody borrows redirectors across calls into this module
+        let new_redirector = unsafe { registry.relocate_redirector(&plan, redirector) };
+        if new_redirector.is_some() {
+            stats.redirectors_moved += 1;
+        }
+        new_redirector
+    };
+    for item in items.iter_mut() {
+        if let Some(redirector) = moved(item.redirector) {
+            item.redirector = redirector;
+        }
+    }
+    // Detached redirectors are still kept alive by their referencers
+    for redirector in pool.occupied_slots() {
+        moved(redirector);
+    }
+    stats.bytes_moved += stats.redirectors_moved * mem::size_of::<super::refs::Redirector>();
+    let chunks_freed = pool.release_empty_chunks();
+    stats.chunks_freed += chunks_freed;
+    stats.bytes_freed += chunks_freed * pool.chunk_size_in_bytes();
+    stats
+}
@@ -0,0 +1,397 @@
+use std::{cell::{Cell, RefCell, UnsafeCell}, cmp, fmt, mem::{self, MaybeUninit}, ops::Range, ptr::{self, NonNull}, rc::Rc};
+
+/// Fixed-capacity storage which never moves its elements, so that pushing can be done via `&self` while other
+/// elements are borrowed.
+#[derive(Debug)]
+pub struct PinArena<T> {
+    memory: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    len: Cell<usize>,
+}
+
+impl<T> PinArena<T> {
+    pub fn with_capacity(cap: usize) -> Self {
+        assert_ne!(cap, 0);
+        Self {
+            memory: (0..cap).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect::<Vec<_>>().into_boxed_slice(),
+            len: Cell::new(0),
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.memory.len()
+    }
+    pub fn is_full(&self) -> bool {
+        self.len.get() >= self.capacity()
+    }
+    /// # Safety
+    ///
+    /// * The arena must not be full.
+    pub unsafe fn push_unchecked(&self, val: T) -> &T {
+        let i = self.len.get();
+        debug_assert!(i < self.capacity());
+        // SAFETY: slot `i` is past `len`, so nobody else can be borrowing it
+        let slot = unsafe { &mut *self.memory.get_unchecked(i).get() };
+        let val = slot.write(val);
+        self.len.set(i + 1);
+        val
+    }
+    /// The pointee is initialized, but may be mutably borrowed: that's up to the caller.
+    pub fn get_ptr(&self, i: usize) -> Option<NonNull<T>> {
+        if i < self.len.get() {
+            NonNull::new(self.memory[i].get().cast())
+        } else {
+            None
+        }
+    }
+    pub fn contains_ptr(&self, p: *const T) -> bool {
+        let start = self.memory.as_ptr().cast::<T>();
+        // SAFETY: one-past-the-end of the allocation
+        let end = unsafe { start.add(self.len.get()) };
+        p >= start && p < end
+    }
+    /// Moves all elements out, in push order, leaving the arena empty.
+    ///
+    /// # Safety
+    ///
+    /// * No element may be borrowed.
+    pub unsafe fn drain_into(&self, f: &mut impl FnMut(T)) {
+        let len = self.len.replace(0);
+        for slot in &self.memory[..len] {
+            // SAFETY: slots below the old `len` are initialized, and `len` was reset so they are never read again
+            f(unsafe { (*slot.get()).assume_init_read() });
+        }
+    }
+}
+
+impl<T> Drop for PinArena<T> {
+    fn drop(&mut self) {
+        let len = self.len.replace(0);
+        for slot in &mut self.memory[..len] {
+            // SAFETY: slots below the old `len` are initialized
+            unsafe { ptr::drop_in_place(slot.get_mut().as_mut_ptr()) };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PinArenaListNode<T> {
+    pin_arena: PinArena<T>,
+    next: RefCell<Option<Rc<PinArenaListNode<T>>>>,
+}
+
+impl<T> PinArenaListNode<T> {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            pin_arena: PinArena::with_capacity(cap),
+            next: RefCell::new(None),
+        }
+    }
+}
+
+/// Linked list of `PinArena`s. Growing never moves existing elements.
+#[derive(Debug)]
+pub struct PinArenaList<T> {
+    head: Rc<PinArenaListNode<T>>,
+    tail: RefCell<Rc<PinArenaListNode<T>>>,
+    chunk_capacity: usize,
+    len: Cell<usize>,
+}
+
+impl<T> PinArenaList<T> {
+    pub fn with_capacity(cap: usize) -> Self {
+        assert_ne!(cap, 0);
+        let tail = Rc::new(PinArenaListNode::with_capacity(cap));
+        Self {
+            head: Rc::clone(&tail),
+            tail: RefCell::new(tail),
+            chunk_capacity: cap,
+            len: Cell::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.nodes().count()
+    }
+
+    pub fn chunk_capacity(&self) -> usize {
+        self.chunk_capacity
+    }
+
+    pub fn push(&self, val: T) -> &T {
+        if self.tail.borrow().pin_arena.is_full() {
+            let new_tail = Rc::new(PinArenaListNode::with_capacity(self.chunk_capacity));
+            let mut tail = self.tail.borrow_mut();
+            *tail.next.borrow_mut() = Some(Rc::clone(&new_tail));
+            *tail = new_tail;
+        }
+
+        self.len.set(self.len.get() + 1);
+        let tail: *const PinArenaListNode<T> = &**self.tail.borrow();
+        // SAFETY: nodes are only freed via `&mut self`, so the tail outlives the returned reference.
+        // We also just made sure the tail has room left.
+        unsafe { (*tail).pin_arena.push_unchecked(val) }
+    }
+
+    pub fn contains_ptr(&self, p: *const T) -> bool {
+        self.nodes().any(|node| node.pin_arena.contains_ptr(p))
+    }
+
+    /// The caller must make sure that no element is mutably borrowed for as long as the iterator is alive.
+    pub fn iter(&self) -> PinArenaListIter<'_, T> {
+        PinArenaListIter { ptrs: self.ptr_iter() }
+    }
+
+    pub fn ptr_iter(&self) -> PinArenaListPtrIter<'_, T> {
+        PinArenaListPtrIter { node: Some(&self.head), i: 0 }
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &PinArenaListNode<T>> {
+        let mut node = Some(&*self.head);
+        std::iter::from_fn(move || {
+            let current = node?;
+            node = current.next_node();
+            Some(current)
+        })
+    }
+
+    /// Moves all elements out, in push order. The list keeps its first chunk for reuse and frees the others.
+    ///
+    /// # Safety
+    ///
+    /// * Nothing may borrow into this list: no element, and no iterator (which borrows nodes), since all chunks but
+    ///   the first are freed even though we only have `&self`;
+    /// * `f` must not push into this list.
+    pub unsafe fn drain_into(&self, mut f: impl FnMut(T)) {
+        for node in self.nodes() {
+            // SAFETY: upheld by the caller
+            unsafe { node.pin_arena.drain_into(&mut f) };
+        }
+        *self.head.next.borrow_mut() = None;
+        *self.tail.borrow_mut() = Rc::clone(&self.head);
+        self.len.set(0);
+    }
+}
+
+impl<T> PinArenaListNode<T> {
+    fn next_node(&self) -> Option<&PinArenaListNode<T>> {
+        let next = self.next.borrow().as_ref().map(Rc::as_ptr)?;
+        // SAFETY: the previous node keeps this one alive. Nodes are only freed via `&mut PinArenaList`, or by
+        // `drain_into()`, whose caller guarantees that nothing borrows into the list, i.e that no `&PinArenaListNode`
+        // obtained here is still alive.
+        Some(unsafe { &*next })
+    }
+}
+
+/// Iterates over a `PinArenaList`, including elements pushed while iterating.
+#[derive(Debug)]
+pub struct PinArenaListIter<'a, T> {
+    ptrs: PinArenaListPtrIter<'a, T>,
+}
+
+impl<'a, T> Iterator for PinArenaListIter<'a, T> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        // SAFETY: elements are initialized, and not mutably borrowed (upheld by the caller of `PinArenaList::iter()`)
+        self.ptrs.next().map(|p| unsafe { p.as_ref() })
+    }
+}
+
+/// Same as `PinArenaListIter`, but yields pointers, which allows mutable access.
+#[derive(Debug)]
+pub struct PinArenaListPtrIter<'a, T> {
+    node: Option<&'a PinArenaListNode<T>>,
+    i: usize,
+}
+
+impl<T> Iterator for PinArenaListPtrIter<'_, T> {
+    type Item = NonNull<T>;
+    fn next(&mut self) -> Option<NonNull<T>> {
+        loop {
+            let node = self.node?;
+            if let Some(p) = node.pin_arena.get_ptr(self.i) {
+                self.i += 1;
+                return Some(p);
+            }
+            if !node.pin_arena.is_full() {
+                // Don't move past a chunk that still has room: elements pushed later will land here.
+                return None;
+            }
+            self.node = node.next_node();
+            self.i = 0;
+        }
+    }
+}
+
+#[repr(C)] // `value` must stay the first member, so that a `NonNull<S>` can be turned back into a `&Slot<S>`.
+struct Slot<S> {
+    value: UnsafeCell<MaybeUninit<S>>,
+    is_occupied: Cell<bool>,
+    generation: Cell<u32>,
+}
+
+/// Chunked pool of pinned slots. Allocating never moves live values, and freed slots are reused.
+#[derive(Debug)]
+pub struct SlotPool<S> {
+    chunks: RefCell<Vec<Box<[Slot<S>]>>>,
+    free_list: RefCell<Vec<NonNull<S>>>,
+    chunk_capacity: usize,
+}
+
+impl<S> fmt::Debug for Slot<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Slot").field("is_occupied", &self.is_occupied.get()).field("generation", &self.generation.get()).finish_non_exhaustive()
+    }
+}
+
+impl<S> SlotPool<S> {
+    pub fn with_chunk_capacity(chunk_capacity: usize) -> Self {
+        assert_ne!(chunk_capacity, 0);
+        Self {
+            chunks: RefCell::new(Vec::new()),
+            free_list: RefCell::new(Vec::new()),
+            chunk_capacity,
+        }
+    }
+    fn slot(p: NonNull<S>) -> NonNull<Slot<S>> {
+        p.cast()
+    }
+    /// Allocates a slot and initializes it with `f(generation)`, where `generation` is incremented every time the
+    /// slot is reused.
+    pub fn alloc(&self, f: impl FnOnce(u32) -> S) -> NonNull<S> {
+        let p = self.free_list.borrow_mut().pop();
+        let p = p.unwrap_or_else(|| self.grow());
+        // SAFETY: free slots belong to one of our chunks, which are only freed when we're dropped
+        let slot = unsafe { Self::slot(p).as_ref() };
+        debug_assert!(!slot.is_occupied.get());
+        let generation = slot.generation.get().wrapping_add(1);
+        slot.generation.set(generation);
+        // SAFETY: the slot is free, so nobody is borrowing its value
+        unsafe { (*slot.value.get()).write(f(generation)) };
+        slot.is_occupied.set(true);
+        p
+    }
+    fn grow(&self) -> NonNull<S> {
+        let chunk: Box<[Slot<S>]> = (0..self.chunk_capacity).map(|_| Slot {
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            is_occupied: Cell::new(false),
+            generation: Cell::new(0),
+        }).collect();
+        let mut free_list = self.free_list.borrow_mut();
+        // Reversed, so that lower addresses are handed out first
+        free_list.extend(chunk.iter().rev().map(|slot| NonNull::from(slot).cast::<S>()));
+        self.chunks.borrow_mut().push(chunk);
+        free_list.pop().unwrap()
+    }
+    /// Drops the value and makes the slot available again.
+    ///
+    /// # Safety
+    ///
+    /// * `p` must have been returned by `alloc()` on this pool, and not freed since;
+    /// * Nobody may be borrowing the value.
+    pub unsafe fn free(&self, p: NonNull<S>) {
+        // SAFETY: upheld by the caller
+        let slot = unsafe { Self::slot(p).as_ref() };
+        assert!(slot.is_occupied.get());
+        slot.is_occupied.set(false);
+        // Dropping may call into user code, which may use this pool. Don't hold any borrow meanwhile.
+        // SAFETY: the slot was occupied, and nobody is borrowing it (upheld by the caller)
+        unsafe { (*slot.value.get()).assume_init_drop() };
+        self.free_list.borrow_mut().push(p);
+    }
+}
+
+/// Chunks of a `SlotPool` whose slots should be moved elsewhere, so that they can be freed.
+#[derive(Debug)]
+pub struct EvacuationPlan {
+    ranges: Vec<Range<usize>>, // Sorted, non-overlapping address ranges
+}
+
+impl EvacuationPlan {
+    pub fn contains<S>(&self, p: NonNull<S>) -> bool {
+        let addr = p.addr().get();
+        let i = self.ranges.partition_point(|range| range.end <= addr);
+        self.ranges.get(i).is_some_and(|range| range.contains(&addr))
+    }
+}
+
+impl<S> SlotPool<S> {
+    pub fn chunk_size_in_bytes(&self) -> usize {
+        self.chunk_capacity * mem::size_of::<Slot<S>>()
+    }
+    pub fn occupied_slots(&self) -> Vec<NonNull<S>> {
+        let chunks = self.chunks.borrow();
+        chunks.iter().flat_map(|chunk| chunk.iter()).filter(|slot| slot.is_occupied.get()).map(|slot| NonNull::from(slot).cast()).collect()
+    }
+    /// Keeps the fullest chunks which are enough to hold all occupied slots, and plans to evacuate the others.
+    /// Also makes `alloc()` hand out slots from the kept chunks first.
+    pub fn plan_evacuation(&self) -> EvacuationPlan {
+        let chunks = self.chunks.borrow();
+        let occupied_count = |chunk: &[Slot<S>]| chunk.iter().filter(|slot| slot.is_occupied.get()).count();
+        let total_occupied_count: usize = chunks.iter().map(|chunk| occupied_count(chunk)).sum();
+        let kept_chunk_count = total_occupied_count.div_ceil(self.chunk_capacity);
+        let mut chunks_by_occupancy = chunks.iter().map(|chunk| (occupied_count(chunk), chunk.as_ptr_range())).collect::<Vec<_>>();
+        chunks_by_occupancy.sort_by_key(|(occupied_count, _)| cmp::Reverse(*occupied_count));
+        let mut ranges = chunks_by_occupancy.into_iter().skip(kept_chunk_count).map(|(_, range)| range.start.addr()..range.end.addr()).collect::<Vec<_>>();
+        ranges.sort_by_key(|range| range.start);
+        let plan = EvacuationPlan { ranges };
+        // `alloc()` pops from the back
+        self.free_list.borrow_mut().sort_by_key(|p| !plan.contains(*p));
+        plan
+    }
+    /// Moves the value into a free slot of a kept chunk, and returns its new location. The caller must patch every
+    /// pointer to the old location. Returns `None` if the slot doesn't need to move, or if there is no room left.
+    ///
+    /// The old slot is only made available again by `release_empty_chunks()`.
+    ///
+    /// # Safety
+    ///
+    /// * `p` must be occupied and belong to this pool;
+    /// * Nobody may be borrowing the value.
+    pub unsafe fn relocate(&self, plan: &EvacuationPlan, p: NonNull<S>) -> Option<NonNull<S>> {
+        if !plan.contains(p) {
+            return None;
+        }
+        let mut free_list = self.free_list.borrow_mut();
+        let new_p = *free_list.last()?;
+        if plan.contains(new_p) {
+            return None;
+        }
+        free_list.pop();
+        // SAFETY: both slots belong to our chunks
+        let (src, dst) = unsafe { (Self::slot(p).as_ref(), Self::slot(new_p).as_ref()) };
+        debug_assert!(src.is_occupied.get() && !dst.is_occupied.get());
+        // SAFETY: the source is initialized, the destination is free, and they are distinct slots
+        unsafe { ptr::copy_nonoverlapping(src.value.get(), dst.value.get(), 1) };
+        src.is_occupied.set(false);
+        dst.generation.set(dst.generation.get().wrapping_add(1));
+        dst.is_occupied.set(true);
+        Some(new_p)
+    }
+    /// Frees chunks which have no occupied slots, and rebuilds the free list. Returns the number of chunks freed.
+    pub fn release_empty_chunks(&self) -> usize {
+        let mut chunks = self.chunks.borrow_mut();
+        let old_chunk_count = chunks.len();
+        chunks.retain(|chunk| chunk.iter().any(|slot| slot.is_occupied.get()));
+        let mut free_list = self.free_list.borrow_mut();
+        free_list.clear();
+        // Reversed, so that lower addresses of the first chunks are handed out first
+        free_list.extend(chunks.iter().rev().flat_map(|chunk| chunk.iter().rev()).filter(|slot| !slot.is_occupied.get()).map(|slot| NonNull::from(slot).cast::<S>()));
+        old_chunk_count - chunks.len()
+    }
+}
+
+impl<S> Drop for SlotPool<S> {
+    fn drop(&mut self) {
+        for chunk in self.chunks.get_mut() {
+            for slot in chunk.iter_mut() {
+                if slot.is_occupied.replace(false) {
+                    // SAFETY: occupied slots are initialized
+                    unsafe { slot.value.get_mut().assume_init_drop() };
+                }
+            }
+        }
+    }
+}
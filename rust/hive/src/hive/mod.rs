@@ -0,0 +1,507 @@
+use std::{cell::{Cell, RefCell, UnsafeCell}, cmp, collections::VecDeque, fmt, mem, ops::Deref, ptr::NonNull, rc::Rc, slice};
+
+mod cycles;
+mod components;
+mod defrag;
+mod imp;
+mod par;
+mod refs;
+mod scheduler;
+mod serialize;
+
+pub use self::components::{compact_all, AnyHive, ComponentRef, EntityComponents};
+pub use self::cycles::{CycleDetector, RefCycle, RefCycleLink};
+pub use self::defrag::{DefragStats, RefVisitor, VisitRefs};
+pub use self::scheduler::{DeferredCommand, HiveMut, IterMut};
+pub use self::serialize::{ParseSerializedRefError, ResolveError, SerializedRef};
+pub use self::refs::{MAGIC_GUID, RefInfo, RefStrength, ReferencerInfo, WeakOrStrongRef, WeakRefAny};
+use self::refs::{Redirector, Registry};
+
+pub const DEFAULT_CHUNK_CAPACITY: usize = 64;
+
+#[repr(C)] // `value` must stay the first member, so that a `&T` handed out by the hive can be turned back into a `&Item<T>`.
+#[derive(Debug)]
+struct Item<T> {
+    value: T,
+    redirector: NonNull<Redirector>,
+}
+
+impl<T> Item<T> {
+    fn redirector(&self) -> &Redirector {
+        // SAFETY: the redirector outlives its item
+        unsafe { self.redirector.as_ref() }
+    }
+    /// Nobody holds a strong ref to it anymore (not even the container).
+    fn is_pending_removal(&self) -> bool {
+        self.redirector().strong_ref_count.get() == 0
+    }
+    fn update_redirector(&self) {
+        self.redirector().item.set((self as *const Self).cast());
+    }
+}
+
+pub type ComparisonPredicate<T> = Box<dyn Fn(&T, &T) -> cmp::Ordering>;
+
+/// How a hive orders its main buffer. Chosen at construction, and applied by `compact()`.
+#[derive(Default)]
+pub enum OrderingPolicy<T> {
+    /// The last added items are at the end, and removals preserve the order.
+    /// Useful for gameplay logic, which needs to be deterministic.
+    #[default]
+    Chronological,
+    /// The order is undefined, which allows removing via `swap_remove()`. This is the fastest.
+    Unordered,
+    /// Items are sorted with a user-defined comparison predicate (stable sort). Useful for render queues.
+    Predicate(ComparisonPredicate<T>),
+}
+
+impl<T> fmt::Debug for OrderingPolicy<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Chronological => f.write_str("Chronological"),
+            Self::Unordered => f.write_str("Unordered"),
+            Self::Predicate(_) => f.write_str("Predicate(..)"),
+        }
+    }
+}
+
+impl<T> OrderingPolicy<T> {
+    pub fn predicate(f: impl Fn(&T, &T) -> cmp::Ordering + 'static) -> Self {
+        Self::Predicate(Box::new(f))
+    }
+}
+
+/// A container which can be added to and removed from while it is being iterated.
+///
+/// - While the hive is locked (i.e at least one `HiveLock` is alive), `add()` pushes into pinned "pending" chunks
+///   instead of the main buffer, so live references are never invalidated;
+/// - The container holds a strong ref to each item. Removal releases it, and the item stays visible to iterators
+///   until `compact()`, which drops items nobody holds a strong ref to;
+/// - `compact()` applies pending removals, then pending adds, then restores the `OrderingPolicy`.
+///   Outside of `compact()`, items added since the last compaction are not guaranteed to follow the policy.
+///
+/// References to items can only be obtained via a `HiveLock`, which is what makes it sound to touch the main buffer
+/// via `&self` whenever the hive is unlocked.
+///
+/// Longer-lived references are `WeakOrStrongRef`s, which go through the item's redirector, and register a
+/// referencer so that we can always tell who is referencing an item.
+///
+/// Mutable access via `&self` is scheduled: when the hive is not borrowed (the "root context"), `borrow_mut()` gives
+/// direct access. Deeper in the call stack, mutations are deferred via `run_or_defer()`, and run as soon as the
+/// outermost borrow ends.
+pub struct Hive<T> {
+    items: UnsafeCell<Vec<Item<T>>>,
+    pending_adds: imp::PinArenaList<Item<T>>,
+    lock_counter: Cell<usize>,
+    is_borrowed_mut: Cell<bool>,
+    deferred_commands: RefCell<VecDeque<DeferredCommand<T>>>,
+    is_flushing_deferred_commands: Cell<bool>,
+    ordering_policy: OrderingPolicy<T>,
+    registry: Rc<Registry>,
+}
+
+impl<T> Drop for Hive<T> {
+    fn drop(&mut self) {
+        // Make refs dangle before dropping items, since dropping them may drop refs to other items of this hive.
+        for item in self.items.get_mut().iter().chain(self.pending_adds.iter()) {
+            // SAFETY: the item is alive, and is about to be dropped along with our fields
+            unsafe { self.registry.detach_redirector(item.redirector) };
+        }
+    }
+}
+
+impl<T> Default for Hive<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for Hive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hive")
+            .field("id", &self.id())
+            .field("main_buffer_len", &self.main_buffer_len())
+            .field("pending_add_count", &self.pending_add_count())
+            .field("lock_counter", &self.lock_counter.get())
+            .field("is_borrowed_mut", &self.is_borrowed_mut.get())
+            .field("deferred_command_count", &self.deferred_command_count())
+            .field("ordering_policy", &self.ordering_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Hive<T> {
+    pub fn new() -> Self {
+        Self::with_chunk_capacity(DEFAULT_CHUNK_CAPACITY)
+    }
+    /// `chunk_capacity` is the number of items per pending chunk.
+    pub fn with_chunk_capacity(chunk_capacity: usize) -> Self {
+        Self::with_chunk_capacity_and_ordering_policy(chunk_capacity, OrderingPolicy::default())
+    }
+    pub fn with_ordering_policy(ordering_policy: OrderingPolicy<T>) -> Self {
+        Self::with_chunk_capacity_and_ordering_policy(DEFAULT_CHUNK_CAPACITY, ordering_policy)
+    }
+    pub fn with_chunk_capacity_and_ordering_policy(chunk_capacity: usize, ordering_policy: OrderingPolicy<T>) -> Self {
+        Self {
+            items: UnsafeCell::new(Vec::new()),
+            pending_adds: imp::PinArenaList::with_capacity(chunk_capacity),
+            lock_counter: Cell::new(0),
+            is_borrowed_mut: Cell::new(false),
+            deferred_commands: RefCell::new(VecDeque::new()),
+            is_flushing_deferred_commands: Cell::new(false),
+            ordering_policy,
+            registry: Rc::new(Registry::new()),
+        }
+    }
+    /// Identifies the hive in serialized refs (see `SerializedRef`). Zero by default.
+    pub fn id(&self) -> u64 {
+        self.registry.hive_id()
+    }
+    /// Tells hives apart within a run (e.g derived from the hive's name). Refs serialized before this call keep the
+    /// old id.
+    pub fn set_id(&mut self, id: u64) {
+        self.registry.set_hive_id(id);
+    }
+    /// Random, and distinct for every hive, so that refs serialized in another run (or by another hive with the same
+    /// id) fail to resolve instead of resolving to whichever item got the same guid.
+    pub fn session_id(&self) -> u64 {
+        self.registry.session_id()
+    }
+    pub fn ordering_policy(&self) -> &OrderingPolicy<T> {
+        &self.ordering_policy
+    }
+    fn main_buffer(&self) -> &[Item<T>] {
+        debug_assert!(!self.is_borrowed_mut.get());
+        // SAFETY: the main buffer is only mutated while the hive is unlocked, i.e when nobody can be borrowing it.
+        // Items may be mutably borrowed via `HiveMut`, in which case we must not get there.
+        unsafe { &*self.items.get() }
+    }
+    /// Doesn't borrow items, so this is fine to call while they're mutably borrowed.
+    fn main_buffer_contains(&self, p: *const Item<T>) -> bool {
+        // SAFETY: only the `Vec` itself is borrowed, and it is only mutated while the hive is unlocked
+        let items = unsafe { &*self.items.get() };
+        let start = items.as_ptr();
+        start <= p && p < start.wrapping_add(items.len())
+    }
+    /// # Safety
+    ///
+    /// * The hive must be unlocked, and must stay that way while the returned reference is alive.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn main_buffer_mut(&self) -> &mut Vec<Item<T>> {
+        debug_assert!(!self.is_locked());
+        // SAFETY: upheld by the caller
+        unsafe { &mut *self.items.get() }
+    }
+    pub fn main_buffer_len(&self) -> usize {
+        // SAFETY: only the `Vec` itself is borrowed, and it is only mutated while the hive is unlocked
+        unsafe { (*self.items.get()).len() }
+    }
+    pub fn pending_add_count(&self) -> usize {
+        self.pending_adds.len()
+    }
+    /// Number of items, including pending adds and pending removals.
+    pub fn len(&self) -> usize {
+        self.main_buffer_len() + self.pending_add_count()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn add(&self, value: T) {
+        self.add_impl(value);
+    }
+    pub fn add_and_make_ref(&self, value: T, strength: RefStrength, info: RefInfo) -> WeakOrStrongRef<T> {
+        let redirector = self.add_impl(value);
+        WeakOrStrongRef::new(Rc::clone(&self.registry), redirector, strength, info)
+    }
+    /// Adds an item which is only kept alive by the returned strong ref, e.g a component owned by its entity.
+    /// Same as `add_and_make_ref()` followed by `take_container_ref()`.
+    pub fn add_and_take_container_ref(&self, value: T, info: RefInfo) -> WeakOrStrongRef<T> {
+        let redirector = self.add_impl(value);
+        // SAFETY: the redirector was just created, and is not referenced by anyone else yet
+        unsafe { redirector.as_ref() }.is_owned_by_container.set(false);
+        // SAFETY: we just gave up the container's strong ref
+        unsafe { WeakOrStrongRef::new_taking_over_container_ref(Rc::clone(&self.registry), redirector, info) }
+    }
+    fn add_impl(&self, value: T) -> NonNull<Redirector> {
+        let redirector = self.registry.create_redirector();
+        let item = Item { value, redirector };
+        if self.is_locked() {
+            self.pending_adds.push(item).update_redirector();
+        } else {
+            // SAFETY: we are not locked, and we don't call back into user code
+            let items = unsafe { self.main_buffer_mut() };
+            let old_ptr = items.as_ptr();
+            items.push(item);
+            if items.as_ptr() == old_ptr {
+                items.last().unwrap().update_redirector();
+            } else {
+                items.iter().for_each(Item::update_redirector);
+            }
+        }
+        redirector
+    }
+    /// Panics if the hive is mutably borrowed (see `borrow_mut()`). Use `run_or_defer()` in that case.
+    pub fn lock(&self) -> HiveLock<'_, T> {
+        assert!(!self.is_borrowed_mut.get(), "The hive is already mutably borrowed");
+        self.increment_lock_counter();
+        HiveLock { hive: self }
+    }
+    pub fn is_locked(&self) -> bool {
+        self.lock_counter.get() > 0
+    }
+    pub fn increment_lock_counter(&self) {
+        self.lock_counter.set(self.lock_counter.get() + 1);
+    }
+    /// Runs deferred commands once the counter reaches zero.
+    ///
+    /// # Safety
+    ///
+    /// * Each call must balance a previous call to `increment_lock_counter()`, and no reference obtained while
+    ///   locked may outlive the moment the counter reaches zero.
+    pub unsafe fn decrement_lock_counter(&self) {
+        // SAFETY: upheld by the caller
+        if unsafe { self.decrement_lock_counter_without_flushing() } {
+            self.flush_deferred_commands();
+        }
+    }
+    /// Returns true if the hive is now unlocked.
+    ///
+    /// # Safety
+    ///
+    /// * Same as `decrement_lock_counter()`.
+    unsafe fn decrement_lock_counter_without_flushing(&self) -> bool {
+        let lock_counter = self.lock_counter.get();
+        assert_ne!(lock_counter, 0);
+        self.lock_counter.set(lock_counter - 1);
+        lock_counter == 1
+    }
+    fn item_of<'a>(&self, value: &'a T) -> &'a Item<T> {
+        let p = (value as *const T).cast::<Item<T>>();
+        assert!(self.main_buffer_contains(p) || self.pending_adds.contains_ptr(p), "This item does not belong to this hive");
+        // SAFETY: `Item<T>` is `repr(C)` and `value` is its first member, and we just checked that `value` lives in one of our items.
+        unsafe { &*p }
+    }
+    /// Returns true if the item is not in the main buffer yet (i.e it was added while the hive was locked).
+    ///
+    /// Forward iterators may break early on the first pending add.
+    pub fn is_pending_add(&self, value: &T) -> bool {
+        let p = self.item_of(value) as *const Item<T>;
+        !self.main_buffer_contains(p)
+    }
+    /// Returns true if nobody holds a strong ref to the item anymore, not even the container.
+    pub fn is_pending_removal(&self, value: &T) -> bool {
+        self.item_of(value).is_pending_removal()
+    }
+    /// Releases the container's strong ref. The item is dropped at the next `compact()`, unless someone else holds a
+    /// strong ref to it by then.
+    pub fn mark_for_removal(&self, value: &T) {
+        let redirector = self.item_of(value).redirector();
+        if redirector.is_owned_by_container.replace(false) {
+            redirector.strong_ref_count.set(redirector.strong_ref_count.get() - 1);
+        }
+    }
+    pub fn is_owned_by_container(&self, value: &T) -> bool {
+        self.item_of(value).redirector().is_owned_by_container.get()
+    }
+    /// Unique within this hive; never reused.
+    pub fn item_guid(&self, value: &T) -> u64 {
+        self.item_of(value).redirector().item_guid
+    }
+    pub fn make_ref(&self, value: &T, strength: RefStrength, info: RefInfo) -> WeakOrStrongRef<T> {
+        WeakOrStrongRef::new(Rc::clone(&self.registry), self.item_of(value).redirector, strength, info)
+    }
+    /// "Steals" the container's strong ref: the item now lives for as long as the returned ref (or any other
+    /// strong ref) does. Returns `None` if the container already gave up its strong ref.
+    pub fn take_container_ref(&self, value: &T, info: RefInfo) -> Option<WeakOrStrongRef<T>> {
+        let item = self.item_of(value);
+        if !item.redirector().is_owned_by_container.replace(false) {
+            return None;
+        }
+        // SAFETY: we just gave up the container's strong ref
+        Some(unsafe { WeakOrStrongRef::new_taking_over_container_ref(Rc::clone(&self.registry), item.redirector, info) })
+    }
+    pub fn weak_ref_any(&self, value: &T) -> WeakRefAny {
+        WeakRefAny::new(Rc::clone(&self.registry), self.item_guid(value))
+    }
+    /// Lists every ref to the item. This does not include the container's own strong ref (see `is_owned_by_container()`).
+    pub fn referencers(&self, value: &T) -> Vec<ReferencerInfo> {
+        refs::referencers_of(self.item_of(value).redirector())
+    }
+    fn check_ref(&self, r: &WeakOrStrongRef<T>) {
+        assert!(Rc::ptr_eq(r.registry(), &self.registry), "This ref does not point into this hive");
+    }
+    pub fn get_mut(&mut self, r: &WeakOrStrongRef<T>) -> Option<&mut T> {
+        self.check_ref(r);
+        let item = r.redirector().item.get().cast::<Item<T>>().cast_mut();
+        // SAFETY: non-null item pointers are kept up to date, and we have `&mut self`
+        unsafe { item.as_mut() }.map(|item| &mut item.value)
+    }
+    /// Applies pending removals, then pending adds, then sorts according to the ordering policy.
+    ///
+    /// Returns false (and does nothing) if the hive is locked. Runs deferred commands afterwards.
+    pub fn compact(&self) -> bool {
+        self.compact_and_count_dropped().is_some()
+    }
+    /// Same as `compact()`, but returns the number of dropped items, or `None` if the hive is locked.
+    pub fn compact_and_count_dropped(&self) -> Option<usize> {
+        if self.is_locked() {
+            return None;
+        }
+        // Take the main buffer out, so that user code called during compaction (i.e the comparison predicate) can't
+        // observe it while we're mutating it. Items it adds go to pending adds, because we stay locked meanwhile.
+        // SAFETY: we are not locked
+        let mut taken = CompactionBuffers { hive: self, items: mem::take(unsafe { self.main_buffer_mut() }), removed: vec![] };
+        {
+            // Not a `HiveLock`, which would run deferred commands while the main buffer is taken out
+            let _lock = CompactionLock::new(self);
+            let CompactionBuffers { items, removed, .. } = &mut taken;
+            match self.ordering_policy {
+                OrderingPolicy::Chronological | OrderingPolicy::Predicate(_) => {
+                    removed.extend(items.extract_if(.., |item| item.is_pending_removal()));
+                },
+                OrderingPolicy::Unordered => {
+                    let mut i = 0;
+                    while i < items.len() {
+                        if items[i].is_pending_removal() {
+                            removed.push(items.swap_remove(i));
+                        } else {
+                            i += 1;
+                        }
+                    }
+                },
+            }
+            // SAFETY: nobody is borrowing pending adds, since we were not locked. The closure doesn't push into the list.
+            unsafe {
+                self.pending_adds.drain_into(|item| {
+                    if item.is_pending_removal() {
+                        removed.push(item);
+                    } else {
+                        items.push(item);
+                    }
+                })
+            };
+            if let OrderingPolicy::Predicate(f) = &self.ordering_policy {
+                items.sort_by(|a, b| f(&a.value, &b.value));
+            }
+        }
+        // No more user code until the buffers are out of `taken`
+        let items = mem::take(&mut taken.items);
+        let removed = mem::take(&mut taken.removed);
+        drop(taken);
+        items.iter().for_each(Item::update_redirector);
+        for item in &removed {
+            // SAFETY: the item is alive, and is about to be dropped
+            unsafe { self.registry.detach_redirector(item.redirector) };
+        }
+        // SAFETY: we are unlocked again
+        let main_buffer = unsafe { self.main_buffer_mut() };
+        debug_assert!(main_buffer.is_empty());
+        *main_buffer = items;
+        // Dropping removed items may call into user code, which may add items to this hive. That's fine now.
+        let dropped_count = removed.len();
+        drop(removed);
+        self.flush_deferred_commands();
+        Some(dropped_count)
+    }
+    /// Compacts, then iterates over all items mutably.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        assert!(self.compact(), "Cannot be locked since we have `&mut self`");
+        self.items.get_mut().iter_mut().map(|item| &mut item.value)
+    }
+}
+
+struct CompactionLock<'a, T> {
+    hive: &'a Hive<T>,
+}
+
+impl<'a, T> CompactionLock<'a, T> {
+    fn new(hive: &'a Hive<T>) -> Self {
+        hive.increment_lock_counter();
+        Self { hive }
+    }
+}
+
+impl<T> Drop for CompactionLock<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: balances the increment in `new()`. Compaction doesn't hand out references.
+        unsafe { self.hive.decrement_lock_counter_without_flushing() };
+    }
+}
+
+/// What `compact()` took out of a hive. Puts the items back (unsorted) if the comparison predicate panics, and makes
+/// refs to removed items dangle before they are dropped, so that redirectors never point to freed memory.
+struct CompactionBuffers<'a, T> {
+    hive: &'a Hive<T>,
+    items: Vec<Item<T>>,
+    removed: Vec<Item<T>>,
+}
+
+impl<T> Drop for CompactionBuffers<'_, T> {
+    fn drop(&mut self) {
+        // Only non-empty when unwinding, after the compaction lock was released
+        // SAFETY: we are unlocked, and the main buffer was taken out, so nobody borrows it
+        let main_buffer = unsafe { self.hive.main_buffer_mut() };
+        debug_assert!(main_buffer.is_empty());
+        *main_buffer = mem::take(&mut self.items);
+        main_buffer.iter().for_each(Item::update_redirector);
+        for item in &self.removed {
+            // SAFETY: the item is alive, and is about to be dropped
+            unsafe { self.hive.registry.detach_redirector(item.redirector) };
+        }
+    }
+}
+
+/// Keeps a hive locked for as long as it is alive. All references to items are borrowed from it.
+pub struct HiveLock<'a, T> {
+    hive: &'a Hive<T>,
+}
+
+impl<T> Deref for HiveLock<'_, T> {
+    type Target = Hive<T>;
+    fn deref(&self) -> &Hive<T> {
+        self.hive
+    }
+}
+
+impl<T> Drop for HiveLock<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: balances the increment in `Hive::lock()`, and references handed out by `iter()` cannot outlive us.
+        unsafe { self.hive.decrement_lock_counter() };
+    }
+}
+
+impl<T> fmt::Debug for HiveLock<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("HiveLock").field(self.hive).finish()
+    }
+}
+
+impl<T> HiveLock<'_, T> {
+    /// Returns `None` if the item has been dropped. Items pending removal are still accessible.
+    pub fn get(&self, r: &WeakOrStrongRef<T>) -> Option<&T> {
+        self.hive.check_ref(r);
+        let item = r.redirector().item.get().cast::<Item<T>>();
+        // SAFETY: non-null item pointers are kept up to date, and items don't move while we're locked
+        unsafe { item.as_ref() }.map(|item| &item.value)
+    }
+    /// Iterates over the main buffer, then over pending adds, including the ones added during iteration.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            items: self.hive.main_buffer().iter(),
+            pending_adds: self.hive.pending_adds.iter(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Iter<'a, T> {
+    items: slice::Iter<'a, Item<T>>,
+    pending_adds: imp::PinArenaListIter<'a, Item<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        self.items.next().or_else(|| self.pending_adds.next()).map(|item| &item.value)
+    }
+}
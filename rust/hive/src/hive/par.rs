@@ -0,0 +1,65 @@
+use rayon::prelude::*;
+
+use super::{HiveLock, HiveMut, Item};
+
+/// Shares the location of items with worker threads.
+///
+/// Only handed out by `par_iter()` (which requires `T: Sync`) and `par_iter_mut()` (which requires `T: Send`, and
+/// visits each item once), so that's what makes it fine to implement Send and Sync regardless of `T`.
+struct ItemPtr<T>(*mut Item<T>);
+
+impl<T> Clone for ItemPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ItemPtr<T> {}
+
+unsafe impl<T> Send for ItemPtr<T> {}
+unsafe impl<T> Sync for ItemPtr<T> {}
+
+impl<T> ItemPtr<T> {
+    /// # Safety
+    ///
+    /// * Same as `ptr::add()`.
+    unsafe fn add(self, i: usize) -> Self {
+        // SAFETY: upheld by the caller
+        Self(unsafe { self.0.add(i) })
+    }
+}
+
+/// Main buffer, then a snapshot of pending adds. Neither can change while the hive is locked and we're inside rayon:
+/// `Hive` is not `Sync`, so worker threads can't touch it, and the calling thread is busy running the query.
+fn item_ptrs<T>(main_buffer: *mut Item<T>, main_buffer_len: usize, pending_adds: Vec<ItemPtr<T>>) -> impl IndexedParallelIterator<Item = ItemPtr<T>> {
+    let main_buffer = ItemPtr(main_buffer);
+    (0..main_buffer_len)
+        // SAFETY: in bounds
+        .into_par_iter().map(move |i| unsafe { main_buffer.add(i) })
+        .chain(pending_adds)
+}
+
+impl<'a, T> HiveLock<'a, T> {
+    /// Parallel version of `iter()`, split into chunks by rayon. Items added by the iteration itself are not visited,
+    /// and items pending removal are visited, same as `iter()`.
+    pub fn par_iter(&self) -> impl IndexedParallelIterator<Item = &T> where T: Sync {
+        let pending_adds = self.hive.pending_adds.ptr_iter().map(|p| ItemPtr(p.as_ptr())).collect();
+        let main_buffer = self.hive.main_buffer();
+        item_ptrs(main_buffer.as_ptr().cast_mut(), main_buffer.len(), pending_adds)
+            // SAFETY: items don't move while we're locked, and are only read
+            .map(|item| unsafe { &(*item.0).value })
+    }
+}
+
+impl<'a, T> HiveMut<'a, T> {
+    /// Parallel version of `iter_mut()`, split into chunks by rayon. Each item is visited by exactly one thread.
+    pub fn par_iter_mut(&mut self) -> impl IndexedParallelIterator<Item = &mut T> where T: Send {
+        let hive = self.hive();
+        let pending_adds = hive.pending_adds.ptr_iter().map(|p| ItemPtr(p.as_ptr())).collect();
+        // SAFETY: only the `Vec` itself is borrowed, and it can't be resized while the hive is locked
+        let main_buffer = unsafe { &mut *hive.items.get() };
+        item_ptrs(main_buffer.as_mut_ptr(), main_buffer.len(), pending_adds)
+            // SAFETY: each item is yielded once, and `HiveMut` guarantees exclusive access
+            .map(|item| unsafe { &mut (*item.0).value })
+    }
+}
@@ -0,0 +1,493 @@
+use std::{borrow::Cow, cell::{Cell, RefCell}, collections::{hash_map::RandomState, HashMap}, fmt, hash::{BuildHasher, Hasher}, marker::PhantomData, panic::Location, ptr::NonNull, rc::Rc, sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}};
+
+use super::imp::{EvacuationPlan, SlotPool};
+
+/// Every `WeakOrStrongRef` starts with this value, immediately followed by its referencer pointer.
+/// This allows finding refs by scanning memory, which is one way to relocate referencers.
+pub const MAGIC_GUID: u64 = 0x4a1e_7e6b_5eed_f00d;
+
+const REDIRECTORS_PER_CHUNK: usize = 256;
+const REFERENCERS_PER_CHUNK: usize = 256;
+
+/// Pinned. Knows where its item currently is; when the item moves in memory, only this pointer gets updated.
+#[repr(C)] // Just for a better debugging experience
+#[derive(Debug)]
+pub(crate) struct Redirector {
+    pub(crate) item: Cell<*const ()>, // Type-erased `*const Item<T>`. Null once the item has been dropped.
+    pub(crate) item_guid: u64,
+    pub(crate) generation: u32,
+    pub(crate) strong_ref_count: Cell<usize>, // Includes the container's strong ref, if it still has it
+    pub(crate) is_owned_by_container: Cell<bool>,
+    pub(crate) referencer_count: Cell<usize>, // The redirector is kept alive by its referencers, even after the item is dropped
+    pub(crate) first_referencer: Cell<Option<NonNull<Referencer>>>,
+}
+
+impl Redirector {
+    pub(crate) fn is_alive(&self) -> bool {
+        !self.item.get().is_null()
+    }
+    pub(crate) fn referencers(&self) -> impl Iterator<Item = &Referencer> {
+        let mut next = self.first_referencer.get();
+        std::iter::from_fn(move || {
+            // SAFETY: referencers unlink themselves from the list before being freed
+            let referencer = unsafe { next?.as_ref() };
+            next = referencer.next.get();
+            Some(referencer)
+        })
+    }
+}
+
+/// Pinned. One per `WeakOrStrongRef`; tracks who is referencing an item.
+#[repr(C)] // Just for a better debugging experience
+#[derive(Debug)]
+pub(crate) struct Referencer {
+    pub(crate) redirector: Cell<NonNull<Redirector>>,
+    pub(crate) is_strong: Cell<bool>,
+    pub(crate) info: RefInfo,
+    pub(crate) prev: Cell<Option<NonNull<Referencer>>>,
+    pub(crate) next: Cell<Option<NonNull<Referencer>>>,
+}
+
+impl Referencer {
+    fn redirector(&self) -> &Redirector {
+        // SAFETY: a redirector is kept alive by its referencers
+        unsafe { self.redirector.get().as_ref() }
+    }
+    pub(crate) fn to_info(&self) -> ReferencerInfo {
+        ReferencerInfo {
+            debug_name: self.info.debug_name.clone(),
+            location: self.info.location,
+            owner: self.info.owner.clone(),
+            is_strong: self.is_strong.get(),
+            is_breakable: self.info.is_breakable,
+        }
+    }
+}
+
+/// Random, so that item guids (which restart at 1 in every registry) can't be mistaken for those of another run.
+fn new_session_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // `RandomState` is seeded from the OS once per thread, then only incremented, so hash in a few more sources
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u32(std::process::id());
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    hasher.finish()
+}
+
+/// Owns the redirectors and referencers of a hive. Outlives the hive for as long as refs to its items exist.
+#[derive(Debug)]
+pub(crate) struct Registry {
+    hive_id: Cell<u64>,
+    session_id: u64,
+    redirectors: SlotPool<Redirector>,
+    referencers: SlotPool<Referencer>,
+    next_item_guid: Cell<u64>,
+    redirectors_by_item_guid: RefCell<HashMap<u64, NonNull<Redirector>>>,
+}
+
+impl Registry {
+    pub(crate) fn new() -> Self {
+        Self {
+            hive_id: Cell::new(0),
+            session_id: new_session_id(),
+            redirectors: SlotPool::with_chunk_capacity(REDIRECTORS_PER_CHUNK),
+            referencers: SlotPool::with_chunk_capacity(REFERENCERS_PER_CHUNK),
+            next_item_guid: Cell::new(1),
+            redirectors_by_item_guid: RefCell::new(HashMap::new()),
+        }
+    }
+    pub(crate) fn hive_id(&self) -> u64 {
+        self.hive_id.get()
+    }
+    pub(crate) fn set_hive_id(&self, hive_id: u64) {
+        self.hive_id.set(hive_id);
+    }
+    pub(crate) fn session_id(&self) -> u64 {
+        self.session_id
+    }
+    /// Creates the redirector of a new item, with the container's strong ref.
+    pub(crate) fn create_redirector(&self) -> NonNull<Redirector> {
+        let item_guid = self.next_item_guid.get();
+        self.next_item_guid.set(item_guid + 1);
+        let redirector = self.redirectors.alloc(|generation| Redirector {
+            item: Cell::new(std::ptr::null()),
+            item_guid,
+            generation,
+            strong_ref_count: Cell::new(1),
+            is_owned_by_container: Cell::new(true),
+            referencer_count: Cell::new(0),
+            first_referencer: Cell::new(None),
+        });
+        self.redirectors_by_item_guid.borrow_mut().insert(item_guid, redirector);
+        redirector
+    }
+    pub(crate) fn redirector_by_item_guid(&self, item_guid: u64) -> Option<NonNull<Redirector>> {
+        self.redirectors_by_item_guid.borrow().get(&item_guid).copied()
+    }
+    /// Redirectors of items which are still alive.
+    pub(crate) fn live_redirectors(&self) -> Vec<NonNull<Redirector>> {
+        self.redirectors_by_item_guid.borrow().values().copied().collect()
+    }
+    /// Called when the item is dropped. The redirector is freed once its last referencer is gone.
+    ///
+    /// # Safety
+    ///
+    /// * `redirector` must be one of ours, and its item must be alive.
+    pub(crate) unsafe fn detach_redirector(&self, redirector: NonNull<Redirector>) {
+        // SAFETY: upheld by the caller
+        let r = unsafe { redirector.as_ref() };
+        debug_assert!(r.is_alive());
+        r.item.set(std::ptr::null());
+        self.redirectors_by_item_guid.borrow_mut().remove(&r.item_guid);
+        if r.referencer_count.get() == 0 {
+            // SAFETY: nobody references it anymore
+            unsafe { self.redirectors.free(redirector) };
+        }
+    }
+    fn create_referencer(&self, redirector: NonNull<Redirector>, is_strong: bool, info: RefInfo) -> NonNull<Referencer> {
+        // SAFETY: the caller has proven that the redirector is alive one way or another (through the hive or another referencer)
+        let r = unsafe { redirector.as_ref() };
+        let first = r.first_referencer.get();
+        let referencer = self.referencers.alloc(|_| Referencer {
+            redirector: Cell::new(redirector),
+            is_strong: Cell::new(is_strong),
+            info,
+            prev: Cell::new(None),
+            next: Cell::new(first),
+        });
+        if let Some(first) = first {
+            // SAFETY: referencers in the list are alive
+            unsafe { first.as_ref() }.prev.set(Some(referencer));
+        }
+        r.first_referencer.set(Some(referencer));
+        r.referencer_count.set(r.referencer_count.get() + 1);
+        if is_strong {
+            r.strong_ref_count.set(r.strong_ref_count.get() + 1);
+        }
+        referencer
+    }
+    /// # Safety
+    ///
+    /// * `referencer` must be one of ours, and must not be used afterwards.
+    unsafe fn destroy_referencer(&self, referencer: NonNull<Referencer>) {
+        // SAFETY: upheld by the caller
+        let rr = unsafe { referencer.as_ref() };
+        let redirector = rr.redirector.get();
+        let r = rr.redirector();
+        match rr.prev.get() {
+            // SAFETY: referencers in the list are alive
+            Some(prev) => unsafe { prev.as_ref() }.next.set(rr.next.get()),
+            None => r.first_referencer.set(rr.next.get()),
+        }
+        if let Some(next) = rr.next.get() {
+            // SAFETY: referencers in the list are alive
+            unsafe { next.as_ref() }.prev.set(rr.prev.get());
+        }
+        if rr.is_strong.get() {
+            assert_ne!(r.strong_ref_count.get(), 0);
+            r.strong_ref_count.set(r.strong_ref_count.get() - 1);
+        }
+        r.referencer_count.set(r.referencer_count.get() - 1);
+        let must_free_redirector = !r.is_alive() && r.referencer_count.get() == 0;
+        // SAFETY: unlinked above, and the caller won't use it anymore
+        unsafe { self.referencers.free(referencer) };
+        if must_free_redirector {
+            // SAFETY: its item is gone, and nobody references it anymore
+            unsafe { self.redirectors.free(redirector) };
+        }
+    }
+}
+
+/// Defragmentation support. Relocating a redirector or referencer means patching every pointer to it.
+impl Registry {
+    pub(crate) fn redirectors(&self) -> &SlotPool<Redirector> {
+        &self.redirectors
+    }
+    pub(crate) fn referencers(&self) -> &SlotPool<Referencer> {
+        &self.referencers
+    }
+    /// Patches referencers and the guid map, but not the item: that's up to the caller, who knows its type.
+    ///
+    /// # Safety
+    ///
+    /// * `redirector` must be one of ours, and nobody may be borrowing it.
+    pub(crate) unsafe fn relocate_redirector(&self, plan: &EvacuationPlan, redirector: NonNull<Redirector>) -> Option<NonNull<Redirector>> {
+        // SAFETY: upheld by the caller
+        let new_redirector = unsafe { self.redirectors.relocate(plan, redirector) }?;
+        // SAFETY: we just moved it there
+        let r = unsafe { new_redirector.as_ref() };
+        for referencer in r.referencers() {
+            referencer.redirector.set(new_redirector);
+        }
+        if r.is_alive() {
+            self.redirectors_by_item_guid.borrow_mut().insert(r.item_guid, new_redirector);
+        }
+        Some(new_redirector)
+    }
+    /// Patches the redirector's list, but not the ref which owns the referencer.
+    ///
+    /// # Safety
+    ///
+    /// * `referencer` must be one of ours, and nobody may be borrowing it.
+    unsafe fn relocate_referencer(&self, plan: &EvacuationPlan, referencer: NonNull<Referencer>) -> Option<NonNull<Referencer>> {
+        // SAFETY: upheld by the caller
+        let new_referencer = unsafe { self.referencers.relocate(plan, referencer) }?;
+        // SAFETY: we just moved it there
+        let rr = unsafe { new_referencer.as_ref() };
+        match rr.prev.get() {
+            // SAFETY: referencers in the list are alive
+            Some(prev) => unsafe { prev.as_ref() }.next.set(Some(new_referencer)),
+            None => rr.redirector().first_referencer.set(Some(new_referencer)),
+        }
+        if let Some(next) = rr.next.get() {
+            // SAFETY: referencers in the list are alive
+            unsafe { next.as_ref() }.prev.set(Some(new_referencer));
+        }
+        Some(new_referencer)
+    }
+}
+
+/// Ownership information which must be provided whenever a ref is created, so that we can always tell who is
+/// referencing an item.
+#[derive(Debug, Clone)]
+pub struct RefInfo {
+    pub debug_name: Cow<'static, str>,
+    pub location: Option<&'static Location<'static>>,
+    pub owner: Option<WeakRefAny>,
+    /// The owner knows how to break a cycle going through this ref (e.g it is stored in an `Option` which gets
+    /// cleared at some point). Cycle detection ignores such refs.
+    pub is_breakable: bool,
+}
+
+impl RefInfo {
+    /// Captures the caller's location.
+    #[track_caller]
+    pub fn new(debug_name: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            debug_name: debug_name.into(),
+            location: Some(Location::caller()),
+            owner: None,
+            is_breakable: false,
+        }
+    }
+    pub fn with_owner(mut self, owner: WeakRefAny) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+    /// See `is_breakable`.
+    pub fn breakable(mut self) -> Self {
+        self.is_breakable = true;
+        self
+    }
+    pub fn without_location(mut self) -> Self {
+        self.location = None;
+        self
+    }
+}
+
+/// Snapshot of a referencer, as returned by the referencer enumeration APIs.
+#[derive(Debug, Clone)]
+pub struct ReferencerInfo {
+    pub debug_name: Cow<'static, str>,
+    pub location: Option<&'static Location<'static>>,
+    pub owner: Option<WeakRefAny>,
+    pub is_strong: bool,
+    pub is_breakable: bool,
+}
+
+impl fmt::Display for ReferencerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" ({}{})", self.debug_name, if self.is_strong { "strong" } else { "weak" }, if self.is_breakable { ", breakable" } else { "" })?;
+        if let Some(location) = self.location {
+            write!(f, " at {location}")?;
+        }
+        if let Some(owner) = &self.owner {
+            write!(f, " owned by item #{}", owner.item_guid())?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn referencers_of(redirector: &Redirector) -> Vec<ReferencerInfo> {
+    redirector.referencers().map(Referencer::to_info).collect()
+}
+
+/// Type-erased, untracked weak ref. Mostly used as the "owner" back pointer of refs.
+#[derive(Clone)]
+pub struct WeakRefAny {
+    registry: Rc<Registry>,
+    item_guid: u64,
+}
+
+impl fmt::Debug for WeakRefAny {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WeakRefAny(#{})", self.item_guid)
+    }
+}
+
+impl PartialEq for WeakRefAny {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.registry, &other.registry) && self.item_guid == other.item_guid
+    }
+}
+
+impl Eq for WeakRefAny {}
+
+impl WeakRefAny {
+    pub(crate) fn new(registry: Rc<Registry>, item_guid: u64) -> Self {
+        Self { registry, item_guid }
+    }
+    pub(crate) fn registry(&self) -> &Rc<Registry> {
+        &self.registry
+    }
+    pub fn item_guid(&self) -> u64 {
+        self.item_guid
+    }
+    pub fn is_dangling(&self) -> bool {
+        self.registry.redirector_by_item_guid(self.item_guid).is_none()
+    }
+    pub fn referencers(&self) -> Vec<ReferencerInfo> {
+        match self.registry.redirector_by_item_guid(self.item_guid) {
+            // SAFETY: redirectors in the map are alive
+            Some(redirector) => referencers_of(unsafe { redirector.as_ref() }),
+            None => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum RefStrength {
+    Weak,
+    Strong,
+}
+
+/// A tracked reference to a hive item, which is either weak or strong, and can switch at runtime.
+///
+/// Cannot be cloned without providing new ownership information (see `clone_with()`).
+/// Items can be accessed via `HiveLock::get()`.
+#[repr(C)] // `magic` must be immediately followed by `referencer`, see `MAGIC_GUID`.
+pub struct WeakOrStrongRef<T> {
+    magic: u64,
+    referencer: NonNull<Referencer>,
+    registry: Rc<Registry>,
+    phantom: PhantomData<*const T>,
+}
+
+impl<T> fmt::Debug for WeakOrStrongRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeakOrStrongRef")
+            .field("debug_name", &self.referencer().info.debug_name)
+            .field("item_guid", &self.item_guid())
+            .field("is_strong", &self.is_strong())
+            .field("is_dangling", &self.is_dangling())
+            .finish()
+    }
+}
+
+impl<T> Drop for WeakOrStrongRef<T> {
+    fn drop(&mut self) {
+        // SAFETY: the referencer is ours, and we're going away
+        unsafe { self.registry.destroy_referencer(self.referencer) };
+    }
+}
+
+impl<T> WeakOrStrongRef<T> {
+    pub(crate) fn new(registry: Rc<Registry>, redirector: NonNull<Redirector>, strength: RefStrength, info: RefInfo) -> Self {
+        let referencer = registry.create_referencer(redirector, strength == RefStrength::Strong, info);
+        Self {
+            magic: MAGIC_GUID,
+            referencer,
+            registry,
+            phantom: PhantomData,
+        }
+    }
+    /// # Safety
+    ///
+    /// * The container's strong ref must have been given up by the caller, for this new strong ref to take it over.
+    pub(crate) unsafe fn new_taking_over_container_ref(registry: Rc<Registry>, redirector: NonNull<Redirector>, info: RefInfo) -> Self {
+        let r = Self::new(registry, redirector, RefStrength::Strong, info);
+        let redirector = r.redirector();
+        redirector.strong_ref_count.set(redirector.strong_ref_count.get() - 1);
+        r
+    }
+    fn referencer(&self) -> &Referencer {
+        // SAFETY: we own our referencer
+        unsafe { self.referencer.as_ref() }
+    }
+    pub(crate) fn redirector(&self) -> &Redirector {
+        self.referencer().redirector()
+    }
+    pub(crate) fn registry(&self) -> &Rc<Registry> {
+        &self.registry
+    }
+    /// Returns true if the referencer was moved.
+    pub(crate) fn relocate_referencer(&mut self, plan: &EvacuationPlan) -> bool {
+        // SAFETY: the referencer is ours, and `&mut self` guarantees nobody is borrowing it
+        match unsafe { self.registry.relocate_referencer(plan, self.referencer) } {
+            Some(referencer) => {
+                self.referencer = referencer;
+                true
+            },
+            None => false,
+        }
+    }
+    pub fn item_guid(&self) -> u64 {
+        self.redirector().item_guid
+    }
+    /// See `Hive::set_id()`.
+    pub fn hive_id(&self) -> u64 {
+        self.registry.hive_id()
+    }
+    pub fn debug_name(&self) -> &str {
+        &self.referencer().info.debug_name
+    }
+    pub fn strength(&self) -> RefStrength {
+        if self.is_strong() { RefStrength::Strong } else { RefStrength::Weak }
+    }
+    pub fn is_strong(&self) -> bool {
+        self.referencer().is_strong.get()
+    }
+    /// True once the item has been dropped (always false for strong refs, unless the hive itself was dropped).
+    pub fn is_dangling(&self) -> bool {
+        !self.redirector().is_alive()
+    }
+    /// Turns this ref into a strong ref, unless the item is gone.
+    /// An item which is pending removal is still present, so upgrading rescues it.
+    pub fn upgrade(&mut self) -> bool {
+        let redirector = self.redirector();
+        if self.is_strong() {
+            return true;
+        }
+        if !redirector.is_alive() {
+            return false;
+        }
+        redirector.strong_ref_count.set(redirector.strong_ref_count.get() + 1);
+        self.referencer().is_strong.set(true);
+        true
+    }
+    pub fn downgrade(&mut self) {
+        if self.is_strong() {
+            let redirector = self.redirector();
+            redirector.strong_ref_count.set(redirector.strong_ref_count.get() - 1);
+            self.referencer().is_strong.set(false);
+        }
+    }
+    /// Creates a new ref to the same item, with the same strength.
+    pub fn clone_with(&self, info: RefInfo) -> Self {
+        Self::new(Rc::clone(&self.registry), self.referencer().redirector.get(), self.strength(), info)
+    }
+    pub fn clone_weak_with(&self, info: RefInfo) -> Self {
+        Self::new(Rc::clone(&self.registry), self.referencer().redirector.get(), RefStrength::Weak, info)
+    }
+    pub fn to_weak_any(&self) -> WeakRefAny {
+        WeakRefAny::new(Rc::clone(&self.registry), self.item_guid())
+    }
+    pub fn info(&self) -> &RefInfo {
+        &self.referencer().info
+    }
+    /// Lists every ref to the same item (including this one).
+    pub fn referencers(&self) -> Vec<ReferencerInfo> {
+        referencers_of(self.redirector())
+    }
+}
@@ -0,0 +1,140 @@
+use std::{fmt, marker::PhantomData, ptr::NonNull};
+
+use super::{imp::PinArenaListPtrIter, Hive, Item, WeakOrStrongRef};
+
+/// A mutation which had to wait for the hive to be free. See `Hive::run_or_defer()`.
+pub type DeferredCommand<T> = Box<dyn FnOnce(&mut HiveMut<'_, T>)>;
+
+impl<T> Hive<T> {
+    /// Mutable access to items, only available in the "root context", i.e when the hive isn't borrowed at all.
+    /// Returns `None` otherwise, in which case `run_or_defer()` is the way to go.
+    ///
+    /// The hive stays locked meanwhile: adds go to pending adds, and deferred commands wait for the borrow to end.
+    pub fn borrow_mut(&self) -> Option<HiveMut<'_, T>> {
+        if self.is_locked() {
+            return None;
+        }
+        self.increment_lock_counter();
+        self.is_borrowed_mut.set(true);
+        Some(HiveMut { hive: self })
+    }
+    pub fn is_borrowed_mut(&self) -> bool {
+        self.is_borrowed_mut.get()
+    }
+    /// Runs `f` right away if the hive is free. Otherwise, queues it, to be run as soon as the outermost borrow ends.
+    /// Returns true if `f` was run right away.
+    ///
+    /// Commands run in order, and may themselves defer more commands (chain reactions), which run in the same flush.
+    pub fn run_or_defer(&self, f: impl FnOnce(&mut HiveMut<'_, T>) + 'static) -> bool {
+        match self.borrow_mut() {
+            Some(mut hive) => {
+                f(&mut hive);
+                true
+            },
+            None => {
+                self.deferred_commands.borrow_mut().push_back(Box::new(f));
+                false
+            },
+        }
+    }
+    pub fn deferred_command_count(&self) -> usize {
+        self.deferred_commands.borrow().len()
+    }
+    /// Called whenever the hive becomes free.
+    pub(super) fn flush_deferred_commands(&self) {
+        // Each command borrows the hive, and would flush again when done. Only the outermost flush loops.
+        if self.is_flushing_deferred_commands.replace(true) {
+            return;
+        }
+        struct FlushGuard<'a>(&'a std::cell::Cell<bool>);
+        impl Drop for FlushGuard<'_> {
+            fn drop(&mut self) {
+                self.0.set(false);
+            }
+        }
+        let _guard = FlushGuard(&self.is_flushing_deferred_commands);
+        while !self.is_locked() {
+            let Some(command) = self.deferred_commands.borrow_mut().pop_front() else { break };
+            command(&mut self.borrow_mut().unwrap());
+        }
+    }
+}
+
+/// Exclusive access to the items of a hive, obtained via `Hive::borrow_mut()` or by deferred commands.
+///
+/// Other handles to the hive can still add items (they go to pending adds, and are visited by `iter_mut()`),
+/// mark items for removal, and defer commands; but they can't lock it.
+pub struct HiveMut<'a, T> {
+    hive: &'a Hive<T>,
+}
+
+impl<T> Drop for HiveMut<'_, T> {
+    fn drop(&mut self) {
+        self.hive.is_borrowed_mut.set(false);
+        // SAFETY: balances the increment in `Hive::borrow_mut()`, and references handed out cannot outlive us.
+        unsafe { self.hive.decrement_lock_counter() };
+    }
+}
+
+impl<T> fmt::Debug for HiveMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("HiveMut").field(self.hive).finish()
+    }
+}
+
+impl<'a, T> HiveMut<'a, T> {
+    /// Not tied to the borrow of `self`, so that items can be added or marked for removal while iterating.
+    pub fn hive(&self) -> &'a Hive<T> {
+        self.hive
+    }
+    /// Iterates over the main buffer, then over pending adds, including the ones added during iteration.
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        // SAFETY: only the `Vec` itself is borrowed, and it can't be resized while the hive is locked
+        let items = unsafe { &mut *self.hive.items.get() };
+        IterMut {
+            items: items.as_mut_ptr(),
+            len: items.len(),
+            i: 0,
+            pending_adds: self.hive.pending_adds.ptr_iter(),
+            phantom: PhantomData,
+        }
+    }
+    /// Returns `None` if the item has been dropped.
+    pub fn get_mut(&mut self, r: &WeakOrStrongRef<T>) -> Option<&mut T> {
+        self.hive.check_ref(r);
+        let item = r.redirector().item.get().cast::<Item<T>>().cast_mut();
+        // SAFETY: non-null item pointers are kept up to date, items don't move while we're locked, and we have
+        // exclusive access to items.
+        unsafe { item.as_mut() }.map(|item| &mut item.value)
+    }
+}
+
+pub struct IterMut<'a, T> {
+    items: *mut Item<T>,
+    len: usize,
+    i: usize,
+    pending_adds: PinArenaListPtrIter<'a, Item<T>>,
+    phantom: PhantomData<&'a mut T>,
+}
+
+impl<T> fmt::Debug for IterMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IterMut").field("len", &self.len).field("i", &self.i).finish_non_exhaustive()
+    }
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+    fn next(&mut self) -> Option<&'a mut T> {
+        let item = if self.i < self.len {
+            // SAFETY: in bounds
+            let item = unsafe { NonNull::new_unchecked(self.items.add(self.i)) };
+            self.i += 1;
+            item
+        } else {
+            self.pending_adds.next()?
+        };
+        // SAFETY: each item is yielded once, and `HiveMut` guarantees exclusive access
+        Some(unsafe { &mut (*item.as_ptr()).value })
+    }
+}
@@ -0,0 +1,149 @@
+use std::{error::Error, fmt, rc::Rc, str::FromStr};
+
+use super::{Hive, RefInfo, RefStrength, WeakOrStrongRef};
+
+/// Encoding of a weak ref, for logs and snapshots: hive id (see `Hive::set_id()`), session id (see
+/// `Hive::session_id()`), item guid, and the generation of the item's redirector as an extra consistency check.
+///
+/// Item guids restart at 1 in every hive, so a serialized ref only resolves within the hive instance which serialized
+/// it, i.e within the same run. Refs from another run fail with `ResolveError::WrongSession`.
+///
+/// Text form is `<hive_id:session_id:#item_guid:generation>`, with the session id in hex, e.g `<7:9f3c05d1e2a4b867:#42:1>`;
+/// it is delimited so that it can be found within log lines (see `find_in()`). Binary form is `ENCODED_LEN` bytes,
+/// little-endian, in the same order.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct SerializedRef {
+    pub hive_id: u64,
+    pub session_id: u64,
+    pub item_guid: u64,
+    pub generation: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParseSerializedRefError;
+
+impl fmt::Display for ParseSerializedRefError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected a serialized ref of the form <hive_id:session_id:#item_guid:generation>")
+    }
+}
+
+impl Error for ParseSerializedRefError {}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    /// The ref was serialized from another hive.
+    WrongHive { expected: u64, found: u64 },
+    /// The ref was serialized in another run, or by another hive instance with the same id.
+    WrongSession { expected: u64, found: u64 },
+    /// The item has been dropped.
+    Dangling,
+    /// An item with this guid exists, but it isn't the one which was serialized.
+    GenerationMismatch { expected: u32, found: u32 },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongHive { expected, found } => write!(f, "ref belongs to hive {found}, not hive {expected}"),
+            Self::WrongSession { expected, found } => write!(f, "ref belongs to session {found:x}, not session {expected:x}"),
+            Self::Dangling => f.write_str("item no longer exists"),
+            Self::GenerationMismatch { expected, found } => write!(f, "item has generation {found}, but the ref has generation {expected}"),
+        }
+    }
+}
+
+impl Error for ResolveError {}
+
+impl fmt::Display for SerializedRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}:{:x}:#{}:{}>", self.hive_id, self.session_id, self.item_guid, self.generation)
+    }
+}
+
+impl FromStr for SerializedRef {
+    type Err = ParseSerializedRefError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('<').and_then(|s| s.strip_suffix('>')).ok_or(ParseSerializedRefError)?;
+        let mut parts = s.split(':');
+        let mut next = || parts.next().ok_or(ParseSerializedRefError);
+        let hive_id = next()?.parse().map_err(|_| ParseSerializedRefError)?;
+        let session_id = u64::from_str_radix(next()?, 16).map_err(|_| ParseSerializedRefError)?;
+        let item_guid = next()?.strip_prefix('#').ok_or(ParseSerializedRefError)?.parse().map_err(|_| ParseSerializedRefError)?;
+        let generation = next()?.parse().map_err(|_| ParseSerializedRefError)?;
+        if parts.next().is_some() {
+            return Err(ParseSerializedRefError);
+        }
+        Ok(Self { hive_id, session_id, item_guid, generation })
+    }
+}
+
+impl SerializedRef {
+    pub const ENCODED_LEN: usize = 8 + 8 + 8 + 4;
+
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[..8].copy_from_slice(&self.hive_id.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.session_id.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.item_guid.to_le_bytes());
+        bytes[24..].copy_from_slice(&self.generation.to_le_bytes());
+        bytes
+    }
+    pub fn from_bytes(bytes: [u8; Self::ENCODED_LEN]) -> Self {
+        Self {
+            hive_id: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            session_id: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            item_guid: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            generation: u32::from_le_bytes(bytes[24..].try_into().unwrap()),
+        }
+    }
+    /// Every serialized ref within `text`, e.g a log line, in order. Anything else is skipped.
+    pub fn find_in(text: &str) -> impl Iterator<Item = Self> + '_ {
+        text.match_indices('<').filter_map(|(start, _)| {
+            let len = text[start..].find('>')? + 1;
+            text[start..start + len].parse().ok()
+        })
+    }
+}
+
+impl<T> WeakOrStrongRef<T> {
+    /// Works even if the item has been dropped, in which case resolving it will fail.
+    pub fn serialize(&self) -> SerializedRef {
+        let redirector = self.redirector();
+        SerializedRef {
+            hive_id: self.registry().hive_id(),
+            session_id: self.registry().session_id(),
+            item_guid: redirector.item_guid,
+            generation: redirector.generation,
+        }
+    }
+}
+
+impl<T> Hive<T> {
+    pub fn serialize_ref(&self, value: &T) -> SerializedRef {
+        let redirector = self.item_of(value).redirector();
+        SerializedRef {
+            hive_id: self.id(),
+            session_id: self.session_id(),
+            item_guid: redirector.item_guid,
+            generation: redirector.generation,
+        }
+    }
+    /// Turns a serialized ref back into a live weak ref, e.g when inspecting a log line. Only refs serialized by this
+    /// very hive resolve; see `SerializedRef`.
+    pub fn resolve(&self, serialized: &SerializedRef, info: RefInfo) -> Result<WeakOrStrongRef<T>, ResolveError> {
+        if serialized.hive_id != self.id() {
+            return Err(ResolveError::WrongHive { expected: self.id(), found: serialized.hive_id });
+        }
+        if serialized.session_id != self.session_id() {
+            return Err(ResolveError::WrongSession { expected: self.session_id(), found: serialized.session_id });
+        }
+        let redirector = self.registry.redirector_by_item_guid(serialized.item_guid).ok_or(ResolveError::Dangling)?;
+        // SAFETY: redirectors in the map are alive
+        let generation = unsafe { redirector.as_ref() }.generation;
+        if generation != serialized.generation {
+            return Err(ResolveError::GenerationMismatch { expected: serialized.generation, found: generation });
+        }
+        Ok(WeakOrStrongRef::new(Rc::clone(&self.registry), redirector, RefStrength::Weak, info))
+    }
+}
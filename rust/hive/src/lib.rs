@@ -1,4 +1,4 @@
-#![feature(sync_unsafe_cell)]
+#![cfg_attr(test, feature(sync_unsafe_cell))]
 
 // Ce que je veux pouvoir faire:
 // - Ajouter pendant l'itération
@@ -27,6 +27,7 @@
 // - Un component dérivé référence sa "base" via StrongRef<BaseComponent>.
 //   Lorsque le refcount d'une Entity atteint 0, on clear sa liste de StrongRef<Component>.
 //   Vu que chaque component dérivé a une StrongRef sur sa base, les components dérivés vont être drop en premier, puis ensuite les bases.
+//   Voir ComponentRef, EntityComponents, et compact_all() pour le teardown sur plusieurs hives.
 // - Les components ne doivent jamais avoir de StrongRef sur leur propre entité
 //   Chaque entité a une liste de StrongRef de ses components, donc cela créerait un cycle.
 // - On pourrait imaginer un cas où une Entity a une WeakRef sur un de ses components au lieu d'une StrongRef.
@@ -51,9 +52,12 @@
 //       - Soit le code s'arrange pour que en général (mais pas forcément tout le temps), l'info de "owner" soit bien renseignée et permette de choper un pointeur vers la réf
 //   C'est un design pour des objets "lourds" (et encore, ça sera probablement plus rapide que UE/Unity), mais pour des particle systems, une approche classique à base de Vec<_> n'est jamais interdite...
 //
-// TODO: détection de cycles de strong refs (mais attention, un cycle peut être bénin s'il y a une Option/enum dans la chaîne)
+// Détection de cycles de strong refs : voir CycleDetector. Un cycle peut être bénin s'il y a une Option/enum dans la chaîne,
+// dans ce cas on annote la ref avec RefInfo::breakable().
 // - https://manishearth.github.io/blog/2021/04/05/a-tour-of-safe-tracing-gc-designs-in-rust/
-// TODO: sérialiser des weakrefs dans le log?
+// Sérialiser des weakrefs dans le log ou un snapshot : voir SerializedRef et Hive::resolve().
+// Les guids repartent de 1 à chaque run, donc une réf sérialisée ne se résout que dans la hive qui l'a produite (cf session_id).
+// TODO: snapshots persistants ? Il faudrait restaurer les guids au chargement, et la session avec.
 // TODO: multithreading ?
 //
 // Gestion mutable/immutable
@@ -118,137 +122,8 @@ extern crate parking_lot;
 #[cfg(test)]
 mod tests;
 
-/*
-pub mod hive {
-    use std::rc::Rc;
-    use std::cell::{Cell, RefCell};
+pub mod arena;
+pub mod context;
+pub mod hive;
 
-    mod imp {
-        use std::{cell::{Cell, RefCell, UnsafeCell}, mem::MaybeUninit, rc::{Rc, Weak}};
-        use std::pin::Pin;
-        
-        #[derive(Debug)]
-        pub struct PinArena<T> {
-            memory: Box<[UnsafeCell<MaybeUninit<T>>]>,
-            len: Cell<usize>,
-        }
-
-        impl <T> PinArena<T> {
-            pub fn with_capacity(cap: usize) -> Self {
-                assert_ne!(cap, 0);
-                Self {
-                    memory: (0..cap).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect::<Vec<_>>().into_boxed_slice(),
-                    len: Cell::new(0),
-                }
-            }
-            pub fn capacity(&self) -> usize {
-                self.memory.len()
-            }
-            pub fn is_full(&self) -> bool {
-                self.len.get() >= self.capacity()
-            }
-            pub fn try_push(&self, val: T) -> Option<Pin<&mut T>> {
-                if self.is_full() {
-                    None
-                } else {
-                    unsafe { Some(self.push_unchecked(val)) }
-                }
-            }
-            pub unsafe fn push_unchecked(&self, val: T) -> Pin<&mut T> {
-                let i = self.len.get();
-                self.len.set(i + 1);
-                Pin::new_unchecked((*self.memory[i].get()).write(val))
-            }
-        }
-
-        pub struct PinArenaListNode<T> {
-            pin_arena: PinArena<T>,
-            next: RefCell<Option<Rc<PinArenaListNode<T>>>>,
-            prev: RefCell<Option<Weak<PinArenaListNode<T>>>>,
-        }
-
-        impl<T> PinArenaListNode<T> {
-            pub fn with_capacity(cap: usize) -> Self {
-                Self {
-                    pin_arena: PinArena::with_capacity(cap),
-                    next: RefCell::new(None),
-                    prev: RefCell::new(None),
-                }
-            }
-        }
-
-        pub struct PinArenaList<T> {
-            head: Rc<PinArenaListNode<T>>,
-            tail: RefCell<Rc<PinArenaListNode<T>>>,
-        }
-
-        impl<T> PinArenaList<T> {
-            pub fn with_capacity(cap: usize) -> Self {
-                assert_ne!(cap, 0);
-                let tail = Rc::new(PinArenaListNode::with_capacity(cap));
-                Self {
-                    head: Rc::clone(&tail),
-                    tail: RefCell::new(tail),
-                }
-            }
-
-            pub fn push(&self, val: T) {
-                if self.tail.borrow().pin_arena.is_full() {
-                    let new_tail = Rc::new(PinArenaListNode {
-                        pin_arena: PinArena::with_capacity(64),
-                        prev: RefCell::new(Some(Rc::downgrade(&self.tail.borrow()))),
-                        next: RefCell::new(None),
-                    });
-                    let mut tail = self.tail.borrow_mut();
-                    *tail.next.borrow_mut() = Some(Rc::clone(&new_tail));
-                    *tail = new_tail;
-                }
-
-                unsafe { self.tail.borrow().pin_arena.push_unchecked(val); }
-            }
-        }
-
-        pub struct PinnedHandlePayload<T> {
-            item_ptr: *const T,
-            generation: std::num::Wrapping<usize>,
-        }
-
-        pub struct Handle<T> {
-            payload: *const PinnedHandlePayload<T>,
-            generation: std::num::Wrapping<usize>,
-        }
-    }
-
-    #[derive(Debug, Clone)]
-    pub struct Hive<T> {
-        items: Vec<T>,
-        nodes: RefCell<Vec<Rc<imp::PinArena<T>>>>,
-        lock_counter: Cell<usize>,
-    }
-
-    impl<T> Hive<T> {
-        pub fn add(&self, item: T) {
-            /*
-            if !self.is_locked() {
-                self.items.push(item);
-            } else {
-                let mut vv = self.nodes.borrow_mut();
-                if vv.is_empty() || vv.last().unwrap().is_full() {
-                    vv.push(Rc::new(imp::PinArena::with_capacity(64)));
-                }
-                unsafe { vv.last_mut().unwrap().push_unchecked(item); }
-            }
-             */
-        }
-        pub fn is_locked(&self) -> bool {
-            self.lock_counter.get() > 0
-        }
-        pub fn increment_lock_counter(&self) {
-            self.lock_counter.set(self.lock_counter.get() + 1);
-        }
-        pub fn decrement_lock_counter(&self) {
-            self.lock_counter.set(self.lock_counter.get() - 1);
-        }
-    }
-}
-*/
\ No newline at end of file
+pub use crate::hive::{compact_all, AnyHive, ComponentRef, CycleDetector, DefragStats, EntityComponents, Hive, HiveLock, OrderingPolicy, ParseSerializedRefError, RefCycle, RefInfo, RefStrength, ReferencerInfo, RefVisitor, ResolveError, SerializedRef, VisitRefs, WeakOrStrongRef, WeakRefAny};
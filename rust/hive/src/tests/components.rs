@@ -0,0 +1,143 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{compact_all, ComponentRef, EntityComponents, Hive, RefInfo, RefStrength, WeakOrStrongRef};
+
+type DropLog = Rc<RefCell<Vec<&'static str>>>;
+
+struct Entity {
+    components: EntityComponents,
+    log: DropLog,
+}
+
+impl Drop for Entity {
+    fn drop(&mut self) {
+        self.log.borrow_mut().push("entity");
+    }
+}
+
+struct Transform {
+    log: DropLog,
+}
+
+impl Drop for Transform {
+    fn drop(&mut self) {
+        self.log.borrow_mut().push("transform");
+    }
+}
+
+/// Derived from `Transform`.
+struct Collider {
+    base: WeakOrStrongRef<Transform>,
+    log: DropLog,
+}
+
+impl Drop for Collider {
+    fn drop(&mut self) {
+        assert!(!self.base.is_dangling(), "Bases must outlive derived components");
+        self.log.borrow_mut().push("collider");
+    }
+}
+
+#[test]
+fn test_derived_components_drop_before_bases() {
+    let log = DropLog::default();
+    let entities = Hive::new();
+    let transforms = Hive::new();
+    let colliders = Hive::new();
+    let entity = entities.add_and_make_ref(Entity { components: EntityComponents::new(), log: Rc::clone(&log) }, RefStrength::Weak, RefInfo::new("spawner"));
+    let owner = entity.to_weak_any();
+    let transform = transforms.add_and_take_container_ref(Transform { log: Rc::clone(&log) }, RefInfo::new("entity -> transform").with_owner(owner.clone()));
+    let base = transform.clone_with(RefInfo::new("collider -> transform"));
+    let collider = colliders.add_and_take_container_ref(Collider { base, log: Rc::clone(&log) }, RefInfo::new("entity -> collider").with_owner(owner));
+    let component_ref = ComponentRef::new(entity.clone_weak_with(RefInfo::new("collider ref")), collider.clone_weak_with(RefInfo::new("collider ref")));
+    {
+        let mut entities = entities.borrow_mut().unwrap();
+        let components = &mut entities.get_mut(&entity).unwrap().components;
+        components.add(transform);
+        components.add(collider);
+        assert_eq!(components.len(), 2);
+        assert!(components.get::<Collider>().unwrap().is_strong());
+    }
+    assert!(!component_ref.is_dangling());
+    assert!(component_ref.get(&entities.lock(), &colliders.lock()).is_some());
+
+    // Components live for as long as their entity does
+    assert!(compact_all(&[&entities, &transforms, &colliders]));
+    assert!(log.borrow().is_empty());
+
+    {
+        let entities = entities.lock();
+        entities.mark_for_removal(entities.get(&entity).unwrap());
+    }
+    // Bases are compacted first on purpose: the collider's strong ref keeps the transform alive until the next round
+    assert!(compact_all(&[&entities, &transforms, &colliders]));
+    assert_eq!(*log.borrow(), ["entity", "collider", "transform"]);
+    assert!(entities.is_empty() && transforms.is_empty() && colliders.is_empty());
+    assert!(component_ref.is_dangling());
+}
+
+struct Script {
+    entity: WeakOrStrongRef<Entity>,
+    log: DropLog,
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        assert!(self.entity.is_strong());
+        self.log.borrow_mut().push("script");
+    }
+}
+
+#[test]
+fn test_component_keeps_its_entity_alive() {
+    let log = DropLog::default();
+    let entities = Hive::new();
+    let scripts = Hive::new();
+    let entity = entities.add_and_take_container_ref(Entity { components: EntityComponents::new(), log: Rc::clone(&log) }, RefInfo::new("spawner"));
+    let script = scripts.add_and_make_ref(Script { entity: entity.clone_with(RefInfo::new("script -> entity")), log: Rc::clone(&log) }, RefStrength::Weak, RefInfo::new("entity -> script"));
+    let script_ref = ComponentRef::new(entity, script);
+    entities.borrow_mut().unwrap().get_mut(&script_ref.entity).unwrap().components.add(script_ref.component.clone_with(RefInfo::new("entity -> script")));
+    let mut weak_script_ref = script_ref.clone_with(RefInfo::new("script ref"), RefInfo::new("script ref"));
+    weak_script_ref.entity.downgrade();
+    assert!(!weak_script_ref.entity.is_strong() && !weak_script_ref.component.is_strong());
+    drop(script_ref); // The spawner lets go of the entity
+    let script_ref = weak_script_ref;
+
+    // The entity only holds a weak ref to its script, and can't go away before it
+    assert!(compact_all(&[&entities, &scripts]));
+    assert!(log.borrow().is_empty());
+    assert!(!script_ref.is_dangling());
+
+    {
+        let scripts = scripts.lock();
+        scripts.mark_for_removal(scripts.get(&script_ref.component).unwrap());
+    }
+    assert!(compact_all(&[&entities, &scripts]));
+    assert_eq!(*log.borrow(), ["script", "entity"]);
+    assert!(script_ref.is_dangling());
+}
+
+#[test]
+fn test_remove_component_from_entity() {
+    let transforms = Hive::new();
+    let log = DropLog::default();
+    let mut components = EntityComponents::new();
+    let a = transforms.add_and_take_container_ref(Transform { log: Rc::clone(&log) }, RefInfo::new("a"));
+    let b = transforms.add_and_take_container_ref(Transform { log: Rc::clone(&log) }, RefInfo::new("b"));
+    let (a_any, b_any) = (a.to_weak_any(), b.to_weak_any());
+    components.add(a);
+    components.add(b);
+    assert!(components.remove(&a_any));
+    assert!(!components.remove(&a_any));
+    assert!(!components.contains(&a_any) && components.contains(&b_any));
+    assert!(compact_all(&[&transforms]));
+    assert_eq!(log.borrow().len(), 1);
+    assert_eq!(transforms.len(), 1);
+    {
+        let _lock = transforms.lock();
+        assert!(!compact_all(&[&transforms]), "Locked hives can't be compacted");
+    }
+    drop(components);
+    assert!(compact_all(&[&transforms]));
+    assert!(transforms.is_empty());
+}
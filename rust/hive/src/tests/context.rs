@@ -0,0 +1,119 @@
+use std::{cell::RefCell, rc::Rc};
+use crate::{context::{self, Context, HasContext, WeakContext}, Hive, RefInfo, RefStrength};
+
+trait Log {
+    fn log(&self, message: &str);
+}
+
+#[derive(Default)]
+struct MemoryLog {
+    lines: RefCell<Vec<String>>,
+}
+
+impl Log for MemoryLog {
+    fn log(&self, message: &str) {
+        self.lines.borrow_mut().push(message.to_owned());
+    }
+}
+
+/// Prefixes messages, then defers to the parent's provider.
+struct PrefixLog {
+    prefix: &'static str,
+    context: WeakContext,
+}
+
+impl Log for PrefixLog {
+    fn log(&self, message: &str) {
+        let context = self.context.upgrade().unwrap();
+        context.find_in_parent::<dyn Log>().unwrap().log(&format!("[{}] {message}", self.prefix));
+    }
+}
+
+#[test]
+fn test_context_tree() {
+    let process = Context::new_root("process");
+    let game = process.new_child("game");
+    let world = game.new_child("world");
+    let player = game.new_child("local_player");
+    assert_eq!(world.path(), "process/game/world");
+    assert_eq!(world.root(), &process);
+    assert!(player.is_descendant_of(&game));
+    assert!(!player.is_descendant_of(&world));
+    assert_eq!(world.ancestors().map(Context::name).collect::<Vec<_>>(), ["world", "game", "process"]);
+
+    // Children keep their parent alive
+    let weak_game = game.downgrade();
+    drop(game);
+    assert_eq!(weak_game.upgrade().unwrap().name(), "game");
+    drop((world, player));
+    assert!(weak_game.upgrade().is_none());
+}
+
+#[test]
+fn test_service_lookup() {
+    let process = Context::new_root("process");
+    let game = process.new_child("game");
+    let world = game.new_child("world");
+    let process_log = Rc::new(MemoryLog::default());
+    process.provide::<dyn Log>(process_log.clone());
+    process.provide(Rc::new(42_u32));
+
+    assert!(world.get_local::<dyn Log>().is_none());
+    world.find::<dyn Log>().unwrap().log("hello");
+    assert_eq!(*world.find::<u32>().unwrap(), 42);
+    assert!(world.find::<String>().is_none());
+
+    // The world's provider shadows the process', and defers to it
+    world.provide::<dyn Log>(Rc::new(PrefixLog { prefix: "world", context: world.downgrade() }));
+    world.find::<dyn Log>().unwrap().log("hello again");
+    game.find::<dyn Log>().unwrap().log("from the game");
+    assert_eq!(*process_log.lines.borrow(), ["hello", "[world] hello again", "from the game"]);
+
+    assert!(world.remove::<dyn Log>().is_some());
+    assert!(Rc::ptr_eq(&world.find::<dyn Log>().unwrap(), &(process_log.clone() as Rc<dyn Log>)));
+}
+
+#[test]
+fn test_sandboxed_game_instances() {
+    let process = Context::new_root("process");
+    let (server, client) = (process.new_child("server"), process.new_child("client"));
+    let server_log = Rc::new(MemoryLog::default());
+    let client_log = Rc::new(MemoryLog::default());
+    server.provide::<dyn Log>(server_log.clone());
+    client.provide::<dyn Log>(client_log.clone());
+    server.new_child("world").find::<dyn Log>().unwrap().log("server tick");
+    client.new_child("world").find::<dyn Log>().unwrap().log("client tick");
+    assert_eq!(*server_log.lines.borrow(), ["server tick"]);
+    assert_eq!(*client_log.lines.borrow(), ["client tick"]);
+    assert!(process.find::<dyn Log>().is_none());
+}
+
+struct Entity {
+    name: &'static str,
+    context: WeakContext, // Would be a special component in practice
+}
+
+impl HasContext for Entity {
+    fn context(&self) -> Option<Context> {
+        self.context.upgrade()
+    }
+}
+
+#[test]
+fn test_entity_to_context() {
+    let game = Context::new_root("game");
+    let world = game.new_child("world");
+    let log = Rc::new(MemoryLog::default());
+    game.provide::<dyn Log>(log.clone());
+    let entities = Hive::new();
+    let player = entities.add_and_make_ref(Entity { name: "player", context: world.downgrade() }, RefStrength::Weak, RefInfo::new("test"));
+
+    let lock = entities.lock();
+    let entity = lock.get(&player).unwrap();
+    context::find::<dyn Log>(entity).unwrap().log(&format!("{} spawned", entity.name));
+    assert_eq!(entity.context().unwrap(), world);
+    assert_eq!(*log.lines.borrow(), ["player spawned"]);
+
+    drop(world);
+    assert!(context::find::<dyn Log>(entity).is_none(), "The entity's context is gone");
+}
@@ -0,0 +1,67 @@
+use crate::{CycleDetector, Hive, RefInfo, RefStrength};
+
+#[test]
+fn test_entity_component_cycle() {
+    let entities = Hive::new();
+    let components = Hive::new();
+    let entity = entities.add_and_make_ref("player", RefStrength::Weak, RefInfo::new("spawner"));
+    let component = components.add_and_make_ref("transform", RefStrength::Weak, RefInfo::new("spawner"));
+    let _entity_to_component = component.clone_with(RefInfo::new("player -> transform").with_owner(entity.to_weak_any()));
+    let mut component_to_entity = entity.clone_with(RefInfo::new("transform -> player").with_owner(component.to_weak_any()));
+
+    let mut detector = CycleDetector::new();
+    detector.add_hive(&entities).add_hive(&components);
+    assert!(detector.find_cycles().is_empty(), "Weak refs can't form cycles");
+
+    let mut entity_to_component = component.clone_with(RefInfo::new("player -> transform (strong)").with_owner(entity.to_weak_any()));
+    assert!(entity_to_component.upgrade());
+    assert!(component_to_entity.upgrade());
+    let cycles = detector.find_cycles();
+    assert_eq!(cycles.len(), 1);
+    let mut names = cycles[0].debug_names();
+    names.sort();
+    assert_eq!(names, ["player -> transform (strong)", "transform -> player"]);
+    let cycle = &cycles[0];
+    assert_eq!(cycle.links[0].target, cycle.links[1].owner);
+    assert_eq!(cycle.links[1].target, cycle.links[0].owner);
+    assert!(cycle.to_string().contains("--\"transform -> player\"-->"));
+
+    // Only seeing one of the hives, we can't tell
+    let mut partial_detector = CycleDetector::new();
+    partial_detector.add_hive(&components);
+    assert!(partial_detector.find_cycles().is_empty());
+
+    // Annotated as intentionally breakable: not reported
+    let mut breakable = entity.clone_with(RefInfo::new("transform -> player").with_owner(component.to_weak_any()).breakable());
+    assert!(breakable.upgrade());
+    drop(component_to_entity);
+    assert!(detector.find_cycles().is_empty());
+    assert_eq!(breakable.referencers().iter().find(|r| r.is_breakable).unwrap().to_string().split(" at ").next().unwrap(), "\"transform -> player\" (strong, breakable)");
+}
+
+#[test]
+fn test_longer_cycles_and_self_loops() {
+    let hive = Hive::new();
+    let refs = (0..5).map(|i| hive.add_and_make_ref(i, RefStrength::Weak, RefInfo::new("spawner"))).collect::<Vec<_>>();
+    // 0 -> 1 -> 2 -> 0, with 3 hanging off the cycle, and 4 referencing itself
+    let edges = [(0, 1), (1, 2), (2, 0), (2, 3), (4, 4)];
+    let _strong_refs = edges.map(|(from, to)| {
+        let mut r = refs[to].clone_with(RefInfo::new(format!("{from} -> {to}")).with_owner(refs[from].to_weak_any()));
+        assert!(r.upgrade());
+        r
+    });
+
+    let mut detector = CycleDetector::new();
+    detector.add_hive(&hive);
+    let mut cycles = detector.find_cycles();
+    cycles.sort_by_key(|cycle| cycle.links.len());
+    assert_eq!(cycles.len(), 2);
+    assert_eq!(cycles[0].debug_names(), ["4 -> 4"]);
+    let names = cycles[1].debug_names();
+    assert_eq!(names.len(), 3);
+    assert!(["0 -> 1", "1 -> 2", "2 -> 0"].iter().all(|name| names.contains(name)));
+    // Links are chained
+    for (link, next) in cycles[1].links.iter().zip(cycles[1].links.iter().cycle().skip(1)) {
+        assert_eq!(link.target, next.owner);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{Hive, RefInfo, RefStrength, RefVisitor, VisitRefs, WeakOrStrongRef};
+
+#[test]
+fn test_defragment_redirectors() {
+    let mut hive = Hive::new();
+    let refs = (0..2000).map(|i| hive.add_and_make_ref(i, RefStrength::Weak, RefInfo::new("ref"))).collect::<Vec<_>>();
+    {
+        let lock = hive.lock();
+        for item in lock.iter() {
+            if item % 10 != 0 {
+                lock.mark_for_removal(item);
+            }
+        }
+    }
+    assert!(hive.compact());
+    let guids_before = refs.iter().map(WeakOrStrongRef::item_guid).collect::<Vec<_>>();
+    // Dangling refs keep their redirectors alive, so drop most of them to actually make room
+    let (refs, dangling): (Vec<_>, Vec<_>) = refs.into_iter().partition(|r| !r.is_dangling());
+    let dangling = dangling.into_iter().last().unwrap();
+    let any = {
+        let lock = hive.lock();
+        lock.weak_ref_any(lock.get(&refs[5]).unwrap())
+    };
+
+    let stats = hive.defragment();
+    assert!(stats.redirectors_moved > 0);
+    assert!(stats.chunks_freed > 0);
+    assert!(stats.bytes_freed > 0);
+
+    let lock = hive.lock();
+    assert_eq!(lock.len(), 200);
+    for (i, r) in refs.iter().enumerate() {
+        assert_eq!(lock.get(r), Some(&(i * 10)));
+        assert_eq!(r.item_guid(), guids_before[i * 10]);
+    }
+    assert!(!any.is_dangling());
+    assert_eq!(any.referencers().len(), 1);
+    assert!(dangling.is_dangling());
+    assert_eq!(dangling.item_guid(), guids_before[1999]);
+    assert_eq!(lock.get(&dangling), None);
+}
+
+#[test]
+fn test_defragment_pending_adds() {
+    let mut hive = Hive::with_chunk_capacity(4);
+    {
+        let lock = hive.lock();
+        for i in 0..20 {
+            lock.add(i);
+        }
+    }
+    assert_eq!(hive.pending_add_count(), 20);
+    let stats = hive.defragment();
+    assert_eq!(stats.items_moved, 20);
+    assert!(stats.items_reallocated == 0 || stats.items_reallocated == 20);
+    assert_eq!(stats.chunks_freed, 4);
+    assert_eq!(hive.pending_add_count(), 0);
+    assert!(hive.lock().iter().copied().eq(0..20));
+}
+
+#[test]
+fn test_defragment_pending_adds_pending_removal() {
+    let mut hive = Hive::with_chunk_capacity(4);
+    hive.add(-1);
+    {
+        let lock = hive.lock();
+        for i in 0..20 {
+            lock.add(i);
+        }
+        for item in lock.iter() {
+            if item % 4 == 0 {
+                lock.mark_for_removal(item);
+            }
+        }
+    }
+    assert_eq!(hive.pending_add_count(), 20);
+    let stats = hive.defragment();
+    // Dropped pending adds are not moved, and the main buffer's item doesn't count either
+    assert_eq!(stats.items_moved, 15);
+    assert_eq!(stats.chunks_freed, 4);
+    assert!(hive.lock().iter().copied().eq(std::iter::once(-1).chain((0..20).filter(|i| i % 4 != 0))));
+}
+
+struct Node {
+    value: u32,
+    target: Option<WeakOrStrongRef<Node>>,
+}
+
+impl VisitRefs for Node {
+    fn visit_refs(&mut self, visitor: &mut RefVisitor) {
+        self.target.visit_refs(visitor);
+    }
+}
+
+#[test]
+fn test_defragment_referencers() {
+    let mut hive = Hive::new();
+    let target = hive.add_and_make_ref(Node { value: 42, target: None }, RefStrength::Weak, RefInfo::new("target"));
+    // Lots of short-lived refs, interleaved with long-lived ones: referencer chunks end up sparse
+    let mut short_lived = vec![];
+    for i in 0..2000 {
+        let r = target.clone_with(RefInfo::new("node -> target"));
+        if i % 10 == 0 {
+            hive.add(Node { value: i, target: Some(r) });
+        } else {
+            short_lived.push(r);
+        }
+    }
+    drop(short_lived);
+    assert_eq!(target.referencers().len(), 201);
+
+    let stats = hive.defragment_with_referencers();
+    assert!(stats.referencers_moved > 0);
+    assert!(stats.chunks_freed > 0);
+
+    // The list of referencers was patched, and refs still work
+    assert_eq!(target.referencers().len(), 201);
+    {
+        let lock = hive.lock();
+        for node in lock.iter().filter(|node| node.target.is_some()) {
+            assert_eq!(lock.get(node.target.as_ref().unwrap()).unwrap().value, 42);
+        }
+    }
+    // Unlinking relocated referencers must keep the list consistent
+    for node in hive.iter_mut().step_by(2) {
+        node.target = None;
+    }
+    assert_eq!(target.referencers().len(), 101);
+    assert!(target.referencers().iter().all(|info| info.debug_name == "target" || info.debug_name == "node -> target"));
+}
+
+#[test]
+fn test_ref_visitor_outside_hive() {
+    let hive = Hive::new();
+    let target = hive.add_and_make_ref("target", RefStrength::Weak, RefInfo::new("target"));
+    let refs = (0..1000).map(|_| target.clone_with(RefInfo::new("ref"))).collect::<Vec<_>>();
+    let mut kept = refs.into_iter().step_by(100).collect::<Vec<_>>();
+    let mut visitor = RefVisitor::new();
+    visitor.visit_all(&mut kept);
+    let stats = visitor.finish();
+    assert!(stats.referencers_moved > 0);
+    assert!(stats.chunks_freed > 0);
+    let lock = hive.lock();
+    assert!(kept.iter().all(|r| lock.get(r) == Some(&"target")));
+    assert_eq!(target.referencers().len(), 11);
+}
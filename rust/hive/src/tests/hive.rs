@@ -0,0 +1,98 @@
+use std::{cell::Cell, rc::Rc};
+
+use crate::{Hive, OrderingPolicy};
+
+struct DropCounter(Rc<Cell<usize>>);
+
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
+
+#[test]
+fn test_add_while_iterating() {
+    let hive = Hive::with_chunk_capacity(2);
+    hive.add(0);
+    hive.add(1);
+    {
+        let lock = hive.lock();
+        let first = lock.iter().next().unwrap();
+        let first_ptr: *const i32 = first;
+        let mut seen = vec![];
+        for &i in lock.iter() {
+            seen.push(i);
+            if i < 4 {
+                lock.add(i + 2); // Spills over several pending chunks
+            }
+        }
+        assert_eq!(seen, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(first_ptr, first as *const i32, "Adding while locked must not move live items");
+        assert!(!lock.is_pending_add(first));
+        assert!(lock.iter().skip(2).all(|i| lock.is_pending_add(i)));
+        assert!(!hive.compact(), "Cannot compact while locked");
+    }
+    assert_eq!(hive.main_buffer_len(), 2);
+    assert_eq!(hive.pending_add_count(), 4);
+    assert!(hive.compact());
+    assert_eq!(hive.main_buffer_len(), 6);
+    assert_eq!(hive.pending_add_count(), 0);
+    assert!(hive.lock().iter().copied().eq(0..6));
+}
+
+#[test]
+fn test_remove_while_iterating() {
+    let drop_count = Rc::new(Cell::new(0));
+    let hive = Hive::new();
+    for _ in 0..4 {
+        hive.add(DropCounter(Rc::clone(&drop_count)));
+    }
+    {
+        let lock = hive.lock();
+        for (i, item) in lock.iter().enumerate() {
+            if i % 2 == 0 {
+                lock.mark_for_removal(item);
+            }
+        }
+        lock.add(DropCounter(Rc::clone(&drop_count)));
+        let pending_add = lock.iter().last().unwrap();
+        lock.mark_for_removal(pending_add);
+        assert!(lock.is_pending_add(pending_add) && lock.is_pending_removal(pending_add));
+        assert_eq!(lock.iter().count(), 5, "Items pending removal are still visible");
+        assert_eq!(lock.iter().filter(|x| lock.is_pending_removal(x)).count(), 3);
+    }
+    assert_eq!(drop_count.get(), 0);
+    assert!(hive.compact());
+    assert_eq!(drop_count.get(), 3);
+    assert_eq!(hive.len(), 2);
+    drop(hive);
+    assert_eq!(drop_count.get(), 5);
+}
+
+fn compact_after_removing_evens(hive: Hive<i32>) -> Vec<i32> {
+    for i in 0..8 {
+        hive.add(i);
+    }
+    {
+        let lock = hive.lock();
+        for i in lock.iter() {
+            if i % 2 == 0 {
+                lock.mark_for_removal(i);
+            }
+        }
+        lock.add(-1);
+        lock.add(9);
+    }
+    assert!(hive.compact());
+    let values = hive.lock().iter().copied().collect();
+    values
+}
+
+#[test]
+fn test_ordering_policies() {
+    assert_eq!(compact_after_removing_evens(Hive::with_ordering_policy(OrderingPolicy::Chronological)), [1, 3, 5, 7, -1, 9]);
+    assert_eq!(compact_after_removing_evens(Hive::with_ordering_policy(OrderingPolicy::predicate(|a: &i32, b: &i32| b.cmp(a)))), [9, 7, 5, 3, 1, -1]);
+    let mut unordered = compact_after_removing_evens(Hive::with_ordering_policy(OrderingPolicy::Unordered));
+    unordered.sort();
+    assert_eq!(unordered, [-1, 1, 3, 5, 7, 9]);
+}
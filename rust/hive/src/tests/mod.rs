@@ -1,2 +1,12 @@
 mod arena;
-mod idiomatic_ecs;
\ No newline at end of file
+mod components;
+mod context;
+mod cycles;
+mod defrag;
+mod hive;
+#[allow(clippy::upper_case_acronyms, clippy::type_complexity)]
+mod idiomatic_ecs;
+mod par;
+mod refs;
+mod scheduler;
+mod serialize;
\ No newline at end of file
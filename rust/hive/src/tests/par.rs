@@ -0,0 +1,62 @@
+use rayon::prelude::*;
+use crate::Hive;
+
+#[test]
+fn test_par_iter() {
+    let hive = Hive::new();
+    for i in 0..100_000_u64 {
+        hive.add(i);
+    }
+    let lock = hive.lock();
+    for i in 100_000..100_010 {
+        lock.add(i); // Pending adds are visited too
+    }
+    assert_eq!(lock.par_iter().len(), 100_010);
+    assert_eq!(lock.par_iter().sum::<u64>(), (0..100_010).sum());
+    assert_eq!(lock.par_iter().copied().collect::<Vec<_>>(), lock.iter().copied().collect::<Vec<_>>());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position([f32; 3]);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Velocity([f32; 3]);
+
+#[test]
+fn test_par_physics_over_two_hives() {
+    // Structure of arrays: items of both hives are added in lockstep, so they're aligned in chronological order
+    let positions = Hive::new();
+    let velocities = Hive::new();
+    for i in 0..10_000 {
+        positions.add(Position([0.; 3]));
+        velocities.add(Velocity([i as f32, 1., -1.]));
+    }
+
+    let mut positions_mut = positions.borrow_mut().unwrap();
+    let velocities = velocities.lock();
+    let dt = 0.5;
+    positions_mut.par_iter_mut().zip(velocities.par_iter()).with_min_len(256).for_each(|(p, v)| {
+        for axis in 0..3 {
+            p.0[axis] += v.0[axis] * dt;
+        }
+    });
+    drop(positions_mut);
+
+    let positions = positions.lock();
+    assert!(positions.iter().zip(velocities.iter()).all(|(p, v)| p.0 == v.0.map(|x| x * dt)));
+}
+
+#[test]
+fn test_par_iter_mut_visits_each_item_once() {
+    let hive = Hive::new();
+    for _ in 0..50_000 {
+        hive.add(0_u32);
+    }
+    {
+        let mut hive = hive.borrow_mut().unwrap();
+        hive.hive().add(0); // Pending add
+        hive.par_iter_mut().for_each(|x| *x += 1);
+    }
+    assert!(hive.lock().iter().all(|&x| x == 1));
+    assert_eq!(hive.len(), 50_001);
+}
@@ -0,0 +1,125 @@
+use crate::{Hive, OrderingPolicy, RefInfo, RefStrength};
+
+#[test]
+fn test_weak_ref_follows_item_and_dangles() {
+    let hive = Hive::with_ordering_policy(OrderingPolicy::Unordered);
+    let first = hive.add_and_make_ref(0, RefStrength::Weak, RefInfo::new("first"));
+    let last = hive.add_and_make_ref(1, RefStrength::Weak, RefInfo::new("last"));
+    for i in 2..100 {
+        hive.add(i); // Reallocates the main buffer a few times
+    }
+    {
+        let lock = hive.lock();
+        assert_eq!(lock.get(&first), Some(&0));
+        lock.mark_for_removal(lock.get(&first).unwrap());
+        assert!(lock.is_pending_removal(lock.get(&first).unwrap()));
+    }
+    assert!(hive.compact()); // swap_remove() moves the last item into the first slot...
+    let lock = hive.lock();
+    assert!(first.is_dangling());
+    assert_eq!(lock.get(&first), None);
+    assert_eq!(lock.get(&last), Some(&1)); // ...but refs still find their item
+}
+
+#[test]
+fn test_strong_refs_keep_items_alive() {
+    let hive = Hive::new();
+    hive.add("entity");
+    let mut weak = {
+        let lock = hive.lock();
+        let item = lock.iter().next().unwrap();
+        let strong = lock.make_ref(item, RefStrength::Strong, RefInfo::new("strong"));
+        lock.mark_for_removal(item);
+        assert!(!lock.is_owned_by_container(item));
+        assert!(!lock.is_pending_removal(item), "Still held by a strong ref");
+        let weak = strong.clone_weak_with(RefInfo::new("weak"));
+        drop(strong);
+        assert!(lock.is_pending_removal(item));
+        weak
+    };
+    assert!(weak.upgrade(), "Items pending removal can be rescued");
+    assert!(hive.compact());
+    assert_eq!(hive.len(), 1);
+    weak.downgrade();
+    assert!(hive.compact());
+    assert!(hive.is_empty() && weak.is_dangling());
+    assert!(!weak.upgrade());
+
+    let stolen = hive.add_and_make_ref("stolen", RefStrength::Weak, RefInfo::new("weak"));
+    let stolen = {
+        let lock = hive.lock();
+        let stolen = lock.take_container_ref(lock.get(&stolen).unwrap(), RefInfo::new("thief")).unwrap();
+        assert!(lock.take_container_ref(lock.get(&stolen).unwrap(), RefInfo::new("thief")).is_none());
+        stolen
+    };
+    assert!(hive.compact());
+    assert_eq!(hive.len(), 1);
+    drop(stolen);
+    assert!(hive.compact());
+    assert!(hive.is_empty());
+}
+
+#[test]
+fn test_list_referencers() {
+    let entities = Hive::new();
+    let components = Hive::new();
+    let entity = entities.add_and_make_ref("player", RefStrength::Weak, RefInfo::new("spawner"));
+    let component = components.add_and_make_ref("transform", RefStrength::Weak, RefInfo::new("spawner").without_location());
+    let owner = entity.to_weak_any();
+    let by_entity = component.clone_with(RefInfo::new(format!("{}'s transform", entities.lock().get(&entity).unwrap())).with_owner(owner.clone()));
+    let mut strong = by_entity.clone_with(RefInfo::new("renderer"));
+    assert!(strong.upgrade());
+
+    let referencers = component.referencers();
+    assert_eq!(referencers.len(), 3);
+    assert_eq!(referencers.iter().filter(|r| r.is_strong).count(), 1);
+    let by_entity_info = referencers.iter().find(|r| r.debug_name == "player's transform").unwrap();
+    assert_eq!(by_entity_info.owner.as_ref(), Some(&owner));
+    assert_eq!(by_entity_info.location.unwrap().file(), file!());
+    assert_eq!(referencers.iter().find(|r| r.debug_name == "spawner").unwrap().to_string(), "\"spawner\" (weak)");
+
+    drop(by_entity);
+    let lock = components.lock();
+    assert_eq!(lock.referencers(lock.get(&component).unwrap()).len(), 2);
+    assert!(owner.referencers().iter().all(|r| r.debug_name == "spawner"));
+}
+
+#[test]
+fn test_refs_survive_a_panicking_predicate() {
+    let panics = std::rc::Rc::new(std::cell::Cell::new(false));
+    let hive = Hive::with_ordering_policy(OrderingPolicy::predicate({
+        let panics = panics.clone();
+        move |a: &i32, b: &i32| {
+            assert!(!panics.get(), "predicate panicked");
+            a.cmp(b)
+        }
+    }));
+    let refs = (0..10).rev().map(|i| hive.add_and_make_ref(i, RefStrength::Weak, RefInfo::new("ref"))).collect::<Vec<_>>();
+    assert!(hive.compact());
+    {
+        let lock = hive.lock();
+        for i in 10..20 {
+            lock.add(i);
+        }
+        for item in lock.iter() {
+            if item % 3 == 0 {
+                lock.mark_for_removal(item);
+            }
+        }
+    }
+    panics.set(true);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| hive.compact()));
+    assert!(result.is_err());
+    panics.set(false);
+
+    // Removals were applied, and the other items are still reachable, in some order
+    let lock = hive.lock();
+    assert_eq!(lock.len(), 13);
+    for (i, r) in (0..10).rev().zip(&refs) {
+        assert_eq!(r.is_dangling(), i % 3 == 0);
+        assert_eq!(lock.get(r), if i % 3 == 0 { None } else { Some(&i) });
+    }
+    drop(lock);
+    assert!(hive.compact());
+    assert!(hive.lock().iter().copied().eq((1..20).filter(|i| i % 3 != 0)));
+}
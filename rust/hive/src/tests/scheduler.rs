@@ -0,0 +1,129 @@
+use std::{cell::RefCell, rc::Rc};
+use crate::{Hive, RefInfo, RefStrength, WeakOrStrongRef};
+
+#[test]
+fn test_root_context_runs_immediately() {
+    let hive = Hive::new();
+    hive.add(1);
+    hive.add(2);
+    {
+        let mut hive = hive.borrow_mut().unwrap();
+        hive.iter_mut().for_each(|x| *x *= 10);
+    }
+    assert!(hive.run_or_defer(|hive| hive.iter_mut().for_each(|x| *x += 1)));
+    assert_eq!(hive.deferred_command_count(), 0);
+    assert!(hive.lock().iter().copied().eq([11, 21]));
+}
+
+#[test]
+fn test_nested_borrow_defers() {
+    let hive = Hive::new();
+    hive.add(1);
+    {
+        let lock = hive.lock();
+        assert!(hive.borrow_mut().is_none());
+        let _nested = hive.lock();
+        assert!(!hive.run_or_defer(|hive| hive.iter_mut().for_each(|x| *x += 1)));
+        assert_eq!(lock.iter().copied().collect::<Vec<_>>(), [1], "Not run yet");
+        assert_eq!(hive.deferred_command_count(), 1);
+    }
+    // Flushed when the outermost borrow ended
+    assert_eq!(hive.deferred_command_count(), 0);
+    assert!(hive.lock().iter().copied().eq([2]));
+
+    let mut borrowed = hive.borrow_mut().unwrap();
+    assert!(!hive.run_or_defer(|hive| hive.iter_mut().for_each(|x| *x *= 3)));
+    for x in borrowed.iter_mut() {
+        *x += 1;
+    }
+    drop(borrowed);
+    assert!(hive.lock().iter().copied().eq([9]));
+}
+
+#[test]
+#[should_panic(expected = "already mutably borrowed")]
+fn test_cannot_lock_while_borrowed_mut() {
+    let hive = Hive::<u32>::new();
+    let _borrowed = hive.borrow_mut().unwrap();
+    let _lock = hive.lock();
+}
+
+struct Unit {
+    name: String,
+    hp: i32,
+}
+
+type Units = Rc<Hive<Unit>>;
+
+/// Damage may kill the unit, which then explodes: it spawns two weaker units, which get damaged in turn.
+fn damage(units: &Units, target: WeakOrStrongRef<Unit>, amount: i32, log: &Rc<RefCell<Vec<String>>>) {
+    let (units_, log) = (Rc::clone(units), Rc::clone(log));
+    units.run_or_defer(move |hive| {
+        let Some(unit) = hive.get_mut(&target) else { return };
+        if unit.hp <= 0 {
+            return; // Already dead
+        }
+        unit.hp -= amount;
+        log.borrow_mut().push(format!("{} took {amount}", unit.name));
+        if unit.hp > 0 {
+            return;
+        }
+        log.borrow_mut().push(format!("{} died", unit.name));
+        let name = unit.name.clone();
+        let units = hive.hive();
+        units.mark_for_removal(hive.get_mut(&target).unwrap());
+        if amount > 1 {
+            for i in 0..2 {
+                let child = units.add_and_make_ref(Unit { name: format!("{name}.{i}"), hp: 1 }, RefStrength::Weak, RefInfo::new("spawned"));
+                // The children react to the explosion too, once we're done
+                damage(&units_, child, amount / 2, &log);
+            }
+        }
+    });
+}
+
+#[test]
+fn test_chain_reaction() {
+    let units: Units = Rc::new(Hive::new());
+    let log = Rc::new(RefCell::new(vec![]));
+    let a = units.add_and_make_ref(Unit { name: "a".into(), hp: 3 }, RefStrength::Weak, RefInfo::new("test"));
+    units.add(Unit { name: "b".into(), hp: 10 });
+
+    // Damage is dealt while iterating, i.e in a nested context
+    {
+        let lock = units.lock();
+        for unit in lock.iter() {
+            if unit.name == "a" {
+                damage(&units, a.clone_with(RefInfo::new("damage event")), 4, &log);
+            }
+        }
+        assert!(log.borrow().is_empty(), "Deferred while iterating");
+    }
+    assert_eq!(units.deferred_command_count(), 0);
+    assert_eq!(*log.borrow(), [
+        "a took 4", "a died",
+        "a.0 took 2", "a.0 died",
+        "a.1 took 2", "a.1 died",
+        "a.0.0 took 1", "a.0.0 died",
+        "a.0.1 took 1", "a.0.1 died",
+        "a.1.0 took 1", "a.1.0 died",
+        "a.1.1 took 1", "a.1.1 died",
+    ]);
+    assert!(units.compact());
+    assert!(units.lock().iter().map(|unit| &*unit.name).eq(["b"]));
+}
+
+#[test]
+fn test_deferred_commands_see_pending_adds() {
+    let hive = Rc::new(Hive::with_ordering_policy(crate::OrderingPolicy::predicate(|a: &i32, b: &i32| a.cmp(b))));
+    hive.add(3);
+    hive.add(1);
+    {
+        let lock = hive.lock();
+        lock.add(2);
+        assert!(!hive.run_or_defer(|hive| hive.iter_mut().for_each(|x| *x *= 10)));
+    }
+    assert!(hive.lock().iter().copied().eq([30, 10, 20]));
+    assert!(hive.compact());
+    assert!(hive.lock().iter().copied().eq([10, 20, 30]));
+}
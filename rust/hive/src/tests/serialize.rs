@@ -0,0 +1,78 @@
+use crate::{Hive, RefInfo, RefStrength, ResolveError, SerializedRef};
+
+#[test]
+fn test_serialize_and_resolve() {
+    let mut hive = Hive::new();
+    hive.set_id(7);
+    let player = hive.add_and_make_ref("player", RefStrength::Weak, RefInfo::new("spawner"));
+    let enemy = hive.add_and_make_ref("enemy", RefStrength::Weak, RefInfo::new("spawner"));
+
+    let serialized = player.serialize();
+    assert_eq!(serialized.hive_id, 7);
+    assert_eq!(serialized.session_id, hive.session_id());
+    assert_eq!(serialized.item_guid, player.item_guid());
+    assert_eq!(serialized, hive.serialize_ref(hive.lock().get(&player).unwrap()));
+
+    let text = serialized.to_string();
+    assert_eq!(text, format!("<7:{:x}:#{}:{}>", hive.session_id(), player.item_guid(), serialized.generation));
+    assert_eq!(text.parse(), Ok(serialized));
+    assert_eq!(SerializedRef::from_bytes(serialized.to_bytes()), serialized);
+
+    let resolved = hive.resolve(&serialized, RefInfo::new("editor")).unwrap();
+    assert!(!resolved.is_strong());
+    assert_eq!(hive.lock().get(&resolved), Some(&"player"));
+    assert_eq!(player.referencers().len(), 2);
+
+    // Dropped items can still be serialized, but no longer resolve
+    let enemy_serialized = enemy.serialize();
+    {
+        let lock = hive.lock();
+        lock.mark_for_removal(lock.get(&enemy).unwrap());
+    }
+    assert!(hive.compact());
+    assert_eq!(enemy.serialize(), enemy_serialized);
+    assert_eq!(hive.resolve(&enemy_serialized, RefInfo::new("editor")).unwrap_err(), ResolveError::Dangling);
+
+    let other_hive = Hive::<&str>::new();
+    assert_eq!(other_hive.resolve(&serialized, RefInfo::new("editor")).unwrap_err(), ResolveError::WrongHive { expected: 0, found: 7 });
+    let stale = SerializedRef { generation: serialized.generation + 1, ..serialized };
+    assert!(matches!(hive.resolve(&stale, RefInfo::new("editor")), Err(ResolveError::GenerationMismatch { .. })));
+}
+
+#[test]
+fn test_find_refs_in_log_lines() {
+    let mut hive = Hive::new();
+    hive.set_id(3);
+    let a = hive.add_and_make_ref(1, RefStrength::Weak, RefInfo::new("a"));
+    let b = hive.add_and_make_ref(2, RefStrength::Weak, RefInfo::new("b"));
+    let line = format!("[warn] <physics> {} collided with {} at <1.0, 2.0>", a.serialize(), b.serialize());
+    let found = SerializedRef::find_in(&line).collect::<Vec<_>>();
+    assert_eq!(found, [a.serialize(), b.serialize()]);
+    let lock = hive.lock();
+    let resolved = found.iter().map(|s| *lock.get(&hive.resolve(s, RefInfo::new("log viewer")).unwrap()).unwrap()).collect::<Vec<_>>();
+    assert_eq!(resolved, [1, 2]);
+
+    for invalid in ["", "<>", "<3:#4:5>", "<3:a:4:5>", "<3:a:#4>", "<3:a:#4:5:6>", "3:a:#4:5", "<-3:a:#4:5>", "<3:g:#4:5>"] {
+        assert!(invalid.parse::<SerializedRef>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_refs_from_another_run_dont_resolve() {
+    // Same id, same items in the same order: guids and generations match, only the session tells the runs apart
+    let run = || {
+        let mut hive = Hive::new();
+        hive.set_id(7);
+        let refs = ["player", "enemy"].map(|value| hive.add_and_make_ref(value, RefStrength::Weak, RefInfo::new("spawner")));
+        (hive, refs)
+    };
+    let (hive, [_, enemy]) = run();
+    let serialized = enemy.serialize();
+    let (fresh_hive, [_, fresh_enemy]) = run();
+    assert_eq!((fresh_enemy.item_guid(), fresh_enemy.serialize().generation), (serialized.item_guid, serialized.generation));
+    assert_ne!(fresh_hive.session_id(), hive.session_id());
+
+    let reparsed = serialized.to_string().parse().unwrap();
+    assert_eq!(fresh_hive.resolve(&reparsed, RefInfo::new("log viewer")).unwrap_err(), ResolveError::WrongSession { expected: fresh_hive.session_id(), found: hive.session_id() });
+    assert!(hive.resolve(&reparsed, RefInfo::new("log viewer")).is_ok());
+}
@@ -0,0 +1,437 @@
+use std::cmp;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use super::{Info, Key};
+
+const CHUNK_COUNT: usize = 32;
+
+/// Like `SparseMap`, but shared between threads: lookups are lock-free, while insertions and removals take a lock.
+///
+/// Readers `pin()` the map, and look values up through the returned `Guard`. Removed values are not dropped right
+/// away, since a reader may still be looking at them: they are deferred until every guard which was pinned before
+/// the removal is dropped (epoch-based reclamation, with two epochs in flight). Keep guards short-lived, or removed
+/// values pile up.
+///
+/// Slots are allocated in chunks of growing size which never move, so that readers never see freed slots either.
+pub struct ConcurrentSparseMap<V, K = super::K> {
+    chunks: [AtomicPtr<Slot<V>>; CHUNK_COUNT], // Chunk `i` has `2^i` slots.
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2], // Pinned guards, by parity of the epoch they were pinned in.
+    writer: Mutex<Writer<V>>,
+    // Values are handed out to readers of any thread, and dropped by the writer of any thread.
+    _values: PhantomData<*const V>,
+    key_type: PhantomData<K>,
+}
+
+unsafe impl<V: Send, K: Send> Send for ConcurrentSparseMap<V, K> {}
+unsafe impl<V: Send + Sync, K: Sync> Sync for ConcurrentSparseMap<V, K> {}
+
+struct Slot<V> {
+    info: AtomicU32, // `Info` bits
+    value: AtomicPtr<V>, // Null while vacant
+}
+
+struct Writer<V> {
+    frees: Vec<u32>,
+    slot_count: usize, // Slots which have been occupied at some point.
+    len: usize,
+    retired: usize,
+    garbage: [Vec<Box<V>>; 2], // Removed values, by parity of the epoch they were removed in.
+}
+
+/// Lets the current thread read from the map. Values it returns can't be dropped until it is.
+pub struct Guard<'a, V: 'a, K: 'a = super::K> {
+    map: &'a ConcurrentSparseMap<V, K>,
+    parity: usize,
+}
+
+impl<V, K> Drop for ConcurrentSparseMap<V, K> {
+    fn drop(&mut self) {
+        for (i, chunk) in self.chunks.iter_mut().enumerate() {
+            let chunk = *chunk.get_mut();
+            if chunk.is_null() {
+                continue;
+            }
+            let mut slots = unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(chunk, 1 << i)) };
+            for slot in slots.iter_mut() {
+                let value = *slot.value.get_mut();
+                if !value.is_null() {
+                    drop(unsafe { Box::from_raw(value) });
+                }
+            }
+        }
+        // Garbage is dropped along with the writer.
+    }
+}
+
+impl<V, K: Key> Default for ConcurrentSparseMap<V, K> {
+    fn default() -> Self {
+        Self::with_key()
+    }
+}
+
+impl<V, K> fmt::Debug for ConcurrentSparseMap<V, K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        f.debug_struct("ConcurrentSparseMap")
+            .field("len", &writer.len)
+            .field("epoch", &self.epoch.load(Ordering::Relaxed))
+            .field("deferred", &(writer.garbage[0].len() + writer.garbage[1].len()))
+            .finish()
+    }
+}
+
+impl<V> ConcurrentSparseMap<V> {
+    pub fn new() -> Self {
+        Self::with_key()
+    }
+}
+
+impl<V, K: Key> ConcurrentSparseMap<V, K> {
+    /// Like `new()`, for a key type other than `K` (see `new_key_type!`).
+    pub fn with_key() -> Self {
+        Self {
+            chunks: Default::default(),
+            epoch: AtomicUsize::new(0),
+            readers: Default::default(),
+            writer: Mutex::new(Writer { frees: Vec::new(), slot_count: 0, len: 0, retired: 0, garbage: Default::default() }),
+            _values: PhantomData,
+            key_type: PhantomData,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.lock().len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// See `SparseMap::retired_slot_count()`.
+    pub fn retired_slot_count(&self) -> usize {
+        self.lock().retired
+    }
+    /// Number of removed values which are waiting for readers before being dropped.
+    pub fn deferred_count(&self) -> usize {
+        let writer = self.lock();
+        writer.garbage[0].len() + writer.garbage[1].len()
+    }
+    pub fn pin(&self) -> Guard<'_, V, K> {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let parity = epoch % 2;
+            self.readers[parity].fetch_add(1, Ordering::SeqCst);
+            // If the epoch moved meanwhile, the writer may not have seen us: pin again, in the new epoch.
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                return Guard { map: self, parity };
+            }
+            self.readers[parity].fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    pub fn contains_key(&self, k: K) -> bool {
+        self.pin().contains_key(k)
+    }
+    pub fn get_cloned(&self, k: K) -> Option<V> where V: Clone {
+        self.pin().get(k).cloned()
+    }
+    pub fn insert(&self, v: V) -> K {
+        let value = Box::into_raw(Box::new(v));
+        let mut writer = self.lock();
+        let (i, info) = match writer.frees.pop() {
+            Some(i) => {
+                let mut info = Info(self.slot(i as usize).unwrap().info.load(Ordering::Relaxed));
+                debug_assert!(info.is_vacant());
+                info.make_occupied_and_increment_version();
+                (i as usize, info)
+            },
+            None => {
+                let i = writer.slot_count;
+                assert!(i <= cmp::min(K::MAX_INDEX, u32::MAX as usize - 1), "ConcurrentSparseMap can't hold more slots than its key type can index");
+                let (chunk, offset) = chunk_and_offset(i);
+                if offset == 0 {
+                    self.allocate_chunk(chunk);
+                }
+                writer.slot_count += 1;
+                (i, Info::new_occupied())
+            },
+        };
+        let slot = self.slot(i).unwrap();
+        // The value must be visible to whoever sees the slot occupied.
+        slot.value.store(value, Ordering::Release);
+        slot.info.store(info.0, Ordering::Release);
+        writer.len += 1;
+        let garbage = self.try_advance_epoch(&mut writer);
+        drop(writer);
+        drop(garbage);
+        K::with_index_and_version(i, info.version())
+    }
+    /// The value is dropped once no reader can be looking at it anymore, which may be right away.
+    pub fn remove(&self, k: K) -> bool {
+        let mut writer = self.lock();
+        let slot = match self.slot(k.index()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let mut info = Info(slot.info.load(Ordering::Relaxed));
+        if !(info.is_occupied() && info.version() == k.version()) {
+            return false;
+        }
+        info.make_vacant();
+        slot.info.store(info.0, Ordering::Release);
+        // Readers which see the slot empty also see it vacant.
+        let value = slot.value.swap(ptr::null_mut(), Ordering::AcqRel);
+        let parity = self.epoch.load(Ordering::Relaxed) % 2;
+        writer.garbage[parity].push(unsafe { Box::from_raw(value) });
+        if info.can_be_reused::<K>() {
+            writer.frees.push(k.index() as u32);
+        } else {
+            writer.retired += 1;
+        }
+        writer.len -= 1;
+        let garbage = self.try_advance_epoch(&mut writer);
+        drop(writer);
+        drop(garbage);
+        true
+    }
+    /// Drops the removed values which no reader can be looking at anymore.
+    /// Insertions and removals do it too, so this is only needed to reclaim memory when they stop happening.
+    pub fn reclaim(&self) {
+        // Values removed in the current epoch are dropped once it is over, and the next one too.
+        for _ in 0..2 {
+            let garbage = self.try_advance_epoch(&mut self.lock());
+            drop(garbage);
+        }
+    }
+    /// Moves to the next epoch if no guard is pinned in the previous one. Values which were removed in the previous
+    /// epoch can't be reached by any guard then, so they are returned, to be dropped once the lock is released.
+    fn try_advance_epoch(&self, writer: &mut Writer<V>) -> Vec<Box<V>> {
+        let epoch = self.epoch.load(Ordering::Relaxed); // Only written with the lock held
+        let previous = (epoch + 1) % 2;
+        if self.readers[previous].load(Ordering::SeqCst) != 0 {
+            return Vec::new();
+        }
+        let garbage = mem::take(&mut writer.garbage[previous]);
+        self.epoch.store(epoch.wrapping_add(1), Ordering::SeqCst);
+        garbage
+    }
+    fn lock(&self) -> MutexGuard<'_, Writer<V>> {
+        // Values are only dropped outside of the lock, so it can't be poisoned by them.
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn allocate_chunk(&self, chunk: usize) {
+        let slots: Box<[Slot<V>]> = (0..1usize << chunk).map(|_| {
+            let mut info = Info::new_occupied();
+            info.make_vacant();
+            Slot { info: AtomicU32::new(info.0), value: AtomicPtr::new(ptr::null_mut()) }
+        }).collect();
+        let slots = Box::into_raw(slots) as *mut Slot<V>;
+        self.chunks[chunk].store(slots, Ordering::Release);
+    }
+    fn slot(&self, i: usize) -> Option<&Slot<V>> {
+        if i >= u32::MAX as usize {
+            return None;
+        }
+        let (chunk, offset) = chunk_and_offset(i);
+        let slots = self.chunks[chunk].load(Ordering::Acquire);
+        if slots.is_null() {
+            None
+        } else {
+            Some(unsafe { &*slots.add(offset) })
+        }
+    }
+}
+
+fn chunk_and_offset(i: usize) -> (usize, usize) {
+    let n = i + 1;
+    let chunk = (usize::BITS - 1 - n.leading_zeros()) as usize;
+    (chunk, n - (1 << chunk))
+}
+
+impl<'a, V, K: Key> Guard<'a, V, K> {
+    pub fn get(&self, k: K) -> Option<&V> {
+        let slot = self.map.slot(k.index())?;
+        let info = slot.info.load(Ordering::Acquire);
+        if !(Info(info).is_occupied() && Info(info).version() == k.version()) {
+            return None;
+        }
+        let value = slot.value.load(Ordering::Acquire);
+        // If the slot changed meanwhile, the value may belong to another key.
+        if value.is_null() || slot.info.load(Ordering::Acquire) != info {
+            return None;
+        }
+        // Not dropped until we are.
+        Some(unsafe { &*value })
+    }
+    pub fn contains_key(&self, k: K) -> bool {
+        self.get(k).is_some()
+    }
+}
+
+impl<'a, V, K> Drop for Guard<'a, V, K> {
+    fn drop(&mut self) {
+        self.map.readers[self.parity].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use tests::{Rng, TinyKey};
+    use K;
+
+    #[cfg(not(miri))] const OP_COUNT: usize = 20000;
+    #[cfg(miri)]      const OP_COUNT: usize = 100;
+
+    /// Remembers its key, and tells readers if it has been dropped while they were looking at it.
+    struct Asset {
+        key: AtomicU32,
+        is_dropped: AtomicBool,
+        drops: Arc<AtomicUsize>,
+    }
+
+    impl Drop for Asset {
+        fn drop(&mut self) {
+            assert!(!self.is_dropped.swap(true, Ordering::SeqCst), "Dropped twice");
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn versions_and_slot_reuse() {
+        let map = ConcurrentSparseMap::new();
+        let a = map.insert("a");
+        let b = map.insert("b");
+        let c = map.insert("c");
+        assert_eq!((c.index(), c.version()), (2, 0));
+        assert_eq!(map.len(), 3);
+        assert!(map.remove(b));
+        assert!(!map.remove(b));
+        let d = map.insert("d");
+        assert_eq!((d.index(), d.version()), (1, 1));
+        let guard = map.pin();
+        assert_eq!(guard.get(a), Some(&"a"));
+        assert_eq!(guard.get(b), None);
+        assert_eq!(guard.get(d), Some(&"d"));
+        assert_eq!(guard.get(K::with_index(1000)), None);
+        drop(guard);
+        assert_eq!(map.get_cloned(c), Some("c"));
+        assert!(!map.contains_key(K::with_index_and_version(0, 1)));
+
+        let keys: Vec<K> = (0..100).map(|i| map.insert(if i % 2 == 0 { "even" } else { "odd" })).collect();
+        assert!(keys.iter().enumerate().all(|(i, k)| map.get_cloned(*k) == Some(if i % 2 == 0 { "even" } else { "odd" })));
+        assert_eq!(map.len(), 103);
+
+        let map: ConcurrentSparseMap<u8, TinyKey> = ConcurrentSparseMap::with_key();
+        for _ in 0..=TinyKey::MAX_VERSION {
+            let k = map.insert(0);
+            assert!(map.remove(k));
+        }
+        assert_eq!(map.retired_slot_count(), 1);
+        assert_eq!(map.insert(1).index(), 1);
+    }
+
+    #[test]
+    fn removed_values_outlive_guards() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let asset = |key| Asset { key: AtomicU32::new(key), is_dropped: AtomicBool::new(false), drops: drops.clone() };
+        let map = ConcurrentSparseMap::new();
+        let k = map.insert(asset(0));
+        map.insert(asset(1));
+
+        let guard = map.pin();
+        let value = guard.get(k).unwrap();
+        assert!(map.remove(k));
+        for i in 2..10 {
+            let k = map.insert(asset(i));
+            map.remove(k);
+            map.reclaim();
+        }
+        assert!(!value.is_dropped.load(Ordering::SeqCst));
+        assert_eq!(value.key.load(Ordering::SeqCst), 0);
+        assert!(guard.get(k).is_none());
+        assert_eq!(drops.load(Ordering::SeqCst), 0, "Nothing can be dropped while a guard from before is pinned");
+        assert_eq!(map.deferred_count(), 9);
+        drop(guard);
+
+        map.reclaim();
+        assert_eq!(drops.load(Ordering::SeqCst), 9);
+        assert_eq!(map.deferred_count(), 0);
+        drop(map);
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn stress_readers_against_writers() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let map = Arc::new(ConcurrentSparseMap::<Asset>::new());
+        let keys = Arc::new(Mutex::new(Vec::<K>::new())); // Live and dead keys, for readers to try
+        let is_done = Arc::new(AtomicBool::new(false));
+        let start = Arc::new(Barrier::new(5));
+
+        let readers: Vec<_> = (0..3).map(|seed| {
+            let (map, keys, is_done, start) = (map.clone(), keys.clone(), is_done.clone(), start.clone());
+            thread::spawn(move || {
+                let mut rng = Rng(seed + 1);
+                let (mut lookups, mut hits) = (0, 0);
+                start.wait();
+                while !is_done.load(Ordering::SeqCst) || lookups < OP_COUNT / 10 {
+                    lookups += 1;
+                    let k = {
+                        let keys = keys.lock().unwrap();
+                        if keys.is_empty() { continue; }
+                        keys[rng.below(keys.len())]
+                    };
+                    let guard = map.pin();
+                    if let Some(asset) = guard.get(k) {
+                        thread::yield_now();
+                        assert!(!asset.is_dropped.load(Ordering::SeqCst), "Read a dropped value");
+                        assert_eq!(asset.key.load(Ordering::SeqCst), k.index() as u32 | k.version() << 16, "Read another key's value");
+                        hits += 1;
+                    }
+                }
+                hits
+            })
+        }).collect();
+
+        let writers: Vec<_> = (0..2).map(|seed| {
+            let (map, keys, drops, start) = (map.clone(), keys.clone(), drops.clone(), start.clone());
+            thread::spawn(move || {
+                let mut rng = Rng(seed + 10);
+                let mut mine = vec![];
+                start.wait();
+                for _ in 0..OP_COUNT / 2 {
+                    if mine.is_empty() || rng.below(3) != 0 {
+                        let asset = Asset { key: AtomicU32::new(u32::MAX), is_dropped: AtomicBool::new(false), drops: drops.clone() };
+                        // Keys are only known once inserted; readers can't find the value before this store anyway.
+                        let k = map.insert(asset);
+                        map.pin().get(k).unwrap().key.store(k.index() as u32 | k.version() << 16, Ordering::SeqCst);
+                        keys.lock().unwrap().push(k);
+                        mine.push(k);
+                    } else {
+                        let k = mine.swap_remove(rng.below(mine.len()));
+                        assert!(map.remove(k));
+                    }
+                }
+                mine.len()
+            })
+        }).collect();
+
+        let live: usize = writers.into_iter().map(|t| t.join().unwrap()).sum();
+        is_done.store(true, Ordering::SeqCst);
+        let hits: usize = readers.into_iter().map(|t| t.join().unwrap()).sum();
+        assert!(cfg!(miri) || hits > 0);
+        assert_eq!(map.len(), live);
+        map.reclaim();
+        let created = keys.lock().unwrap().len();
+        assert_eq!(drops.load(Ordering::SeqCst), created - live);
+        drop(map);
+        assert_eq!(drops.load(Ordering::SeqCst), created);
+    }
+}
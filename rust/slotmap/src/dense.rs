@@ -0,0 +1,439 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+use std::slice;
+use std::vec;
+
+use super::{Info, Key};
+
+/// Like `SparseMap`, but values are kept tightly packed in a `Vec`, so that iterating over them is as fast as
+/// iterating over a slice (see `values()`).
+///
+/// Keys go through an indirection table: retrieval is two array accesses instead of one, and removal is a
+/// `swap_remove()`, which changes the order of values. Favors iteration over insertion and removal.
+#[derive(Clone)]
+pub struct DenseSlotMap<V, K = super::K> {
+    values: Vec<V>,
+    ofni: Vec<u32>, // Indexed like `values`. Retrieves a slot index.
+    slots: Vec<Slot>, // Indexed by K::index().
+    frees: Vec<u32>, // Slot indices which were used at some point but are now available. Saturated slots are retired instead.
+    key_type: PhantomData<K>,
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+struct Slot {
+    info: Info,
+    index: u32, // Index into `values`, only meaningful while occupied.
+}
+
+impl<V, K: Key> Default for DenseSlotMap<V, K> {
+    fn default() -> Self {
+        Self::with_key()
+    }
+}
+
+impl<V: fmt::Debug, K: Key> fmt::Debug for DenseSlotMap<V, K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<V, K: Key> Index<K> for DenseSlotMap<V, K> {
+    type Output = V;
+    #[inline]
+    fn index(&self, k: K) -> &V {
+        self.get(k).unwrap()
+    }
+}
+
+impl<V, K: Key> IndexMut<K> for DenseSlotMap<V, K> {
+    #[inline]
+    fn index_mut(&mut self, k: K) -> &mut V {
+        self.get_mut(k).unwrap()
+    }
+}
+
+impl<V> DenseSlotMap<V> {
+    pub fn new() -> Self {
+        Self::with_key()
+    }
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_and_key(cap)
+    }
+}
+
+impl<V, K: Key> DenseSlotMap<V, K> {
+    /// Like `new()`, for a key type other than `K` (see `new_key_type!`).
+    pub fn with_key() -> Self {
+        Self::with_capacity_and_key(0)
+    }
+    pub fn with_capacity_and_key(cap: usize) -> Self {
+        Self {
+            values: Vec::with_capacity(cap),
+            ofni: Vec::with_capacity(cap),
+            slots: Vec::with_capacity(cap),
+            frees: Vec::new(),
+            key_type: PhantomData,
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+    pub fn reserve(&mut self, additional: usize) {
+        self.values.reserve(additional);
+        self.ofni.reserve(additional);
+        self.slots.reserve(additional);
+    }
+    pub fn len(&self) -> usize { self.values.len() }
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+    pub fn contains_key(&self, k: K) -> bool {
+        match self.slots.get(k.index()) {
+            None => false,
+            Some(slot) => slot.info.is_occupied() && slot.info.version() == k.version(),
+        }
+    }
+    /// # Safety
+    ///
+    /// `k` must be a key of this map, i.e `contains_key(k)` must be true.
+    #[inline]
+    pub unsafe fn get_unchecked(&self, k: K) -> &V {
+        debug_assert!(self.contains_key(k));
+        let i = self.slots.get_unchecked(k.index()).index as usize;
+        self.values.get_unchecked(i)
+    }
+    /// # Safety
+    ///
+    /// `k` must be a key of this map, i.e `contains_key(k)` must be true.
+    #[inline]
+    pub unsafe fn get_unchecked_mut(&mut self, k: K) -> &mut V {
+        debug_assert!(self.contains_key(k));
+        let i = self.slots.get_unchecked(k.index()).index as usize;
+        self.values.get_unchecked_mut(i)
+    }
+    pub fn get(&self, k: K) -> Option<&V> {
+        if self.contains_key(k) {
+            Some(unsafe { self.get_unchecked(k) })
+        } else {
+            None
+        }
+    }
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        if self.contains_key(k) {
+            Some(unsafe { self.get_unchecked_mut(k) })
+        } else {
+            None
+        }
+    }
+    pub fn insert(&mut self, v: V) -> K {
+        let i = self.values.len() as u32;
+        let slot_i = match self.frees.pop() {
+            None => {
+                let slot_i = self.slots.len();
+                assert!(slot_i <= K::MAX_INDEX, "DenseSlotMap can't hold more slots than its key type can index");
+                self.slots.push(Slot { info: Info::new_occupied(), index: i });
+                slot_i
+            },
+            Some(slot_i) => {
+                let slot = &mut self.slots[slot_i as usize];
+                debug_assert!(slot.info.is_vacant());
+                slot.info.make_occupied_and_increment_version();
+                slot.index = i;
+                slot_i as usize
+            },
+        };
+        self.values.push(v);
+        self.ofni.push(slot_i as u32);
+        K::with_index_and_version(slot_i, self.slots[slot_i].info.version())
+    }
+    /// Moves the last value into the removed value's place.
+    pub fn remove(&mut self, k: K) -> Option<V> {
+        if !self.contains_key(k) {
+            return None;
+        }
+        let slot = &mut self.slots[k.index()];
+        let i = slot.index as usize;
+        slot.info.make_vacant();
+        if slot.info.can_be_reused::<K>() {
+            self.frees.push(k.index() as u32);
+        }
+        self.ofni.swap_remove(i);
+        if let Some(&moved) = self.ofni.get(i) {
+            self.slots[moved as usize].index = i as u32;
+        }
+        Some(self.values.swap_remove(i))
+    }
+    /// Removes all values, but keeps slots (and their versions), so that old keys stay invalid.
+    pub fn clear(&mut self) {
+        self.vacate_all_slots();
+        self.values.clear();
+    }
+    /// Removes the values for which `f` returns false. Each value is visited once, but not in order of insertion.
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&mut self, mut f: F) {
+        let mut i = 0;
+        while i < self.values.len() {
+            let k = self.key_at(i);
+            if f(k, &mut self.values[i]) {
+                i += 1;
+            } else {
+                // The last value takes its place, and is visited next
+                self.remove(k);
+            }
+        }
+    }
+    /// Key of the `i`-th value of `values()`.
+    pub fn key_at(&self, i: usize) -> K {
+        let slot_i = self.ofni[i] as usize;
+        K::with_index_and_version(slot_i, self.slots[slot_i].info.version())
+    }
+    /// All values, tightly packed. Their order is unspecified, and changes on removal.
+    pub fn values(&self) -> &[V] {
+        &self.values
+    }
+    /// All values, tightly packed. See `values()`.
+    pub fn values_mut(&mut self) -> &mut [V] {
+        &mut self.values
+    }
+    pub fn keys(&self) -> Keys<'_, K> {
+        Keys { ofni: self.ofni.iter(), slots: &self.slots, key_type: PhantomData }
+    }
+    pub fn iter(&self) -> Iter<'_, V, K> {
+        Iter { keys: self.keys(), values: self.values.iter() }
+    }
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, K> {
+        IterMut { keys: Keys { ofni: self.ofni.iter(), slots: &self.slots, key_type: PhantomData }, values: self.values.iter_mut() }
+    }
+    /// Like `clear()`, but yields the removed values.
+    pub fn drain(&mut self) -> Drain<'_, V, K> {
+        let keys = self.keys().collect::<Vec<_>>().into_iter();
+        self.vacate_all_slots();
+        Drain { keys, values: self.values.drain(..) }
+    }
+    /// Number of slots which were retired because their version saturated (see `SparseMap::retired_slot_count()`).
+    pub fn retired_slot_count(&self) -> usize {
+        self.slots.len() - self.frees.len() - self.values.len()
+    }
+    /// Leaves values to the caller.
+    fn vacate_all_slots(&mut self) {
+        for slot in &mut self.slots {
+            if slot.info.is_occupied() {
+                slot.info.make_vacant();
+            }
+        }
+        // Reversed, so that lower indices are reused first
+        let slots = &self.slots;
+        self.frees.clear();
+        self.frees.extend((0..slots.len() as u32).rev().filter(|&i| slots[i as usize].info.can_be_reused::<K>()));
+        self.ofni.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Keys<'a, K = super::K> {
+    ofni: slice::Iter<'a, u32>,
+    slots: &'a [Slot],
+    key_type: PhantomData<K>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Iter<'a, V: 'a, K = super::K> {
+    keys: Keys<'a, K>,
+    values: slice::Iter<'a, V>,
+}
+
+#[derive(Debug)]
+pub struct IterMut<'a, V: 'a, K = super::K> {
+    keys: Keys<'a, K>,
+    values: slice::IterMut<'a, V>,
+}
+
+#[derive(Debug)]
+pub struct IntoIter<V, K = super::K> {
+    keys: vec::IntoIter<K>,
+    values: vec::IntoIter<V>,
+}
+
+/// Values are already removed when this is created; dropping it drops the rest.
+#[derive(Debug)]
+pub struct Drain<'a, V: 'a, K = super::K> {
+    keys: vec::IntoIter<K>,
+    values: vec::Drain<'a, V>,
+}
+
+impl<'a, K: Key> Keys<'a, K> {
+    fn key(&self, slot_i: u32) -> K {
+        K::with_index_and_version(slot_i as usize, self.slots[slot_i as usize].info.version())
+    }
+}
+
+impl<'a, K: Key> Iterator for Keys<'a, K> {
+    type Item = K;
+    fn next(&mut self) -> Option<K> { self.ofni.next().map(|&slot_i| self.key(slot_i)) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.ofni.size_hint() }
+}
+
+impl<'a, K: Key> DoubleEndedIterator for Keys<'a, K> {
+    fn next_back(&mut self) -> Option<K> { self.ofni.next_back().map(|&slot_i| self.key(slot_i)) }
+}
+
+macro_rules! impl_zipped_iterator {
+    ($($Iter:ident<$($lt:lifetime,)* $V:ident> => $Item:ty;)*) => {$(
+        impl<$($lt,)* $V, K: Key> Iterator for $Iter<$($lt,)* $V, K> {
+            type Item = (K, $Item);
+            fn next(&mut self) -> Option<Self::Item> { Some((self.keys.next()?, self.values.next()?)) }
+            fn size_hint(&self) -> (usize, Option<usize>) { self.values.size_hint() }
+        }
+        impl<$($lt,)* $V, K: Key> DoubleEndedIterator for $Iter<$($lt,)* $V, K> {
+            fn next_back(&mut self) -> Option<Self::Item> { Some((self.keys.next_back()?, self.values.next_back()?)) }
+        }
+        impl<$($lt,)* $V, K: Key> ExactSizeIterator for $Iter<$($lt,)* $V, K> {}
+    )*};
+}
+
+impl_zipped_iterator! {
+    Iter<'a, V> => &'a V;
+    IterMut<'a, V> => &'a mut V;
+    IntoIter<V> => V;
+    Drain<'a, V> => V;
+}
+
+impl<'a, K: Key> ExactSizeIterator for Keys<'a, K> {}
+
+impl<V, K: Key> IntoIterator for DenseSlotMap<V, K> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<V, K>;
+    fn into_iter(self) -> IntoIter<V, K> {
+        IntoIter { keys: self.keys().collect::<Vec<_>>().into_iter(), values: self.values.into_iter() }
+    }
+}
+
+impl<'a, V, K: Key> IntoIterator for &'a DenseSlotMap<V, K> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, V, K>;
+    fn into_iter(self) -> Iter<'a, V, K> { self.iter() }
+}
+
+impl<'a, V, K: Key> IntoIterator for &'a mut DenseSlotMap<V, K> {
+    type Item = (K, &'a mut V);
+    type IntoIter = IterMut<'a, V, K>;
+    fn into_iter(self) -> IterMut<'a, V, K> { self.iter_mut() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use tests::{Rng, TinyKey, Tracked};
+    use K;
+
+    #[cfg(not(miri))] const OP_COUNT: usize = 5000;
+    #[cfg(miri)]      const OP_COUNT: usize = 200;
+
+    #[test]
+    fn matches_hashmap_model() {
+        for seed in 1..9 {
+            let mut rng = Rng(seed);
+            let drops = Rc::new(Cell::new(0));
+            let mut created = 0;
+            let mut map = DenseSlotMap::new();
+            let mut model = HashMap::new();
+            let mut dead_keys = vec![];
+            for _ in 0..OP_COUNT {
+                match rng.below(10) {
+                    0..=3 => {
+                        let value = rng.next();
+                        created += 1;
+                        let k = map.insert(Tracked { value, drops: drops.clone() });
+                        assert!(model.insert(k, value).is_none(), "{:?} was handed out twice", k);
+                    },
+                    4..=6 if !model.is_empty() => {
+                        let k = *model.keys().nth(rng.below(model.len())).unwrap();
+                        assert_eq!(map.remove(k).map(|v| v.value), model.remove(&k));
+                        assert!(map.remove(k).is_none());
+                        dead_keys.push(k);
+                    },
+                    7 if !model.is_empty() => {
+                        let k = *model.keys().nth(rng.below(model.len())).unwrap();
+                        let value = rng.next();
+                        map[k].value = value;
+                        model.insert(k, value);
+                    },
+                    8 => {
+                        let modulo = 3 + rng.below(5) as u64;
+                        map.retain(|_, v| v.value % modulo != 0);
+                        dead_keys.extend(model.iter().filter(|&(_, v)| v % modulo == 0).map(|(k, _)| *k));
+                        model.retain(|_, v| *v % modulo != 0);
+                    },
+                    9 if rng.below(20) == 0 => {
+                        if rng.below(2) == 0 {
+                            map.clear();
+                        } else {
+                            assert_eq!(map.drain().len(), model.len());
+                        }
+                        dead_keys.extend(model.drain().map(|(k, _)| k));
+                    },
+                    _ => {},
+                }
+                assert_eq!(map.len(), model.len());
+                assert_eq!(map.values().len(), model.len());
+                for (i, v) in map.values().iter().enumerate() {
+                    let k = map.key_at(i);
+                    assert_eq!(model[&k], v.value);
+                    assert_eq!(map[k].value, v.value);
+                }
+                assert!(dead_keys.iter().all(|k| !map.contains_key(*k)));
+                assert_eq!(created - drops.get(), map.len(), "Values were leaked or dropped twice");
+            }
+            drop(map);
+            assert_eq!(drops.get(), created);
+        }
+    }
+
+    #[test]
+    fn values_are_packed() {
+        let mut map = DenseSlotMap::new();
+        let keys: Vec<K> = (0..5).map(|i| map.insert(i)).collect();
+        assert_eq!(map.remove(keys[1]), Some(1));
+        assert_eq!(map.values(), [0, 4, 2, 3]);
+        assert_eq!(map.key_at(1), keys[4]);
+        for v in map.values_mut() {
+            *v *= 10;
+        }
+        let k = map.insert(50);
+        assert_eq!((k.index(), k.version()), (1, 1));
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(keys[0], &0), (keys[4], &40), (keys[2], &20), (keys[3], &30), (k, &50)]);
+        assert_eq!(map.keys().next_back(), Some(k));
+        for (_, v) in &mut map {
+            *v += 1;
+        }
+        assert_eq!(map.clone().into_iter().map(|(_, v)| v).collect::<Vec<_>>(), [1, 41, 21, 31, 51]);
+        assert_eq!(map.iter().len(), 5);
+
+        map.clear();
+        assert!(map.is_empty() && keys.iter().all(|k| !map.contains_key(*k)));
+        assert_eq!(map.insert(0).index(), 0);
+    }
+
+    #[test]
+    fn saturated_slots_are_retired() {
+        let mut map: DenseSlotMap<u32, TinyKey> = DenseSlotMap::with_key();
+        let keep = map.insert(100);
+        let mut stale = vec![];
+        for i in 0..4 {
+            let k = map.insert(i);
+            assert_eq!((k.index(), k.version()), (1, i));
+            assert_eq!(map.remove(k), Some(i));
+            stale.push(k);
+        }
+        assert_eq!(map.retired_slot_count(), 1);
+        assert_eq!(map.insert(4).index(), 2);
+        map.clear();
+        assert_eq!(map.retired_slot_count(), 1);
+        assert_eq!(map.insert(5).index(), 0);
+        assert_eq!(map.insert(6).index(), 2);
+        assert!(!map.contains_key(keep));
+        assert!(stale.iter().all(|k| !map.contains_key(*k)));
+    }
+}
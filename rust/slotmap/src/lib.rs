@@ -1,9 +1,146 @@
-// use std::marker::PhantomData;
+#![allow(non_snake_case)] // The crate is named COLONY
 
+use std::cmp;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::marker::PhantomData;
+use std::ops;
 use std::ptr;
-use std::mem;
+use std::mem::{self, MaybeUninit};
 use std::ops::{Index, IndexMut};
+use std::slice;
+use std::vec;
 
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub extern crate serde as __serde;
+
+pub mod concurrent;
+pub mod dense;
+pub mod secondary;
+pub mod sparse_secondary;
+#[cfg(feature = "serde")]
+mod serialize;
+
+pub use concurrent::ConcurrentSparseMap;
+pub use dense::DenseSlotMap;
+pub use secondary::SecondaryMap;
+pub use sparse_secondary::SparseSecondaryMap;
+
+/// An index into a map's slots, and the version the slot had when the key was handed out.
+///
+/// Implemented by `K`, `K32`, and by the key types declared with `new_key_type!`, which give each kind of map its own
+/// key type, so that e.g a mesh key can't be used to look up a texture by mistake.
+///
+/// The bit widths of the index and version are up to the implementor: maps can't hold more than `MAX_INDEX + 1`
+/// slots, and a slot whose version reaches `MAX_VERSION` is retired when vacated, instead of being reused with a
+/// version that wraps around (which would make stale keys valid again).
+pub trait Key: Copy + Eq + Hash + fmt::Debug {
+    const MAX_INDEX: usize;
+    const MAX_VERSION: u32;
+    fn with_index_and_version(i: usize, v: u32) -> Self;
+    fn index(&self) -> usize;
+    fn version(&self) -> u32;
+    #[inline] fn with_index(i: usize) -> Self { Self::with_index_and_version(i, 0) }
+}
+
+/// Declares key types which are distinct from `K` and from one another, for use as the key type of a map.
+/// They wrap `K` unless another key type is given, as in `struct Name(K32);`.
+///
+/// ```
+/// #[macro_use] extern crate COLONY;
+/// use COLONY::{SparseMap, Key};
+///
+/// new_key_type! {
+///     pub struct TextureKey;
+///     pub struct MeshKey;
+///     pub struct ComponentKey(COLONY::K32);
+/// }
+///
+/// # fn main() {
+/// let mut textures: SparseMap<&str, TextureKey> = SparseMap::with_key();
+/// let k = textures.insert("grass.png");
+/// assert_eq!(textures[k], "grass.png");
+/// # let _: Option<MeshKey> = None;
+/// # }
+/// ```
+///
+/// Keys of one type can't be used with a map of another:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate COLONY;
+/// # use COLONY::SparseMap;
+/// # new_key_type! { struct TextureKey; struct MeshKey; }
+/// # fn main() {
+/// let mut textures: SparseMap<&str, TextureKey> = SparseMap::with_key();
+/// let meshes: SparseMap<&str, MeshKey> = SparseMap::with_key();
+/// let k = textures.insert("grass.png");
+/// meshes.get(k);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! new_key_type {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident; $($rest:tt)*) => {
+        $crate::new_key_type! { $(#[$meta])* $vis struct $name($crate::K); $($rest)* }
+    };
+    ($(#[$meta:meta])* $vis:vis struct $name:ident($raw:ty); $($rest:tt)*) => {
+        $(#[$meta])*
+        #[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq)]
+        $vis struct $name($raw);
+
+        impl $crate::Key for $name {
+            const MAX_INDEX: usize = <$raw as $crate::Key>::MAX_INDEX;
+            const MAX_VERSION: u32 = <$raw as $crate::Key>::MAX_VERSION;
+            #[inline] fn with_index_and_version(i: usize, v: u32) -> Self { $name(<$raw as $crate::Key>::with_index_and_version(i, v)) }
+            #[inline] fn index(&self) -> usize { $crate::Key::index(&self.0) }
+            #[inline] fn version(&self) -> u32 { $crate::Key::version(&self.0) }
+        }
+
+        $crate::__new_key_type_serde!($name, $raw);
+
+        impl From<$raw> for $name {
+            #[inline] fn from(k: $raw) -> Self { $name(k) }
+        }
+
+        impl From<$name> for $raw {
+            #[inline] fn from(k: $name) -> Self { k.0 }
+        }
+
+        $crate::new_key_type! { $($rest)* }
+    };
+    () => {};
+}
+
+// Implements `Serialize` and `Deserialize` for key types declared with `new_key_type!`, if the `serde` feature is on
+// (in this crate, not in the one which invokes the macro).
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __new_key_type_serde {
+    ($name:ident, $raw:ty) => {
+        impl $crate::__serde::Serialize for $name {
+            fn serialize<S: $crate::__serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                $crate::__serde::Serialize::serialize(&self.0, serializer)
+            }
+        }
+
+        impl<'de> $crate::__serde::Deserialize<'de> for $name {
+            fn deserialize<D: $crate::__serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                <$raw as $crate::__serde::Deserialize<'de>>::deserialize(deserializer).map($name)
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "serde"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __new_key_type_serde {
+    ($name:ident, $raw:ty) => {};
+}
+
+/// The default key type. Untyped: use `new_key_type!` to tell keys of different maps apart.
 #[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct K(u64);
 
@@ -14,33 +151,125 @@ impl K {
     #[inline] pub fn version(&self) -> u32 { (self.0 >> 32) as u32 }
 }
 
+impl Key for K {
+    const MAX_INDEX: usize = u32::MAX as usize;
+    const MAX_VERSION: u32 = u32::MAX;
+    #[inline] fn with_index_and_version(i: usize, v: u32) -> Self { K::with_index_and_version(i, v) }
+    #[inline] fn index(&self) -> usize { K::index(self) }
+    #[inline] fn version(&self) -> u32 { K::version(self) }
+}
+
+/// A 16-bit index and a 16-bit version, packed into a `u32`, for when keys are stored in bulk (e.g in components).
+/// Maps keyed by it can't hold more than 2^16 slots, and their slots are retired after 2^16 reuses.
+#[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct K32(u32);
+
+impl Key for K32 {
+    const MAX_INDEX: usize = 0xffff;
+    const MAX_VERSION: u32 = 0xffff;
+    #[inline]
+    fn with_index_and_version(i: usize, v: u32) -> Self {
+        debug_assert!(i <= Self::MAX_INDEX && v <= Self::MAX_VERSION);
+        K32(i as u32 | (v << 16))
+    }
+    #[inline] fn index(&self) -> usize { (self.0 & 0xffff) as usize }
+    #[inline] fn version(&self) -> u32 { self.0 >> 16 }
+}
+
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 struct Info(u32);
 
 impl Info {
+    const MAX_VERSION: u32 = 0x7fffffff;
+
     #[inline] fn new_occupied() -> Self     { Info(0) }
     #[inline] fn version(&self) -> u32      {  self.0 & 0x7fffffff }
     #[inline] fn is_vacant(&self) -> bool   { (self.0 & 0x80000000) != 0 }
     #[inline] fn is_occupied(&self) -> bool { (self.0 & 0x80000000) == 0 }
     #[inline] fn make_vacant(&mut self)     { self.0 |= 0x80000000; }
-    #[inline] fn make_occupied_and_increment_version(&mut self) { self.0 += 1; self.0 &= 0x7fffffff; }
+    #[inline]
+    fn make_occupied_and_increment_version(&mut self) {
+        debug_assert!(self.version() < Info::MAX_VERSION, "Saturated slots must be retired, not reused");
+        self.0 += 1;
+        self.0 &= 0x7fffffff;
+    }
+    /// Whether the slot can be reused once vacant, i.e its version can be incremented without wrapping around.
+    #[inline] fn can_be_reused<K: Key>(&self) -> bool { self.version() < cmp::min(K::MAX_VERSION, Info::MAX_VERSION) }
 }
 
-#[derive(Debug, Default, Clone, Hash, PartialEq, Eq)]
-pub struct SparseMap<V> {
-    // Guarantee that all elements are tightly packed for fast iteration on all occupied slots.
-    items: Vec<V>,
+/// Values stay where they were inserted, and vacant slots stay in between, so iteration has to skip them.
+/// See `DenseSlotMap` for a variant which keeps values tightly packed.
+///
+/// `V` comes first so that the key type can default to `K`.
+pub struct SparseMap<V, K = self::K> {
+    // A slot is initialized if and only if its info says it is occupied.
+    slots: Vec<MaybeUninit<V>>,
     infos: Vec<Info>,
     frees: Vec<u32>,
+    retired: usize, // Vacant slots which are not in `frees`, because their version is saturated.
+    key_type: PhantomData<K>,
 }
 
-impl<V> Drop for SparseMap<V> {
+impl<V, K> Drop for SparseMap<V, K> {
     fn drop(&mut self) {
-        self.forget_vacant_items_before_dropping_everything();
+        for (slot, info) in self.slots.iter_mut().zip(&self.infos) {
+            if info.is_occupied() {
+                unsafe {
+                    ptr::drop_in_place(slot.as_mut_ptr());
+                }
+            }
+        }
+    }
+}
+
+impl<V, K: Key> Default for SparseMap<V, K> {
+    fn default() -> Self {
+        Self::with_key()
+    }
+}
+
+impl<V: Clone, K> Clone for SparseMap<V, K> {
+    fn clone(&self) -> Self {
+        let slots = self.slots.iter().zip(&self.infos).map(|(slot, info)| if info.is_occupied() {
+            MaybeUninit::new(unsafe { slot.assume_init_ref() }.clone())
+        } else {
+            MaybeUninit::uninit()
+        }).collect();
+        Self {
+            slots,
+            infos: self.infos.clone(),
+            frees: self.frees.clone(),
+            retired: self.retired,
+            key_type: PhantomData,
+        }
+    }
+}
+
+impl<V: fmt::Debug, K: Key> fmt::Debug for SparseMap<V, K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// Two maps are equal if the same keys (versions included) map to equal values; vacant slots don't matter.
+impl<V: PartialEq, K: Key> PartialEq for SparseMap<V, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<V: Eq, K: Key> Eq for SparseMap<V, K> {}
+
+impl<V: Hash, K: Key> Hash for SparseMap<V, K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for entry in self.iter() {
+            entry.hash(state);
+        }
     }
 }
 
-impl<V> Index<K> for SparseMap<V> {
+impl<V, K: Key> Index<K> for SparseMap<V, K> {
     type Output = V;
     #[inline]
     fn index(&self, k: K) -> &V {
@@ -48,30 +277,41 @@ impl<V> Index<K> for SparseMap<V> {
     }
 }
 
-impl<V> IndexMut<K> for SparseMap<V> {
+impl<V, K: Key> IndexMut<K> for SparseMap<V, K> {
     #[inline]
     fn index_mut(&mut self, k: K) -> &mut V {
         self.get_mut(k).unwrap()
     }
 }
 
-
 impl<V> SparseMap<V> {
     pub fn new() -> Self {
-        Self::with_capacity(0)
+        Self::with_key()
     }
     pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_and_key(cap)
+    }
+}
+
+impl<V, K: Key> SparseMap<V, K> {
+    /// Like `new()`, for a key type other than `K` (see `new_key_type!`).
+    pub fn with_key() -> Self {
+        Self::with_capacity_and_key(0)
+    }
+    pub fn with_capacity_and_key(cap: usize) -> Self {
         Self { 
-            items: Vec::with_capacity(cap),
+            slots: Vec::with_capacity(cap),
             infos: Vec::with_capacity(cap),
             frees: Vec::new(),
+            retired: 0,
+            key_type: PhantomData,
         }
     }
     pub fn capacity(&self) -> usize {
-        self.items.capacity()
+        self.slots.capacity()
     }
     pub fn reserve(&mut self, additional: usize) {
-        self.items.reserve(additional);
+        self.slots.reserve(additional);
         self.infos.reserve(additional);
     }
     pub fn reserve_free_indices(&mut self, additional: usize) {
@@ -83,13 +323,21 @@ impl<V> SparseMap<V> {
             Some(info) => info.is_occupied() && info.version() == k.version(),
         }
     }
+    /// # Safety
+    ///
+    /// `k` must be a key of this map, i.e `contains_key(k)` must be true.
     #[inline]
     pub unsafe fn get_unchecked(&self, k: K) -> &V {
-        &self.items[k.index()]
+        debug_assert!(self.contains_key(k));
+        self.slots.get_unchecked(k.index()).assume_init_ref()
     }
+    /// # Safety
+    ///
+    /// `k` must be a key of this map, i.e `contains_key(k)` must be true.
     #[inline]
     pub unsafe fn get_unchecked_mut(&mut self, k: K) -> &mut V {
-        &mut self.items[k.index()]
+        debug_assert!(self.contains_key(k));
+        self.slots.get_unchecked_mut(k.index()).assume_init_mut()
     }
     pub fn get(&self, k: K) -> Option<&V> {
         if self.contains_key(k) {
@@ -107,21 +355,16 @@ impl<V> SparseMap<V> {
     }
     pub fn remove(&mut self, k: K) -> Option<V> {
         if self.contains_key(k) {
-            let i = k.index();
-            self.infos[i].make_vacant();
-            self.frees.push(i as u32);
-            let item = unsafe { 
-                ptr::read(&self.items[i]) 
-            };
-            Some(item)
+            self.vacate(k.index()).map(|(_, v)| v)
         } else {
             None
         }
     }
     pub fn push(&mut self, v: V) -> K {
-        debug_assert_eq!(self.items.len(), self.infos.len());
-        let i = self.items.len();
-        self.items.push(v);
+        debug_assert_eq!(self.slots.len(), self.infos.len());
+        let i = self.slots.len();
+        assert!(i <= K::MAX_INDEX, "SparseMap can't hold more slots than its key type can index");
+        self.slots.push(MaybeUninit::new(v));
         self.infos.push(Info::new_occupied());
         K::with_index(i)
     }
@@ -133,78 +376,654 @@ impl<V> SparseMap<V> {
                 let info = &mut self.infos[i];
                 debug_assert!(info.is_vacant());
                 info.make_occupied_and_increment_version();
-                mem::forget(mem::replace(&mut self.items[i], v));
+                // The slot was vacant, so there's nothing to drop.
+                self.slots[i] = MaybeUninit::new(v);
                 K::with_index_and_version(i, info.version())
             },
         }
     }
-    pub fn keys(&self) -> Keys {
+    pub fn keys(&self) -> Keys<'_, K> {
         Keys::new(self)
     }
-    pub fn values(&self) -> Values<V> {
+    pub fn values(&self) -> Values<'_, V, K> {
         Values::new(self)
     }
-    pub fn values_mut(&mut self) -> ValuesMut<V> {
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V, K> {
         ValuesMut::new(self)
     }
-    pub fn iter(&self) -> Iter<V> {
+    pub fn iter(&self) -> Iter<'_, V, K> {
         Iter::new(self)
     }
-    pub fn iter_mut(&mut self) -> IterMut<V> {
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, K> {
         IterMut::new(self)
     }
-    pub fn len(&self) -> usize { self.items.len() - self.frees.len() }
+    pub fn len(&self) -> usize { self.slots.len() - self.frees.len() - self.retired }
     pub fn is_empty(&self) -> bool { self.len() == 0 }
+    /// Removes all values, but keeps slots (and their versions), so that old keys stay invalid.
     pub fn clear(&mut self) {
-        self.forget_vacant_items_before_dropping_everything();
-        self.items.clear();
-        self.infos.clear();
-        self.frees.clear();
+        for i in 0..self.slots.len() {
+            drop(self.vacate(i));
+        }
+        self.reuse_lower_indices_first();
+    }
+    /// Like `clear()`, but yields the removed values.
+    pub fn drain(&mut self) -> Drain<'_, V, K> {
+        Drain::new(self)
+    }
+    /// Removes the values for which `f` returns false, in index order.
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&mut self, mut f: F) {
+        for i in 0..self.slots.len() {
+            let info = self.infos[i];
+            if info.is_occupied() {
+                let k = K::with_index_and_version(i, info.version());
+                if !f(k, unsafe { self.get_unchecked_mut(k) }) {
+                    drop(self.vacate(i));
+                }
+            }
+        }
     }
-    fn forget_vacant_items_before_dropping_everything(&mut self) {
-        for free in &self.frees {
-            mem::forget(self.items.swap_remove(*free as _));
+    /// Takes the value out of slot `i`, if it is occupied.
+    /// The slot is vacant by the time the caller drops the value: if that panics, we're still in a consistent state.
+    fn vacate(&mut self, i: usize) -> Option<(K, V)> {
+        let info = &mut self.infos[i];
+        if info.is_vacant() {
+            return None;
         }
+        let k = K::with_index_and_version(i, info.version());
+        info.make_vacant();
+        if info.can_be_reused::<K>() {
+            self.frees.push(i as u32);
+        } else {
+            self.retired += 1;
+        }
+        // The slot is vacant now, so nobody else will read or drop this value.
+        Some((k, unsafe { self.slots[i].assume_init_read() }))
+    }
+    /// Only valid once every slot is vacant.
+    fn reuse_lower_indices_first(&mut self) {
+        debug_assert!(self.is_empty());
+        let infos = &self.infos;
+        self.frees.clear();
+        self.frees.extend((0..infos.len() as u32).rev().filter(|&i| infos[i as usize].can_be_reused::<K>()));
+    }
+    /// Number of slots which were retired because their version saturated. They are never reused, so that stale
+    /// keys stay invalid; they only cost memory.
+    pub fn retired_slot_count(&self) -> usize {
+        self.retired
     }
 }
 
-#[derive(Debug)]
-pub struct Keys<'a> {
-    infos: &'a [Info],
-    frees: &'a [u32],
+type Entries<'a, V> = iter::Enumerate<iter::Zip<slice::Iter<'a, Info>, slice::Iter<'a, MaybeUninit<V>>>>;
+type EntriesMut<'a, V> = iter::Enumerate<iter::Zip<slice::Iter<'a, Info>, slice::IterMut<'a, MaybeUninit<V>>>>;
+type IntoEntries<V> = iter::Enumerate<iter::Zip<vec::IntoIter<Info>, vec::IntoIter<MaybeUninit<V>>>>;
+
+#[derive(Debug, Clone)]
+pub struct Keys<'a, K = self::K> {
+    infos: iter::Enumerate<slice::Iter<'a, Info>>,
+    len: usize,
+    key_type: PhantomData<K>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Values<'a, V: 'a, K = self::K> {
+    iter: Iter<'a, V, K>,
 }
 
 #[derive(Debug)]
-pub struct Values<'a, V: 'a> {
-    c: &'a SparseMap<V>,
+pub struct ValuesMut<'a, V: 'a, K = self::K> {
+    iter: IterMut<'a, V, K>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Iter<'a, V: 'a, K = self::K> {
+    entries: Entries<'a, V>,
+    len: usize,
+    key_type: PhantomData<K>,
 }
 
 #[derive(Debug)]
-pub struct ValuesMut<'a, V: 'a> {
-    c: &'a mut SparseMap<V>,
+pub struct IterMut<'a, V: 'a, K = self::K> {
+    entries: EntriesMut<'a, V>,
+    len: usize,
+    key_type: PhantomData<K>,
 }
 
 #[derive(Debug)]
-pub struct Iter<'a, V: 'a> {
-    c: &'a SparseMap<V>,
+pub struct IntoIter<V, K: Key = self::K> {
+    entries: IntoEntries<V>,
+    len: usize,
+    key_type: PhantomData<K>,
 }
 
+/// Removes values as they are yielded. Dropping it removes the rest.
 #[derive(Debug)]
-pub struct IterMut<'a, V: 'a> {
-    c: &'a mut SparseMap<V>,
+pub struct Drain<'a, V: 'a, K: Key + 'a = self::K> {
+    c: &'a mut SparseMap<V, K>,
+    indices: ops::Range<usize>,
+    len: usize,
 }
 
-impl<'a> Keys<'a> { pub fn new<V>(c: &'a SparseMap<V>) -> Self { Self { infos: &c.infos, frees: &c.frees, } } }
-impl<'a, V> Values<'a, V> { pub fn new(c: &'a SparseMap<V>) -> Self { Self { c } } }
-impl<'a, V> ValuesMut<'a, V> { pub fn new(c: &'a mut SparseMap<V>) -> Self { Self { c } } }
-impl<'a, V> Iter<'a, V> { pub fn new(c: &'a SparseMap<V>) -> Self { Self { c } } }
-impl<'a, V> IterMut<'a, V> { pub fn new(c: &'a mut SparseMap<V>) -> Self { Self { c } } }
+impl<'a, K: Key> Keys<'a, K> { pub fn new<V>(c: &'a SparseMap<V, K>) -> Self { Self { infos: c.infos.iter().enumerate(), len: c.len(), key_type: PhantomData } } }
+impl<'a, V, K: Key> Values<'a, V, K> { pub fn new(c: &'a SparseMap<V, K>) -> Self { Self { iter: Iter::new(c) } } }
+impl<'a, V, K: Key> ValuesMut<'a, V, K> { pub fn new(c: &'a mut SparseMap<V, K>) -> Self { Self { iter: IterMut::new(c) } } }
+impl<'a, V, K: Key> Iter<'a, V, K> { pub fn new(c: &'a SparseMap<V, K>) -> Self { Self { entries: c.infos.iter().zip(c.slots.iter()).enumerate(), len: c.len(), key_type: PhantomData } } }
+impl<'a, V, K: Key> IterMut<'a, V, K> { pub fn new(c: &'a mut SparseMap<V, K>) -> Self { Self { len: c.len(), entries: c.infos.iter().zip(c.slots.iter_mut()).enumerate(), key_type: PhantomData } } }
+impl<'a, V, K: Key> Drain<'a, V, K> { pub fn new(c: &'a mut SparseMap<V, K>) -> Self { Self { indices: 0..c.slots.len(), len: c.len(), c } } }
+
+impl<'a, K: Key> Iterator for Keys<'a, K> {
+    type Item = K;
+    fn next(&mut self) -> Option<K> {
+        let (i, info) = self.infos.find(|(_, info)| info.is_occupied())?;
+        self.len -= 1;
+        Some(K::with_index_and_version(i, info.version()))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<'a, K: Key> DoubleEndedIterator for Keys<'a, K> {
+    fn next_back(&mut self) -> Option<K> {
+        let (i, info) = self.infos.rfind(|(_, info)| info.is_occupied())?;
+        self.len -= 1;
+        Some(K::with_index_and_version(i, info.version()))
+    }
+}
+
+impl<'a, V, K: Key> Iterator for Iter<'a, V, K> {
+    type Item = (K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (i, (info, slot)) = self.entries.find(|(_, (info, _))| info.is_occupied())?;
+        self.len -= 1;
+        Some((K::with_index_and_version(i, info.version()), unsafe { slot.assume_init_ref() }))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for Iter<'a, V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (i, (info, slot)) = self.entries.rfind(|(_, (info, _))| info.is_occupied())?;
+        self.len -= 1;
+        Some((K::with_index_and_version(i, info.version()), unsafe { slot.assume_init_ref() }))
+    }
+}
+
+impl<'a, V, K: Key> Iterator for IterMut<'a, V, K> {
+    type Item = (K, &'a mut V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (i, (info, slot)) = self.entries.find(|(_, (info, _))| info.is_occupied())?;
+        self.len -= 1;
+        Some((K::with_index_and_version(i, info.version()), unsafe { slot.assume_init_mut() }))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for IterMut<'a, V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (i, (info, slot)) = self.entries.rfind(|(_, (info, _))| info.is_occupied())?;
+        self.len -= 1;
+        Some((K::with_index_and_version(i, info.version()), unsafe { slot.assume_init_mut() }))
+    }
+}
+
+impl<V, K: Key> Iterator for IntoIter<V, K> {
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (i, (info, slot)) = self.entries.find(|(_, (info, _))| info.is_occupied())?;
+        self.len -= 1;
+        Some((K::with_index_and_version(i, info.version()), unsafe { slot.assume_init() }))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<V, K: Key> DoubleEndedIterator for IntoIter<V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (i, (info, slot)) = self.entries.rfind(|(_, (info, _))| info.is_occupied())?;
+        self.len -= 1;
+        Some((K::with_index_and_version(i, info.version()), unsafe { slot.assume_init() }))
+    }
+}
+
+impl<V, K: Key> Drop for IntoIter<V, K> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
+impl<'a, V, K: Key> Iterator for Drain<'a, V, K> {
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
+        for i in self.indices.by_ref() {
+            if let Some(entry) = self.c.vacate(i) {
+                self.len -= 1;
+                return Some(entry);
+            }
+        }
+        None
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for Drain<'a, V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(i) = self.indices.next_back() {
+            if let Some(entry) = self.c.vacate(i) {
+                self.len -= 1;
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+impl<'a, V, K: Key> Drop for Drain<'a, V, K> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+        self.c.reuse_lower_indices_first();
+    }
+}
+
+impl<'a, V, K: Key> Iterator for Values<'a, V, K> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<&'a V> { self.iter.next().map(|(_, v)| v) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for Values<'a, V, K> {
+    fn next_back(&mut self) -> Option<&'a V> { self.iter.next_back().map(|(_, v)| v) }
+}
+
+impl<'a, V, K: Key> Iterator for ValuesMut<'a, V, K> {
+    type Item = &'a mut V;
+    fn next(&mut self) -> Option<&'a mut V> { self.iter.next().map(|(_, v)| v) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for ValuesMut<'a, V, K> {
+    fn next_back(&mut self) -> Option<&'a mut V> { self.iter.next_back().map(|(_, v)| v) }
+}
+
+impl<'a, K: Key> ExactSizeIterator for Keys<'a, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for Values<'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for ValuesMut<'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for Iter<'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for IterMut<'a, V, K> {}
+impl<V, K: Key> ExactSizeIterator for IntoIter<V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for Drain<'a, V, K> {}
+
+impl<V, K: Key> IntoIterator for SparseMap<V, K> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<V, K>;
+    fn into_iter(mut self) -> IntoIter<V, K> {
+        let len = self.len();
+        // Our own drop then has nothing left to do.
+        let slots = mem::take(&mut self.slots);
+        let infos = mem::take(&mut self.infos);
+        IntoIter { entries: infos.into_iter().zip(slots).enumerate(), len, key_type: PhantomData }
+    }
+}
+
+impl<'a, V, K: Key> IntoIterator for &'a SparseMap<V, K> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, V, K>;
+    fn into_iter(self) -> Iter<'a, V, K> { self.iter() }
+}
+
+impl<'a, V, K: Key> IntoIterator for &'a mut SparseMap<V, K> {
+    type Item = (K, &'a mut V);
+    type IntoIter = IterMut<'a, V, K>;
+    fn into_iter(self) -> IterMut<'a, V, K> { self.iter_mut() }
+}
 
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    #[cfg(not(miri))] const OP_COUNT: usize = 5000;
+    #[cfg(miri)]      const OP_COUNT: usize = 200;
+
+    /// xorshift64*, so that failures are reproducible from the seed.
+    pub struct Rng(pub u64);
+
+    impl Rng {
+        pub fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+        pub fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Counts how many times values are dropped.
+    #[derive(Debug, Clone)]
+    pub struct Tracked {
+        pub value: u64,
+        pub drops: Rc<Cell<usize>>,
+    }
+
+    impl PartialEq for Tracked {
+        fn eq(&self, other: &Self) -> bool {
+            self.value == other.value
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn check_against_model(map: &SparseMap<Tracked>, model: &HashMap<K, u64>, dead_keys: &[K]) {
+        assert_eq!(map.len(), model.len());
+        assert_eq!(map.is_empty(), model.is_empty());
+        for (k, v) in model {
+            assert!(map.contains_key(*k));
+            assert_eq!(map[*k].value, *v);
+        }
+        for k in dead_keys {
+            assert!(!map.contains_key(*k), "{:?} was removed", k);
+            assert!(map.get(*k).is_none());
+        }
+        let entries: HashMap<K, u64> = map.iter().map(|(k, v)| (k, v.value)).collect();
+        assert_eq!(&entries, model);
+        assert_eq!(map.iter().len(), model.len());
+        assert!(map.keys().eq(map.iter().map(|(k, _)| k)));
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn matches_hashmap_model() {
+        for seed in 1..9 {
+            let mut rng = Rng(seed);
+            let drops = Rc::new(Cell::new(0));
+            let mut created = 0;
+            let mut map = SparseMap::new();
+            let mut model = HashMap::new();
+            let mut dead_keys = vec![];
+            for _ in 0..OP_COUNT {
+                match rng.below(10) {
+                    0..=3 => {
+                        let value = rng.next();
+                        created += 1;
+                        let v = Tracked { value, drops: drops.clone() };
+                        let k = if rng.below(4) == 0 { map.push(v) } else { map.insert(v) };
+                        assert!(model.insert(k, value).is_none(), "{:?} was handed out twice", k);
+                    },
+                    4..=6 if !model.is_empty() => {
+                        let k = *model.keys().nth(rng.below(model.len())).unwrap();
+                        let removed = map.remove(k).unwrap();
+                        assert_eq!(Some(removed.value), model.remove(&k));
+                        assert_eq!(map.remove(k), None);
+                        dead_keys.push(k);
+                    },
+                    7 if !model.is_empty() => {
+                        let k = *model.keys().nth(rng.below(model.len())).unwrap();
+                        let value = rng.next();
+                        map.get_mut(k).unwrap().value = value;
+                        model.insert(k, value);
+                    },
+                    8 => {
+                        let clone = map.clone();
+                        created += map.len();
+                        assert_eq!(clone, map);
+                    },
+                    9 if rng.below(20) == 0 => {
+                        map.clear();
+                        dead_keys.extend(model.drain().map(|(k, _)| k));
+                    },
+                    9 if rng.below(20) == 0 => {
+                        let drained: HashMap<K, u64> = map.drain().map(|(k, v)| (k, v.value)).collect();
+                        assert_eq!(drained, model);
+                        dead_keys.extend(model.drain().map(|(k, _)| k));
+                    },
+                    9 => {
+                        let modulo = 2 + rng.below(3) as u64;
+                        map.retain(|_, v| v.value % modulo != 0);
+                        dead_keys.extend(model.iter().filter(|&(_, v)| v % modulo == 0).map(|(k, _)| *k));
+                        model.retain(|_, v| *v % modulo != 0);
+                    },
+                    _ => {},
+                }
+                check_against_model(&map, &model, &dead_keys);
+                assert_eq!(created - drops.get(), map.len(), "Values were leaked or dropped twice");
+            }
+            drop(map);
+            assert_eq!(drops.get(), created);
+        }
+    }
+
+    #[test]
+    fn drops_each_value_once() {
+        let drops = Rc::new(Cell::new(0));
+        let tracked = |value| Tracked { value, drops: drops.clone() };
+        let mut map = SparseMap::new();
+        let keys: Vec<K> = (0..10).map(|i| map.insert(tracked(i))).collect();
+        for k in keys.iter().step_by(3) {
+            drop(map.remove(*k));
+        }
+        assert_eq!(drops.get(), 4);
+        // Reuses vacant slots, which must not drop anything
+        let reused: Vec<K> = (10..14).map(|i| map.insert(tracked(i))).collect();
+        assert_eq!(drops.get(), 4);
+        assert!(reused.iter().all(|k| k.version() == 1));
+        assert!(keys.iter().step_by(3).all(|k| !map.contains_key(*k)));
+
+        map.clear();
+        assert_eq!(drops.get(), 14);
+        assert!(map.is_empty());
+        assert!(keys.iter().chain(&reused).all(|k| !map.contains_key(*k)), "Old keys must stay invalid after clear()");
+        let k = map.insert(tracked(14));
+        assert_eq!(k.index(), 0);
+        drop(map);
+        assert_eq!(drops.get(), 15);
+    }
+
+    #[test]
+    fn panicking_drop_in_clear() {
+        struct PanicOnDrop(Rc<Cell<usize>>, bool);
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+                if self.1 {
+                    panic!("boom");
+                }
+            }
+        }
+        let drops = Rc::new(Cell::new(0));
+        let mut map = SparseMap::new();
+        for i in 0..5 {
+            map.insert(PanicOnDrop(drops.clone(), i == 2));
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| map.clear()));
+        assert!(result.is_err());
+        assert_eq!(drops.get(), 3);
+        assert_eq!(map.len(), 2);
+        drop(map);
+        assert_eq!(drops.get(), 5, "The remaining values are dropped exactly once");
+    }
+
+    #[test]
+    fn iterators_skip_vacant_slots() {
+        let mut map = SparseMap::new();
+        let keys: Vec<K> = (0..6).map(|i| map.insert(i)).collect();
+        map.remove(keys[0]);
+        map.remove(keys[3]);
+        map.remove(keys[5]);
+        let k = map.insert(30);
+        assert_eq!((k.index(), k.version()), (5, 1));
+
+        assert_eq!(map.keys().collect::<Vec<_>>(), [keys[1], keys[2], keys[4], k]);
+        assert_eq!(map.keys().rev().collect::<Vec<_>>(), [k, keys[4], keys[2], keys[1]]);
+        assert_eq!(map.values().cloned().collect::<Vec<_>>(), [1, 2, 4, 30]);
+        assert_eq!(map.iter().next_back(), Some((k, &30)));
+        let mut iter = map.iter();
+        assert_eq!(iter.len(), 4);
+        assert_eq!(iter.next(), Some((keys[1], &1)));
+        assert_eq!(iter.next_back(), Some((k, &30)));
+        assert_eq!(iter.len(), 2);
+        assert_eq!(iter.map(|(_, v)| *v).collect::<Vec<_>>(), [2, 4]);
+
+        for v in map.values_mut() {
+            *v *= 10;
+        }
+        for (_, v) in map.iter_mut().rev().take(1) {
+            *v += 1;
+        }
+        for (k, v) in &mut map {
+            *v += k.index() as i32;
+        }
+        assert_eq!((&map).into_iter().map(|(_, v)| *v).collect::<Vec<_>>(), [11, 22, 44, 306]);
+        assert_eq!(map.into_iter().collect::<Vec<_>>(), [(keys[1], 11), (keys[2], 22), (keys[4], 44), (k, 306)]);
+    }
+
+    #[test]
+    fn into_iter_and_drain_drop_the_rest() {
+        let drops = Rc::new(Cell::new(0));
+        let tracked = |value| Tracked { value, drops: drops.clone() };
+        let mut map = SparseMap::new();
+        let keys: Vec<K> = (0..8).map(|i| map.insert(tracked(i))).collect();
+        map.remove(keys[1]);
+        assert_eq!(drops.get(), 1);
+
+        let mut into_iter = map.clone().into_iter();
+        assert_eq!(into_iter.len(), 7);
+        assert_eq!(into_iter.next_back().map(|(k, v)| (k, v.value)), Some((keys[7], 7)));
+        drop(into_iter);
+        assert_eq!(drops.get(), 8);
+
+        {
+            let mut drain = map.drain();
+            assert_eq!(drain.next().map(|(k, v)| (k, v.value)), Some((keys[0], 0)));
+            assert_eq!(drain.next_back().map(|(k, v)| (k, v.value)), Some((keys[7], 7)));
+            assert_eq!(drain.len(), 5);
+        }
+        assert_eq!(drops.get(), 15);
+        assert!(map.is_empty());
+        assert!(keys.iter().all(|k| !map.contains_key(*k)));
+        assert_eq!(map.insert(tracked(8)).index(), 0);
+
+        for i in 9..12 {
+            map.insert(tracked(i));
+        }
+        map.retain(|k, v| k.index() != 1 && v.value != 10);
+        assert_eq!(map.values().map(|v| v.value).collect::<Vec<_>>(), [8, 11]);
+        assert_eq!(drops.get(), 17);
+    }
+
+    #[test]
+    fn equality_ignores_vacant_slots() {
+        let mut a = SparseMap::new();
+        let mut b = SparseMap::new();
+        let k = a.insert(String::from("x"));
+        assert_eq!(b.insert(String::from("x")), k);
+        a.insert(String::from("y"));
+        assert_ne!(a, b);
+        a.remove(K::with_index(1));
+        assert_eq!(a, b);
+        assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        b[k].push('!');
+        assert_ne!(a, b);
+    }
+
+    new_key_type! {
+        struct TextureKey;
+        /// Doc comments are forwarded.
+        pub(crate) struct MeshKey;
+    }
+
+    #[test]
+    fn typed_keys() {
+        let mut textures: SparseMap<&str, TextureKey> = SparseMap::with_key();
+        let mut meshes: DenseSlotMap<&str, MeshKey> = DenseSlotMap::with_capacity_and_key(4);
+        let mut lods: SecondaryMap<u8, MeshKey> = SecondaryMap::default();
+        let grass = textures.insert("grass.png");
+        let rock = textures.insert("rock.png");
+        let cube = meshes.insert("cube.obj");
+        lods.insert(cube, 3);
+        assert_eq!(textures[rock], "rock.png");
+        assert_eq!(lods[cube], 3);
+        assert!(textures.keys().eq(vec![grass, rock]));
+        assert_eq!(textures.remove(grass), Some("grass.png"));
+        assert!(!textures.contains_key(grass));
+        assert_eq!(cube, MeshKey::with_index(0));
+
+        // Conversions to and from the untyped key, for when the type is known elsewhere
+        let untyped: K = rock.into();
+        assert_eq!((untyped.index(), untyped.version()), (rock.index(), rock.version()));
+        assert_eq!(textures.get(TextureKey::from(untyped)), Some(&"rock.png"));
+    }
+
+    /// Saturates after 3 reuses, so that tests can reach it quickly.
+    #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+    pub struct TinyKey(K32);
+
+    impl Key for TinyKey {
+        const MAX_INDEX: usize = 7;
+        const MAX_VERSION: u32 = 3;
+        fn with_index_and_version(i: usize, v: u32) -> Self { TinyKey(K32::with_index_and_version(i, v)) }
+        fn index(&self) -> usize { self.0.index() }
+        fn version(&self) -> u32 { self.0.version() }
+    }
+
+    #[test]
+    fn saturated_slots_are_retired() {
+        let mut map: SparseMap<u32, TinyKey> = SparseMap::with_key();
+        let mut stale = vec![];
+        for i in 0..4 {
+            let k = map.insert(i);
+            assert_eq!((k.index(), k.version()), (0, i));
+            assert_eq!(map.remove(k), Some(i));
+            stale.push(k);
+        }
+        assert_eq!(map.retired_slot_count(), 1);
+        assert!(map.is_empty());
+        let k = map.insert(4);
+        assert_eq!((k.index(), k.version()), (1, 0), "Slot 0 is never reused");
+        map.insert(5);
+        map.clear();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.insert(6).index(), 1, "clear() doesn't bring retired slots back");
+        assert!(stale.iter().all(|k| !map.contains_key(*k) && map.get(*k).is_none()));
+        assert_eq!(map.keys().count(), map.len());
+        assert_eq!(map.clone().retired_slot_count(), 1);
+        drop(map.drain());
+        assert_eq!(map.insert(7).index(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't hold more slots than its key type can index")]
+    fn index_overflow() {
+        let mut map: SparseMap<u32, TinyKey> = SparseMap::with_key();
+        for i in 0..9 {
+            map.insert(i);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // Too slow
+    fn k32_saturates_after_2_pow_16_reuses() {
+        assert_eq!(mem::size_of::<K32>(), 4);
+        let k = K32::with_index_and_version(0xffff, 0xabcd);
+        assert_eq!((k.index(), k.version()), (0xffff, 0xabcd));
+
+        let mut map: DenseSlotMap<(), K32> = DenseSlotMap::with_key();
+        let first = map.insert(());
+        let mut last = first;
+        for _ in 0..K32::MAX_VERSION {
+            map.remove(last);
+            last = map.insert(());
+            assert_eq!(last.index(), 0);
+        }
+        assert_eq!(last.version(), K32::MAX_VERSION);
+        map.remove(last);
+        assert_eq!(map.retired_slot_count(), 1);
+        let k = map.insert(());
+        assert_eq!((k.index(), k.version()), (1, 0));
+        assert!(!map.contains_key(first) && !map.contains_key(last));
     }
 }
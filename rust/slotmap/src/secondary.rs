@@ -0,0 +1,298 @@
+use std::iter;
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+use std::slice;
+
+use super::Key;
+
+/// Attaches extra data to keys issued by a primary map (`SparseMap` or `DenseSlotMap`), without touching the primary
+/// map's value type.
+///
+/// Storage is a `Vec` indexed like the primary map's slots, so lookups are one array access, and memory is
+/// proportional to the highest key index. See `SparseSecondaryMap` for data only few keys have.
+///
+/// Each slot remembers the version of the last key it saw: stale keys (i.e removed from the primary map, then
+/// reused) are rejected, as long as the newer key has been inserted in this map too.
+#[derive(Debug, Clone)]
+pub struct SecondaryMap<V, K = super::K> {
+    slots: Vec<Slot<V>>,
+    len: usize,
+    key_type: PhantomData<K>,
+}
+
+#[derive(Debug, Clone)]
+struct Slot<V> {
+    version: u32,
+    value: Option<V>,
+}
+
+impl<V, K: Key> Default for SecondaryMap<V, K> {
+    fn default() -> Self {
+        Self::with_key()
+    }
+}
+
+impl<V, K: Key> Index<K> for SecondaryMap<V, K> {
+    type Output = V;
+    #[inline]
+    fn index(&self, k: K) -> &V {
+        self.get(k).unwrap()
+    }
+}
+
+impl<V, K: Key> IndexMut<K> for SecondaryMap<V, K> {
+    #[inline]
+    fn index_mut(&mut self, k: K) -> &mut V {
+        self.get_mut(k).unwrap()
+    }
+}
+
+impl<V> SecondaryMap<V> {
+    pub fn new() -> Self {
+        Self::with_key()
+    }
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_and_key(cap)
+    }
+}
+
+impl<V, K: Key> SecondaryMap<V, K> {
+    /// Like `new()`, for a key type other than `K` (see `new_key_type!`).
+    pub fn with_key() -> Self {
+        Self::with_capacity_and_key(0)
+    }
+    pub fn with_capacity_and_key(cap: usize) -> Self {
+        Self { slots: Vec::with_capacity(cap), len: 0, key_type: PhantomData }
+    }
+    pub fn capacity(&self) -> usize {
+        self.slots.capacity()
+    }
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+    pub fn contains_key(&self, k: K) -> bool {
+        self.get(k).is_some()
+    }
+    /// Returns the previous value for this exact key, if any.
+    /// Does nothing and returns `None` if `k` is older than a key which was previously inserted at the same index.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let i = k.index();
+        if i >= self.slots.len() {
+            let missing = i + 1 - self.slots.len();
+            self.slots.extend(iter::repeat_with(|| Slot { version: 0, value: None }).take(missing));
+        }
+        let slot = &mut self.slots[i];
+        if k.version() < slot.version {
+            return None;
+        }
+        let is_same_key = k.version() == slot.version;
+        slot.version = k.version();
+        match slot.value.replace(v) {
+            Some(old) if is_same_key => Some(old),
+            None => {
+                self.len += 1;
+                None
+            },
+            // A value left behind by a stale key. Not returned, since it belongs to another key.
+            Some(_) => None,
+        }
+    }
+    pub fn remove(&mut self, k: K) -> Option<V> {
+        let slot = self.slots.get_mut(k.index())?;
+        if slot.version != k.version() {
+            return None;
+        }
+        let v = slot.value.take()?;
+        self.len -= 1;
+        Some(v)
+    }
+    pub fn get(&self, k: K) -> Option<&V> {
+        match self.slots.get(k.index()) {
+            Some(slot) if slot.version == k.version() => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        match self.slots.get_mut(k.index()) {
+            Some(slot) if slot.version == k.version() => slot.value.as_mut(),
+            _ => None,
+        }
+    }
+    /// Removes all values, but remembers versions, so that stale keys stay rejected.
+    pub fn clear(&mut self) {
+        for slot in &mut self.slots {
+            slot.value = None;
+        }
+        self.len = 0;
+    }
+    /// Removes the values for which `f` returns false, in index order.
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&mut self, mut f: F) {
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            let keep = match slot.value {
+                Some(ref mut v) => f(K::with_index_and_version(i, slot.version), v),
+                None => true,
+            };
+            if !keep {
+                slot.value = None;
+                self.len -= 1;
+            }
+        }
+    }
+    pub fn iter(&self) -> Iter<'_, V, K> {
+        Iter { slots: self.slots.iter().enumerate(), len: self.len, key_type: PhantomData }
+    }
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, K> {
+        IterMut { slots: self.slots.iter_mut().enumerate(), len: self.len, key_type: PhantomData }
+    }
+    pub fn keys(&self) -> Keys<'_, V, K> {
+        Keys { iter: self.iter() }
+    }
+    pub fn values(&self) -> Values<'_, V, K> {
+        Values { iter: self.iter() }
+    }
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V, K> {
+        ValuesMut { iter: self.iter_mut() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Iter<'a, V: 'a, K = super::K> {
+    slots: iter::Enumerate<slice::Iter<'a, Slot<V>>>,
+    len: usize,
+    key_type: PhantomData<K>,
+}
+
+#[derive(Debug)]
+pub struct IterMut<'a, V: 'a, K = super::K> {
+    slots: iter::Enumerate<slice::IterMut<'a, Slot<V>>>,
+    len: usize,
+    key_type: PhantomData<K>,
+}
+
+#[derive(Debug, Clone)] pub struct Keys     <'a, V: 'a, K = super::K> { iter: Iter   <'a, V, K>, }
+#[derive(Debug, Clone)] pub struct Values   <'a, V: 'a, K = super::K> { iter: Iter   <'a, V, K>, }
+#[derive(Debug)]        pub struct ValuesMut<'a, V: 'a, K = super::K> { iter: IterMut<'a, V, K>, }
+
+impl<'a, V, K: Key> Iterator for Iter<'a, V, K> {
+    type Item = (K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.slots.by_ref().find_map(|(i, slot)| Some((K::with_index_and_version(i, slot.version), slot.value.as_ref()?)))?;
+        self.len -= 1;
+        Some(entry)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for Iter<'a, V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let entry = self.slots.by_ref().rev().find_map(|(i, slot)| Some((K::with_index_and_version(i, slot.version), slot.value.as_ref()?)))?;
+        self.len -= 1;
+        Some(entry)
+    }
+}
+
+impl<'a, V, K: Key> Iterator for IterMut<'a, V, K> {
+    type Item = (K, &'a mut V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.slots.by_ref().find_map(|(i, slot)| Some((K::with_index_and_version(i, slot.version), slot.value.as_mut()?)))?;
+        self.len -= 1;
+        Some(entry)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { (self.len, Some(self.len)) }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for IterMut<'a, V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let entry = self.slots.by_ref().rev().find_map(|(i, slot)| Some((K::with_index_and_version(i, slot.version), slot.value.as_mut()?)))?;
+        self.len -= 1;
+        Some(entry)
+    }
+}
+
+impl<'a, V, K: Key> Iterator for Keys<'a, V, K> {
+    type Item = K;
+    fn next(&mut self) -> Option<K> { self.iter.next().map(|(k, _)| k) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> Iterator for Values<'a, V, K> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<&'a V> { self.iter.next().map(|(_, v)| v) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> Iterator for ValuesMut<'a, V, K> {
+    type Item = &'a mut V;
+    fn next(&mut self) -> Option<&'a mut V> { self.iter.next().map(|(_, v)| v) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> DoubleEndedIterator for Keys     <'a, V, K> { fn next_back(&mut self) -> Option<K>          { self.iter.next_back().map(|(k, _)| k) } }
+impl<'a, V, K: Key> DoubleEndedIterator for Values   <'a, V, K> { fn next_back(&mut self) -> Option<&'a V>     { self.iter.next_back().map(|(_, v)| v) } }
+impl<'a, V, K: Key> DoubleEndedIterator for ValuesMut<'a, V, K> { fn next_back(&mut self) -> Option<&'a mut V> { self.iter.next_back().map(|(_, v)| v) } }
+
+impl<'a, V, K: Key> ExactSizeIterator for Iter     <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for IterMut  <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for Keys     <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for Values   <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for ValuesMut<'a, V, K> {}
+
+impl<'a, V, K: Key> IntoIterator for &'a SecondaryMap<V, K> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, V, K>;
+    fn into_iter(self) -> Iter<'a, V, K> { self.iter() }
+}
+
+impl<'a, V, K: Key> IntoIterator for &'a mut SecondaryMap<V, K> {
+    type Item = (K, &'a mut V);
+    type IntoIter = IterMut<'a, V, K>;
+    fn into_iter(self) -> IterMut<'a, V, K> { self.iter_mut() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SparseMap;
+
+    #[test]
+    fn attaches_data_to_primary_keys() {
+        let mut names = SparseMap::new();
+        let mut healths = SecondaryMap::new();
+        let a = names.insert("a");
+        let b = names.insert("b");
+        let c = names.insert("c");
+        assert_eq!(healths.insert(c, 30), None);
+        assert_eq!(healths.insert(a, 10), None);
+        assert_eq!(healths.insert(a, 11), Some(10));
+        assert_eq!(healths.len(), 2);
+        assert!(!healths.contains_key(b));
+        assert_eq!(healths.iter().collect::<Vec<_>>(), [(a, &11), (c, &30)]);
+        assert_eq!(healths.keys().rev().collect::<Vec<_>>(), [c, a]);
+
+        // `a` is removed from the primary map, and its slot reused
+        names.remove(a);
+        let d = names.insert("d");
+        assert_eq!(d.index(), a.index());
+        assert_eq!(healths.get(d), None);
+        assert_eq!(healths.insert(d, 40), None, "The stale value belongs to `a`");
+        assert_eq!(healths.len(), 2);
+        assert_eq!(healths.get(a), None);
+        assert_eq!(healths.insert(a, 12), None, "Stale keys are rejected");
+        assert_eq!(healths.remove(a), None);
+        assert_eq!(healths[d], 40);
+
+        for v in healths.values_mut() {
+            *v += 1;
+        }
+        healths.retain(|k, _| k != c);
+        assert_eq!(healths.values().collect::<Vec<_>>(), [&41]);
+        assert_eq!(healths.remove(d), Some(41));
+        assert!(healths.is_empty());
+        assert_eq!(healths.insert(a, 12), None);
+        assert!(!healths.contains_key(a), "Versions are remembered after removal");
+
+        healths.insert(c, 31);
+        healths.clear();
+        assert!(healths.is_empty() && healths.get(c).is_none());
+    }
+}
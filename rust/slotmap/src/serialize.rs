@@ -0,0 +1,273 @@
+//! `Serialize` and `Deserialize` for keys and `SparseMap`, behind the `serde` feature.
+//!
+//! A map is saved slot by slot, with versions, vacant slots and the free list, so that keys saved elsewhere (e.g in
+//! a save game) refer to the same values once it is loaded, stale keys stay stale, and the next insertions return
+//! the same keys as they would have before saving.
+//!
+//! Human-readable formats get one `{ version, value }` entry per slot, `value` being `null` for vacant slots.
+//! Other formats get a compact layout instead: the raw slot infos (one `u32` each, vacant bit included), followed by
+//! the values of occupied slots only, in index order, then the free list.
+//!
+//! Keys are saved as the integer they pack their index and version into.
+
+use std::mem::MaybeUninit;
+
+use __serde::de::{self, Deserializer};
+use __serde::ser::{SerializeStruct, Serializer};
+use __serde::{Deserialize, Serialize};
+
+use super::{Info, Key, SparseMap, K, K32};
+
+impl Serialize for K {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for K {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(K)
+    }
+}
+
+impl Serialize for K32 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for K32 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(K32)
+    }
+}
+
+impl Serialize for Info {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Info {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(Info)
+    }
+}
+
+#[derive(Serialize)]
+struct SlotRef<'a, V: 'a> {
+    version: u32,
+    value: Option<&'a V>,
+}
+
+#[derive(Deserialize)]
+struct Slot<V> {
+    version: u32,
+    value: Option<V>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "SparseMap")]
+struct Readable<V> {
+    slots: Vec<Slot<V>>,
+    frees: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "SparseMap")]
+struct Compact<V> {
+    infos: Vec<Info>,
+    values: Vec<V>,
+    frees: Vec<u32>,
+}
+
+// Serialized as sequences, straight from the map.
+struct Slots<'a, V: 'a, K: 'a>(&'a SparseMap<V, K>);
+struct Values<'a, V: 'a, K: 'a>(&'a SparseMap<V, K>);
+
+impl<'a, V: Serialize, K: Key> Serialize for Slots<'a, V, K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.infos.iter().enumerate().map(|(i, info)| SlotRef {
+            version: info.version(),
+            value: if info.is_occupied() { Some(unsafe { self.0.slots[i].assume_init_ref() }) } else { None },
+        }))
+    }
+}
+
+impl<'a, V: Serialize, K: Key> Serialize for Values<'a, V, K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.values())
+    }
+}
+
+impl<V: Serialize, K: Key> Serialize for SparseMap<V, K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let is_human_readable = serializer.is_human_readable();
+        let mut state = serializer.serialize_struct("SparseMap", if is_human_readable { 2 } else { 3 })?;
+        if is_human_readable {
+            state.serialize_field("slots", &Slots(self))?;
+        } else {
+            state.serialize_field("infos", &self.infos)?;
+            state.serialize_field("values", &Values(self))?;
+        }
+        state.serialize_field("frees", &self.frees)?;
+        state.end()
+    }
+}
+
+impl<'de, V: Deserialize<'de>, K: Key> Deserialize<'de> for SparseMap<V, K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let Readable { slots, frees } = Readable::deserialize(deserializer)?;
+            let mut infos = Vec::with_capacity(slots.len());
+            let mut values = Vec::with_capacity(slots.len());
+            for slot in slots {
+                if slot.version > Info::MAX_VERSION {
+                    return Err(de::Error::custom(format_args!("slot version {} is too large", slot.version)));
+                }
+                let mut info = Info(slot.version);
+                match slot.value {
+                    Some(v) => values.push(v),
+                    None => info.make_vacant(),
+                }
+                infos.push(info);
+            }
+            from_parts(infos, values, frees)
+        } else {
+            let Compact { infos, values, frees } = Compact::deserialize(deserializer)?;
+            from_parts(infos, values, frees)
+        }
+    }
+}
+
+/// Rebuilds a map from its slot infos, the values of occupied slots in index order, and its free list, which must be
+/// consistent with one another.
+fn from_parts<V, K: Key, E: de::Error>(infos: Vec<Info>, values: Vec<V>, frees: Vec<u32>) -> Result<SparseMap<V, K>, E> {
+    if infos.len() > K::MAX_INDEX + 1 {
+        return Err(E::invalid_length(infos.len(), &"no more slots than the key type can index"));
+    }
+    let occupied = infos.iter().filter(|info| info.is_occupied()).count();
+    if values.len() != occupied {
+        return Err(E::invalid_length(values.len(), &format!("one value per occupied slot ({})", occupied).as_str()));
+    }
+    // Values are moved in one by one, so that everything is dropped properly if we bail out.
+    let mut map = SparseMap::<V, K>::with_capacity_and_key(infos.len());
+    let mut values = values.into_iter();
+    for info in infos {
+        if info.version() > K::MAX_VERSION {
+            return Err(E::custom(format_args!("slot version {} is too large for the key type", info.version())));
+        }
+        map.slots.push(if info.is_occupied() { MaybeUninit::new(values.next().unwrap()) } else { MaybeUninit::uninit() });
+        map.infos.push(info);
+    }
+
+    let mut is_free = vec![false; map.infos.len()];
+    for &i in &frees {
+        match map.infos.get(i as usize) {
+            None => return Err(E::custom(format_args!("free slot {} is out of bounds", i))),
+            Some(info) if info.is_occupied() => return Err(E::custom(format_args!("free slot {} is occupied", i))),
+            Some(info) if !info.can_be_reused::<K>() => return Err(E::custom(format_args!("free slot {} is saturated", i))),
+            Some(_) if is_free[i as usize] => return Err(E::custom(format_args!("slot {} is free twice", i))),
+            Some(_) => is_free[i as usize] = true,
+        }
+    }
+    for (i, info) in map.infos.iter().enumerate() {
+        if info.is_vacant() && !is_free[i] {
+            if info.can_be_reused::<K>() {
+                return Err(E::custom(format_args!("vacant slot {} is neither free nor saturated", i)));
+            }
+            map.retired += 1;
+        }
+    }
+    map.frees = frees;
+    Ok(map)
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate bincode;
+    extern crate serde_json;
+
+    use super::*;
+    use new_key_type;
+    use tests::TinyKey;
+
+    fn churned_map() -> (SparseMap<String>, Vec<K>) {
+        let mut map = SparseMap::new();
+        let keys: Vec<K> = (0..6).map(|i| map.insert(i.to_string())).collect();
+        map.remove(keys[1]);
+        map.remove(keys[4]);
+        map.remove(keys[2]);
+        map.insert("reused".to_string());
+        (map, keys)
+    }
+
+    fn check_round_trip(map: &SparseMap<String>, loaded: &mut SparseMap<String>, keys: &[K]) {
+        assert_eq!(loaded, map);
+        assert_eq!(loaded.len(), map.len());
+        for k in keys {
+            assert_eq!(loaded.get(*k), map.get(*k), "{:?} must resolve the same way", k);
+        }
+        let mut map = map.clone();
+        for _ in 0..3 {
+            assert_eq!(loaded.insert("new".to_string()), map.insert("new".to_string()), "The free list must be kept");
+        }
+    }
+
+    #[test]
+    fn round_trips_through_text_and_binary() {
+        let (map, keys) = churned_map();
+
+        let json = serde_json::to_string(&map).unwrap();
+        assert!(json.starts_with(r#"{"slots":[{"version":0,"value":"0"},{"version":0,"value":null},"#), "{}", json);
+        let mut loaded: SparseMap<String> = serde_json::from_str(&json).unwrap();
+        check_round_trip(&map, &mut loaded, &keys);
+
+        let bytes = bincode::serialize(&map).unwrap();
+        let mut loaded: SparseMap<String> = bincode::deserialize(&bytes).unwrap();
+        check_round_trip(&map, &mut loaded, &keys);
+
+        let keys_json = serde_json::to_string(&keys).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<K>>(&keys_json).unwrap(), keys);
+        new_key_type! { struct SaveKey; }
+        let k = SaveKey::from(keys[3]);
+        assert_eq!(serde_json::to_string(&k).unwrap(), serde_json::to_string(&keys[3]).unwrap());
+        assert_eq!(serde_json::from_str::<SaveKey>(&serde_json::to_string(&k).unwrap()).unwrap(), k);
+        assert_eq!(bincode::serialize(&K32::with_index_and_version(1, 2)).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn keeps_retired_slots_retired() {
+        let mut map: SparseMap<u8, TinyKey> = SparseMap::with_key();
+        for _ in 0..=TinyKey::MAX_VERSION {
+            let k = map.insert(0);
+            map.remove(k);
+        }
+        map.insert(1);
+        assert_eq!(map.retired_slot_count(), 1);
+        let mut loaded: SparseMap<u8, TinyKey> = serde_json::from_str(&serde_json::to_string(&map).unwrap()).unwrap();
+        assert_eq!(loaded.retired_slot_count(), 1);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.insert(2).index(), 2);
+    }
+
+    #[test]
+    fn rejects_inconsistent_input() {
+        let load = |json: &str| serde_json::from_str::<SparseMap<u8>>(json).unwrap_err().to_string();
+        let slots = r#"[{"version":1,"value":7},{"version":2,"value":null}]"#;
+        assert!(serde_json::from_str::<SparseMap<u8>>(&format!(r#"{{"slots":{},"frees":[1]}}"#, slots)).is_ok());
+        assert!(load(&format!(r#"{{"slots":{},"frees":[0]}}"#, slots)).contains("free slot 0 is occupied"));
+        assert!(load(&format!(r#"{{"slots":{},"frees":[1,1]}}"#, slots)).contains("slot 1 is free twice"));
+        assert!(load(&format!(r#"{{"slots":{},"frees":[2]}}"#, slots)).contains("out of bounds"));
+        assert!(load(&format!(r#"{{"slots":{},"frees":[]}}"#, slots)).contains("neither free nor saturated"));
+        assert!(load(r#"{"slots":[{"version":2147483648,"value":1}],"frees":[]}"#).contains("too large"));
+
+        // Compact layout, with one value too many
+        let bytes = bincode::serialize(&(vec![0u32, 0x80000000], vec![1u8, 2], vec![1u32])).unwrap();
+        let error = bincode::deserialize::<SparseMap<u8>>(&bytes).unwrap_err().to_string();
+        assert!(error.contains("one value per occupied slot (1)"), "{}", error);
+        let bytes = bincode::serialize(&(vec![0u32, 0x80000000], vec![1u8], vec![1u32])).unwrap();
+        assert_eq!(bincode::deserialize::<SparseMap<u8>>(&bytes).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::hash_map::{self, HashMap, Entry};
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+
+use super::Key;
+
+/// Like `SecondaryMap`, but hash-based, so memory is proportional to the number of values rather than to the
+/// highest key index. Suits data which only few keys have.
+///
+/// Stale keys are rejected while a newer key's value is present; unlike `SecondaryMap`, versions are forgotten on
+/// removal.
+#[derive(Debug, Clone)]
+pub struct SparseSecondaryMap<V, K = super::K> {
+    slots: HashMap<u32, (u32, V)>, // Key index -> (key version, value)
+    key_type: PhantomData<K>,
+}
+
+impl<V, K: Key> Default for SparseSecondaryMap<V, K> {
+    fn default() -> Self {
+        Self::with_key()
+    }
+}
+
+impl<V, K: Key> Index<K> for SparseSecondaryMap<V, K> {
+    type Output = V;
+    #[inline]
+    fn index(&self, k: K) -> &V {
+        self.get(k).unwrap()
+    }
+}
+
+impl<V, K: Key> IndexMut<K> for SparseSecondaryMap<V, K> {
+    #[inline]
+    fn index_mut(&mut self, k: K) -> &mut V {
+        self.get_mut(k).unwrap()
+    }
+}
+
+impl<V> SparseSecondaryMap<V> {
+    pub fn new() -> Self {
+        Self::with_key()
+    }
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_and_key(cap)
+    }
+}
+
+impl<V, K: Key> SparseSecondaryMap<V, K> {
+    /// Like `new()`, for a key type other than `K` (see `new_key_type!`).
+    pub fn with_key() -> Self {
+        Self::with_capacity_and_key(0)
+    }
+    pub fn with_capacity_and_key(cap: usize) -> Self {
+        Self { slots: HashMap::with_capacity(cap), key_type: PhantomData }
+    }
+    pub fn capacity(&self) -> usize {
+        self.slots.capacity()
+    }
+    pub fn len(&self) -> usize { self.slots.len() }
+    pub fn is_empty(&self) -> bool { self.slots.is_empty() }
+    pub fn contains_key(&self, k: K) -> bool {
+        self.get(k).is_some()
+    }
+    /// Returns the previous value for this exact key, if any.
+    /// Does nothing and returns `None` if a value is present for a newer key at the same index.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        match self.slots.entry(k.index() as u32) {
+            Entry::Vacant(entry) => {
+                entry.insert((k.version(), v));
+                None
+            },
+            Entry::Occupied(mut entry) => {
+                let version = entry.get().0;
+                if k.version() < version {
+                    return None;
+                }
+                let (_, old) = entry.insert((k.version(), v));
+                // A value left behind by a stale key isn't returned, since it belongs to another key.
+                if version == k.version() { Some(old) } else { None }
+            },
+        }
+    }
+    pub fn remove(&mut self, k: K) -> Option<V> {
+        match self.slots.entry(k.index() as u32) {
+            Entry::Occupied(entry) if entry.get().0 == k.version() => Some(entry.remove().1),
+            _ => None,
+        }
+    }
+    pub fn get(&self, k: K) -> Option<&V> {
+        match self.slots.get(&(k.index() as u32)) {
+            Some(&(version, ref v)) if version == k.version() => Some(v),
+            _ => None,
+        }
+    }
+    pub fn get_mut(&mut self, k: K) -> Option<&mut V> {
+        match self.slots.get_mut(&(k.index() as u32)) {
+            Some(&mut (version, ref mut v)) if version == k.version() => Some(v),
+            _ => None,
+        }
+    }
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+    /// Removes the values for which `f` returns false, in arbitrary order.
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&mut self, mut f: F) {
+        self.slots.retain(|&i, &mut (version, ref mut v)| f(K::with_index_and_version(i as usize, version), v));
+    }
+    /// In arbitrary order.
+    pub fn iter(&self) -> Iter<'_, V, K> {
+        Iter { slots: self.slots.iter(), key_type: PhantomData }
+    }
+    /// In arbitrary order.
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, K> {
+        IterMut { slots: self.slots.iter_mut(), key_type: PhantomData }
+    }
+    pub fn keys(&self) -> Keys<'_, V, K> {
+        Keys { iter: self.iter() }
+    }
+    pub fn values(&self) -> Values<'_, V, K> {
+        Values { iter: self.iter() }
+    }
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V, K> {
+        ValuesMut { iter: self.iter_mut() }
+    }
+}
+
+#[derive(Debug, Clone)] pub struct Iter     <'a, V: 'a, K = super::K> { slots: hash_map::Iter   <'a, u32, (u32, V)>, key_type: PhantomData<K>, }
+#[derive(Debug)]        pub struct IterMut  <'a, V: 'a, K = super::K> { slots: hash_map::IterMut<'a, u32, (u32, V)>, key_type: PhantomData<K>, }
+#[derive(Debug, Clone)] pub struct Keys     <'a, V: 'a, K = super::K> { iter: Iter   <'a, V, K>, }
+#[derive(Debug, Clone)] pub struct Values   <'a, V: 'a, K = super::K> { iter: Iter   <'a, V, K>, }
+#[derive(Debug)]        pub struct ValuesMut<'a, V: 'a, K = super::K> { iter: IterMut<'a, V, K>, }
+
+impl<'a, V, K: Key> Iterator for Iter<'a, V, K> {
+    type Item = (K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.slots.next().map(|(&i, &(version, ref v))| (K::with_index_and_version(i as usize, version), v))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.slots.size_hint() }
+}
+
+impl<'a, V, K: Key> Iterator for IterMut<'a, V, K> {
+    type Item = (K, &'a mut V);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.slots.next().map(|(&i, &mut (version, ref mut v))| (K::with_index_and_version(i as usize, version), v))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.slots.size_hint() }
+}
+
+impl<'a, V, K: Key> Iterator for Keys<'a, V, K> {
+    type Item = K;
+    fn next(&mut self) -> Option<K> { self.iter.next().map(|(k, _)| k) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> Iterator for Values<'a, V, K> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<&'a V> { self.iter.next().map(|(_, v)| v) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> Iterator for ValuesMut<'a, V, K> {
+    type Item = &'a mut V;
+    fn next(&mut self) -> Option<&'a mut V> { self.iter.next().map(|(_, v)| v) }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<'a, V, K: Key> ExactSizeIterator for Iter     <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for IterMut  <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for Keys     <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for Values   <'a, V, K> {}
+impl<'a, V, K: Key> ExactSizeIterator for ValuesMut<'a, V, K> {}
+
+impl<'a, V, K: Key> IntoIterator for &'a SparseSecondaryMap<V, K> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, V, K>;
+    fn into_iter(self) -> Iter<'a, V, K> { self.iter() }
+}
+
+impl<'a, V, K: Key> IntoIterator for &'a mut SparseSecondaryMap<V, K> {
+    type Item = (K, &'a mut V);
+    type IntoIter = IterMut<'a, V, K>;
+    fn into_iter(self) -> IterMut<'a, V, K> { self.iter_mut() }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {DenseSlotMap, K};
+
+    #[test]
+    fn attaches_data_to_primary_keys() {
+        let mut entities = DenseSlotMap::new();
+        let keys: Vec<K> = (0..1000).map(|i| entities.insert(i)).collect();
+        let mut tags = SparseSecondaryMap::new();
+        assert_eq!(tags.insert(keys[999], "boss"), None);
+        assert_eq!(tags.insert(keys[3], "player"), None);
+        assert_eq!(tags.insert(keys[3], "hero"), Some("player"));
+        assert_eq!(tags.len(), 2);
+        assert!(tags.capacity() < 1000);
+        let mut entries = tags.iter().map(|(k, v)| (k, *v)).collect::<Vec<_>>();
+        entries.sort_by_key(|&(k, _)| k.index());
+        assert_eq!(entries, [(keys[3], "hero"), (keys[999], "boss")]);
+
+        entities.remove(keys[3]);
+        let reused = entities.insert(3);
+        assert_eq!(reused.index(), keys[3].index());
+        assert!(!tags.contains_key(reused));
+        assert_eq!(tags.insert(reused, "npc"), None, "The stale value belongs to the removed key");
+        assert_eq!(tags.get(keys[3]), None);
+        assert_eq!(tags.insert(keys[3], "hero"), None, "Stale keys are rejected");
+        assert_eq!(tags[reused], "npc");
+
+        for v in tags.values_mut() {
+            *v = "tagged";
+        }
+        tags.retain(|k, _| k != keys[999]);
+        assert_eq!(tags.keys().collect::<Vec<_>>(), [reused]);
+        assert_eq!(tags.remove(keys[3]), None);
+        assert_eq!(tags.remove(reused), Some("tagged"));
+        assert!(tags.is_empty());
+    }
+}